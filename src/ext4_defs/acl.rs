@@ -0,0 +1,296 @@
+use crate::prelude::*;
+use crate::return_errno_with_message;
+
+use super::*;
+
+/// Names of the ACL xattrs.
+pub const EXT4_XATTR_POSIX_ACL_ACCESS: &str = "system.posix_acl_access";
+pub const EXT4_XATTR_POSIX_ACL_DEFAULT: &str = "system.posix_acl_default";
+
+/// Version in the header of an on-disk ACL.
+pub const EXT4_ACL_VERSION: u32 = 0x0001;
+
+/// ACL entry tags.
+pub const ACL_USER_OBJ: u16 = 0x01;
+pub const ACL_USER: u16 = 0x02;
+pub const ACL_GROUP_OBJ: u16 = 0x04;
+pub const ACL_GROUP: u16 = 0x08;
+pub const ACL_MASK: u16 = 0x10;
+pub const ACL_OTHER: u16 = 0x20;
+
+/// Id of the entries without one.
+pub const ACL_UNDEFINED_ID: u32 = u32::MAX;
+
+/// One ACL entry, `perm` holds the rwx bits.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Ext4AclEntry {
+    pub tag: u16,
+    pub perm: u16,
+    /// Uid or gid of `ACL_USER` and `ACL_GROUP` entries.
+    pub id: u32,
+}
+
+/// A POSIX ACL, entries in the canonical order: owner, named users, owning
+/// group, named groups, mask, other.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Ext4Acl {
+    pub entries: Vec<Ext4AclEntry>,
+}
+
+impl Ext4Acl {
+    /// Decode the ext4 on-disk ACL format: a version header, then 8 byte
+    /// entries for named users and groups and 4 byte ones for the others.
+    ///
+    /// Params:
+    /// data: &[u8] - value of an ACL xattr
+    ///
+    /// Returns:
+    /// `Result<Ext4Acl>` - the ACL, `EINVAL` if it is malformed
+    pub fn from_disk(data: &[u8]) -> Result<Self> {
+        if data.len() < 4 || u32::from_le_bytes(data[..4].try_into().unwrap()) != EXT4_ACL_VERSION {
+            return_errno_with_message!(Errno::EINVAL, "bad ACL version");
+        }
+
+        let mut entries = Vec::new();
+        let mut offset = 4;
+        while offset < data.len() {
+            if offset + 4 > data.len() {
+                return_errno_with_message!(Errno::EINVAL, "truncated ACL entry");
+            }
+            let tag = u16::from_le_bytes([data[offset], data[offset + 1]]);
+            let perm = u16::from_le_bytes([data[offset + 2], data[offset + 3]]);
+            offset += 4;
+
+            let id = match tag {
+                ACL_USER | ACL_GROUP => {
+                    if offset + 4 > data.len() {
+                        return_errno_with_message!(Errno::EINVAL, "truncated ACL entry");
+                    }
+                    offset += 4;
+                    u32::from_le_bytes(data[offset - 4..offset].try_into().unwrap())
+                }
+                ACL_USER_OBJ | ACL_GROUP_OBJ | ACL_MASK | ACL_OTHER => ACL_UNDEFINED_ID,
+                _ => return_errno_with_message!(Errno::EINVAL, "bad ACL tag"),
+            };
+            entries.push(Ext4AclEntry { tag, perm, id });
+        }
+
+        let acl = Self { entries };
+        acl.validate()?;
+        Ok(acl)
+    }
+
+    /// Encode the ACL in the ext4 on-disk format.
+    pub fn to_disk(&self) -> Vec<u8> {
+        let mut data = EXT4_ACL_VERSION.to_le_bytes().to_vec();
+        for entry in &self.entries {
+            data.extend_from_slice(&entry.tag.to_le_bytes());
+            data.extend_from_slice(&entry.perm.to_le_bytes());
+            if matches!(entry.tag, ACL_USER | ACL_GROUP) {
+                data.extend_from_slice(&entry.id.to_le_bytes());
+            }
+        }
+        data
+    }
+
+    /// Check the entries are in the canonical order, with exactly one
+    /// owner, owning group and other entry, and a mask if there are named
+    /// entries, like `posix_acl_valid`.
+    pub fn validate(&self) -> Result<()> {
+        let mut last_tag = 0;
+        let mut last_id = None;
+        let mut named = false;
+        let mut has_mask = false;
+
+        for entry in &self.entries {
+            if entry.perm & !0o7 != 0 {
+                return_errno_with_message!(Errno::EINVAL, "bad ACL permissions");
+            }
+            let repeatable = matches!(entry.tag, ACL_USER | ACL_GROUP);
+            if entry.tag < last_tag || (entry.tag == last_tag && !repeatable) {
+                return_errno_with_message!(Errno::EINVAL, "ACL entries out of order");
+            }
+            if entry.tag != last_tag {
+                last_id = None;
+            }
+            if repeatable {
+                if last_id.is_some_and(|id| entry.id <= id) {
+                    return_errno_with_message!(Errno::EINVAL, "ACL entries out of order");
+                }
+                last_id = Some(entry.id);
+                named = true;
+            }
+            has_mask |= entry.tag == ACL_MASK;
+            last_tag = entry.tag;
+        }
+
+        let has = |tag| self.entries.iter().any(|e| e.tag == tag);
+        if !has(ACL_USER_OBJ) || !has(ACL_GROUP_OBJ) || !has(ACL_OTHER) || (named && !has_mask) {
+            return_errno_with_message!(Errno::EINVAL, "incomplete ACL");
+        }
+        Ok(())
+    }
+
+    /// Returns true if the ACL grants `access_mode`, like `posix_acl_permission`.
+    ///
+    /// The owner entry applies to the owner, then a named user entry, then
+    /// every group entry matching `gid`: access is granted if one of them
+    /// allows it. Named users and groups are limited by the mask. `umask`
+    /// limits the result like in `Ext4Inode::check_access`.
+    ///
+    /// Params:
+    /// inode: &Ext4Inode - inode the ACL belongs to, for its owner and group
+    /// uid: u16 - user asking for access
+    /// gid: u16 - group of that user
+    /// access_mode: u16 - `R_OK`, `W_OK` and `X_OK` bits
+    /// umask: u16 - umask of the caller
+    pub fn check_access(&self, inode: &Ext4Inode, uid: u16, gid: u16, access_mode: u16, umask: u16) -> bool {
+        let want = access_mode & 0o7;
+        if want & ((umask & 0o700) >> 6) != 0 {
+            return false;
+        }
+
+        let mask = self
+            .entries
+            .iter()
+            .find(|e| e.tag == ACL_MASK)
+            .map_or(0o7, |e| e.perm);
+        let grants = |perm: u16| perm & want == want;
+
+        let mut group_found = false;
+        for entry in &self.entries {
+            match entry.tag {
+                ACL_USER_OBJ if inode.uid == uid => return grants(entry.perm),
+                ACL_USER if entry.id == uid as u32 => return grants(entry.perm & mask),
+                ACL_GROUP_OBJ | ACL_GROUP => {
+                    let matches = if entry.tag == ACL_GROUP_OBJ {
+                        inode.gid == gid
+                    } else {
+                        entry.id == gid as u32
+                    };
+                    if matches {
+                        group_found = true;
+                        if grants(entry.perm & mask) {
+                            return true;
+                        }
+                    }
+                }
+                ACL_OTHER => return !group_found && grants(entry.perm),
+                _ => {}
+            }
+        }
+        false
+    }
+
+    /// Fit an inherited default ACL to the mode of a new inode, like
+    /// `posix_acl_create_masq`: the owner, group class and other entries
+    /// are limited by `mode`, and `mode` by them.
+    ///
+    /// Params:
+    /// mode: &mut u16 - mode of the new inode, updated
+    ///
+    /// Returns:
+    /// `bool` - true if the ACL says more than the mode bits and has to be stored
+    pub fn create_masq(&mut self, mode: &mut u16) -> bool {
+        let mut m = *mode;
+        let mut not_equiv = false;
+        let mut group_class = None;
+
+        for (i, entry) in self.entries.iter_mut().enumerate() {
+            match entry.tag {
+                ACL_USER_OBJ => {
+                    entry.perm &= (m >> 6) & 0o7;
+                    m &= (entry.perm << 6) | !0o700;
+                }
+                ACL_USER | ACL_GROUP => not_equiv = true,
+                // the mask comes after the owning group and replaces it
+                ACL_GROUP_OBJ => group_class = Some(i),
+                ACL_MASK => {
+                    group_class = Some(i);
+                    not_equiv = true;
+                }
+                ACL_OTHER => {
+                    entry.perm &= m & 0o7;
+                    m &= entry.perm | !0o007;
+                }
+                _ => {}
+            }
+        }
+
+        // the mask, or the owning group without one, stands for the group bits
+        if let Some(i) = group_class {
+            let entry = &mut self.entries[i];
+            entry.perm &= (m >> 3) & 0o7;
+            m &= (entry.perm << 3) | !0o070;
+        }
+
+        *mode = m;
+        not_equiv
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(tag: u16, perm: u16, id: u32) -> Ext4AclEntry {
+        Ext4AclEntry { tag, perm, id }
+    }
+
+    fn sample() -> Ext4Acl {
+        Ext4Acl {
+            entries: vec![
+                entry(ACL_USER_OBJ, 0o7, ACL_UNDEFINED_ID),
+                entry(ACL_USER, 0o6, 1001),
+                entry(ACL_GROUP_OBJ, 0o5, ACL_UNDEFINED_ID),
+                entry(ACL_GROUP, 0o7, 2000),
+                entry(ACL_MASK, 0o5, ACL_UNDEFINED_ID),
+                entry(ACL_OTHER, 0o0, ACL_UNDEFINED_ID),
+            ],
+        }
+    }
+
+    #[test]
+    fn test_acl_disk_format() {
+        let acl = sample();
+        let data = acl.to_disk();
+        assert_eq!(data.len(), 4 + 4 * 4 + 2 * 8);
+        assert_eq!(Ext4Acl::from_disk(&data).unwrap(), acl);
+
+        // named entries need a mask
+        let mut no_mask = sample();
+        no_mask.entries.remove(4);
+        assert!(Ext4Acl::from_disk(&no_mask.to_disk()).is_err());
+    }
+
+    #[test]
+    fn test_acl_check_access() {
+        let acl = sample();
+        let inode = Ext4Inode {
+            mode: 0o750,
+            uid: 1000,
+            gid: 1000,
+            ..Default::default()
+        };
+
+        assert!(acl.check_access(&inode, 1000, 1000, (R_OK | W_OK) as u16, 0));
+        // the named user gets rw- limited by the r-x mask
+        assert!(acl.check_access(&inode, 1001, 3000, R_OK as u16, 0));
+        assert!(!acl.check_access(&inode, 1001, 3000, W_OK as u16, 0));
+        assert!(acl.check_access(&inode, 1002, 2000, X_OK as u16, 0));
+        // a matching group that does not grant it denies, other is not tried
+        assert!(!acl.check_access(&inode, 1002, 1000, W_OK as u16, 0));
+        assert!(!acl.check_access(&inode, 1002, 3000, R_OK as u16, 0));
+    }
+
+    #[test]
+    fn test_acl_create_masq() {
+        let mut acl = sample();
+        let mut mode = 0o100640;
+        assert!(acl.create_masq(&mut mode));
+        assert_eq!(mode, 0o100640);
+        assert_eq!(acl.entries[0].perm, 0o6);
+        assert_eq!(acl.entries[4].perm, 0o4);
+        assert_eq!(acl.entries[5].perm, 0o0);
+    }
+}
@@ -1,8 +1,41 @@
 use crate::prelude::*;
+use crate::return_errno_with_message;
 
+/// Backing storage of the filesystem.
+///
+/// Implementations report device failures as `Errno::EIO`; the error is
+/// passed through unchanged to the caller of the failing operation.
 pub trait BlockDevice: Send + Sync + Any {
-    fn read_offset(&self, offset: usize) -> Vec<u8>;
-    fn write_offset(&self, offset: usize, data: &[u8]);
+    fn read_offset(&self, offset: usize) -> Result<Vec<u8>>;
+    fn write_offset(&self, offset: usize, data: &[u8]) -> Result<()>;
+
+    /// Fill `buf` with the bytes starting at `offset` in a single request.
+    ///
+    /// The file data path calls this once per run of physically contiguous
+    /// blocks, `offset` and `buf.len()` are then multiples of the block size.
+    /// The default implementation falls back to `read_offset`, devices that
+    /// handle large requests efficiently should override it.
+    fn read_blocks(&self, offset: usize, buf: &mut [u8]) -> Result<()> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            let chunk = self.read_offset(offset + filled)?;
+            if chunk.is_empty() {
+                return_errno_with_message!(Errno::EIO, "short read from block device");
+            }
+            let len = min(chunk.len(), buf.len() - filled);
+            buf[filled..filled + len].copy_from_slice(&chunk[..len]);
+            filled += len;
+        }
+        Ok(())
+    }
+
+    /// Write `data` starting at `offset` in a single request.
+    ///
+    /// Counterpart of `read_blocks`, the default implementation forwards to
+    /// `write_offset`.
+    fn write_blocks(&self, offset: usize, data: &[u8]) -> Result<()> {
+        self.write_offset(offset, data)
+    }
 }
 
 pub struct Block {
@@ -11,13 +44,25 @@ pub struct Block {
 }
 
 impl Block {
-    /// Load the block from the disk.
-    pub fn load(block_device: Arc<dyn BlockDevice>, offset: usize) -> Self {
-        let data = block_device.read_offset(offset);
-        Block {
+    /// Load `size` bytes starting at `offset` from the disk.
+    ///
+    /// `read_offset` may return less or more than `size` bytes, so keep reading
+    /// until the request is filled and drop whatever the device returned beyond it.
+    /// A device that stops returning data before that is treated as an I/O error.
+    pub fn load(block_device: Arc<dyn BlockDevice>, offset: usize, size: usize) -> Result<Self> {
+        let mut data = Vec::with_capacity(size);
+        while data.len() < size {
+            let chunk = block_device.read_offset(offset + data.len())?;
+            if chunk.is_empty() {
+                return_errno_with_message!(Errno::EIO, "short read from block device");
+            }
+            data.extend_from_slice(&chunk);
+        }
+        data.truncate(size);
+        Ok(Block {
             disk_offset: offset,
             data,
-        }
+        })
     }
 
     /// Load the block from inode block
@@ -40,10 +85,20 @@ impl Block {
     }
 
     /// Read the block as a specific type at a specific offset.
+    ///
+    /// Bytes of `T` that fall beyond the end of the block are zeroed.
     pub fn read_offset_as<T: Copy>(&self, offset: usize) -> T {
+        let len = min(size_of::<T>(), self.data.len().saturating_sub(offset));
+        let mut value = core::mem::MaybeUninit::<T>::zeroed();
         unsafe {
-            let ptr = self.data.as_ptr().add(offset) as *const T;
-            ptr.read_unaligned()
+            if len > 0 {
+                core::ptr::copy_nonoverlapping(
+                    self.data.as_ptr().add(offset),
+                    value.as_mut_ptr() as *mut u8,
+                    len,
+                );
+            }
+            value.assume_init()
         }
     }
 
@@ -77,7 +132,49 @@ impl Block {
 
 
 impl Block{
-    pub fn sync_blk_to_disk(&self, block_device: Arc<dyn BlockDevice>){
-        block_device.write_offset(self.disk_offset, &self.data);
+    pub fn sync_blk_to_disk(&self, block_device: Arc<dyn BlockDevice>) -> Result<()> {
+        block_device.write_offset(self.disk_offset, &self.data)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Serves 512-byte chunks of a fixed pattern and fails past `len`.
+    struct MemDevice {
+        len: usize,
     }
-}
\ No newline at end of file
+
+    impl BlockDevice for MemDevice {
+        fn read_offset(&self, offset: usize) -> Result<Vec<u8>> {
+            if offset >= self.len {
+                return_errno_with_message!(Errno::EIO, "read past end of device");
+            }
+            Ok((offset..offset + 512).map(|i| i as u8).collect())
+        }
+
+        fn write_offset(&self, _offset: usize, _data: &[u8]) -> Result<()> {
+            return_errno_with_message!(Errno::EIO, "read-only device");
+        }
+    }
+
+    #[test]
+    fn test_load_fills_requested_size() {
+        let dev: Arc<dyn BlockDevice> = Arc::new(MemDevice { len: 8192 });
+        let block = Block::load(dev, 1024, 4096).expect("load failed");
+        assert_eq!(block.data.len(), 4096);
+        assert_eq!(block.data[0], 0);
+        assert_eq!(block.data[4095], (1024 + 4095) as u8);
+    }
+
+    #[test]
+    fn test_device_errors_propagate() {
+        let dev: Arc<dyn BlockDevice> = Arc::new(MemDevice { len: 2048 });
+        let err = Block::load(dev.clone(), 1024, 4096).err().expect("expected EIO");
+        assert_eq!(err.error(), Errno::EIO);
+
+        let block = Block::load(dev.clone(), 0, 1024).expect("load failed");
+        let err = block.sync_blk_to_disk(dev).expect_err("expected EIO");
+        assert_eq!(err.error(), Errno::EIO);
+    }
+}
@@ -39,17 +39,18 @@ impl Ext4BlockGroup {
         block_device: Arc<dyn BlockDevice>,
         super_block: &Ext4Superblock,
         block_group_idx: usize,
-    ) -> Self {
-        let dsc_cnt = BLOCK_SIZE / super_block.desc_size as usize;
+    ) -> Result<Self> {
+        let block_size = super_block.block_size() as usize;
+        let dsc_cnt = block_size / super_block.desc_size() as usize;
         let dsc_id = block_group_idx / dsc_cnt;
         let first_data_block = super_block.first_data_block;
         let block_id = first_data_block as usize + dsc_id + 1;
-        let offset = (block_group_idx % dsc_cnt) * super_block.desc_size as usize;
+        let offset = (block_group_idx % dsc_cnt) * super_block.desc_size() as usize;
 
-        let ext4block = Block::load(block_device, block_id * BLOCK_SIZE);
+        let ext4block = Block::load(block_device, block_id * block_size, block_size)?;
         let bg: Ext4BlockGroup = ext4block.read_offset_as(offset);
 
-        bg
+        Ok(bg)
     }
 }
 
@@ -125,6 +126,26 @@ impl Ext4BlockGroup {
     pub fn get_inode_table_blk_num(&self) -> u32 {
         ((self.inode_table_first_block_hi as u64) << 32) as u32 | self.inode_table_first_block_lo
     }
+
+    /// Check if the block bitmap of this block group has not been initialized yet.
+    pub fn is_block_uninit(&self) -> bool {
+        self.flags & EXT4_BLOCK_GROUP_BLOCK_UNINIT != 0
+    }
+
+    /// Mark the block bitmap of this block group as initialized.
+    pub fn clear_block_uninit(&mut self) {
+        self.flags &= !EXT4_BLOCK_GROUP_BLOCK_UNINIT;
+    }
+
+    /// Returns true if the inode bitmap of this group was never initialized.
+    pub fn is_inode_uninit(&self) -> bool {
+        self.flags & EXT4_BLOCK_GROUP_INODE_UNINIT != 0
+    }
+
+    /// Mark the inode bitmap of this block group as initialized.
+    pub fn clear_inode_uninit(&mut self) {
+        self.flags &= !EXT4_BLOCK_GROUP_INODE_UNINIT;
+    }
 }
 
 /// sync block group to disk
@@ -170,19 +191,66 @@ impl Ext4BlockGroup {
         block_device: Arc<dyn BlockDevice>,
         bgid: usize,
         super_block: &Ext4Superblock,
-    ) {
-        let dsc_cnt = BLOCK_SIZE / super_block.desc_size as usize;
+    ) -> Result<()> {
+        let block_size = super_block.block_size() as usize;
+        let dsc_cnt = block_size / super_block.desc_size() as usize;
         // let dsc_per_block = dsc_cnt;
         let dsc_id = bgid / dsc_cnt;
         // let first_meta_bg = super_block.first_meta_bg;
         let first_data_block = super_block.first_data_block;
         let block_id = first_data_block as usize + dsc_id + 1;
-        let offset = (bgid % dsc_cnt) * super_block.desc_size as usize;
+        let offset = (bgid % dsc_cnt) * super_block.desc_size() as usize;
 
+        // 32-byte descriptors must not spill into the next one
         let data = unsafe {
-            core::slice::from_raw_parts(self as *const _ as *const u8, size_of::<Ext4BlockGroup>())
+            core::slice::from_raw_parts(
+                self as *const _ as *const u8,
+                super_block.desc_size() as usize,
+            )
         };
-        block_device.write_offset(block_id * BLOCK_SIZE + offset, data);
+        block_device.write_offset(block_id * block_size + offset, data)
+    }
+
+    /// Returns true if the stored descriptor checksum matches its content.
+    pub fn verify_checksum(&self, bgid: u32, super_block: &Ext4Superblock) -> bool {
+        let mut bg = *self;
+        bg.get_block_group_checksum(bgid, super_block) == self.checksum
+    }
+
+    /// Returns the checksum of the block bitmap stored in the descriptor.
+    pub fn get_block_bitmap_csum(&self, s: &Ext4Superblock) -> u32 {
+        let mut v = self.block_bitmap_csum_lo as u32;
+        if s.desc_size() == EXT4_MAX_BLOCK_GROUP_DESCRIPTOR_SIZE {
+            v |= (self.block_bitmap_csum_hi as u32) << 16;
+        }
+        v
+    }
+
+    /// Returns the checksum of the inode bitmap stored in the descriptor.
+    pub fn get_inode_bitmap_csum(&self, s: &Ext4Superblock) -> u32 {
+        let mut v = self.inode_bitmap_csum_lo as u32;
+        if s.desc_size() == EXT4_MAX_BLOCK_GROUP_DESCRIPTOR_SIZE {
+            v |= (self.inode_bitmap_csum_hi as u32) << 16;
+        }
+        v
+    }
+
+    /// Returns true if the stored block bitmap checksum matches `bitmap`.
+    pub fn verify_block_bitmap_csum(&self, s: &Ext4Superblock, bitmap: &[u8]) -> bool {
+        let mut csum = s.ext4_balloc_bitmap_csum(bitmap);
+        if s.desc_size() < EXT4_MAX_BLOCK_GROUP_DESCRIPTOR_SIZE {
+            csum &= 0xFFFF;
+        }
+        csum == self.get_block_bitmap_csum(s)
+    }
+
+    /// Returns true if the stored inode bitmap checksum matches `bitmap`.
+    pub fn verify_inode_bitmap_csum(&self, s: &Ext4Superblock, bitmap: &[u8]) -> bool {
+        let mut csum = s.ext4_ialloc_bitmap_csum(bitmap);
+        if s.desc_size() < EXT4_MAX_BLOCK_GROUP_DESCRIPTOR_SIZE {
+            csum &= 0xFFFF;
+        }
+        csum == self.get_inode_bitmap_csum(s)
     }
 
     /// Set the checksum of the block group descriptor.
@@ -197,7 +265,7 @@ impl Ext4BlockGroup {
         block_device: Arc<dyn BlockDevice>,
         bgid: usize,
         super_block: &Ext4Superblock,
-    ) {
+    ) -> Result<()> {
         self.set_block_group_checksum(bgid as u32, super_block);
         self.sync_block_group_to_disk(block_device, bgid, super_block)
     }
@@ -210,7 +278,7 @@ impl Ext4BlockGroup {
         let lo_csum = (csum & 0xFFFF).to_le();
         let hi_csum = (csum >> 16).to_le();
 
-        if (s.features_read_only & 0x400) >> 10 == 0 {
+        if !s.has_metadata_csum() {
             return;
         }
         self.block_bitmap_csum_lo = lo_csum as u16;
@@ -243,7 +311,7 @@ impl Ext4BlockGroup {
         let lo_csum = (csum & 0xFFFF).to_le();
         let hi_csum = (csum >> 16).to_le();
 
-        if (s.features_read_only & 0x400) >> 10 == 0 {
+        if !s.has_metadata_csum() {
             return;
         }
         self.inode_bitmap_csum_lo = lo_csum as u16;
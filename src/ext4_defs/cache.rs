@@ -0,0 +1,719 @@
+use crate::prelude::*;
+use crate::return_errno_with_message;
+
+use super::*;
+
+/// Default number of blocks kept in memory by the buffer cache.
+pub const BLOCK_CACHE_DEFAULT_CAPACITY: usize = 1024;
+
+/// A cached copy of one filesystem block.
+struct CacheEntry {
+    data: Vec<u8>,
+    /// Modified in memory and not yet written to the device.
+    dirty: bool,
+    /// Pinned entries are never evicted.
+    pins: usize,
+    /// Position in the LRU order, larger is more recent.
+    tick: u64,
+}
+
+/// Content and dirty state of a block before an update wrote it, `None`
+/// for a block that was not cached.
+type SavedBlock = Option<(Vec<u8>, bool)>;
+
+/// The journal transaction running in the cache.
+#[derive(Default)]
+struct RunningTransaction {
+    /// Blocks written since the transaction started, logged at commit.
+    blocks: BTreeSet<u64>,
+    /// Blocks released from the transaction because they were freed. They
+    /// are not logged but stay held until the transaction ends.
+    released: BTreeSet<u64>,
+    /// The blocks written by the current update, as they were before it.
+    /// `None` outside an update.
+    undo: Option<BTreeMap<u64, SavedBlock>>,
+}
+
+struct CacheInner {
+    capacity: usize,
+    entries: BTreeMap<u64, CacheEntry>,
+    /// tick -> block number, the first entry is the least recently used block.
+    lru: BTreeMap<u64, u64>,
+    tick: u64,
+    /// The running journal transaction, `None` when no transaction is
+    /// running. Its blocks are held in memory until it is committed.
+    running: Option<RunningTransaction>,
+    /// Writes are refused with `EROFS`.
+    read_only: bool,
+}
+
+/// LRU write-back buffer cache sitting between `Ext4` and the `BlockDevice`.
+///
+/// The cache is itself a `BlockDevice` addressed by byte offset, so every
+/// `Block::load` and `write_offset` done by the filesystem goes through it.
+/// Writes only update the cached block and mark it dirty; dirty blocks reach
+/// the device when they are evicted or when `sync` is called. Contiguous
+/// dirty blocks are written back with a single device request.
+///
+/// While a journal transaction is running, blocks written through the cache
+/// are held: they are neither evicted nor written back until the transaction
+/// is taken for commit with `take_transaction`. The writes of one update,
+/// the work of a single journal handle, can be undone with `abort_update`.
+///
+/// Runs of whole blocks passed to `read_blocks`/`write_blocks`, which is how
+/// file data moves, go straight to the device so that large transfers reach
+/// it as large requests and do not push metadata out of the cache.
+pub struct BlockCache {
+    device: Arc<dyn BlockDevice>,
+    block_size: usize,
+    inner: Mutex<CacheInner>,
+}
+
+impl CacheInner {
+    /// Held by the running transaction, must not reach the device yet.
+    fn is_held(&self, block: u64) -> bool {
+        self.running
+            .as_ref()
+            .is_some_and(|r| r.blocks.contains(&block) || r.released.contains(&block))
+    }
+
+    /// Remember the content of `block` before the running update writes it
+    /// for the first time.
+    fn save_undo(&mut self, block: u64) {
+        let Some(undo) = self.running.as_mut().and_then(|r| r.undo.as_mut()) else {
+            return;
+        };
+        let entries = &self.entries;
+        undo.entry(block)
+            .or_insert_with(|| entries.get(&block).map(|e| (e.data.clone(), e.dirty)));
+    }
+
+    /// Dirty and free to be written back.
+    fn is_write_back_candidate(&self, block: u64) -> bool {
+        self.entries.get(&block).is_some_and(|e| e.dirty) && !self.is_held(block)
+    }
+}
+
+impl BlockCache {
+    /// Create a cache of `capacity` blocks of `block_size` bytes over `device`.
+    pub fn new(device: Arc<dyn BlockDevice>, block_size: usize, capacity: usize) -> Self {
+        BlockCache {
+            device,
+            block_size,
+            inner: Mutex::new(CacheInner {
+                capacity: capacity.max(1),
+                entries: BTreeMap::new(),
+                lru: BTreeMap::new(),
+                tick: 0,
+                running: None,
+                read_only: false,
+            }),
+        }
+    }
+
+    /// The device behind the cache.
+    pub fn device(&self) -> Arc<dyn BlockDevice> {
+        self.device.clone()
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.inner.lock().capacity
+    }
+
+    /// Change the number of cached blocks, evicting (and writing back) blocks
+    /// that no longer fit.
+    pub fn set_capacity(&self, capacity: usize) -> Result<()> {
+        let mut inner = self.inner.lock();
+        inner.capacity = capacity.max(1);
+        self.shrink(&mut inner, 0)
+    }
+
+    /// Refuse every write with `EROFS`, used for read-only mounts.
+    pub fn set_read_only(&self, read_only: bool) {
+        self.inner.lock().read_only = read_only;
+    }
+
+    /// Number of blocks currently held in memory.
+    pub fn cached_blocks(&self) -> usize {
+        self.inner.lock().entries.len()
+    }
+
+    /// Number of blocks modified in memory and not yet written back.
+    pub fn dirty_blocks(&self) -> usize {
+        self.inner.lock().entries.values().filter(|e| e.dirty).count()
+    }
+
+    /// Keep `block` in memory until a matching `unpin`.
+    ///
+    /// Pins nest, the block becomes evictable again once every pin is released.
+    pub fn pin(&self, block: u64) -> Result<()> {
+        let mut inner = self.inner.lock();
+        self.entry(&mut inner, block, false)?.pins += 1;
+        Ok(())
+    }
+
+    /// Release one pin taken by `pin`.
+    pub fn unpin(&self, block: u64) {
+        let mut inner = self.inner.lock();
+        if let Some(entry) = inner.entries.get_mut(&block) {
+            entry.pins = entry.pins.saturating_sub(1);
+        }
+    }
+
+    /// Write every dirty block back to the device, except the blocks held by
+    /// the running transaction.
+    pub fn sync(&self) -> Result<()> {
+        let mut inner = self.inner.lock();
+        let dirty: Vec<u64> = inner
+            .entries
+            .iter()
+            .filter(|(&b, e)| e.dirty && !inner.is_held(b))
+            .map(|(&b, _)| b)
+            .collect();
+
+        let mut i = 0;
+        while i < dirty.len() {
+            // extend the run while the next dirty block is adjacent
+            let mut j = i + 1;
+            while j < dirty.len() && dirty[j] == dirty[j - 1] + 1 {
+                j += 1;
+            }
+            self.write_run(&mut inner, dirty[i], (j - i) as u64)?;
+            i = j;
+        }
+        Ok(())
+    }
+
+    /// Drop every clean, unpinned block from memory.
+    pub fn invalidate_clean(&self) {
+        let mut inner = self.inner.lock();
+        let victims: Vec<(u64, u64)> = inner
+            .entries
+            .iter()
+            .filter(|(_, e)| !e.dirty && e.pins == 0)
+            .map(|(&b, e)| (b, e.tick))
+            .collect();
+        for (block, tick) in victims {
+            inner.entries.remove(&block);
+            inner.lru.remove(&tick);
+        }
+    }
+
+    /// Start holding written blocks for a journal transaction.
+    pub fn start_transaction(&self) {
+        let mut inner = self.inner.lock();
+        if inner.running.is_none() {
+            inner.running = Some(RunningTransaction::default());
+        }
+    }
+
+    /// Start recording the blocks written by an update of the running
+    /// transaction so that `abort_update` can restore them.
+    pub fn start_update(&self) {
+        if let Some(running) = self.inner.lock().running.as_mut() {
+            running.undo = Some(BTreeMap::new());
+        }
+    }
+
+    /// The update succeeded, forget what it wrote over.
+    pub fn finish_update(&self) {
+        if let Some(running) = self.inner.lock().running.as_mut() {
+            running.undo = None;
+        }
+    }
+
+    /// Undo every write of the current update.
+    ///
+    /// Blocks written by the update get back their previous content, blocks
+    /// that were not cached before it are dropped. The writes of earlier
+    /// updates stay in the running transaction.
+    pub fn abort_update(&self) {
+        let mut inner = self.inner.lock();
+        let inner = &mut *inner;
+        let Some(running) = inner.running.as_mut() else {
+            return;
+        };
+        let Some(undo) = running.undo.take() else {
+            return;
+        };
+
+        for (block, saved) in undo {
+            // the block may have been freed by the update only
+            if running.released.remove(&block) {
+                running.blocks.insert(block);
+            }
+            match saved {
+                Some((data, dirty)) => {
+                    // held blocks are never evicted
+                    let entry = inner.entries.get_mut(&block).unwrap();
+                    entry.data = data;
+                    entry.dirty = dirty;
+                }
+                None => {
+                    running.blocks.remove(&block);
+                    if let Some(entry) = inner.entries.remove(&block) {
+                        inner.lru.remove(&entry.tick);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn transaction_running(&self) -> bool {
+        self.inner.lock().running.is_some()
+    }
+
+    /// Number of blocks held by the running transaction.
+    pub fn transaction_blocks(&self) -> usize {
+        self.inner.lock().running.as_ref().map_or(0, |r| r.blocks.len())
+    }
+
+    /// End the running transaction and return a copy of every block it holds.
+    ///
+    /// The blocks stay dirty in the cache and are written back as usual from
+    /// now on, the caller must have logged the copies before that can happen.
+    pub fn take_transaction(&self) -> Vec<(u64, Vec<u8>)> {
+        let mut inner = self.inner.lock();
+        let Some(running) = inner.running.take() else {
+            return Vec::new();
+        };
+        running
+            .blocks
+            .into_iter()
+            .filter_map(|b| inner.entries.get(&b).map(|e| (b, e.data.clone())))
+            .collect()
+    }
+
+    /// Drop the blocks in `first..first + count` from the blocks logged by
+    /// the running transaction, used when they are freed. They are still
+    /// not written back before the transaction ends. Returns the released
+    /// blocks.
+    pub fn release_from_transaction(&self, first: u64, count: u64) -> Vec<u64> {
+        let mut inner = self.inner.lock();
+        let Some(running) = inner.running.as_mut() else {
+            return Vec::new();
+        };
+        let released: Vec<u64> = running.blocks.range(first..first + count).copied().collect();
+        for block in released.iter() {
+            running.blocks.remove(block);
+            running.released.insert(*block);
+        }
+        released
+    }
+
+    /// Look up `block`, loading it from the device unless `overwrite` says the
+    /// caller is about to replace its whole content.
+    fn entry<'a>(
+        &self,
+        inner: &'a mut CacheInner,
+        block: u64,
+        overwrite: bool,
+    ) -> Result<&'a mut CacheEntry> {
+        inner.tick += 1;
+        let tick = inner.tick;
+
+        if let Some(old_tick) = inner.entries.get(&block).map(|e| e.tick) {
+            inner.lru.remove(&old_tick);
+            inner.lru.insert(tick, block);
+            let entry = inner.entries.get_mut(&block).unwrap();
+            entry.tick = tick;
+            return Ok(entry);
+        }
+
+        self.shrink(inner, 1)?;
+
+        let data = if overwrite {
+            vec![0u8; self.block_size]
+        } else {
+            Block::load(
+                self.device.clone(),
+                block as usize * self.block_size,
+                self.block_size,
+            )?
+            .data
+        };
+
+        inner.lru.insert(tick, block);
+        inner.entries.insert(
+            block,
+            CacheEntry {
+                data,
+                dirty: false,
+                pins: 0,
+                tick,
+            },
+        );
+        Ok(inner.entries.get_mut(&block).unwrap())
+    }
+
+    /// Evict least recently used blocks until `room` more blocks fit.
+    ///
+    /// If every cached block is pinned the cache is allowed to grow.
+    fn shrink(&self, inner: &mut CacheInner, room: usize) -> Result<()> {
+        while inner.entries.len() + room > inner.capacity {
+            let victim = inner
+                .lru
+                .values()
+                .copied()
+                .find(|&b| inner.entries[&b].pins == 0 && !inner.is_held(b));
+            let Some(victim) = victim else {
+                break;
+            };
+
+            if inner.entries[&victim].dirty {
+                // take the dirty neighbours along, they are likely to be evicted soon
+                let mut first = victim;
+                while first > 0 && inner.is_write_back_candidate(first - 1) {
+                    first -= 1;
+                }
+                let mut last = victim;
+                while inner.is_write_back_candidate(last + 1) {
+                    last += 1;
+                }
+                self.write_run(inner, first, last - first + 1)?;
+            }
+
+            let entry = inner.entries.remove(&victim).unwrap();
+            inner.lru.remove(&entry.tick);
+        }
+        Ok(())
+    }
+
+    /// Fill `buf` from cached blocks, loading missing ones.
+    fn read_cached(&self, offset: usize, buf: &mut [u8]) -> Result<()> {
+        let mut inner = self.inner.lock();
+        let mut filled = 0;
+
+        while filled < buf.len() {
+            let pos = offset + filled;
+            let block = (pos / self.block_size) as u64;
+            let start = pos % self.block_size;
+            let len = min(self.block_size - start, buf.len() - filled);
+
+            let entry = self.entry(&mut inner, block, false)?;
+            buf[filled..filled + len].copy_from_slice(&entry.data[start..start + len]);
+
+            filled += len;
+        }
+        Ok(())
+    }
+
+    /// Write `data` at `offset` into the cached blocks, marking them dirty
+    /// and adding them to the running transaction.
+    fn write_cached(&self, inner: &mut CacheInner, offset: usize, data: &[u8]) -> Result<()> {
+        let mut written = 0;
+
+        while written < data.len() {
+            let pos = offset + written;
+            let block = (pos / self.block_size) as u64;
+            let start = pos % self.block_size;
+            let len = min(self.block_size - start, data.len() - written);
+
+            inner.save_undo(block);
+            let overwrite = start == 0 && len == self.block_size;
+            let entry = self.entry(inner, block, overwrite)?;
+            entry.data[start..start + len].copy_from_slice(&data[written..written + len]);
+            entry.dirty = true;
+            if let Some(running) = inner.running.as_mut() {
+                running.blocks.insert(block);
+            }
+
+            written += len;
+        }
+        Ok(())
+    }
+
+    /// Write `count` cached dirty blocks starting at `first` with one request.
+    fn write_run(&self, inner: &mut CacheInner, first: u64, count: u64) -> Result<()> {
+        let mut buf = Vec::with_capacity(count as usize * self.block_size);
+        for block in first..first + count {
+            buf.extend_from_slice(&inner.entries[&block].data);
+        }
+        self.device
+            .write_offset(first as usize * self.block_size, &buf)?;
+        for block in first..first + count {
+            inner.entries.get_mut(&block).unwrap().dirty = false;
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for BlockCache {
+    /// Returns the cached data from `offset` up to the end of its block.
+    fn read_offset(&self, offset: usize) -> Result<Vec<u8>> {
+        let block = (offset / self.block_size) as u64;
+        let start = offset % self.block_size;
+
+        let mut inner = self.inner.lock();
+        let entry = self.entry(&mut inner, block, false)?;
+        Ok(entry.data[start..].to_vec())
+    }
+
+    fn write_offset(&self, offset: usize, data: &[u8]) -> Result<()> {
+        let mut inner = self.inner.lock();
+        if inner.read_only {
+            return_errno_with_message!(Errno::EROFS, "block cache is read-only");
+        }
+        self.write_cached(&mut inner, offset, data)
+    }
+
+    /// Whole-block requests bypass the cache and go to the device as a single
+    /// request, cached copies of the blocks in range take precedence since
+    /// they may hold data that has not been written back yet.
+    fn read_blocks(&self, offset: usize, buf: &mut [u8]) -> Result<()> {
+        if offset % self.block_size != 0 || buf.len() % self.block_size != 0 {
+            return self.read_cached(offset, buf);
+        }
+
+        let first = (offset / self.block_size) as u64;
+        let count = (buf.len() / self.block_size) as u64;
+
+        let inner = self.inner.lock();
+        self.device.read_blocks(offset, buf)?;
+        for (&block, entry) in inner.entries.range(first..first + count) {
+            let start = (block - first) as usize * self.block_size;
+            buf[start..start + self.block_size].copy_from_slice(&entry.data);
+        }
+        Ok(())
+    }
+
+    /// Whole-block requests are written through to the device as a single
+    /// request. Cached copies of the blocks in range are refreshed and become
+    /// clean, blocks that are not cached are not pulled into the cache.
+    ///
+    /// Blocks held by the running transaction must not reach the device
+    /// before it commits, they are written to the cache instead and split
+    /// the request.
+    fn write_blocks(&self, offset: usize, data: &[u8]) -> Result<()> {
+        if offset % self.block_size != 0 || data.len() % self.block_size != 0 {
+            return self.write_offset(offset, data);
+        }
+
+        let first = (offset / self.block_size) as u64;
+        let count = (data.len() / self.block_size) as u64;
+
+        let mut inner = self.inner.lock();
+        if inner.read_only {
+            return_errno_with_message!(Errno::EROFS, "block cache is read-only");
+        }
+
+        let mut i = 0;
+        while i < count {
+            let start = i as usize * self.block_size;
+            if inner.is_held(first + i) {
+                self.write_cached(&mut inner, offset + start, &data[start..start + self.block_size])?;
+                i += 1;
+                continue;
+            }
+
+            let mut j = i + 1;
+            while j < count && !inner.is_held(first + j) {
+                j += 1;
+            }
+            let end = j as usize * self.block_size;
+            self.device.write_blocks(offset + start, &data[start..end])?;
+            for (&block, entry) in inner.entries.range_mut(first + i..first + j) {
+                let pos = (block - first) as usize * self.block_size;
+                entry.data.copy_from_slice(&data[pos..pos + self.block_size]);
+                entry.dirty = false;
+            }
+            i = j;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for BlockCache {
+    fn drop(&mut self) {
+        if let Err(e) = self.sync() {
+            log::error!("block cache write back failed on drop: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BS: usize = 1024;
+
+    /// In-memory device recording every write request.
+    struct MemDevice {
+        data: Mutex<Vec<u8>>,
+        writes: Mutex<Vec<(usize, usize)>>,
+    }
+
+    impl MemDevice {
+        fn new(blocks: usize) -> Arc<Self> {
+            Arc::new(MemDevice {
+                data: Mutex::new(vec![0; blocks * BS]),
+                writes: Mutex::new(Vec::new()),
+            })
+        }
+    }
+
+    impl BlockDevice for MemDevice {
+        fn read_offset(&self, offset: usize) -> Result<Vec<u8>> {
+            Ok(self.data.lock()[offset..offset + BS].to_vec())
+        }
+
+        fn write_offset(&self, offset: usize, data: &[u8]) -> Result<()> {
+            self.data.lock()[offset..offset + data.len()].copy_from_slice(data);
+            self.writes.lock().push((offset, data.len()));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_write_back_on_sync() {
+        let dev = MemDevice::new(16);
+        let cache = BlockCache::new(dev.clone(), BS, 8);
+
+        cache.write_offset(BS + 10, &[1, 2, 3]).unwrap();
+        assert_eq!(cache.read_offset(BS + 10).unwrap()[..3], [1, 2, 3]);
+        assert!(dev.writes.lock().is_empty());
+        assert_eq!(cache.dirty_blocks(), 1);
+
+        cache.sync().unwrap();
+        assert_eq!(dev.data.lock()[BS + 10..BS + 13], [1, 2, 3]);
+        assert_eq!(cache.dirty_blocks(), 0);
+    }
+
+    #[test]
+    fn test_sync_coalesces_contiguous_blocks() {
+        let dev = MemDevice::new(16);
+        let cache = BlockCache::new(dev.clone(), BS, 16);
+
+        cache.write_offset(2 * BS, &[7; 3 * BS]).unwrap();
+        cache.write_offset(8 * BS, &[9; 4]).unwrap();
+        cache.sync().unwrap();
+
+        assert_eq!(*dev.writes.lock(), [(2 * BS, 3 * BS), (8 * BS, BS)]);
+    }
+
+    #[test]
+    fn test_lru_eviction_and_pinning() {
+        let dev = MemDevice::new(16);
+        let cache = BlockCache::new(dev.clone(), BS, 2);
+
+        cache.pin(0).unwrap();
+        cache.write_offset(BS, &[1]).unwrap();
+        // block 1 is the only evictable block, it is written back on eviction
+        cache.read_offset(2 * BS).unwrap();
+        assert_eq!(cache.cached_blocks(), 2);
+        assert_eq!(*dev.writes.lock(), [(BS, BS)]);
+        assert_eq!(dev.data.lock()[BS], 1);
+
+        // everything pinned, the cache grows instead of failing
+        cache.pin(2).unwrap();
+        cache.read_offset(3 * BS).unwrap();
+        assert_eq!(cache.cached_blocks(), 3);
+
+        cache.unpin(0);
+        cache.read_offset(4 * BS).unwrap();
+        assert_eq!(cache.cached_blocks(), 2);
+    }
+
+    #[test]
+    fn test_read_only_refuses_writes() {
+        let dev = MemDevice::new(4);
+        let cache = BlockCache::new(dev.clone(), BS, 2);
+
+        cache.set_read_only(true);
+        assert_eq!(cache.write_offset(0, &[1]).unwrap_err().error(), Errno::EROFS);
+        assert_eq!(cache.write_blocks(BS, &[1; BS]).unwrap_err().error(), Errno::EROFS);
+        cache.read_offset(0).unwrap();
+        cache.sync().unwrap();
+        assert!(dev.writes.lock().is_empty());
+    }
+
+    #[test]
+    fn test_transaction_holds_blocks() {
+        let dev = MemDevice::new(16);
+        let cache = BlockCache::new(dev.clone(), BS, 2);
+
+        cache.start_transaction();
+        cache.write_offset(BS, &[1]).unwrap();
+        cache.write_offset(3 * BS, &[3]).unwrap();
+        // held blocks are neither written back nor evicted
+        cache.sync().unwrap();
+        cache.read_offset(5 * BS).unwrap();
+        assert!(dev.writes.lock().is_empty());
+        assert_eq!(cache.transaction_blocks(), 2);
+
+        assert_eq!(cache.release_from_transaction(3, 1), [3]);
+        let blocks = cache.take_transaction();
+        assert_eq!(blocks.len(), 1);
+        assert_eq!((blocks[0].0, blocks[0].1[0]), (1, 1));
+        assert!(!cache.transaction_running());
+
+        cache.sync().unwrap();
+        assert_eq!(dev.data.lock()[BS], 1);
+        assert_eq!(dev.data.lock()[3 * BS], 3);
+    }
+
+    #[test]
+    fn test_abort_update() {
+        let dev = MemDevice::new(16);
+        let cache = BlockCache::new(dev.clone(), BS, 16);
+
+        cache.write_offset(BS, &[1]).unwrap();
+        cache.start_transaction();
+        cache.start_update();
+        cache.write_offset(2 * BS, &[2]).unwrap();
+        cache.finish_update();
+
+        cache.start_update();
+        cache.write_offset(BS, &[3]).unwrap();
+        cache.write_offset(2 * BS, &[4]).unwrap();
+        cache.write_offset(4 * BS, &[5]).unwrap();
+        cache.release_from_transaction(2, 1);
+        cache.abort_update();
+
+        // the first update is kept, the second one is gone
+        assert_eq!(cache.read_offset(BS).unwrap()[0], 1);
+        assert_eq!(cache.read_offset(2 * BS).unwrap()[0], 2);
+        assert_eq!(cache.read_offset(4 * BS).unwrap()[0], 0);
+        let blocks: Vec<u64> = cache.take_transaction().into_iter().map(|(b, _)| b).collect();
+        assert_eq!(blocks, [1, 2]);
+        assert!(dev.writes.lock().is_empty());
+    }
+
+    #[test]
+    fn test_block_runs_skip_held_blocks() {
+        let dev = MemDevice::new(16);
+        let cache = BlockCache::new(dev.clone(), BS, 16);
+
+        cache.start_transaction();
+        cache.write_offset(3 * BS, &[5; 4]).unwrap();
+        cache.write_blocks(2 * BS, &[6; 4 * BS]).unwrap();
+
+        // the held block stays in memory, the run is split around it
+        assert_eq!(*dev.writes.lock(), [(2 * BS, BS), (4 * BS, 2 * BS)]);
+        assert_eq!(dev.data.lock()[3 * BS], 0);
+        assert_eq!(cache.read_offset(3 * BS).unwrap()[0], 6);
+        assert_eq!(cache.transaction_blocks(), 1);
+    }
+
+    #[test]
+    fn test_block_runs_bypass_cache() {
+        let dev = MemDevice::new(16);
+        let cache = BlockCache::new(dev.clone(), BS, 16);
+
+        // a dirty cached block inside the run is seen by reads and refreshed by writes
+        cache.write_offset(3 * BS, &[5; 4]).unwrap();
+        let mut buf = vec![0; 4 * BS];
+        cache.read_blocks(2 * BS, &mut buf).unwrap();
+        assert_eq!(buf[BS..BS + 4], [5; 4]);
+        assert_eq!(cache.cached_blocks(), 1);
+
+        cache.write_blocks(2 * BS, &[6; 4 * BS]).unwrap();
+        assert_eq!(*dev.writes.lock(), [(2 * BS, 4 * BS)]);
+        assert_eq!(cache.dirty_blocks(), 0);
+        assert_eq!(cache.read_offset(3 * BS).unwrap()[0], 6);
+    }
+}
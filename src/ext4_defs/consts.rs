@@ -1,7 +1,14 @@
 use bitflags::bitflags;
 
+/// Default I/O size for `BlockDevice` implementations.
+///
+/// The filesystem itself always uses the block size recorded in the superblock,
+/// see `Ext4Superblock::block_size`.
 pub const BLOCK_SIZE: usize = 4096;
 
+/// Largest device request of `Ext4::copy_file_range`, a multiple of every block size.
+pub const COPY_CHUNK_SIZE: usize = 4 << 20;
+
 pub type Ext4Lblk = u32;
 pub type Ext4Fsblk = u64;
 
@@ -17,21 +24,52 @@ pub const EXT4_INODE_MODE_TYPE_MASK: u16 = 0xF000;
 pub const EXT4_INODE_MODE_PERM_MASK: u16 = 0x0FFF;
 pub const EXT4_INODE_BLOCK_SIZE: usize = 512;
 pub const EXT4_GOOD_OLD_INODE_SIZE: u16 = 128;
+pub const EXT4_INODE_FLAG_INDEX: u32 = 0x00001000; /* Hash-indexed directory */
 pub const EXT4_INODE_FLAG_EXTENTS: usize = 0x00080000; /* Inode uses extents */
 
+/// Indirect block map
+pub const EXT4_NDIR_BLOCKS: usize = 12;
+pub const EXT4_IND_BLOCK: usize = EXT4_NDIR_BLOCKS;
+pub const EXT4_DIND_BLOCK: usize = EXT4_IND_BLOCK + 1;
+pub const EXT4_TIND_BLOCK: usize = EXT4_DIND_BLOCK + 1;
+pub const EXT4_FAST_SYMLINK_MAX: usize = 59; /* Longest symlink target kept in i_block */
+pub const EXT4_MAX_SYMLINK_HOPS: usize = 40; /* Links followed in one lookup before ELOOP */
+
+/// Dir entry
+pub const EXT4_NAME_LEN: usize = 255;
+
 /// Extent
 pub const EXT_INIT_MAX_LEN: u16 = 32768;
 pub const EXT_UNWRITTEN_MAX_LEN: u16 = 65535;
 pub const EXT_MAX_BLOCKS: Ext4Lblk = u32::MAX;
 pub const EXT4_EXTENT_MAGIC: u16 = 0xF30A;
 
+/// Directory index (htree)
+pub const EXT4_DX_HASH_LEGACY: u8 = 0;
+pub const EXT4_DX_HASH_HALF_MD4: u8 = 1;
+pub const EXT4_DX_HASH_TEA: u8 = 2;
+pub const EXT4_DX_HASH_LEGACY_UNSIGNED: u8 = 3;
+pub const EXT4_DX_HASH_HALF_MD4_UNSIGNED: u8 = 4;
+pub const EXT4_DX_HASH_TEA_UNSIGNED: u8 = 5;
+pub const EXT4_DX_HASH_SIPHASH: u8 = 6;
+pub const EXT4_HTREE_EOF_32BIT: u32 = 0x7fffffff;
+/// Index levels, dx_root included, without the largedir feature.
+pub const EXT4_HTREE_LEVEL: u8 = 2;
+
 /// BLock group descriptor flags.
 pub const EXT4_MIN_BLOCK_GROUP_DESCRIPTOR_SIZE: u16 = 32;
 pub const EXT4_MAX_BLOCK_GROUP_DESCRIPTOR_SIZE: u16 = 64;
+pub const EXT4_BLOCK_GROUP_INODE_UNINIT: u16 = 0x0001;
+pub const EXT4_BLOCK_GROUP_BLOCK_UNINIT: u16 = 0x0002;
+pub const EXT4_BLOCK_GROUP_ITABLE_ZEROED: u16 = 0x0004;
 
 /// SuperBlock
 pub const SUPERBLOCK_OFFSET: usize = 1024;
 pub const EXT4_SUPERBLOCK_OS_HURD: u32 = 1;
+pub const EXT4_SUPERBLOCK_MAGIC: u16 = 0xEF53;
+pub const EXT4_CRC32C_CHKSUM: u8 = 1;
+pub const EXT4_FLAGS_SIGNED_HASH: u32 = 0x0001;
+pub const EXT4_FLAGS_UNSIGNED_HASH: u32 = 0x0002;
 
 /// File
 /// libc file open flags
@@ -59,4 +97,33 @@ pub const O_DSYNC: i32 = 0o010000;
 pub const F_OK: i32 = 0;
 pub const R_OK: i32 = 4;
 pub const W_OK: i32 = 2;
-pub const X_OK: i32 = 1;
\ No newline at end of file
+pub const X_OK: i32 = 1;
+/// linux renameat2 flags
+pub const RENAME_NOREPLACE: u32 = 1 << 0;
+pub const RENAME_EXCHANGE: u32 = 1 << 1;
+pub const RENAME_WHITEOUT: u32 = 1 << 2;
+/// linux setxattr flags
+pub const XATTR_CREATE: u32 = 0x1;
+pub const XATTR_REPLACE: u32 = 0x2;
+/// linux fallocate flags
+pub const FALLOC_FL_KEEP_SIZE: u32 = 0x01;
+pub const FALLOC_FL_PUNCH_HOLE: u32 = 0x02;
+pub const FALLOC_FL_COLLAPSE_RANGE: u32 = 0x08;
+pub const FALLOC_FL_ZERO_RANGE: u32 = 0x10;
+pub const FALLOC_FL_INSERT_RANGE: u32 = 0x20;
+/// linux lseek whence
+pub const SEEK_SET: u32 = 0;
+pub const SEEK_CUR: u32 = 1;
+pub const SEEK_END: u32 = 2;
+pub const SEEK_DATA: u32 = 3;
+pub const SEEK_HOLE: u32 = 4;
+/// linux fiemap flags
+pub const FIEMAP_FLAG_SYNC: u32 = 0x01;
+/// specific to this crate, not a linux flag: also report the blocks of the
+/// extent tree
+pub const FIEMAP_FLAG_METADATA: u32 = 0x4000_0000;
+pub const FIEMAP_EXTENT_LAST: u32 = 0x0001;
+pub const FIEMAP_EXTENT_UNWRITTEN: u32 = 0x0800;
+pub const FIEMAP_EXTENT_MERGED: u32 = 0x1000;
+/// specific to this crate, not a linux flag: a block of the extent tree
+pub const FIEMAP_EXTENT_METADATA: u32 = 0x4000_0000;
\ No newline at end of file
@@ -189,27 +189,6 @@ impl Ext4DirEntry {
 
 impl Ext4DirEntry {
 
-    /// Get the checksum of the directory entry.
-    #[allow(unused)]
-    pub fn ext4_dir_get_csum(&self, s: &Ext4Superblock, blk_data: &[u8], ino_gen: u32) -> u32 {
-        let ino_index = self.inode;
-
-        let mut csum = 0;
-
-        let uuid = s.uuid;
-
-        csum = ext4_crc32c(EXT4_CRC32_INIT, &uuid, uuid.len() as u32);
-        csum = ext4_crc32c(csum, &ino_index.to_le_bytes(), 4);
-        csum = ext4_crc32c(csum, &ino_gen.to_le_bytes(), 4);
-        let mut data = [0u8; 0xff4];
-        unsafe {
-            core::ptr::copy_nonoverlapping(blk_data.as_ptr(), data.as_mut_ptr(), blk_data.len());
-        }
-
-        csum = ext4_crc32c(csum, &data[..], 0xff4);
-        csum
-    }
-
     /// Write de to block
     pub fn write_de_to_blk(&self, dst_blk: &mut Block, offset: usize) {
         let count = core::mem::size_of::<Ext4DirEntry>() / core::mem::size_of::<u8>();
@@ -222,10 +201,13 @@ impl Ext4DirEntry {
     }
 
     /// Copy the directory entry to a slice.
+    ///
+    /// Only the header and the name are copied, so the bytes following the
+    /// entry (the next entry or the block tail) are left untouched.
     pub fn copy_to_slice(&self, array: &mut [u8], offset: usize) {
         let de_ptr = self as *const Ext4DirEntry as *const u8;
         let array_ptr = array as *mut [u8] as *mut u8;
-        let count = core::mem::size_of::<Ext4DirEntry>() / core::mem::size_of::<u8>();
+        let count = min(self.actual_len(), array.len() - offset);
         unsafe {
             core::ptr::copy_nonoverlapping(de_ptr, array_ptr.add(offset), count);
         }
@@ -244,20 +226,47 @@ impl Ext4DirEntryTail{
         }
     }
 
+    /// Returns true if this looks like a checksum tail rather than a
+    /// regular entry.
+    pub fn is_tail(&self) -> bool {
+        self.reserved_zero1 == 0
+            && self.rec_len as usize == size_of::<Ext4DirEntryTail>()
+            && self.reserved_zero2 == 0
+            && self.reserved_ft == 0xDE
+    }
+
+    /// Get the checksum of a directory block.
+    ///
+    /// Params:
+    /// s: &Ext4Superblock - superblock, for the uuid
+    /// dir_ino: u32 - inode number of the directory owning the block
+    /// ino_gen: u32 - generation of that inode
+    /// blk_data: &[u8] - the whole block, the tail itself is not covered
+    pub fn dir_csum(s: &Ext4Superblock, dir_ino: u32, ino_gen: u32, blk_data: &[u8]) -> u32 {
+        let uuid = s.uuid;
+
+        let mut csum = ext4_crc32c(EXT4_CRC32_INIT, &uuid, uuid.len() as u32);
+        csum = ext4_crc32c(csum, &dir_ino.to_le_bytes(), 4);
+        csum = ext4_crc32c(csum, &ino_gen.to_le_bytes(), 4);
+
+        // everything up to the tail is covered
+        let size = blk_data.len() - size_of::<Ext4DirEntryTail>();
+        ext4_crc32c(csum, &blk_data[..size], size as u32)
+    }
+
     pub fn tail_set_csum(
         &mut self,
         s: &Ext4Superblock,
-        diren: &Ext4DirEntry,
+        dir_ino: u32,
         blk_data: &[u8],
         ino_gen: u32,
     ) {
-        let csum = diren.ext4_dir_get_csum(s, blk_data, ino_gen);
-        self.checksum = csum;
+        self.checksum = Self::dir_csum(s, dir_ino, ino_gen, blk_data);
     }
 
     pub fn copy_to_slice(&self, array: &mut [u8]) {
         unsafe {
-        let offset = BLOCK_SIZE - core::mem::size_of::<Ext4DirEntryTail>();
+        let offset = array.len() - core::mem::size_of::<Ext4DirEntryTail>();
         let de_ptr = self as *const Ext4DirEntryTail as *const u8;
         let array_ptr = array as *mut [u8] as *mut u8;
         let count = core::mem::size_of::<Ext4DirEntryTail>();
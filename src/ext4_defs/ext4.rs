@@ -3,6 +3,37 @@ use crate::prelude::*;
 use super::*;
 
 pub struct Ext4 {
+    /// Device used by the filesystem code, this is `block_cache` itself.
     pub block_device: Arc<dyn BlockDevice>,
+    pub block_cache: Arc<BlockCache>,
     pub super_block: Ext4Superblock,
+    /// Metadata journal, `None` if the filesystem has no journal.
+    pub journal: Option<Mutex<Journal>>,
+    pub mount_options: Ext4MountOptions,
+}
+
+/// Options given to `Ext4::open_with_options`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Ext4MountOptions {
+    /// Reject every modification with `EROFS`. The journal is not replayed
+    /// and nothing is ever written to the device.
+    pub read_only: bool,
+    /// Let a read-only mount go on when the journal needs recovery, like
+    /// the `norecovery` mount option. The filesystem is then seen without
+    /// the transactions left in the log. Needs `read_only`.
+    pub no_recovery: bool,
+    /// What to do when a metadata checksum does not match.
+    pub checksum_policy: Ext4ChecksumPolicy,
+}
+
+/// Handling of metadata checksum mismatches on `metadata_csum` filesystems.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Ext4ChecksumPolicy {
+    /// Fail the operation with `EIO`.
+    #[default]
+    Error,
+    /// Log a warning and use the metadata anyway.
+    Warn,
+    /// Do not verify checksums.
+    Ignore,
 }
@@ -1,5 +1,6 @@
 use crate::prelude::*;
 use crate::return_errno_with_message;
+use crate::utils::*;
 
 use super::*;
 
@@ -56,6 +57,15 @@ pub struct Ext4Extent {
     pub start_lo: u32,
 }
 
+/// Tail of a non-root extent tree block, stored right after the last
+/// possible entry on metadata_csum filesystems.
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+pub struct Ext4ExtentTail {
+    /// crc32c of the uuid, inode number, generation and the block up to the tail.
+    pub checksum: u32,
+}
+
 /// Extent tree node. Includes the header, the data.
 #[derive(Clone, Debug)]
 pub struct ExtentNode {
@@ -68,7 +78,7 @@ pub struct ExtentNode {
 #[derive(Clone, Debug)]
 pub enum NodeData {
     Root([u32; 15]),
-    Internal(Vec<u8>), // size = BLOCK_SIZE
+    Internal(Vec<u8>), // size = block size
 }
 
 /// Search path in the extent tree.
@@ -168,6 +178,11 @@ impl Ext4Extent {
     }
 }
 
+/// Non-root extent nodes occupy a whole filesystem block.
+fn is_valid_block_size(len: usize) -> bool {
+    len.is_power_of_two() && (1024..=65536).contains(&len)
+}
+
 impl ExtentNode {
     /// Load the extent node from the data.
     pub fn load_from_data(data: &[u8], is_root: bool) -> Result<Self> {
@@ -189,8 +204,8 @@ impl ExtentNode {
                 is_root,
             })
         } else {
-            if data.len() != BLOCK_SIZE {
-                return_errno_with_message!(Errno::EINVAL, "Invalid data length for root node");
+            if !is_valid_block_size(data.len()) {
+                return_errno_with_message!(Errno::EINVAL, "Invalid data length for non-root node");
             }
             let header = Ext4ExtentHeader::load_from_u8(&data[..size_of::<Ext4ExtentHeader>()]);
             Ok(ExtentNode {
@@ -221,8 +236,8 @@ impl ExtentNode {
                 is_root,
             })
         } else {
-            if data.len() != BLOCK_SIZE {
-                return_errno_with_message!(Errno::EINVAL, "Invalid data length for root node");
+            if !is_valid_block_size(data.len()) {
+                return_errno_with_message!(Errno::EINVAL, "Invalid data length for non-root node");
             }
             let mut header = *Ext4ExtentHeader::load_from_u8_mut(&mut data[..size_of::<Ext4ExtentHeader>()]);
             Ok(ExtentNode {
@@ -235,63 +250,52 @@ impl ExtentNode {
 }
 
 impl ExtentNode {
-    /// Binary search for the extent that contains the given block.
-    pub fn binsearch_extent(&mut self, lblock: Ext4Lblk) -> Option<(Ext4Extent, usize)> {
-        // empty node
+    /// Returns true if the tail checksum of a non-root node matches its
+    /// content. The root lives in the inode and has no tail.
+    pub fn verify_checksum(&self, s: &Ext4Superblock, inode_num: u32, ino_gen: u32) -> bool {
+        match &self.data {
+            NodeData::Root(_) => true,
+            NodeData::Internal(data) => Ext4ExtentTail::verify_checksum(s, inode_num, ino_gen, data),
+        }
+    }
+
+    /// Binary search for the position of the extent closest to the given block,
+    /// i.e. the last extent that starts at or before `lblock`.
+    ///
+    /// Returns 0 if the node is empty or `lblock` is before the first extent.
+    pub fn binsearch_extent_pos(&self, lblock: Ext4Lblk) -> usize {
         if self.header.entries_count == 0 {
-            match &self.data {
-                NodeData::Root(root_data) => {
-                    let extent = Ext4Extent::load_from_u32(&root_data[3..]);
-                    return Some((extent, 0));
-                }
-                NodeData::Internal(internal_data) => {
-                    let extent = Ext4Extent::load_from_u8(&internal_data[12..]);
-                    return Some((extent, 0));
-                }
-            }
+            return 0;
         }
 
-        match &mut self.data {
-            NodeData::Root(root_data) => {
-                let header = self.header;
-                let mut l = 1;
-                let mut r = header.entries_count as usize - 1;
-                while l <= r {
-                    let m = l + (r - l) / 2;
-                    let idx = 3 + m * 3;
-                    let ext = Ext4Extent::load_from_u32(&root_data[idx..]);
-                    if lblock < ext.first_block {
-                        r = m - 1;
-                    } else {
-                        l = m + 1;
-                    }
-                }
-                let idx = 3 + (l - 1) * 3;
-                let ext = Ext4Extent::load_from_u32(&root_data[idx..]);
-    
-                Some((ext, l - 1))
+        let mut l = 1;
+        let mut r = self.header.entries_count as usize - 1;
+        while l <= r {
+            let m = l + (r - l) / 2;
+            let ext = self.get_extent(m).unwrap();
+            if lblock < ext.first_block {
+                r = m - 1;
+            } else {
+                l = m + 1;
             }
-            NodeData::Internal(internal_data) => {
-                let mut l = 1;
-                let mut r = (self.header.entries_count - 1) as usize;
-    
-                while l <= r {
-                    let m = l + (r - l) / 2;
-                    let offset = size_of::<Ext4ExtentHeader>() + m * size_of::<Ext4Extent>();
-                    let mut ext = Ext4Extent::load_from_u8_mut(&mut internal_data[offset..]);
+        }
 
-                    if lblock < ext.first_block {
-                        r = m - 1;
-                    } else {
-                        l = m + 1;  // Otherwise, move to the right half
-                    }
-                }
-                let offset = size_of::<Ext4ExtentHeader>() + (l - 1) * size_of::<Ext4Extent>();
-                let mut ext = Ext4Extent::load_from_u8_mut(&mut internal_data[offset..]);
+        l - 1
+    }
 
-                Some((ext, l - 1))
-            }
+    /// Binary search for the extent that contains the given block.
+    pub fn binsearch_extent(&mut self, lblock: Ext4Lblk) -> Option<(Ext4Extent, usize)> {
+        if self.header.entries_count == 0 {
+            return None;
+        }
+
+        let pos = self.binsearch_extent_pos(lblock);
+        let ext = self.get_extent(pos)?;
+        if lblock < ext.first_block || lblock - ext.first_block >= ext.get_actual_len() as u32 {
+            return None;
         }
+
+        Some((ext, pos))
     }
 
     /// Binary search for the closest index of the given block.
@@ -335,7 +339,7 @@ impl ExtentNode {
                 let start = size_of::<Ext4ExtentHeader>();
                 let indexes = &internal_data[start..];
 
-                let mut l = 0;
+                let mut l = 1; // Like the root, the first index also covers the blocks before it
                 let mut r = (self.header.entries_count - 1) as usize;
 
                 while l <= r {
@@ -413,6 +417,17 @@ impl Ext4ExtentIndex {
 }
 
 impl Ext4Extent {
+    /// Create an initialized extent mapping `len` blocks from `first_block` to `pblock`.
+    pub fn new(first_block: Ext4Lblk, pblock: Ext4Fsblk, len: u16) -> Self {
+        let mut extent = Self {
+            first_block,
+            block_count: len,
+            ..Default::default()
+        };
+        extent.store_pblock(pblock);
+        extent
+    }
+
     /// Get the first block number(logical) of the extent.
     pub fn get_first_block(&self) -> u32 {
         self.first_block
@@ -462,7 +477,17 @@ impl Ext4Extent {
 
     /// Get the last file block number that this extent covers.
     pub fn get_last_block(&self) -> u32 {
-        self.first_block + self.block_count as u32 - 1
+        self.first_block + self.get_actual_len() as u32 - 1
+    }
+
+    /// Returns true if the extent maps the logical block `lblock`.
+    pub fn contains(&self, lblock: Ext4Lblk) -> bool {
+        lblock >= self.first_block && lblock - self.first_block < self.get_actual_len() as u32
+    }
+
+    /// Get the physical block that the logical block `lblock` of the extent maps to.
+    pub fn pblock_of(&self, lblock: Ext4Lblk) -> Ext4Fsblk {
+        self.get_pblock() + (lblock - self.first_block) as Ext4Fsblk
     }
 
     /// Set the last file block number for this extent.
@@ -471,6 +496,52 @@ impl Ext4Extent {
     }
 }
 
+impl Ext4ExtentTail {
+    /// Get the offset of the tail in an extent block.
+    pub fn offset(header: &Ext4ExtentHeader) -> usize {
+        size_of::<Ext4ExtentHeader>() + header.max_entries_count as usize * size_of::<Ext4Extent>()
+    }
+
+    /// Compute the checksum of an extent block.
+    ///
+    /// Params:
+    /// s: &Ext4Superblock - superblock, for the uuid
+    /// inode_num: u32 - inode owning the extent tree
+    /// ino_gen: u32 - generation of that inode
+    /// data: &[u8] - the whole extent block
+    pub fn compute_checksum(s: &Ext4Superblock, inode_num: u32, ino_gen: u32, data: &[u8]) -> u32 {
+        let header = Ext4ExtentHeader::load_from_u8(&data[..size_of::<Ext4ExtentHeader>()]);
+        let size = Self::offset(&header).min(data.len());
+
+        let mut csum = ext4_crc32c(EXT4_CRC32_INIT, &s.uuid, s.uuid.len() as u32);
+        csum = ext4_crc32c(csum, &inode_num.to_le_bytes(), 4);
+        csum = ext4_crc32c(csum, &ino_gen.to_le_bytes(), 4);
+        ext4_crc32c(csum, &data[..size], size as u32)
+    }
+
+    /// Returns true if the tail of an extent block matches its content.
+    pub fn verify_checksum(s: &Ext4Superblock, inode_num: u32, ino_gen: u32, data: &[u8]) -> bool {
+        let header = Ext4ExtentHeader::load_from_u8(&data[..size_of::<Ext4ExtentHeader>()]);
+        let offset = Self::offset(&header);
+        if offset + size_of::<Ext4ExtentTail>() > data.len() {
+            return false;
+        }
+        let stored = u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+        stored == Self::compute_checksum(s, inode_num, ino_gen, data)
+    }
+
+    /// Store the checksum of an extent block in its tail.
+    pub fn set_checksum(s: &Ext4Superblock, inode_num: u32, ino_gen: u32, data: &mut [u8]) {
+        let header = Ext4ExtentHeader::load_from_u8(&data[..size_of::<Ext4ExtentHeader>()]);
+        let offset = Self::offset(&header);
+        if offset + size_of::<Ext4ExtentTail>() > data.len() {
+            return;
+        }
+        let csum = Self::compute_checksum(s, inode_num, ino_gen, data);
+        data[offset..offset + 4].copy_from_slice(&csum.to_le_bytes());
+    }
+}
+
 impl Ext4ExtentHeader {
     pub fn new(magic: u16, entries: u16, max_entries: u16, depth: u16, generation: u32) -> Self {
         Self {
@@ -553,6 +624,26 @@ mod tests {
         assert!(result.is_err(), "Expected error for invalid internal node data length");
     }
 
+    #[test]
+    fn test_extent_tail_checksum() {
+        let mut sb: Ext4Superblock = unsafe { core::mem::zeroed() };
+        sb.uuid = [0x5a; 16];
+
+        let mut data: Vec<u8> = vec![0; BLOCK_SIZE];
+        data[0..2].copy_from_slice(&EXT4_EXTENT_MAGIC.to_le_bytes());
+        let max_entries = ((BLOCK_SIZE - size_of::<Ext4ExtentHeader>()) / size_of::<Ext4Extent>()) as u16;
+        data[4..6].copy_from_slice(&max_entries.to_le_bytes());
+
+        Ext4ExtentTail::set_checksum(&sb, 12, 7, &mut data);
+        let node = ExtentNode::load_from_data(&data, false).unwrap();
+        assert!(node.verify_checksum(&sb, 12, 7));
+        assert!(!node.verify_checksum(&sb, 12, 8));
+
+        data[size_of::<Ext4ExtentHeader>()] = 1;
+        let node = ExtentNode::load_from_data(&data, false).unwrap();
+        assert!(!node.verify_checksum(&sb, 12, 7));
+    }
+
     #[test]
     fn test_binsearch_extent() {
         // Create a mock extent node
@@ -750,4 +841,20 @@ mod tests {
         assert_eq!(extent.first_block, 10);
         assert_eq!(extent.block_count, 10);
     }
+
+    #[test]
+    fn test_unwritten_extent() {
+        let mut extent = Ext4Extent::new(100, 5000, 20);
+        extent.mark_unwritten();
+        assert!(extent.is_unwritten());
+        assert_eq!(extent.get_actual_len(), 20);
+        assert_eq!(extent.get_last_block(), 119);
+        assert!(extent.contains(119));
+        assert!(!extent.contains(120));
+        assert!(!extent.contains(99));
+        assert_eq!(extent.pblock_of(110), 5010);
+
+        // the longest initialized extent is not unwritten
+        assert!(!Ext4Extent::new(0, 0, EXT_INIT_MAX_LEN).is_unwritten());
+    }
 }
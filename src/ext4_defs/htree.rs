@@ -0,0 +1,229 @@
+use crate::prelude::*;
+use crate::utils::*;
+
+use super::*;
+
+/// Header of a dx_root block, right after the fake "." and ".." entries.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Ext4DxRootInfo {
+    pub reserved_zero: u32,
+    pub hash_version: u8,
+    /// Length of this structure, always 8.
+    pub info_length: u8,
+    /// Number of index levels below the root.
+    pub indirect_levels: u8,
+    pub unused_flags: u8,
+}
+
+/// Overlays the hash of the first entry of an index block.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Ext4DxCountLimit {
+    pub limit: u16,
+    pub count: u16,
+}
+
+/// Index entry, entries are sorted by hash. The first entry has no hash and
+/// covers everything below the second one.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Ext4DxEntry {
+    pub hash: u32,
+    /// Logical block in the directory, the top bits are reserved.
+    pub block: u32,
+}
+
+/// Tail of an index block on metadata_csum filesystems, right after the
+/// last possible entry.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Ext4DxTail {
+    pub reserved: u32,
+    pub checksum: u32,
+}
+
+/// One index block on an htree lookup path.
+pub struct Ext4DxFrame {
+    /// Logical block of the index block in the directory.
+    pub lblock: Ext4Lblk,
+    pub block: Block,
+    /// Offset of the count/limit header, i.e. of the first entry.
+    pub entries_offset: usize,
+    /// Entry followed to the next level.
+    pub position: usize,
+}
+
+/// Offset of the dx_root info in block 0: "." takes 12 bytes, ".." 12 more.
+pub const EXT4_DX_ROOT_INFO_OFFSET: usize = 24;
+
+/// Offset of the entries in dx_root, right after the root info.
+pub const EXT4_DX_ROOT_ENTRIES_OFFSET: usize = EXT4_DX_ROOT_INFO_OFFSET + size_of::<Ext4DxRootInfo>();
+
+/// Offset of the entries in an index node: one empty fake entry header.
+pub const EXT4_DX_NODE_ENTRIES_OFFSET: usize = 8;
+
+impl Ext4DxEntry {
+    /// Get the logical block this entry points to.
+    pub fn block(&self) -> Ext4Lblk {
+        self.block & 0x0fffffff
+    }
+}
+
+impl Ext4DxTail {
+    /// Compute the checksum of an index block.
+    ///
+    /// Params:
+    /// s: &Ext4Superblock - superblock, for the uuid
+    /// dir_ino: u32 - inode number of the directory
+    /// ino_gen: u32 - generation of that inode
+    /// block: &Block - the index block
+    /// entries_offset: usize - offset of the count/limit header
+    ///
+    /// Returns:
+    /// `Option<u32>` - the checksum, `None` if the block has no room for a tail
+    pub fn dx_csum(
+        s: &Ext4Superblock,
+        dir_ino: u32,
+        ino_gen: u32,
+        block: &Block,
+        entries_offset: usize,
+    ) -> Option<u32> {
+        let data = &block.data;
+        let cl: Ext4DxCountLimit = block.read_offset_as(entries_offset);
+        let tail_offset = entries_offset + cl.limit as usize * size_of::<Ext4DxEntry>();
+        if tail_offset + size_of::<Ext4DxTail>() > data.len() {
+            return None;
+        }
+        let size = entries_offset + cl.count as usize * size_of::<Ext4DxEntry>();
+        if size > tail_offset {
+            return None;
+        }
+
+        let mut csum = ext4_crc32c(EXT4_CRC32_INIT, &s.uuid, s.uuid.len() as u32);
+        csum = ext4_crc32c(csum, &dir_ino.to_le_bytes(), 4);
+        csum = ext4_crc32c(csum, &ino_gen.to_le_bytes(), 4);
+        csum = ext4_crc32c(csum, &data[..size], size as u32);
+        // the tail is covered with its checksum field zeroed
+        csum = ext4_crc32c(csum, &data[tail_offset..tail_offset + 4], 4);
+        Some(ext4_crc32c(csum, &[0u8; 4], 4))
+    }
+
+    /// Update the checksum in the tail of an index block.
+    ///
+    /// Params:
+    /// s: &Ext4Superblock - superblock, for the uuid
+    /// dir_ino: u32 - inode number of the directory
+    /// ino_gen: u32 - generation of that inode
+    /// block: &mut Block - the index block
+    /// entries_offset: usize - offset of the count/limit header
+    pub fn set_dx_csum(
+        s: &Ext4Superblock,
+        dir_ino: u32,
+        ino_gen: u32,
+        block: &mut Block,
+        entries_offset: usize,
+    ) {
+        if let Some(csum) = Self::dx_csum(s, dir_ino, ino_gen, block, entries_offset) {
+            let cl: Ext4DxCountLimit = block.read_offset_as(entries_offset);
+            let tail_offset = entries_offset + cl.limit as usize * size_of::<Ext4DxEntry>();
+            let tail: &mut Ext4DxTail = block.read_offset_as_mut(tail_offset);
+            tail.checksum = csum;
+        }
+    }
+
+    /// Get the checksum stored in the tail of an index block.
+    pub fn stored_csum(block: &Block, entries_offset: usize) -> u32 {
+        let cl: Ext4DxCountLimit = block.read_offset_as(entries_offset);
+        let tail_offset = entries_offset + cl.limit as usize * size_of::<Ext4DxEntry>();
+        block.read_offset_as::<Ext4DxTail>(tail_offset).checksum
+    }
+}
+
+impl Ext4DxFrame {
+    pub fn count_limit(&self) -> Ext4DxCountLimit {
+        self.block.read_offset_as(self.entries_offset)
+    }
+
+    pub fn entry(&self, idx: usize) -> Ext4DxEntry {
+        self.block.read_offset_as(self.entries_offset + idx * size_of::<Ext4DxEntry>())
+    }
+
+    pub fn set_count(&mut self, count: u16) {
+        let cl: &mut Ext4DxCountLimit = self.block.read_offset_as_mut(self.entries_offset);
+        cl.count = count;
+    }
+
+    pub fn set_entry(&mut self, idx: usize, entry: Ext4DxEntry) {
+        let offset = self.entries_offset + idx * size_of::<Ext4DxEntry>();
+        let dst: &mut Ext4DxEntry = self.block.read_offset_as_mut(offset);
+        // the first entry only holds a block, its hash overlays count/limit
+        if idx != 0 {
+            dst.hash = entry.hash;
+        }
+        dst.block = entry.block;
+    }
+
+    /// Insert an entry at `idx`, shifting the following ones. The caller
+    /// makes sure the block is not full.
+    pub fn insert_entry(&mut self, idx: usize, entry: Ext4DxEntry) {
+        let count = self.count_limit().count as usize;
+        let start = self.entries_offset + idx * size_of::<Ext4DxEntry>();
+        let end = self.entries_offset + count * size_of::<Ext4DxEntry>();
+        self.block
+            .data
+            .copy_within(start..end, start + size_of::<Ext4DxEntry>());
+        self.set_entry(idx, entry);
+        self.set_count(count as u16 + 1);
+    }
+
+    /// Binary search for the entry covering `hash`.
+    pub fn binsearch(&self, hash: u32) -> usize {
+        let count = self.count_limit().count as usize;
+
+        let mut l = 1;
+        let mut r = count;
+        while l < r {
+            let m = l + (r - l) / 2;
+            if self.entry(m).hash > hash {
+                r = m;
+            } else {
+                l = m + 1;
+            }
+        }
+
+        l - 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dx_frame_insert() {
+        let mut frame = Ext4DxFrame {
+            lblock: 0,
+            block: Block {
+                disk_offset: 0,
+                data: vec![0u8; 1024],
+            },
+            entries_offset: EXT4_DX_NODE_ENTRIES_OFFSET,
+            position: 0,
+        };
+        let cl: &mut Ext4DxCountLimit = frame.block.read_offset_as_mut(EXT4_DX_NODE_ENTRIES_OFFSET);
+        cl.limit = 127;
+        cl.count = 1;
+        frame.set_entry(0, Ext4DxEntry { hash: 0, block: 1 });
+
+        frame.insert_entry(1, Ext4DxEntry { hash: 0x800, block: 3 });
+        frame.insert_entry(1, Ext4DxEntry { hash: 0x400, block: 2 });
+
+        let cl = frame.count_limit();
+        assert_eq!((cl.count, cl.limit), (3, 127));
+        assert_eq!(frame.binsearch(0x100), 0);
+        assert_eq!(frame.binsearch(0x400), 1);
+        assert_eq!(frame.binsearch(0x7fe), 1);
+        assert_eq!(frame.entry(frame.binsearch(0xffff)).block(), 3);
+    }
+}
@@ -165,6 +165,17 @@ impl Ext4Inode {
         self.flags = flags;
     }
 
+    /// Returns true if the blocks are mapped by an extent tree rather
+    /// than the indirect block map.
+    pub fn has_extents(&self) -> bool {
+        self.flags & EXT4_INODE_FLAG_EXTENTS as u32 != 0
+    }
+
+    /// Returns true if this directory has an htree index.
+    pub fn is_indexed(&self) -> bool {
+        self.flags & EXT4_INODE_FLAG_INDEX != 0
+    }
+
     pub fn osd1(&self) -> u32 {
         self.osd1
     }
@@ -197,6 +208,16 @@ impl Ext4Inode {
         self.file_acl = file_acl;
     }
 
+    /// Get the xattr block, with the high 16 bits of `osd2`.
+    pub fn file_acl_block(&self) -> Ext4Fsblk {
+        self.file_acl as Ext4Fsblk | (self.osd2.l_i_file_acl_high as Ext4Fsblk) << 32
+    }
+
+    pub fn set_file_acl_block(&mut self, block: Ext4Fsblk) {
+        self.file_acl = block as u32;
+        self.osd2.l_i_file_acl_high = (block >> 32) as u16;
+    }
+
     pub fn size_hi(&self) -> u32 {
         self.size_hi
     }
@@ -291,6 +312,20 @@ impl Ext4Inode {
         InodeFileType::from_bits_truncate(self.mode & EXT4_INODE_MODE_TYPE_MASK)
     }
 
+    /// File type stored in the directory entries pointing to this inode.
+    pub fn dir_entry_type(&self) -> DirEntryType {
+        match self.file_type() {
+            InodeFileType::S_IFREG => DirEntryType::EXT4_DE_REG_FILE,
+            InodeFileType::S_IFDIR => DirEntryType::EXT4_DE_DIR,
+            InodeFileType::S_IFLNK => DirEntryType::EXT4_DE_SYMLINK,
+            InodeFileType::S_IFCHR => DirEntryType::EXT4_DE_CHRDEV,
+            InodeFileType::S_IFBLK => DirEntryType::EXT4_DE_BLKDEV,
+            InodeFileType::S_IFIFO => DirEntryType::EXT4_DE_FIFO,
+            InodeFileType::S_IFSOCK => DirEntryType::EXT4_DE_SOCK,
+            _ => DirEntryType::EXT4_DE_UNKNOWN,
+        }
+    }
+
     pub fn file_perm(&self) -> InodePerm {
         InodePerm::from_bits_truncate(self.mode & EXT4_INODE_MODE_PERM_MASK)
     }
@@ -307,6 +342,34 @@ impl Ext4Inode {
         self.file_type() == InodeFileType::S_IFLNK
     }
 
+    /// Returns true if this is a symlink with its target stored in `block`.
+    pub fn is_fast_symlink(&self) -> bool {
+        self.is_link()
+            && !self.has_extents()
+            && self.size() <= EXT4_FAST_SYMLINK_MAX as u64
+    }
+
+    /// Get the target of a fast symlink.
+    pub fn fast_symlink_target(&self) -> Vec<u8> {
+        self.block
+            .iter()
+            .flat_map(|w| w.to_le_bytes())
+            .take(self.size() as usize)
+            .collect()
+    }
+
+    /// Store a symlink target of at most `EXT4_FAST_SYMLINK_MAX` bytes in
+    /// `block`, replacing the extent tree.
+    pub fn set_fast_symlink_target(&mut self, target: &[u8]) {
+        let mut raw = [0u8; 60];
+        raw[..target.len()].copy_from_slice(target);
+        for (w, chunk) in self.block.iter_mut().zip(raw.chunks_exact(4)) {
+            *w = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        }
+        self.flags &= !(EXT4_INODE_FLAG_EXTENTS as u32);
+        self.set_size(target.len() as u64);
+    }
+
     pub fn can_read(&self) -> bool {
         self.file_perm().contains(InodePerm::S_IREAD)
     }
@@ -387,87 +450,87 @@ impl Ext4Inode {
         }
     }
 
-    fn get_checksum(&self, super_block: &Ext4Superblock) -> u32 {
-        let inode_size = super_block.inode_size;
-        let mut v: u32 = self.osd2.l_i_checksum_lo as u32;
-        if inode_size > 128 {
-            v |= (self.i_checksum_hi as u32) << 16;
-        }
-        v
-    }
-    #[allow(unused)]
-    pub fn set_inode_checksum_value(
-        &mut self,
-        super_block: &Ext4Superblock,
-        inode_id: u32,
-        checksum: u32,
-    ) {
-        let inode_size = super_block.inode_size();
-
-        self.osd2.l_i_checksum_lo = (checksum & 0xffff) as u16;
-        if inode_size > 128 {
-            self.i_checksum_hi = (checksum >> 16) as u16;
+    /// Returns true if the on-disk inode record `raw` has room for
+    /// `i_checksum_hi`.
+    fn has_checksum_hi(raw: &[u8]) -> bool {
+        if raw.len() <= EXT4_GOOD_OLD_INODE_SIZE as usize {
+            return false;
         }
-    }
-    fn copy_to_slice(&self, slice: &mut [u8]) {
-        unsafe {
-            let inode_ptr = self as *const Ext4Inode as *const u8;
-            let array_ptr = slice.as_ptr() as *mut u8;
-            core::ptr::copy_nonoverlapping(inode_ptr, array_ptr, 0x9c);
+        let extra_isize = u16::from_le_bytes([raw[0x80], raw[0x81]]) as usize;
+        extra_isize >= 4
+    }
+
+    /// Compute the crc32c of the on-disk inode record `raw`, the stored
+    /// checksum fields count as zero.
+    ///
+    /// Params:
+    /// raw: &[u8] - the whole `inode_size` bytes record
+    /// inode_num: u32 - inode number
+    /// super_block: &Ext4Superblock - superblock, for the uuid
+    ///
+    /// Returns:
+    /// `u32` - checksum, only the low 16 bits are stored without
+    /// `i_checksum_hi`
+    pub fn compute_checksum(raw: &[u8], inode_num: u32, super_block: &Ext4Superblock) -> u32 {
+        let mut record = raw.to_vec();
+        record[0x7c..0x7e].fill(0);
+        if Self::has_checksum_hi(raw) {
+            record[0x82..0x84].fill(0);
         }
-    }
-    #[allow(unused)]
-    pub fn get_inode_checksum(&mut self, inode_id: u32, super_block: &Ext4Superblock) -> u32 {
-        let inode_size = super_block.inode_size();
-
-        let orig_checksum = self.get_checksum(super_block);
-        let mut checksum = 0;
-
-        let ino_index = inode_id;
-        let ino_gen = self.generation;
-
-        // Preparation: temporarily set bg checksum to 0
-        self.osd2.l_i_checksum_lo = 0;
-        self.i_checksum_hi = 0;
+        let generation = &raw[0x64..0x68];
 
-        checksum = ext4_crc32c(
+        let mut checksum = ext4_crc32c(
             EXT4_CRC32_INIT,
             &super_block.uuid,
             super_block.uuid.len() as u32,
         );
-        checksum = ext4_crc32c(checksum, &ino_index.to_le_bytes(), 4);
-        checksum = ext4_crc32c(checksum, &ino_gen.to_le_bytes(), 4);
-
-        let mut raw_data = [0u8; 0x100];
-        self.copy_to_slice(&mut raw_data);
-
-        // inode checksum
-        checksum = ext4_crc32c(checksum, &raw_data, inode_size as u32);
-
-        self.set_inode_checksum_value(super_block, inode_id, checksum);
-
-        if inode_size == 128 {
-            checksum &= 0xFFFF;
+        checksum = ext4_crc32c(checksum, &inode_num.to_le_bytes(), 4);
+        checksum = ext4_crc32c(checksum, generation, 4);
+        ext4_crc32c(checksum, &record, record.len() as u32)
+    }
+
+    /// Returns true if the checksum stored in the on-disk inode record `raw`
+    /// matches its content.
+    pub fn verify_checksum(raw: &[u8], inode_num: u32, super_block: &Ext4Superblock) -> bool {
+        let checksum = Self::compute_checksum(raw, inode_num, super_block);
+        let mut stored = u16::from_le_bytes([raw[0x7c], raw[0x7d]]) as u32;
+        if Self::has_checksum_hi(raw) {
+            stored |= (u16::from_le_bytes([raw[0x82], raw[0x83]]) as u32) << 16;
+            checksum == stored
+        } else {
+            checksum & 0xffff == stored
         }
-
-        checksum
     }
 
-    pub fn set_inode_checksum(&mut self, super_block: &Ext4Superblock, inode_id: u32) {
-        let inode_size = super_block.inode_size();
-        let checksum = self.get_inode_checksum(inode_id, super_block);
-
-        self.osd2.l_i_checksum_lo = ((checksum << 16) >> 16) as u16;
-        if inode_size > 128 {
+    /// Store the checksum of the on-disk inode record `raw` in it and in `self`.
+    pub fn set_inode_checksum(&mut self, raw: &mut [u8], inode_num: u32, super_block: &Ext4Superblock) {
+        let checksum = Self::compute_checksum(raw, inode_num, super_block);
+        self.osd2.l_i_checksum_lo = checksum as u16;
+        raw[0x7c..0x7e].copy_from_slice(&self.osd2.l_i_checksum_lo.to_le_bytes());
+        if Self::has_checksum_hi(raw) {
             self.i_checksum_hi = (checksum >> 16) as u16;
+            raw[0x82..0x84].copy_from_slice(&self.i_checksum_hi.to_le_bytes());
         }
     }
 
-    pub fn sync_inode_to_disk(&self, block_device: Arc<dyn BlockDevice>, inode_pos: usize) {
-        let data = unsafe {
-            core::slice::from_raw_parts(self as *const _ as *const u8, size_of::<Ext4Inode>())
-        };
-        block_device.write_offset(inode_pos, data);
+    /// Copy the fields of `self` over the start of the on-disk inode record
+    /// `raw`, bytes past `Ext4Inode` (in-inode xattrs) are kept.
+    pub fn copy_to_raw(&self, raw: &mut [u8]) {
+        let len = min(raw.len(), size_of::<Ext4Inode>());
+        let data = unsafe { core::slice::from_raw_parts(self as *const _ as *const u8, len) };
+        raw[..len].copy_from_slice(data);
+    }
+
+    pub fn sync_inode_to_disk(
+        &self,
+        block_device: Arc<dyn BlockDevice>,
+        inode_pos: usize,
+        super_block: &Ext4Superblock,
+    ) -> Result<()> {
+        // never write past the on-disk inode record
+        let inode_size = min(super_block.inode_size() as usize, size_of::<Ext4Inode>());
+        let data = unsafe { core::slice::from_raw_parts(self as *const _ as *const u8, inode_size) };
+        block_device.write_offset(inode_pos, data)
     }
 }
 
@@ -633,4 +696,45 @@ mod tests {
         inode.set_file_perm(InodePerm::S_IREAD | InodePerm::S_IWRITE | InodePerm::S_IEXEC);
         assert_eq!(inode.mode, InodeFileType::S_IFREG.bits() | (InodePerm::S_IREAD | InodePerm::S_IWRITE | InodePerm::S_IEXEC).bits()); // Regular file with rwx permissions
     }
+
+    #[test]
+    fn test_inode_checksum() {
+        let mut sb: Ext4Superblock = unsafe { core::mem::zeroed() };
+        sb.uuid = [0x5a; 16];
+
+        let mut inode = Ext4Inode {
+            mode: 0o100644,
+            i_extra_isize: 32,
+            ..Default::default()
+        };
+        let mut raw = vec![0u8; 256];
+        inode.copy_to_raw(&mut raw);
+        inode.set_inode_checksum(&mut raw, 12, &sb);
+        assert!(Ext4Inode::verify_checksum(&raw, 12, &sb));
+        assert!(!Ext4Inode::verify_checksum(&raw, 13, &sb));
+
+        // bytes past the inode structure are covered as well
+        raw[200] ^= 1;
+        assert!(!Ext4Inode::verify_checksum(&raw, 12, &sb));
+    }
+
+    #[test]
+    fn test_fast_symlink_target() {
+        let mut inode = Ext4Inode {
+            mode: InodeFileType::S_IFLNK.bits() | 0o777,
+            flags: EXT4_INODE_FLAG_EXTENTS as u32,
+            ..Default::default()
+        };
+        inode.extent_tree_init();
+
+        let target = b"../some/where/else";
+        inode.set_fast_symlink_target(target);
+        assert!(inode.is_fast_symlink());
+        assert_eq!(inode.size(), target.len() as u64);
+        assert_eq!(inode.fast_symlink_target(), target.to_vec());
+
+        let longest = [b'x'; EXT4_FAST_SYMLINK_MAX];
+        inode.set_fast_symlink_target(&longest);
+        assert_eq!(inode.fast_symlink_target(), longest.to_vec());
+    }
 }
@@ -0,0 +1,690 @@
+use crate::prelude::*;
+use crate::return_errno_with_message;
+use crate::utils::*;
+
+use super::*;
+
+/// JBD2 on-disk structures. Unlike the rest of ext4 every field is big-endian.
+pub const JBD2_MAGIC_NUMBER: u32 = 0xC03B3998;
+
+/// Journal block types.
+pub const JBD2_DESCRIPTOR_BLOCK: u32 = 1;
+pub const JBD2_COMMIT_BLOCK: u32 = 2;
+pub const JBD2_SUPERBLOCK_V1: u32 = 3;
+pub const JBD2_SUPERBLOCK_V2: u32 = 4;
+pub const JBD2_REVOKE_BLOCK: u32 = 5;
+
+/// Descriptor block tag flags.
+pub const JBD2_FLAG_ESCAPE: u32 = 1; // 数据块以 JBD2_MAGIC_NUMBER 开头, 写入日志时被清零
+pub const JBD2_FLAG_SAME_UUID: u32 = 2; // 标签后没有 UUID
+pub const JBD2_FLAG_DELETED: u32 = 4;
+pub const JBD2_FLAG_LAST_TAG: u32 = 8; // 描述块中的最后一个标签
+
+/// Journal features.
+pub const JBD2_FEATURE_COMPAT_CHECKSUM: u32 = 0x1;
+pub const JBD2_FEATURE_INCOMPAT_REVOKE: u32 = 0x1;
+pub const JBD2_FEATURE_INCOMPAT_64BIT: u32 = 0x2;
+pub const JBD2_FEATURE_INCOMPAT_ASYNC_COMMIT: u32 = 0x4;
+pub const JBD2_FEATURE_INCOMPAT_CSUM_V2: u32 = 0x8;
+pub const JBD2_FEATURE_INCOMPAT_CSUM_V3: u32 = 0x10;
+pub const JBD2_FEATURE_INCOMPAT_FAST_COMMIT: u32 = 0x20;
+pub const JBD2_KNOWN_INCOMPAT_FEATURES: u32 = JBD2_FEATURE_INCOMPAT_REVOKE
+    | JBD2_FEATURE_INCOMPAT_64BIT
+    | JBD2_FEATURE_INCOMPAT_ASYNC_COMMIT
+    | JBD2_FEATURE_INCOMPAT_CSUM_V2
+    | JBD2_FEATURE_INCOMPAT_CSUM_V3
+    | JBD2_FEATURE_INCOMPAT_FAST_COMMIT;
+
+/// Checksum type of the journal superblock with csum v2/v3.
+pub const JBD2_CRC32C_CHKSUM: u8 = 4;
+
+/// Size of the `t_checksum` tail of descriptor and revoke blocks.
+pub const JBD2_BLOCK_TAIL_SIZE: usize = 4;
+
+/// Header common to every journal metadata block.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct JournalHeader {
+    magic: u32,     // JBD2_MAGIC_NUMBER
+    blocktype: u32, // 块类型
+    sequence: u32,  // 所属事务的序号
+}
+
+impl JournalHeader {
+    pub fn new(blocktype: u32, sequence: u32) -> Self {
+        JournalHeader {
+            magic: JBD2_MAGIC_NUMBER.to_be(),
+            blocktype: blocktype.to_be(),
+            sequence: sequence.to_be(),
+        }
+    }
+
+    pub fn magic(&self) -> u32 {
+        u32::from_be(self.magic)
+    }
+
+    pub fn blocktype(&self) -> u32 {
+        u32::from_be(self.blocktype)
+    }
+
+    pub fn sequence(&self) -> u32 {
+        u32::from_be(self.sequence)
+    }
+}
+
+/// The journal superblock, stored in the first block of the journal.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct JournalSuperblock {
+    header: JournalHeader,
+    blocksize: u32,         // 日志块大小
+    maxlen: u32,            // 日志总块数
+    first: u32,             // 第一个日志块
+    sequence: u32,          // 日志中第一个事务的序号
+    start: u32,             // 日志起始块, 0 表示日志为空
+    errno: i32,             // 错误码
+    feature_compat: u32,    // 兼容特性集
+    feature_incompat: u32,  // 不兼容特性集
+    feature_ro_compat: u32, // 只读兼容特性集
+    uuid: [u8; 16],         // 日志的UUID
+    nr_users: u32,          // 共享日志的文件系统数
+    dynsuper: u32,          // 动态超级块的位置
+    max_transaction: u32,   // 每个事务的最大块数
+    max_trans_data: u32,    // 每个事务的最大数据块数
+    checksum_type: u8,      // 校验和算法
+    padding2: [u8; 3],
+    num_fc_blks: u32,    // fast commit 块数
+    head: u32,           // 日志头部块
+    padding: [u32; 40],
+    checksum: u32,       // crc32c(superblock)
+    users: [u8; 16 * 48], // 共享日志的文件系统的UUID
+}
+
+impl JournalSuperblock {
+    pub fn header(&self) -> JournalHeader {
+        self.header
+    }
+
+    pub fn blocksize(&self) -> u32 {
+        u32::from_be(self.blocksize)
+    }
+
+    pub fn maxlen(&self) -> u32 {
+        u32::from_be(self.maxlen)
+    }
+
+    pub fn first(&self) -> u32 {
+        u32::from_be(self.first)
+    }
+
+    pub fn sequence(&self) -> u32 {
+        u32::from_be(self.sequence)
+    }
+
+    pub fn set_sequence(&mut self, sequence: u32) {
+        self.sequence = sequence.to_be();
+    }
+
+    pub fn start(&self) -> u32 {
+        u32::from_be(self.start)
+    }
+
+    pub fn set_start(&mut self, start: u32) {
+        self.start = start.to_be();
+    }
+
+    pub fn has_incompat(&self, feature: u32) -> bool {
+        u32::from_be(self.feature_incompat) & feature != 0
+    }
+
+    pub fn set_incompat(&mut self, feature: u32) {
+        self.feature_incompat = (u32::from_be(self.feature_incompat) | feature).to_be();
+    }
+
+    pub fn set_checksum_type(&mut self, checksum_type: u8) {
+        self.checksum_type = checksum_type;
+    }
+
+    pub fn uuid(&self) -> [u8; 16] {
+        self.uuid
+    }
+
+    pub fn incompat_features(&self) -> u32 {
+        u32::from_be(self.feature_incompat)
+    }
+
+    /// Returns true if journal blocks carry crc32c checksums (csum v2 or v3).
+    pub fn has_csum_v2or3(&self) -> bool {
+        self.has_incompat(JBD2_FEATURE_INCOMPAT_CSUM_V2 | JBD2_FEATURE_INCOMPAT_CSUM_V3)
+    }
+
+    pub fn checksum_type(&self) -> u8 {
+        self.checksum_type
+    }
+
+    /// Seed of every journal block checksum: crc32c(~0, uuid).
+    pub fn csum_seed(&self) -> u32 {
+        ext4_crc32c(EXT4_CRC32_INIT, &self.uuid, self.uuid.len() as u32)
+    }
+
+    /// Returns the size in bytes of a descriptor block tag, without the UUID.
+    pub fn tag_bytes(&self) -> usize {
+        if self.has_incompat(JBD2_FEATURE_INCOMPAT_CSUM_V3) {
+            return 16;
+        }
+        let mut size = 12;
+        if self.has_incompat(JBD2_FEATURE_INCOMPAT_CSUM_V2) {
+            size += 2;
+        }
+        if self.has_incompat(JBD2_FEATURE_INCOMPAT_64BIT) {
+            size
+        } else {
+            size - 4
+        }
+    }
+
+    /// Returns the superblock checksum: crc32c(~0, superblock with a zero checksum).
+    pub fn compute_checksum(&self) -> u32 {
+        let mut sb = *self;
+        sb.checksum = 0;
+        let data = unsafe {
+            core::slice::from_raw_parts(&sb as *const _ as *const u8, size_of::<JournalSuperblock>())
+        };
+        ext4_crc32c(EXT4_CRC32_INIT, data, data.len() as u32)
+    }
+
+    pub fn verify_checksum(&self) -> bool {
+        !self.has_csum_v2or3() || u32::from_be(self.checksum) == self.compute_checksum()
+    }
+
+    pub fn set_checksum(&mut self) {
+        if self.has_csum_v2or3() {
+            self.checksum = self.compute_checksum().to_be();
+        }
+    }
+
+    /// Check that this is a journal the crate knows how to replay.
+    pub fn validate(&self, fs_block_size: u32) -> Result<()> {
+        if self.header.magic() != JBD2_MAGIC_NUMBER {
+            return_errno_with_message!(Errno::EINVAL, "bad journal superblock magic");
+        }
+        let blocktype = self.header.blocktype();
+        if blocktype != JBD2_SUPERBLOCK_V1 && blocktype != JBD2_SUPERBLOCK_V2 {
+            return_errno_with_message!(Errno::EINVAL, "unknown journal superblock type");
+        }
+        if self.blocksize() != fs_block_size {
+            return_errno_with_message!(Errno::EINVAL, "journal block size differs from filesystem");
+        }
+        if self.first() == 0 || self.first() >= self.maxlen() {
+            return_errno_with_message!(Errno::EINVAL, "bad journal geometry");
+        }
+        // v1 superblocks have no feature fields
+        if blocktype == JBD2_SUPERBLOCK_V2 {
+            if self.incompat_features() & !JBD2_KNOWN_INCOMPAT_FEATURES != 0 {
+                return_errno_with_message!(Errno::ENOTSUP, "unsupported journal features");
+            }
+            if self.has_csum_v2or3() && self.checksum_type != JBD2_CRC32C_CHKSUM {
+                return_errno_with_message!(Errno::EINVAL, "unknown journal checksum type");
+            }
+            if !self.verify_checksum() {
+                return_errno_with_message!(Errno::EIO, "journal superblock checksum mismatch");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Commit block header, marks the end of a transaction.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct JournalCommitHeader {
+    pub header: JournalHeader,
+    pub chksum_type: u8,
+    pub chksum_size: u8,
+    pub padding: [u8; 2],
+    pub chksum: [u32; 8], // csum v2/v3 只使用 chksum[0]
+    pub commit_sec: u64,
+    pub commit_nsec: u32,
+}
+
+/// Offset of `chksum[0]` in the commit block.
+pub const JBD2_COMMIT_CHKSUM_OFFSET: usize = 16;
+
+/// Revoke block header, followed by `count - 16` bytes of block numbers.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct JournalRevokeHeader {
+    pub header: JournalHeader,
+    count: u32, // 已使用的字节数, 包括头部
+}
+
+impl JournalRevokeHeader {
+    pub fn count(&self) -> u32 {
+        u32::from_be(self.count)
+    }
+}
+
+/// A decoded descriptor block tag: where a logged block goes in the filesystem.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct JournalBlockTag {
+    pub blocknr: u64,
+    pub flags: u32,
+    pub checksum: u32,
+}
+
+/// Read a big-endian u32 at `offset`.
+pub fn be32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+/// Read a big-endian u16 at `offset`.
+pub fn be16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+/// The journal of a mounted filesystem.
+///
+/// Transactions are appended to the log from `first` on and the whole log is
+/// checkpointed once it is full, so the log never wraps around.
+pub struct Journal {
+    /// Inode holding the journal, its blocks are the journal blocks.
+    pub inode_ref: Ext4InodeRef,
+    pub super_block: JournalSuperblock,
+    csum_seed: u32,
+    /// Open handles of the running transaction.
+    pub handles: usize,
+    /// Next free log block.
+    pub head: u32,
+    /// Id of the next transaction to commit.
+    pub sequence: u32,
+    /// Blocks freed by the running transaction that have copies in the log.
+    pub revoked: BTreeSet<u64>,
+    /// `revoked` before the current update, restored if it is aborted.
+    pub revoked_before_update: BTreeSet<u64>,
+    /// A handle of the current update failed.
+    pub aborted: bool,
+    /// Filesystem blocks with a copy in the log since the last checkpoint.
+    pub logged: BTreeSet<u64>,
+    /// The filesystem superblock on disk carries the needs_recovery flag.
+    pub recovery_flag_set: bool,
+}
+
+impl Journal {
+    pub fn new(inode_ref: Ext4InodeRef, super_block: JournalSuperblock) -> Self {
+        let csum_seed = super_block.csum_seed();
+        Journal {
+            inode_ref,
+            super_block,
+            csum_seed,
+            handles: 0,
+            head: super_block.first(),
+            sequence: super_block.sequence(),
+            revoked: BTreeSet::new(),
+            revoked_before_update: BTreeSet::new(),
+            aborted: false,
+            logged: BTreeSet::new(),
+            recovery_flag_set: false,
+        }
+    }
+
+    /// Number of blocks available to transactions.
+    pub fn capacity(&self) -> u32 {
+        self.super_block.maxlen() - self.super_block.first()
+    }
+
+    /// Number of tags that fit in one descriptor block.
+    pub fn tags_per_descriptor(&self) -> usize {
+        let mut space = self.block_size() - size_of::<JournalHeader>() - 16;
+        if self.super_block.has_csum_v2or3() {
+            space -= JBD2_BLOCK_TAIL_SIZE;
+        }
+        space / self.super_block.tag_bytes()
+    }
+
+    /// Number of revoke records that fit in one revoke block.
+    pub fn records_per_revoke(&self) -> usize {
+        let mut space = self.block_size() - size_of::<JournalRevokeHeader>();
+        if self.super_block.has_csum_v2or3() {
+            space -= JBD2_BLOCK_TAIL_SIZE;
+        }
+        space / self.revoke_record_bytes()
+    }
+
+    fn revoke_record_bytes(&self) -> usize {
+        if self.super_block.has_incompat(JBD2_FEATURE_INCOMPAT_64BIT) {
+            8
+        } else {
+            4
+        }
+    }
+
+    /// Number of log blocks used by a transaction logging `blocks` blocks and
+    /// revoking `revoked` blocks, commit block included.
+    pub fn transaction_len(&self, blocks: usize, revoked: usize) -> usize {
+        blocks
+            + blocks.div_ceil(self.tags_per_descriptor())
+            + revoked.div_ceil(self.records_per_revoke())
+            + 1
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.super_block.blocksize() as usize
+    }
+
+    /// Returns the journal block following `block`, the log is circular
+    /// between `first` and `maxlen`.
+    pub fn next_block(&self, block: u32) -> u32 {
+        self.wrap(block + 1)
+    }
+
+    /// Returns `block` wrapped back into the log area.
+    pub fn wrap(&self, block: u32) -> u32 {
+        let first = self.super_block.first();
+        let maxlen = self.super_block.maxlen();
+        if block >= maxlen {
+            block - (maxlen - first)
+        } else {
+            block
+        }
+    }
+
+    /// Checksum of a descriptor or revoke block, computed with a zero tail.
+    fn block_tail_csum(&self, data: &[u8]) -> u32 {
+        let body = data.len() - JBD2_BLOCK_TAIL_SIZE;
+        let csum = ext4_crc32c(self.csum_seed, &data[..body], body as u32);
+        ext4_crc32c(csum, &[0; JBD2_BLOCK_TAIL_SIZE], JBD2_BLOCK_TAIL_SIZE as u32)
+    }
+
+    /// Verify the tail checksum of a descriptor or revoke block.
+    pub fn verify_block_tail(&self, data: &[u8]) -> bool {
+        if !self.super_block.has_csum_v2or3() {
+            return true;
+        }
+        be32_at(data, data.len() - JBD2_BLOCK_TAIL_SIZE) == self.block_tail_csum(data)
+    }
+
+    /// Checksum of a commit block, computed with a zero `chksum[0]`.
+    fn commit_csum(&self, data: &[u8]) -> u32 {
+        let end = JBD2_COMMIT_CHKSUM_OFFSET;
+        let mut csum = ext4_crc32c(self.csum_seed, &data[..end], end as u32);
+        csum = ext4_crc32c(csum, &[0; 4], 4);
+        ext4_crc32c(csum, &data[end + 4..], (data.len() - end - 4) as u32)
+    }
+
+    /// Verify the checksum of a commit block.
+    pub fn verify_commit(&self, data: &[u8]) -> bool {
+        if !self.super_block.has_csum_v2or3() {
+            return true;
+        }
+        be32_at(data, JBD2_COMMIT_CHKSUM_OFFSET) == self.commit_csum(data)
+    }
+
+    /// Checksum of a logged data block: crc32c(seed, sequence, data).
+    fn data_csum(&self, sequence: u32, data: &[u8]) -> u32 {
+        let csum = ext4_crc32c(self.csum_seed, &sequence.to_be_bytes(), 4);
+        ext4_crc32c(csum, data, data.len() as u32)
+    }
+
+    /// Verify a logged data block against the checksum in its tag.
+    pub fn verify_data(&self, tag: &JournalBlockTag, sequence: u32, data: &[u8]) -> bool {
+        let sb = &self.super_block;
+        if sb.has_incompat(JBD2_FEATURE_INCOMPAT_CSUM_V3) {
+            tag.checksum == self.data_csum(sequence, data)
+        } else if sb.has_incompat(JBD2_FEATURE_INCOMPAT_CSUM_V2) {
+            tag.checksum == self.data_csum(sequence, data) & 0xFFFF
+        } else {
+            true
+        }
+    }
+
+    /// Build a descriptor block for transaction `sequence` describing `blocks`,
+    /// given as filesystem block number and the data logged for it.
+    ///
+    /// Data starting with the journal magic must be logged with its first
+    /// four bytes zeroed, `escape_block` does that and tells when it is needed.
+    pub fn build_descriptor(&self, sequence: u32, blocks: &[(u64, &[u8])]) -> Vec<u8> {
+        let sb = &self.super_block;
+        let tag_bytes = sb.tag_bytes();
+        let csum_v3 = sb.has_incompat(JBD2_FEATURE_INCOMPAT_CSUM_V3);
+        let csum_v2 = sb.has_incompat(JBD2_FEATURE_INCOMPAT_CSUM_V2);
+        let is_64bit = sb.has_incompat(JBD2_FEATURE_INCOMPAT_64BIT);
+
+        let mut data = vec![0u8; self.block_size()];
+        write_struct(&mut data, 0, &JournalHeader::new(JBD2_DESCRIPTOR_BLOCK, sequence));
+
+        let mut offset = size_of::<JournalHeader>();
+        for (i, &(blocknr, block)) in blocks.iter().enumerate() {
+            let mut flags = 0;
+            if i > 0 {
+                flags |= JBD2_FLAG_SAME_UUID;
+            }
+            if i == blocks.len() - 1 {
+                flags |= JBD2_FLAG_LAST_TAG;
+            }
+            if be32_at(block, 0) == JBD2_MAGIC_NUMBER {
+                flags |= JBD2_FLAG_ESCAPE;
+            }
+            let csum = self.data_csum(sequence, &Self::escape_block(block));
+
+            data[offset..offset + 4].copy_from_slice(&(blocknr as u32).to_be_bytes());
+            if csum_v3 {
+                data[offset + 4..offset + 8].copy_from_slice(&flags.to_be_bytes());
+                data[offset + 8..offset + 12].copy_from_slice(&((blocknr >> 32) as u32).to_be_bytes());
+                data[offset + 12..offset + 16].copy_from_slice(&csum.to_be_bytes());
+            } else {
+                if csum_v2 {
+                    data[offset + 4..offset + 6].copy_from_slice(&(csum as u16).to_be_bytes());
+                }
+                data[offset + 6..offset + 8].copy_from_slice(&(flags as u16).to_be_bytes());
+                if is_64bit {
+                    data[offset + 8..offset + 12]
+                        .copy_from_slice(&((blocknr >> 32) as u32).to_be_bytes());
+                }
+            }
+            offset += tag_bytes;
+
+            if i == 0 {
+                data[offset..offset + 16].copy_from_slice(&sb.uuid());
+                offset += 16;
+            }
+        }
+
+        self.set_block_tail(&mut data);
+        data
+    }
+
+    /// Returns the copy of `block` that goes to the log.
+    pub fn escape_block(block: &[u8]) -> Vec<u8> {
+        let mut data = block.to_vec();
+        if be32_at(&data, 0) == JBD2_MAGIC_NUMBER {
+            data[..4].fill(0);
+        }
+        data
+    }
+
+    /// Build a revoke block for transaction `sequence`.
+    pub fn build_revoke(&self, sequence: u32, blocks: &[u64]) -> Vec<u8> {
+        let record_len = self.revoke_record_bytes();
+        let mut data = vec![0u8; self.block_size()];
+        let count = size_of::<JournalRevokeHeader>() + blocks.len() * record_len;
+        write_struct(
+            &mut data,
+            0,
+            &JournalRevokeHeader {
+                header: JournalHeader::new(JBD2_REVOKE_BLOCK, sequence),
+                count: (count as u32).to_be(),
+            },
+        );
+
+        let mut offset = size_of::<JournalRevokeHeader>();
+        for &block in blocks {
+            if record_len == 8 {
+                data[offset..offset + 8].copy_from_slice(&block.to_be_bytes());
+            } else {
+                data[offset..offset + 4].copy_from_slice(&(block as u32).to_be_bytes());
+            }
+            offset += record_len;
+        }
+
+        self.set_block_tail(&mut data);
+        data
+    }
+
+    /// Build the commit block of transaction `sequence`.
+    pub fn build_commit(&self, sequence: u32) -> Vec<u8> {
+        let mut data = vec![0u8; self.block_size()];
+        write_struct(
+            &mut data,
+            0,
+            &JournalCommitHeader {
+                header: JournalHeader::new(JBD2_COMMIT_BLOCK, sequence),
+                ..Default::default()
+            },
+        );
+        if self.super_block.has_csum_v2or3() {
+            let csum = self.commit_csum(&data);
+            data[JBD2_COMMIT_CHKSUM_OFFSET..JBD2_COMMIT_CHKSUM_OFFSET + 4]
+                .copy_from_slice(&csum.to_be_bytes());
+        }
+        data
+    }
+
+    fn set_block_tail(&self, data: &mut [u8]) {
+        if self.super_block.has_csum_v2or3() {
+            let csum = self.block_tail_csum(data);
+            let tail = data.len() - JBD2_BLOCK_TAIL_SIZE;
+            data[tail..].copy_from_slice(&csum.to_be_bytes());
+        }
+    }
+
+    /// Decode the tags of a descriptor block.
+    pub fn parse_tags(&self, data: &[u8]) -> Vec<JournalBlockTag> {
+        let sb = &self.super_block;
+        let tag_bytes = sb.tag_bytes();
+        let csum_v3 = sb.has_incompat(JBD2_FEATURE_INCOMPAT_CSUM_V3);
+        let is_64bit = sb.has_incompat(JBD2_FEATURE_INCOMPAT_64BIT);
+        let end = if sb.has_csum_v2or3() {
+            data.len() - JBD2_BLOCK_TAIL_SIZE
+        } else {
+            data.len()
+        };
+
+        let mut tags = Vec::new();
+        let mut offset = size_of::<JournalHeader>();
+        while offset + tag_bytes <= end {
+            let mut tag = JournalBlockTag {
+                blocknr: be32_at(data, offset) as u64,
+                ..Default::default()
+            };
+            if csum_v3 {
+                // blocknr, flags, blocknr_high, checksum
+                tag.flags = be32_at(data, offset + 4);
+                if is_64bit {
+                    tag.blocknr |= (be32_at(data, offset + 8) as u64) << 32;
+                }
+                tag.checksum = be32_at(data, offset + 12);
+            } else {
+                // blocknr, checksum (u16), flags (u16), blocknr_high
+                tag.checksum = be16_at(data, offset + 4) as u32;
+                tag.flags = be16_at(data, offset + 6) as u32;
+                if is_64bit {
+                    tag.blocknr |= (be32_at(data, offset + 8) as u64) << 32;
+                }
+            }
+            tags.push(tag);
+
+            offset += tag_bytes;
+            if tag.flags & JBD2_FLAG_SAME_UUID == 0 {
+                offset += 16;
+            }
+            if tag.flags & JBD2_FLAG_LAST_TAG != 0 {
+                break;
+            }
+        }
+        tags
+    }
+
+    /// Decode the block numbers of a revoke block.
+    pub fn parse_revoke(&self, data: &[u8]) -> Result<Vec<u64>> {
+        let header: JournalRevokeHeader = unsafe { core::ptr::read_unaligned(data.as_ptr() as *const _) };
+        let count = header.count() as usize;
+        let limit = if self.super_block.has_csum_v2or3() {
+            data.len() - JBD2_BLOCK_TAIL_SIZE
+        } else {
+            data.len()
+        };
+        if count > limit {
+            return_errno_with_message!(Errno::EIO, "journal revoke block overflows");
+        }
+
+        let record_len = if self.super_block.has_incompat(JBD2_FEATURE_INCOMPAT_64BIT) {
+            8
+        } else {
+            4
+        };
+        let mut blocks = Vec::new();
+        let mut offset = size_of::<JournalRevokeHeader>();
+        while offset + record_len <= count {
+            let block = if record_len == 8 {
+                (be32_at(data, offset) as u64) << 32 | be32_at(data, offset + 4) as u64
+            } else {
+                be32_at(data, offset) as u64
+            };
+            blocks.push(block);
+            offset += record_len;
+        }
+        Ok(blocks)
+    }
+}
+
+/// Copy `value` into `data` at `offset`.
+fn write_struct<T: Copy>(data: &mut [u8], offset: usize, value: &T) {
+    let bytes = unsafe { core::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) };
+    data[offset..offset + bytes.len()].copy_from_slice(bytes);
+}
+
+/// Returns true if transaction id `x` is after `y`, ids wrap around.
+pub fn tid_gt(x: u32, y: u32) -> bool {
+    (x.wrapping_sub(y) as i32) > 0
+}
+
+/// Returns true if transaction id `x` is `y` or after it.
+pub fn tid_geq(x: u32, y: u32) -> bool {
+    (x.wrapping_sub(y) as i32) >= 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_tags_csum_v3() {
+        assert_eq!(size_of::<JournalSuperblock>(), 1024);
+
+        let mut sb: JournalSuperblock = unsafe { core::mem::zeroed() };
+        sb.feature_incompat = (JBD2_FEATURE_INCOMPAT_CSUM_V3 | JBD2_FEATURE_INCOMPAT_64BIT).to_be();
+        let journal = Journal::new(
+            Ext4InodeRef {
+                inode_num: JOURNAL_INODE,
+                inode: Ext4Inode::default(),
+            },
+            sb,
+        );
+
+        // first tag carries a UUID, the second one reuses it and ends the block
+        let mut block = vec![0u8; 1024];
+        block[12..16].copy_from_slice(&7u32.to_be_bytes());
+        block[20..24].copy_from_slice(&1u32.to_be_bytes());
+        block[24..28].copy_from_slice(&0xabcdu32.to_be_bytes());
+        let second = 12 + 16 + 16;
+        block[second..second + 4].copy_from_slice(&9u32.to_be_bytes());
+        block[second + 4..second + 8]
+            .copy_from_slice(&(JBD2_FLAG_SAME_UUID | JBD2_FLAG_LAST_TAG).to_be_bytes());
+
+        let tags = journal.parse_tags(&block);
+        assert_eq!(tags.len(), 2);
+        assert_eq!(tags[0].blocknr, (1 << 32) | 7);
+        assert_eq!(tags[0].checksum, 0xabcd);
+        assert_eq!(tags[1].blocknr, 9);
+    }
+}
@@ -1,23 +1,33 @@
 pub mod consts;
+pub mod acl;
 pub mod block_group;
 pub mod direntry;
 pub mod block;
+pub mod cache;
 pub mod file;
 pub mod extents;
+pub mod htree;
 pub mod inode;
+pub mod journal;
 pub mod mount_point;
 pub mod super_block;
+pub mod xattr;
 pub mod ext4;
 
 
 
 pub use consts::*;
+pub use acl::*;
 pub use block_group::*;
 pub use direntry::*;
 pub use block::*;
+pub use cache::*;
 pub use file::*;
 pub use extents::*;
+pub use htree::*;
 pub use inode::*;
+pub use journal::*;
 pub use mount_point::*;
 pub use super_block::*;
+pub use xattr::*;
 pub use ext4::*;
\ No newline at end of file
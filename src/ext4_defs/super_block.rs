@@ -1,7 +1,94 @@
 use crate::prelude::*;
+use crate::return_errno_with_message;
 use crate::utils::*;
 
 use super::*;
+
+bitflags! {
+    /// Compatible features, an implementation may ignore them.
+    #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+    pub struct Ext4FeatureCompat: u32 {
+        const DIR_PREALLOC = 0x0001;
+        const IMAGIC_INODES = 0x0002;
+        const HAS_JOURNAL = 0x0004;
+        const EXT_ATTR = 0x0008;
+        const RESIZE_INODE = 0x0010;
+        const DIR_INDEX = 0x0020;
+        const SPARSE_SUPER2 = 0x0200;
+        const FAST_COMMIT = 0x0400;
+        const STABLE_INODES = 0x0800;
+        const ORPHAN_FILE = 0x1000;
+    }
+}
+
+bitflags! {
+    /// Incompatible features, the filesystem cannot be used at all without
+    /// understanding them.
+    #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+    pub struct Ext4FeatureIncompat: u32 {
+        const COMPRESSION = 0x0001;
+        const FILETYPE = 0x0002;
+        const RECOVER = 0x0004;
+        const JOURNAL_DEV = 0x0008;
+        const META_BG = 0x0010;
+        const EXTENTS = 0x0040;
+        const BIT64 = 0x0080;
+        const MMP = 0x0100;
+        const FLEX_BG = 0x0200;
+        const EA_INODE = 0x0400;
+        const DIRDATA = 0x1000;
+        const CSUM_SEED = 0x2000;
+        const LARGEDIR = 0x4000;
+        const INLINE_DATA = 0x8000;
+        const ENCRYPT = 0x10000;
+        const CASEFOLD = 0x20000;
+    }
+}
+
+bitflags! {
+    /// Read-only compatible features, the filesystem can be read but not
+    /// written without understanding them.
+    #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+    pub struct Ext4FeatureRoCompat: u32 {
+        const SPARSE_SUPER = 0x0001;
+        const LARGE_FILE = 0x0002;
+        const BTREE_DIR = 0x0004;
+        const HUGE_FILE = 0x0008;
+        const GDT_CSUM = 0x0010;
+        const DIR_NLINK = 0x0020;
+        const EXTRA_ISIZE = 0x0040;
+        const HAS_SNAPSHOT = 0x0080;
+        const QUOTA = 0x0100;
+        const BIGALLOC = 0x0200;
+        const METADATA_CSUM = 0x0400;
+        const REPLICA = 0x0800;
+        const READONLY = 0x1000;
+        const PROJECT = 0x2000;
+        const SHARED_BLOCKS = 0x4000;
+        const VERITY = 0x8000;
+        const ORPHAN_PRESENT = 0x10000;
+    }
+}
+
+impl Ext4FeatureIncompat {
+    /// Features this implementation can mount.
+    pub const SUPPORTED: Self = Self::FILETYPE
+        .union(Self::RECOVER)
+        .union(Self::EXTENTS)
+        .union(Self::BIT64)
+        .union(Self::FLEX_BG);
+}
+
+impl Ext4FeatureRoCompat {
+    /// Features this implementation can keep consistent when writing.
+    pub const SUPPORTED: Self = Self::SPARSE_SUPER
+        .union(Self::LARGE_FILE)
+        .union(Self::HUGE_FILE)
+        .union(Self::DIR_NLINK)
+        .union(Self::EXTRA_ISIZE)
+        .union(Self::METADATA_CSUM);
+}
+
 #[repr(C)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Ext4Superblock {
@@ -37,7 +124,7 @@ pub struct Ext4Superblock {
     block_group_index: u16,      // 此超级块的块组索引
     features_compatible: u32,    // 兼容特性集
     features_incompatible: u32,  // 不兼容特性集
-    pub features_read_only: u32, // 只读兼容特性集
+    features_read_only: u32,     // 只读兼容特性集
     pub uuid: [u8; 16],          // 卷的128位uuid
     volume_name: [u8; 16],       // 卷名
     last_mounted: [u8; 64],      // 最后挂载的目录
@@ -105,6 +192,77 @@ pub struct Ext4Superblock {
 }
 
 impl Ext4Superblock {
+    /// Load the superblock from the disk.
+    pub fn load(block_device: Arc<dyn BlockDevice>) -> Result<Self> {
+        let block = Block::load(block_device, SUPERBLOCK_OFFSET, size_of::<Ext4Superblock>())?;
+        Ok(block.read_as())
+    }
+
+    /// Check that this looks like an ext4 superblock this implementation can
+    /// mount.
+    ///
+    /// Unknown incompat features are refused with `ENOTSUP`, a bad magic or
+    /// impossible geometry with `EINVAL`. Unknown ro_compat features are left
+    /// to the caller, see `unsupported_ro_compat`.
+    pub fn validate(&self) -> Result<()> {
+        if self.magic != EXT4_SUPERBLOCK_MAGIC {
+            return_errno_with_message!(Errno::EINVAL, "bad ext4 superblock magic");
+        }
+        if self.log_block_size > 6 {
+            return_errno_with_message!(Errno::EINVAL, "invalid ext4 block size");
+        }
+        if self.blocks_per_group == 0
+            || self.inodes_per_group == 0
+            || self.blocks_per_group > self.block_size() * 8
+            || self.inodes_per_group > self.block_size() * 8
+        {
+            return_errno_with_message!(Errno::EINVAL, "invalid ext4 group geometry");
+        }
+        if self.rev_level > 0
+            && (self.inode_size < 128
+                || !self.inode_size.is_power_of_two()
+                || self.inode_size as u32 > self.block_size())
+        {
+            return_errno_with_message!(Errno::EINVAL, "invalid ext4 inode size");
+        }
+        if self.is_64bit()
+            && (self.desc_size < EXT4_MIN_BLOCK_GROUP_DESCRIPTOR_SIZE
+                || !self.desc_size.is_power_of_two()
+                || self.desc_size as u32 > self.block_size())
+        {
+            return_errno_with_message!(Errno::EINVAL, "invalid ext4 group descriptor size");
+        }
+        let blocks_count = (self.blocks_count_hi as u64) << 32 | self.blocks_count_lo as u64;
+        if self.first_data_block as u64 >= blocks_count {
+            return_errno_with_message!(Errno::EINVAL, "ext4 first data block beyond the end");
+        }
+        if self.block_group_count() as u64 * self.inodes_per_group as u64 != self.inodes_count as u64 {
+            return_errno_with_message!(Errno::EINVAL, "ext4 inode count does not match the groups");
+        }
+
+        let unsupported = self.features_incompat().difference(Ext4FeatureIncompat::SUPPORTED);
+        if !unsupported.is_empty() {
+            log::error!("unsupported ext4 incompat features {:?}", unsupported);
+            return_errno_with_message!(Errno::ENOTSUP, "unsupported ext4 incompat features");
+        }
+        Ok(())
+    }
+
+    /// Returns the compatible feature set.
+    pub fn features_compat(&self) -> Ext4FeatureCompat {
+        Ext4FeatureCompat::from_bits_retain(self.features_compatible)
+    }
+
+    /// Returns the incompatible feature set.
+    pub fn features_incompat(&self) -> Ext4FeatureIncompat {
+        Ext4FeatureIncompat::from_bits_retain(self.features_incompatible)
+    }
+
+    /// Returns the read-only compatible feature set.
+    pub fn features_ro_compat(&self) -> Ext4FeatureRoCompat {
+        Ext4FeatureRoCompat::from_bits_retain(self.features_read_only)
+    }
+
     /// Returns the size of inode structure.
     pub fn inode_size(&self) -> u16 {
         self.inode_size
@@ -156,9 +314,12 @@ impl Ext4Superblock {
 
         let blocks_per_group = self.blocks_per_group as u64;
 
-        let mut block_group_count = blocks_count / blocks_per_group;
+        // block 0 is not part of any group with 1k blocks
+        let data_blocks = blocks_count - self.first_data_block as u64;
+
+        let mut block_group_count = data_blocks / blocks_per_group;
 
-        if (blocks_count % blocks_per_group) != 0 {
+        if (data_blocks % blocks_per_group) != 0 {
             block_group_count += 1;
         }
 
@@ -172,7 +333,8 @@ impl Ext4Superblock {
     pub fn desc_size(&self) -> u16 {
         let size = self.desc_size;
 
-        if size < EXT4_MIN_BLOCK_GROUP_DESCRIPTOR_SIZE {
+        // the field is only meaningful with 64bit block numbers
+        if !self.is_64bit() || size < EXT4_MIN_BLOCK_GROUP_DESCRIPTOR_SIZE {
             EXT4_MIN_BLOCK_GROUP_DESCRIPTOR_SIZE
         } else {
             size
@@ -183,6 +345,50 @@ impl Ext4Superblock {
         self.want_extra_isize
     }
 
+    /// Returns the number of blocks in the given block group.
+    /// Only the last group can be shorter than `blocks_per_group`.
+    pub fn blocks_in_group_cnt(&self, bgid: u32) -> u32 {
+        let block_group_count = self.block_group_count();
+        let blocks_count = (self.blocks_count_hi as u64) << 32 | self.blocks_count_lo as u64;
+
+        if bgid < block_group_count - 1 {
+            self.blocks_per_group
+        } else {
+            (blocks_count
+                - self.first_data_block as u64
+                - (block_group_count as u64 - 1) * self.blocks_per_group as u64) as u32
+        }
+    }
+
+    /// Returns true if the block group holds a superblock backup.
+    /// With sparse_super only groups 0, 1 and powers of 3, 5 and 7 do.
+    pub fn group_has_super(&self, bgid: u32) -> bool {
+        if bgid <= 1 || !self.features_ro_compat().contains(Ext4FeatureRoCompat::SPARSE_SUPER) {
+            return true;
+        }
+        if bgid % 2 == 0 {
+            return false;
+        }
+        [3u32, 5, 7].iter().any(|&base| {
+            let mut n = base;
+            while n < bgid {
+                n *= base;
+            }
+            n == bgid
+        })
+    }
+
+    /// Returns the number of blocks used by the superblock, the group
+    /// descriptor table and the reserved GDT blocks at the start of a group.
+    pub fn group_base_meta_blocks(&self, bgid: u32) -> u32 {
+        if !self.group_has_super(bgid) {
+            return 0;
+        }
+        let desc_per_block = self.block_size() / self.desc_size() as u32;
+        let gdt_blocks = self.block_group_count().div_ceil(desc_per_block);
+        1 + gdt_blocks + self.s_reserved_gdt_blocks as u32
+    }
+
     pub fn get_inodes_in_group_cnt(&self, bgid: u32) -> u32 {
         let block_group_count = self.block_group_count();
         let inodes_per_group = self.inodes_per_group;
@@ -209,24 +415,98 @@ impl Ext4Superblock {
         self.free_blocks_count_hi = (free_blocks >> 32) as u32;
     }
 
-    pub fn sync_to_disk(&self, block_device: Arc<dyn BlockDevice>) {
+    /// Returns true if the filesystem has a journal.
+    pub fn has_journal(&self) -> bool {
+        self.features_compat().contains(Ext4FeatureCompat::HAS_JOURNAL)
+    }
+
+    /// Returns true if the journal has to be replayed before the filesystem is used.
+    pub fn needs_recovery(&self) -> bool {
+        self.features_incompat().contains(Ext4FeatureIncompat::RECOVER)
+    }
+
+    pub fn set_needs_recovery(&mut self, needs_recovery: bool) {
+        let mut features = self.features_incompat();
+        features.set(Ext4FeatureIncompat::RECOVER, needs_recovery);
+        self.features_incompatible = features.bits();
+    }
+
+    /// Returns true if block numbers may exceed 32 bits.
+    pub fn is_64bit(&self) -> bool {
+        self.features_incompat().contains(Ext4FeatureIncompat::BIT64)
+    }
+
+    /// Returns true if files may use extent trees.
+    pub fn has_extents(&self) -> bool {
+        self.features_incompat().contains(Ext4FeatureIncompat::EXTENTS)
+    }
+
+    /// Returns true if metadata blocks carry crc32c checksums.
+    pub fn has_metadata_csum(&self) -> bool {
+        self.features_ro_compat().contains(Ext4FeatureRoCompat::METADATA_CSUM)
+    }
+
+    /// Returns true if directories may be hash-indexed.
+    pub fn has_dir_index(&self) -> bool {
+        self.features_compat().contains(Ext4FeatureCompat::DIR_INDEX)
+    }
+
+    /// Returns the seed of the htree name hash.
+    pub fn hash_seed(&self) -> [u32; 4] {
+        self.hash_seed
+    }
+
+    /// Returns the hash version used by new indexed directories.
+    pub fn default_hash_version(&self) -> u8 {
+        self.default_hash_version
+    }
+
+    /// Returns true if name hashes treat bytes as unsigned chars.
+    pub fn has_unsigned_hash(&self) -> bool {
+        self.flags & EXT4_FLAGS_UNSIGNED_HASH != 0
+    }
+
+    /// Returns the ro_compat features this implementation cannot write.
+    pub fn unsupported_ro_compat(&self) -> Ext4FeatureRoCompat {
+        self.features_ro_compat().difference(Ext4FeatureRoCompat::SUPPORTED)
+    }
+
+    pub fn increase_free_inodes_count(&mut self) {
+        self.free_inodes_count += 1;
+    }
+
+    /// Returns the inode holding the journal, 0 if the journal lives on an
+    /// external device.
+    pub fn journal_inode_number(&self) -> u32 {
+        self.journal_inode_number
+    }
+
+    /// Returns the crc32c of the superblock, the checksum field excluded.
+    pub fn compute_checksum(&self) -> u32 {
         let data = unsafe {
             core::slice::from_raw_parts(self as *const _ as *const u8, size_of::<Ext4Superblock>())
         };
-        block_device.write_offset(SUPERBLOCK_OFFSET, data);
+        ext4_crc32c(EXT4_CRC32_INIT, data, 0x3fc)
     }
 
-    pub fn sync_to_disk_with_csum(&mut self, block_device: Arc<dyn BlockDevice>) {
+    /// Returns true if the stored superblock checksum matches its content.
+    pub fn verify_checksum(&self) -> bool {
+        self.checksum_type == EXT4_CRC32C_CHKSUM && self.compute_checksum() == self.checksum
+    }
+
+    pub fn sync_to_disk(&self, block_device: Arc<dyn BlockDevice>) -> Result<()> {
         let data = unsafe {
             core::slice::from_raw_parts(self as *const _ as *const u8, size_of::<Ext4Superblock>())
         };
-        let checksum = ext4_crc32c(EXT4_CRC32_INIT, data, 0x3fc);
+        block_device.write_offset(SUPERBLOCK_OFFSET, data)
+    }
 
-        self.checksum = checksum;
+    pub fn sync_to_disk_with_csum(&mut self, block_device: Arc<dyn BlockDevice>) -> Result<()> {
+        self.checksum = self.compute_checksum();
         let data = unsafe {
             core::slice::from_raw_parts(self as *const _ as *const u8, size_of::<Ext4Superblock>())
         };
-        block_device.write_offset(SUPERBLOCK_OFFSET, data);
+        block_device.write_offset(SUPERBLOCK_OFFSET, data)
     }
 }
 
@@ -251,3 +531,42 @@ impl Ext4Superblock {
         csum
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_super_block() -> Ext4Superblock {
+        let mut sb: Ext4Superblock = unsafe { core::mem::zeroed() };
+        sb.magic = EXT4_SUPERBLOCK_MAGIC;
+        sb.rev_level = 1;
+        sb.log_block_size = 2;
+        sb.blocks_count_lo = 32768;
+        sb.blocks_per_group = 32768;
+        sb.inodes_per_group = 8192;
+        sb.inodes_count = 8192;
+        sb.inode_size = 256;
+        sb.features_incompatible = (Ext4FeatureIncompat::FILETYPE | Ext4FeatureIncompat::EXTENTS).bits();
+        sb
+    }
+
+    #[test]
+    fn test_validate_features() {
+        let mut sb = test_super_block();
+        sb.validate().unwrap();
+        assert!(sb.unsupported_ro_compat().is_empty());
+
+        sb.features_read_only = (Ext4FeatureRoCompat::METADATA_CSUM | Ext4FeatureRoCompat::PROJECT).bits();
+        sb.validate().unwrap();
+        assert_eq!(sb.unsupported_ro_compat(), Ext4FeatureRoCompat::PROJECT);
+
+        sb.features_incompatible |= Ext4FeatureIncompat::INLINE_DATA.bits();
+        assert_eq!(sb.validate().unwrap_err().error(), Errno::ENOTSUP);
+
+        let mut sb = test_super_block();
+        sb.inodes_count += 1;
+        assert_eq!(sb.validate().unwrap_err().error(), Errno::EINVAL);
+        sb.magic = 0;
+        assert_eq!(sb.validate().unwrap_err().error(), Errno::EINVAL);
+    }
+}
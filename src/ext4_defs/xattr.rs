@@ -0,0 +1,341 @@
+use crate::prelude::*;
+use crate::return_errno_with_message;
+use crate::utils::*;
+
+use super::*;
+
+/// Magic of an xattr block and of the in-inode xattr area.
+pub const EXT4_XATTR_MAGIC: u32 = 0xEA02_0000;
+
+/// Name indexes, the prefix of a name is stored as one of these.
+pub const EXT4_XATTR_INDEX_USER: u8 = 1;
+pub const EXT4_XATTR_INDEX_POSIX_ACL_ACCESS: u8 = 2;
+pub const EXT4_XATTR_INDEX_POSIX_ACL_DEFAULT: u8 = 3;
+pub const EXT4_XATTR_INDEX_TRUSTED: u8 = 4;
+pub const EXT4_XATTR_INDEX_SECURITY: u8 = 6;
+pub const EXT4_XATTR_INDEX_SYSTEM: u8 = 7;
+
+/// Longest name without its prefix.
+pub const EXT4_XATTR_NAME_MAX: usize = 255;
+
+/// Entries and values are padded to 4 bytes.
+pub const EXT4_XATTR_PAD: usize = 4;
+
+/// Prefixes of the name indexes, the ACL names are complete names.
+const EXT4_XATTR_PREFIXES: [(&str, u8); 6] = [
+    ("system.posix_acl_access", EXT4_XATTR_INDEX_POSIX_ACL_ACCESS),
+    ("system.posix_acl_default", EXT4_XATTR_INDEX_POSIX_ACL_DEFAULT),
+    ("user.", EXT4_XATTR_INDEX_USER),
+    ("trusted.", EXT4_XATTR_INDEX_TRUSTED),
+    ("security.", EXT4_XATTR_INDEX_SECURITY),
+    ("system.", EXT4_XATTR_INDEX_SYSTEM),
+];
+
+/// Header of an external xattr block, entries follow it.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Ext4XattrHeader {
+    pub magic: u32,
+    /// Number of inodes sharing this block.
+    pub refcount: u32,
+    /// Always 1.
+    pub blocks: u32,
+    /// Hash of all entry hashes, 0 if the block may not be shared.
+    pub hash: u32,
+    pub checksum: u32,
+    pub reserved: [u32; 3],
+}
+
+/// On-disk xattr entry, the name follows it without a terminating NUL.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Ext4XattrEntry {
+    pub name_len: u8,
+    pub name_index: u8,
+    /// Offset of the value, from the block start or from the first in-inode entry.
+    pub value_offs: u16,
+    /// Inode holding the value with ea_inode, 0 otherwise.
+    pub value_inum: u32,
+    pub value_size: u32,
+    pub hash: u32,
+}
+
+/// One extended attribute, decoded.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Ext4Xattr {
+    pub name_index: u8,
+    /// Name without the prefix of `name_index`.
+    pub name: Vec<u8>,
+    pub value: Vec<u8>,
+}
+
+impl Ext4Xattr {
+    /// Split a full name like "user.mime_type" into its name index and the
+    /// rest of the name.
+    ///
+    /// Params:
+    /// name: &str - full attribute name
+    /// value: &[u8] - attribute value
+    ///
+    /// Returns:
+    /// `Result<Ext4Xattr>` - `ENOTSUP` for an unknown prefix, `ERANGE` for a
+    /// name longer than `EXT4_XATTR_NAME_MAX`
+    pub fn new(name: &str, value: &[u8]) -> Result<Self> {
+        let (name_index, suffix) = match EXT4_XATTR_PREFIXES
+            .iter()
+            .find(|(prefix, _)| name.starts_with(prefix))
+        {
+            Some((prefix, index)) => (*index, &name[prefix.len()..]),
+            None => return_errno_with_message!(Errno::ENOTSUP, "unknown xattr prefix"),
+        };
+
+        let is_acl = matches!(
+            name_index,
+            EXT4_XATTR_INDEX_POSIX_ACL_ACCESS | EXT4_XATTR_INDEX_POSIX_ACL_DEFAULT
+        );
+        if is_acl != suffix.is_empty() {
+            return_errno_with_message!(Errno::EINVAL, "invalid xattr name");
+        }
+        if suffix.len() > EXT4_XATTR_NAME_MAX {
+            return_errno_with_message!(Errno::ERANGE, "xattr name too long");
+        }
+
+        Ok(Self {
+            name_index,
+            name: suffix.as_bytes().to_vec(),
+            value: value.to_vec(),
+        })
+    }
+
+    /// Full name with its prefix, None for name indexes we do not know.
+    pub fn full_name(&self) -> Option<Vec<u8>> {
+        let (prefix, _) = EXT4_XATTR_PREFIXES
+            .iter()
+            .find(|(_, index)| *index == self.name_index)?;
+        let mut name = prefix.as_bytes().to_vec();
+        name.extend_from_slice(&self.name);
+        Some(name)
+    }
+
+    /// Returns true if `other` has the same name.
+    pub fn same_name(&self, other: &Ext4Xattr) -> bool {
+        self.name_index == other.name_index && self.name == other.name
+    }
+
+    /// Size of the entry with its name.
+    pub fn entry_len(&self) -> usize {
+        (size_of::<Ext4XattrEntry>() + self.name.len()).next_multiple_of(EXT4_XATTR_PAD)
+    }
+
+    /// Size of the value with its padding.
+    pub fn value_len(&self) -> usize {
+        self.value.len().next_multiple_of(EXT4_XATTR_PAD)
+    }
+
+    /// Hash of the name and value, like `ext4_xattr_hash_entry`.
+    pub fn hash(&self) -> u32 {
+        let mut hash: u32 = 0;
+        for &c in &self.name {
+            hash = (hash << 5) ^ (hash >> 27) ^ c as u32;
+        }
+
+        let mut value = self.value.clone();
+        value.resize(self.value_len(), 0);
+        for word in value.chunks_exact(4) {
+            let word = u32::from_le_bytes(word.try_into().unwrap());
+            hash = (hash << 16) ^ (hash >> 16) ^ word;
+        }
+        hash
+    }
+
+    /// Order of the entries in an xattr block.
+    fn sort_key(&self) -> (u8, usize, &[u8]) {
+        (self.name_index, self.name.len(), &self.name)
+    }
+}
+
+/// Decode the entries of an xattr area.
+///
+/// Params:
+/// area: &[u8] - the xattr block, or the in-inode area after its magic
+/// first: usize - offset of the first entry in `area`
+///
+/// Returns:
+/// `Result<Vec<Ext4Xattr>>` - the attributes, `EIO` for a corrupted area
+pub fn ext4_xattr_parse(area: &[u8], first: usize) -> Result<Vec<Ext4Xattr>> {
+    let entry_size = size_of::<Ext4XattrEntry>();
+    let mut xattrs = Vec::new();
+    let mut offset = first;
+
+    loop {
+        if offset + 4 > area.len() {
+            return_errno_with_message!(Errno::EIO, "xattr entries overflow their area");
+        }
+        // the entry list ends with 4 zero bytes
+        if area[offset..offset + 4] == [0; 4] {
+            return Ok(xattrs);
+        }
+        if offset + entry_size > area.len() {
+            return_errno_with_message!(Errno::EIO, "xattr entries overflow their area");
+        }
+
+        let entry: Ext4XattrEntry =
+            unsafe { core::ptr::read_unaligned(area[offset..].as_ptr() as *const Ext4XattrEntry) };
+        let name_end = offset + entry_size + entry.name_len as usize;
+        if name_end > area.len() {
+            return_errno_with_message!(Errno::EIO, "xattr name overflows its area");
+        }
+        if entry.value_inum != 0 {
+            return_errno_with_message!(Errno::ENOTSUP, "xattr values in inodes are not supported");
+        }
+
+        let value_start = entry.value_offs as usize;
+        let value_end = value_start + entry.value_size as usize;
+        if entry.value_size != 0 && value_end > area.len() {
+            return_errno_with_message!(Errno::EIO, "xattr value overflows its area");
+        }
+
+        xattrs.push(Ext4Xattr {
+            name_index: entry.name_index,
+            name: area[offset + entry_size..name_end].to_vec(),
+            value: if entry.value_size != 0 {
+                area[value_start..value_end].to_vec()
+            } else {
+                Vec::new()
+            },
+        });
+        offset = name_end.next_multiple_of(EXT4_XATTR_PAD);
+    }
+}
+
+/// Returns true if the attributes fit in `space` bytes, the entries and the
+/// 4 bytes ending them growing from the start and the values from the end.
+pub fn ext4_xattr_fits(xattrs: &[Ext4Xattr], space: usize) -> bool {
+    let used: usize = xattrs.iter().map(|x| x.entry_len() + x.value_len()).sum();
+    used + 4 <= space
+}
+
+/// Encode attributes into an xattr area, the caller checked they fit with
+/// `ext4_xattr_fits`.
+///
+/// Params:
+/// xattrs: &[Ext4Xattr] - attributes, in the order of their entries
+/// area: &mut [u8] - the xattr block, or the in-inode area after its magic
+/// first: usize - offset of the first entry in `area`
+pub fn ext4_xattr_build(xattrs: &[Ext4Xattr], area: &mut [u8], first: usize) {
+    area[first..].fill(0);
+
+    let entry_size = size_of::<Ext4XattrEntry>();
+    let mut offset = first;
+    let mut value_offs = area.len();
+    for xattr in xattrs {
+        let mut entry = Ext4XattrEntry {
+            name_len: xattr.name.len() as u8,
+            name_index: xattr.name_index,
+            value_size: xattr.value.len() as u32,
+            hash: xattr.hash(),
+            ..Default::default()
+        };
+        if !xattr.value.is_empty() {
+            value_offs -= xattr.value_len();
+            entry.value_offs = value_offs as u16;
+            area[value_offs..value_offs + xattr.value.len()].copy_from_slice(&xattr.value);
+        }
+
+        unsafe {
+            core::ptr::write_unaligned(area[offset..].as_mut_ptr() as *mut Ext4XattrEntry, entry)
+        };
+        area[offset + entry_size..offset + entry_size + xattr.name.len()].copy_from_slice(&xattr.name);
+        offset += xattr.entry_len();
+    }
+}
+
+impl Ext4XattrHeader {
+    /// Decode the header at the start of an xattr block.
+    pub fn load(data: &[u8]) -> Self {
+        unsafe { core::ptr::read_unaligned(data.as_ptr() as *const Ext4XattrHeader) }
+    }
+
+    /// Encode the header at the start of an xattr block.
+    pub fn store(&self, data: &mut [u8]) {
+        unsafe { core::ptr::write_unaligned(data.as_mut_ptr() as *mut Ext4XattrHeader, *self) }
+    }
+
+    /// Sort attributes in the order of the entries of an xattr block.
+    pub fn sort(xattrs: &mut [Ext4Xattr]) {
+        xattrs.sort_by(|a, b| a.sort_key().cmp(&b.sort_key()));
+    }
+
+    /// Hash of the whole block from its entry hashes, like `ext4_xattr_rehash`.
+    pub fn block_hash(xattrs: &[Ext4Xattr]) -> u32 {
+        xattrs.iter().fold(0u32, |hash, x| (hash << 16) ^ (hash >> 16) ^ x.hash())
+    }
+
+    /// Compute the checksum of an xattr block.
+    ///
+    /// Params:
+    /// s: &Ext4Superblock - superblock, for the uuid
+    /// pblock: Ext4Fsblk - block number of the xattr block
+    /// data: &[u8] - the whole xattr block
+    pub fn compute_checksum(s: &Ext4Superblock, pblock: Ext4Fsblk, data: &[u8]) -> u32 {
+        // the checksum field counts as zero
+        let offset = 16;
+        let mut csum = ext4_crc32c(EXT4_CRC32_INIT, &s.uuid, s.uuid.len() as u32);
+        csum = ext4_crc32c(csum, &pblock.to_le_bytes(), 8);
+        csum = ext4_crc32c(csum, &data[..offset], offset as u32);
+        csum = ext4_crc32c(csum, &[0; 4], 4);
+        ext4_crc32c(csum, &data[offset + 4..], (data.len() - offset - 4) as u32)
+    }
+
+    /// Returns true if the checksum of an xattr block matches its content.
+    pub fn verify_checksum(s: &Ext4Superblock, pblock: Ext4Fsblk, data: &[u8]) -> bool {
+        Self::load(data).checksum == Self::compute_checksum(s, pblock, data)
+    }
+
+    /// Store the checksum of an xattr block in its header.
+    pub fn set_checksum(s: &Ext4Superblock, pblock: Ext4Fsblk, data: &mut [u8]) {
+        let mut header = Self::load(data);
+        header.checksum = Self::compute_checksum(s, pblock, data);
+        header.store(data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_xattr_name() {
+        let x = Ext4Xattr::new("user.mime_type", b"text/plain").unwrap();
+        assert_eq!(x.name_index, EXT4_XATTR_INDEX_USER);
+        assert_eq!(x.name, b"mime_type");
+        assert_eq!(x.full_name().unwrap(), b"user.mime_type");
+
+        let acl = Ext4Xattr::new("system.posix_acl_access", &[]).unwrap();
+        assert_eq!(acl.name_index, EXT4_XATTR_INDEX_POSIX_ACL_ACCESS);
+        assert!(acl.name.is_empty());
+
+        assert_eq!(Ext4Xattr::new("user.", &[]).unwrap_err().error(), Errno::EINVAL);
+        assert_eq!(Ext4Xattr::new("foo.bar", &[]).unwrap_err().error(), Errno::ENOTSUP);
+    }
+
+    #[test]
+    fn test_xattr_build_parse() {
+        let mut xattrs = vec![
+            Ext4Xattr::new("user.b", b"12345").unwrap(),
+            Ext4Xattr::new("security.selinux", b"system_u:object_r:etc_t:s0\0").unwrap(),
+            Ext4Xattr::new("user.a", &[]).unwrap(),
+        ];
+        Ext4XattrHeader::sort(&mut xattrs);
+        assert_eq!(xattrs[0].name, b"a");
+
+        let mut block = vec![0u8; 1024];
+        let first = size_of::<Ext4XattrHeader>();
+        assert!(ext4_xattr_fits(&xattrs, block.len() - first));
+        ext4_xattr_build(&xattrs, &mut block, first);
+        assert_eq!(ext4_xattr_parse(&block, first).unwrap(), xattrs);
+
+        // a value overflowing the area is refused
+        assert!(!ext4_xattr_fits(&xattrs, 64));
+    }
+}
@@ -0,0 +1,183 @@
+use crate::prelude::*;
+
+use crate::ext4_defs::*;
+
+impl Ext4 {
+    /// Load an ACL of an inode.
+    ///
+    /// Params:
+    /// inode: u32 - inode number
+    /// name: &str - `EXT4_XATTR_POSIX_ACL_ACCESS` or `EXT4_XATTR_POSIX_ACL_DEFAULT`
+    ///
+    /// Returns:
+    /// `Result<Option<Ext4Acl>>` - the ACL, None if the inode has none
+    pub fn get_acl(&self, inode: u32, name: &str) -> Result<Option<Ext4Acl>> {
+        match self.getxattr(inode, name) {
+            Ok(value) => Ok(Some(Ext4Acl::from_disk(&value)?)),
+            Err(e) if e.error() == Errno::ENODATA => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Check whether a user may access an inode, through its access ACL if
+    /// it has one and its mode bits otherwise.
+    ///
+    /// Params:
+    /// inode: u32 - inode number
+    /// uid: u16 - user asking for access
+    /// gid: u16 - group of that user
+    /// access_mode: u16 - `R_OK`, `W_OK` and `X_OK` bits
+    /// umask: u16 - umask of the caller
+    ///
+    /// Returns:
+    /// `Result<bool>` - true if the access is granted
+    pub fn inode_check_access(
+        &self,
+        inode: u32,
+        uid: u16,
+        gid: u16,
+        access_mode: u16,
+        umask: u16,
+    ) -> Result<bool> {
+        let inode_ref = self.get_inode_ref(inode)?;
+        match self.get_acl(inode, EXT4_XATTR_POSIX_ACL_ACCESS)? {
+            Some(acl) => Ok(acl.check_access(&inode_ref.inode, uid, gid, access_mode, umask)),
+            None => Ok(inode_ref.inode.check_access(uid, gid, access_mode, umask)),
+        }
+    }
+
+    /// Give a new inode the default ACL of its parent directory, like
+    /// `posix_acl_create`. Directories keep it as their own default ACL,
+    /// and the mode of the inode is limited by it.
+    ///
+    /// Params:
+    /// parent: u32 - inode number of the parent directory
+    /// inode: u32 - inode number of the new inode
+    pub fn acl_inherit(&self, parent: u32, inode: u32) -> Result<()> {
+        let mut acl = match self.get_acl(parent, EXT4_XATTR_POSIX_ACL_DEFAULT)? {
+            Some(acl) => acl,
+            None => return Ok(()),
+        };
+
+        let mut inode_ref = self.get_inode_ref(inode)?;
+        if inode_ref.inode.is_dir() {
+            self.setxattr(inode, EXT4_XATTR_POSIX_ACL_DEFAULT, &acl.to_disk(), 0)?;
+        }
+
+        let mut mode = inode_ref.inode.mode();
+        let not_equiv = acl.create_masq(&mut mode);
+        inode_ref.inode.set_mode(mode);
+        self.write_back_inode(&mut inode_ref)?;
+
+        // an ACL equivalent to the mode bits is not stored
+        if not_equiv {
+            self.setxattr(inode, EXT4_XATTR_POSIX_ACL_ACCESS, &acl.to_disk(), 0)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ext4_impls::test_utils::*;
+
+    fn acl(entries: &[(u16, u16, u32)]) -> Ext4Acl {
+        let entries = entries
+            .iter()
+            .map(|&(tag, perm, id)| Ext4AclEntry { tag, perm, id })
+            .collect();
+        Ext4Acl { entries }
+    }
+
+    /// Owner rwx, user 1001 rw-, owning group r-x, group 2000 rwx, mask
+    /// r-x, other r--.
+    fn named_acl() -> Ext4Acl {
+        acl(&[
+            (ACL_USER_OBJ, 0o7, ACL_UNDEFINED_ID),
+            (ACL_USER, 0o6, 1001),
+            (ACL_GROUP_OBJ, 0o5, ACL_UNDEFINED_ID),
+            (ACL_GROUP, 0o7, 2000),
+            (ACL_MASK, 0o5, ACL_UNDEFINED_ID),
+            (ACL_OTHER, 0o4, ACL_UNDEFINED_ID),
+        ])
+    }
+
+    #[test]
+    fn test_acl_inherit() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+        ext4.dir_mk("d").unwrap();
+        let dir = lookup(&ext4, "d").unwrap();
+        let default = named_acl();
+        ext4.setxattr(dir, EXT4_XATTR_POSIX_ACL_DEFAULT, &default.to_disk(), 0).unwrap();
+
+        // new inodes are made 0777, the group class bits come from the mask
+        let file = ext4.create(dir, "f", InodeFileType::S_IFREG.bits()).unwrap().inode_num;
+        ext4.dir_mk("d/sub").unwrap();
+        let sub = lookup(&ext4, "d/sub").unwrap();
+        for inode in [file, sub] {
+            assert_eq!(ext4.get_inode_ref(inode).unwrap().inode.mode() & 0o777, 0o754);
+            let access = ext4.get_acl(inode, EXT4_XATTR_POSIX_ACL_ACCESS).unwrap();
+            assert_eq!(access, Some(named_acl()));
+        }
+        // only directories pass the default ACL on
+        assert_eq!(ext4.get_acl(file, EXT4_XATTR_POSIX_ACL_DEFAULT).unwrap(), None);
+        assert_eq!(ext4.get_acl(sub, EXT4_XATTR_POSIX_ACL_DEFAULT).unwrap(), Some(default));
+        let nested = ext4.create(sub, "f", InodeFileType::S_IFREG.bits()).unwrap().inode_num;
+        assert_eq!(ext4.get_inode_ref(nested).unwrap().inode.mode() & 0o777, 0o754);
+
+        // an inherited ACL the mode bits can express is not stored
+        let minimal = acl(&[
+            (ACL_USER_OBJ, 0o6, ACL_UNDEFINED_ID),
+            (ACL_GROUP_OBJ, 0o4, ACL_UNDEFINED_ID),
+            (ACL_OTHER, 0o0, ACL_UNDEFINED_ID),
+        ]);
+        ext4.setxattr(dir, EXT4_XATTR_POSIX_ACL_DEFAULT, &minimal.to_disk(), 0).unwrap();
+        let plain = ext4.create(dir, "plain", InodeFileType::S_IFREG.bits()).unwrap().inode_num;
+        assert_eq!(ext4.get_inode_ref(plain).unwrap().inode.mode() & 0o777, 0o640);
+        assert_eq!(ext4.get_acl(plain, EXT4_XATTR_POSIX_ACL_ACCESS).unwrap(), None);
+
+        // and nothing is inherited without a default ACL
+        let root_file = create_file(&ext4, "f");
+        assert_eq!(ext4.get_inode_ref(root_file).unwrap().inode.mode() & 0o777, 0o777);
+        assert_eq!(ext4.get_acl(root_file, EXT4_XATTR_POSIX_ACL_ACCESS).unwrap(), None);
+    }
+
+    #[test]
+    fn test_acl_access() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let mut ext4 = mount(&disk);
+        let inode = create_file(&ext4, "f");
+        let mut inode_ref = ext4.get_inode_ref(inode).unwrap();
+        inode_ref.inode.set_uid(500);
+        inode_ref.inode.set_gid(600);
+        inode_ref.inode.set_mode(InodeFileType::S_IFREG.bits() | 0o654);
+        ext4.write_back_inode(&mut inode_ref).unwrap();
+        let access = named_acl();
+        ext4.setxattr(inode, EXT4_XATTR_POSIX_ACL_ACCESS, &access.to_disk(), 0).unwrap();
+
+        let (r, w, x) = (R_OK as u16, W_OK as u16, X_OK as u16);
+        let mut check = |uid, gid, mode| ext4.fuse_access(inode as u64, uid, gid, mode, 0).unwrap();
+        // the owner entry is not masked
+        assert!(check(500, 600, r | w | x));
+        // named user and named group entries are limited by the mask
+        assert!(check(1001, 7, r));
+        assert!(!check(1001, 7, w));
+        assert!(check(7, 2000, r | x));
+        assert!(!check(7, 2000, w));
+        // the named user entry decides before any group entry
+        assert!(!check(1001, 2000, x));
+        assert!(check(7, 600, r | x));
+        assert!(!check(7, 600, w));
+        assert!(check(7, 7, r));
+        assert!(!check(7, 7, x));
+
+        // without the ACL, the mode bits decide
+        ext4.removexattr(inode, EXT4_XATTR_POSIX_ACL_ACCESS).unwrap();
+        let mut check = |uid, gid, mode| ext4.fuse_access(inode as u64, uid, gid, mode, 0).unwrap();
+        assert!(!check(500, 600, x));
+        assert!(!check(1001, 7, r | x) && check(1001, 7, r));
+        assert!(check(7, 2000, r) && !check(7, 2000, x));
+    }
+}
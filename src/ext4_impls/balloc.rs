@@ -69,6 +69,91 @@ impl Ext4 {
     }
 
 
+    /// Load a block group descriptor and verify its checksum.
+    ///
+    /// Params:
+    /// `bgid` - Block group index.
+    ///
+    /// Returns:
+    /// `Result<Ext4BlockGroup>` - The block group descriptor.
+    pub fn load_block_group(&self, bgid: u32) -> Result<Ext4BlockGroup> {
+        let bg = Ext4BlockGroup::load_new(self.block_device.clone(), &self.super_block, bgid as usize)?;
+        self.verify_csum("block group", bgid as u64, || {
+            bg.verify_checksum(bgid, &self.super_block)
+        })?;
+        Ok(bg)
+    }
+
+    /// Load the block bitmap of a block group.
+    ///
+    /// A group flagged BLOCK_UNINIT has no bitmap on disk yet, so one is
+    /// built from the group layout and the flag is cleared. The caller
+    /// is responsible for writing back both the bitmap and the descriptor.
+    ///
+    /// Params:
+    /// `bg` - Block group descriptor.
+    /// `bgid` - Block group index.
+    ///
+    /// Returns:
+    /// `Result<Block>` - The block bitmap.
+    pub fn load_block_bitmap(&self, bg: &mut Ext4BlockGroup, bgid: u32) -> Result<Block> {
+        let super_block = &self.super_block;
+        let block_size = super_block.block_size() as usize;
+        let bmp_blk_adr = bg.get_block_bitmap_block(super_block);
+
+        if !bg.is_block_uninit() {
+            let bitmap_block = Block::load(
+                self.block_device.clone(),
+                bmp_blk_adr as usize * block_size,
+                block_size,
+            )?;
+            self.verify_csum("block bitmap", bgid as u64, || {
+                bg.verify_block_bitmap_csum(super_block, &bitmap_block.data)
+            })?;
+            return Ok(bitmap_block);
+        }
+
+        let mut bitmap_block = Block {
+            disk_offset: bmp_blk_adr as usize * block_size,
+            data: vec![0u8; block_size],
+        };
+        let bitmap = &mut bitmap_block.data;
+
+        // superblock backup, group descriptors and reserved GDT blocks
+        let base_meta = super_block.group_base_meta_blocks(bgid);
+        if base_meta > 0 {
+            ext4_bmap_bits_set(bitmap, 0, base_meta - 1);
+        }
+
+        // bitmaps and inode table, unless flex_bg placed them elsewhere
+        let inode_table_blocks = (super_block.inodes_per_group() as u64
+            * super_block.inode_size() as u64)
+            .div_ceil(block_size as u64);
+        let itable = bg.get_inode_table_blk_num() as u64;
+        let metadata = [
+            (bmp_blk_adr, 1),
+            (bg.get_inode_bitmap_block(super_block), 1),
+            (itable, inode_table_blocks),
+        ];
+        for (start, count) in metadata {
+            for blk in start..start + count {
+                if self.get_bgid_of_block(blk) == bgid {
+                    ext4_bmap_bit_set(bitmap, self.addr_to_idx_bg(blk));
+                }
+            }
+        }
+
+        // padding past the end of a short last group
+        let blocks_in_group = super_block.blocks_in_group_cnt(bgid);
+        let bitmap_bits = (block_size * 8) as u32;
+        if blocks_in_group < bitmap_bits {
+            ext4_bmap_bits_set(bitmap, blocks_in_group, bitmap_bits - 1);
+        }
+
+        bg.clear_block_uninit();
+        Ok(bitmap_block)
+    }
+
     /// Allocate a new block.
     ///
     /// Params:
@@ -82,9 +167,13 @@ impl Ext4 {
         inode_ref: &mut Ext4InodeRef,
         goal: Option<Ext4Fsblk>,
     ) -> Result<Ext4Fsblk> {
+        self.check_writable()?;
+
         let mut alloc: Ext4Fsblk = 0;
         let super_block = &self.super_block;
         let blocks_per_group = super_block.blocks_per_group();
+        let block_size = super_block.block_size() as usize;
+        let block_group_count = super_block.block_group_count();
         let mut bgid;
         let mut idx_in_bg;
 
@@ -92,17 +181,16 @@ impl Ext4 {
             bgid = self.get_bgid_of_block(goal);
             idx_in_bg = self.addr_to_idx_bg(goal);
         } else {
-            bgid = 1;
+            // skip the metadata heavy first group unless it is the only one
+            bgid = 1 % block_group_count;
             idx_in_bg = 0;
         }
 
-        let block_group_count = super_block.block_group_count();
         let mut count = block_group_count;
 
         while count > 0 {
             // Load block group reference
-            let mut block_group =
-                Ext4BlockGroup::load_new(self.block_device.clone(), super_block, bgid as usize);
+            let mut block_group = self.load_block_group(bgid)?;
 
             let free_blocks = block_group.get_free_blocks_count();
             if free_blocks == 0 {
@@ -127,19 +215,18 @@ impl Ext4 {
 
             // Load block with bitmap
             let bmp_blk_adr = block_group.get_block_bitmap_block(super_block);
-            let mut bitmap_block =
-                Block::load(self.block_device.clone(), bmp_blk_adr as usize * BLOCK_SIZE);
+            let mut bitmap_block = self.load_block_bitmap(&mut block_group, bgid)?;
 
             // Check if goal is free
             if ext4_bmap_is_bit_clr(&bitmap_block.data, idx_in_bg) {
                 ext4_bmap_bit_set(&mut bitmap_block.data, idx_in_bg);
                 block_group.set_block_group_balloc_bitmap_csum(super_block, &bitmap_block.data);
                 self.block_device
-                    .write_offset(bmp_blk_adr as usize * BLOCK_SIZE, &bitmap_block.data);
+                    .write_offset(bmp_blk_adr as usize * block_size, &bitmap_block.data)?;
                 alloc = self.bg_idx_to_addr(idx_in_bg, bgid);
 
                 /* Update free block counts */
-                self.update_free_block_counts(inode_ref, &mut block_group, bgid as usize)?;
+                self.update_free_block_counts(inode_ref, &mut block_group, bgid as usize, 1)?;
                 return Ok(alloc);
             }
 
@@ -152,9 +239,9 @@ impl Ext4 {
                     ext4_bmap_bit_set(&mut bitmap_block.data, tmp_idx);
                     block_group.set_block_group_balloc_bitmap_csum(super_block, &bitmap_block.data);
                     self.block_device
-                        .write_offset(bmp_blk_adr as usize * BLOCK_SIZE, &bitmap_block.data);
+                        .write_offset(bmp_blk_adr as usize * block_size, &bitmap_block.data)?;
                     alloc = self.bg_idx_to_addr(tmp_idx, bgid);
-                    self.update_free_block_counts(inode_ref, &mut block_group, bgid as usize)?;
+                    self.update_free_block_counts(inode_ref, &mut block_group, bgid as usize, 1)?;
                     return Ok(alloc);
                 }
             }
@@ -165,9 +252,9 @@ impl Ext4 {
                 ext4_bmap_bit_set(&mut bitmap_block.data, rel_blk_idx);
                 block_group.set_block_group_balloc_bitmap_csum(super_block, &bitmap_block.data);
                 self.block_device
-                    .write_offset(bmp_blk_adr as usize * BLOCK_SIZE, &bitmap_block.data);
+                    .write_offset(bmp_blk_adr as usize * block_size, &bitmap_block.data)?;
                 alloc = self.bg_idx_to_addr(rel_blk_idx, bgid);
-                self.update_free_block_counts(inode_ref, &mut block_group, bgid as usize)?;
+                self.update_free_block_counts(inode_ref, &mut block_group, bgid as usize, 1)?;
                 return Ok(alloc);
             }
 
@@ -192,21 +279,23 @@ impl Ext4 {
         inode_ref: &mut Ext4InodeRef,
         start_bgid: &mut u32,
     ) -> Result<Ext4Fsblk> {
+        self.check_writable()?;
+
         let mut alloc: Ext4Fsblk = 0;
         let super_block = &self.super_block;
         let blocks_per_group = super_block.blocks_per_group();
+        let block_size = super_block.block_size() as usize;
+        let block_group_count = super_block.block_group_count();
 
-        let mut bgid = *start_bgid;
+        let mut bgid = *start_bgid % block_group_count;
         let mut idx_in_bg = 0;
 
 
-        let block_group_count = super_block.block_group_count();
         let mut count = block_group_count;
 
         while count > 0 {
             // Load block group reference
-            let mut block_group =
-                Ext4BlockGroup::load_new(self.block_device.clone(), super_block, bgid as usize);
+            let mut block_group = self.load_block_group(bgid)?;
 
             let free_blocks = block_group.get_free_blocks_count();
             if free_blocks == 0 {
@@ -231,19 +320,18 @@ impl Ext4 {
 
             // Load block with bitmap
             let bmp_blk_adr = block_group.get_block_bitmap_block(super_block);
-            let mut bitmap_block =
-                Block::load(self.block_device.clone(), bmp_blk_adr as usize * BLOCK_SIZE);
+            let mut bitmap_block = self.load_block_bitmap(&mut block_group, bgid)?;
 
             // Check if goal is free
             if ext4_bmap_is_bit_clr(&bitmap_block.data, idx_in_bg) {
                 ext4_bmap_bit_set(&mut bitmap_block.data, idx_in_bg);
                 block_group.set_block_group_balloc_bitmap_csum(super_block, &bitmap_block.data);
                 self.block_device
-                    .write_offset(bmp_blk_adr as usize * BLOCK_SIZE, &bitmap_block.data);
+                    .write_offset(bmp_blk_adr as usize * block_size, &bitmap_block.data)?;
                 alloc = self.bg_idx_to_addr(idx_in_bg, bgid);
 
                 /* Update free block counts */
-                self.update_free_block_counts(inode_ref, &mut block_group, bgid as usize)?;
+                self.update_free_block_counts(inode_ref, &mut block_group, bgid as usize, 1)?;
 
                 *start_bgid = bgid;
                 return Ok(alloc);
@@ -258,9 +346,9 @@ impl Ext4 {
                     ext4_bmap_bit_set(&mut bitmap_block.data, tmp_idx);
                     block_group.set_block_group_balloc_bitmap_csum(super_block, &bitmap_block.data);
                     self.block_device
-                        .write_offset(bmp_blk_adr as usize * BLOCK_SIZE, &bitmap_block.data);
+                        .write_offset(bmp_blk_adr as usize * block_size, &bitmap_block.data)?;
                     alloc = self.bg_idx_to_addr(tmp_idx, bgid);
-                    self.update_free_block_counts(inode_ref, &mut block_group, bgid as usize)?;
+                    self.update_free_block_counts(inode_ref, &mut block_group, bgid as usize, 1)?;
 
                     *start_bgid = bgid;
                     return Ok(alloc);
@@ -273,9 +361,9 @@ impl Ext4 {
                 ext4_bmap_bit_set(&mut bitmap_block.data, rel_blk_idx);
                 block_group.set_block_group_balloc_bitmap_csum(super_block, &bitmap_block.data);
                 self.block_device
-                    .write_offset(bmp_blk_adr as usize * BLOCK_SIZE, &bitmap_block.data);
+                    .write_offset(bmp_blk_adr as usize * block_size, &bitmap_block.data)?;
                 alloc = self.bg_idx_to_addr(rel_blk_idx, bgid);
-                self.update_free_block_counts(inode_ref, &mut block_group, bgid as usize)?;
+                self.update_free_block_counts(inode_ref, &mut block_group, bgid as usize, 1)?;
 
                 *start_bgid = bgid;
                 return Ok(alloc);
@@ -289,100 +377,168 @@ impl Ext4 {
         return_errno_with_message!(Errno::ENOSPC, "No free blocks available in all block groups");
     }
 
+    /// Allocate a run of contiguous blocks.
+    ///
+    /// Params:
+    /// `inode_ref` - Reference to the inode.
+    /// `goal` - Absolute address of the block to start the search from.
+    /// `max_count` - Maximum number of blocks to allocate.
+    ///
+    /// Returns:
+    /// `Result<(Ext4Fsblk, u32)>` - The first block allocated and the number of blocks,
+    /// the run stops at the first used block or at the end of the block group.
+    pub fn balloc_alloc_blocks(
+        &self,
+        inode_ref: &mut Ext4InodeRef,
+        goal: Option<Ext4Fsblk>,
+        max_count: u32,
+    ) -> Result<(Ext4Fsblk, u32)> {
+        self.check_writable()?;
+
+        let super_block = &self.super_block;
+        let blocks_per_group = super_block.blocks_per_group();
+        let block_size = super_block.block_size() as usize;
+        let block_group_count = super_block.block_group_count();
+
+        let (mut bgid, mut idx_in_bg) = match goal {
+            Some(goal) => (self.get_bgid_of_block(goal), self.addr_to_idx_bg(goal)),
+            // skip the metadata heavy first group unless it is the only one
+            None => (1 % block_group_count, 0),
+        };
+
+        for _ in 0..block_group_count {
+            let mut block_group = self.load_block_group(bgid)?;
+
+            if block_group.get_free_blocks_count() > 0 {
+                let first_in_bg_index = self.addr_to_idx_bg(self.get_block_of_bgid(bgid));
+                idx_in_bg = idx_in_bg.max(first_in_bg_index);
+
+                let bmp_blk_adr = block_group.get_block_bitmap_block(super_block);
+                let mut bitmap_block = self.load_block_bitmap(&mut block_group, bgid)?;
+
+                let mut start = 0;
+                if idx_in_bg < blocks_per_group
+                    && ext4_bmap_bit_find_clr(&bitmap_block.data, idx_in_bg, blocks_per_group, &mut start)
+                {
+                    let mut count = 1;
+                    while count < max_count
+                        && start + count < blocks_per_group
+                        && ext4_bmap_is_bit_clr(&bitmap_block.data, start + count)
+                    {
+                        count += 1;
+                    }
+
+                    ext4_bmap_bits_set(&mut bitmap_block.data, start, start + count - 1);
+                    block_group.set_block_group_balloc_bitmap_csum(super_block, &bitmap_block.data);
+                    self.block_device
+                        .write_offset(bmp_blk_adr as usize * block_size, &bitmap_block.data)?;
+                    self.update_free_block_counts(inode_ref, &mut block_group, bgid as usize, count)?;
+
+                    return Ok((self.bg_idx_to_addr(start, bgid), count));
+                }
+            }
+
+            // No free block found in this group, try the next one from its start
+            bgid = (bgid + 1) % block_group_count;
+            idx_in_bg = 0;
+        }
+
+        return_errno_with_message!(Errno::ENOSPC, "No free blocks available in all block groups");
+    }
+
     fn update_free_block_counts(
         &self,
         inode_ref: &mut Ext4InodeRef,
         block_group: &mut Ext4BlockGroup,
         bgid: usize,
+        count: u32,
     ) -> Result<()> {
-        let mut super_block = self.super_block;
-        let block_size = BLOCK_SIZE as u64;
+        let mut super_block = Ext4Superblock::load(self.block_device.clone())?;
+        let block_size = super_block.block_size() as u64;
 
         // Update superblock free blocks count
         let mut super_blk_free_blocks = super_block.free_blocks_count();
-        super_blk_free_blocks -= 1;
+        super_blk_free_blocks -= count as u64;
         super_block.set_free_blocks_count(super_blk_free_blocks);
-        super_block.sync_to_disk_with_csum(self.block_device.clone());
+        super_block.sync_to_disk_with_csum(self.block_device.clone())?;
 
         // Update inode blocks (different block size!) count
         let mut inode_blocks = inode_ref.inode.blocks_count();
-        inode_blocks += block_size / EXT4_INODE_BLOCK_SIZE as u64;
+        inode_blocks += count as u64 * (block_size / EXT4_INODE_BLOCK_SIZE as u64);
         inode_ref.inode.set_blocks_count(inode_blocks);
-        self.write_back_inode(inode_ref);
+        self.write_back_inode(inode_ref)?;
 
         // Update block group free blocks count
         let mut fb_cnt = block_group.get_free_blocks_count();
-        fb_cnt -= 1;
+        fb_cnt -= count as u64;
         block_group.set_free_blocks_count(fb_cnt as u32);
-        block_group.sync_to_disk_with_csum(self.block_device.clone(), bgid, &super_block);
+        block_group.sync_to_disk_with_csum(self.block_device.clone(), bgid, &super_block)?;
 
         Ok(())
     }
 
     #[allow(unused)]
-    pub fn balloc_free_blocks(&self, inode_ref: &mut Ext4InodeRef, start: Ext4Fsblk, count: u32) {
+    pub fn balloc_free_blocks(
+        &self,
+        inode_ref: &mut Ext4InodeRef,
+        start: Ext4Fsblk,
+        count: u32,
+    ) -> Result<()> {
+        self.check_writable()?;
+
         // log::trace!("balloc_free_blocks start {:x?} count {:x?}", start, count);
         let mut count = count as usize;
         let mut start = start;
 
-        let mut super_block = self.super_block;
+        let mut super_block = Ext4Superblock::load(self.block_device.clone())?;
 
         let blocks_per_group = super_block.blocks_per_group();
+        let block_size = super_block.block_size() as usize;
 
-        let bgid = start / blocks_per_group as u64;
-
-        let mut bg_first = start / blocks_per_group as u64;
-        let mut bg_last = (start + count as u64 - 1) / blocks_per_group as u64;
-
-        while bg_first <= bg_last {
-            let idx_in_bg = start % blocks_per_group as u64;
+        while count > 0 {
+            let bgid = self.get_bgid_of_block(start);
+            let idx_in_bg = self.addr_to_idx_bg(start);
 
-            let mut bg =
-                Ext4BlockGroup::load_new(self.block_device.clone(), &super_block, bgid as usize);
+            let mut bg = self.load_block_group(bgid)?;
 
             let block_bitmap_block = bg.get_block_bitmap_block(&super_block);
-            let mut raw_data = self
-                .block_device
-                .read_offset(block_bitmap_block as usize * BLOCK_SIZE);
+            let mut raw_data = self.load_block_bitmap(&mut bg, bgid)?.data;
             let mut data: &mut Vec<u8> = &mut raw_data;
 
-            let mut free_cnt = BLOCK_SIZE * 8 - idx_in_bg as usize;
+            // do not cross the end of this block group
+            let free_cnt = min(count, (blocks_per_group - idx_in_bg) as usize);
 
-            if count > free_cnt {
-            } else {
-                free_cnt = count;
-            }
+            ext4_bmap_bits_free(data, idx_in_bg, idx_in_bg + free_cnt as u32 - 1);
 
-            ext4_bmap_bits_free(data, idx_in_bg as u32, free_cnt as u32);
+            self.journal_revoke(start, free_cnt as u64);
 
             count -= free_cnt;
             start += free_cnt as u64;
 
             bg.set_block_group_balloc_bitmap_csum(&super_block, data);
             self.block_device
-                .write_offset(block_bitmap_block as usize * BLOCK_SIZE, data);
+                .write_offset(block_bitmap_block as usize * block_size, data)?;
 
             /* Update superblock free blocks count */
             let mut super_blk_free_blocks = super_block.free_blocks_count();
 
             super_blk_free_blocks += free_cnt as u64;
             super_block.set_free_blocks_count(super_blk_free_blocks);
-            super_block.sync_to_disk_with_csum(self.block_device.clone());
+            super_block.sync_to_disk_with_csum(self.block_device.clone())?;
 
             /* Update inode blocks (different block size!) count */
             let mut inode_blocks = inode_ref.inode.blocks_count();
 
-            inode_blocks -= (free_cnt  * (BLOCK_SIZE / EXT4_INODE_BLOCK_SIZE)) as u64;
+            inode_blocks -= (free_cnt * (block_size / EXT4_INODE_BLOCK_SIZE)) as u64;
             inode_ref.inode.set_blocks_count(inode_blocks);
-            self.write_back_inode(inode_ref);
+            self.write_back_inode(inode_ref)?;
 
             /* Update block group free blocks count */
             let mut fb_cnt = bg.get_free_blocks_count();
             fb_cnt += free_cnt as u64;
             bg.set_free_blocks_count(fb_cnt as u32);
-            bg.sync_to_disk_with_csum(self.block_device.clone(), bgid as usize, &super_block);
-
-            bg_first += 1;
+            bg.sync_to_disk_with_csum(self.block_device.clone(), bgid as usize, &super_block)?;
         }
+        Ok(())
     }
 }
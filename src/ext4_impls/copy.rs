@@ -0,0 +1,254 @@
+use crate::prelude::*;
+use crate::return_errno_with_message;
+
+use crate::ext4_defs::*;
+
+impl Ext4 {
+    /// Copy a byte range between two files, like the copy_file_range syscall.
+    ///
+    /// The source is walked extent by extent: data is read from the device
+    /// in large contiguous requests and written to the destination, holes
+    /// are punched and unwritten extents are zeroed as unwritten extents in
+    /// an extent mapped destination. A destination mapped by indirect blocks
+    /// only gets zeros written where it already has blocks.
+    ///
+    /// Params:
+    /// inode_in: u32 - inode number of the source file
+    /// off_in: u64 - offset in the source file
+    /// inode_out: u32 - inode number of the destination file
+    /// off_out: u64 - offset in the destination file
+    /// len: u64 - number of bytes to copy
+    /// flags: u32 - must be 0
+    ///
+    /// Returns:
+    /// `Result<u64>` - number of bytes copied, short at the end of the source file
+    pub fn copy_file_range(
+        &self,
+        inode_in: u32,
+        off_in: u64,
+        inode_out: u32,
+        off_out: u64,
+        len: u64,
+        flags: u32,
+    ) -> Result<u64> {
+        self.check_writable()?;
+
+        if flags != 0 {
+            return_errno_with_message!(Errno::EINVAL, "unsupported copy_file_range flags");
+        }
+        let in_ref = self.get_inode_ref(inode_in)?;
+        let out_ref = self.get_inode_ref(inode_out)?;
+        for inode_ref in [&in_ref, &out_ref] {
+            if inode_ref.inode.is_dir() {
+                return_errno_with_message!(Errno::EISDIR, "copy_file_range on a directory");
+            }
+            if !inode_ref.inode.is_file() {
+                return_errno_with_message!(Errno::EINVAL, "copy_file_range on a special file");
+            }
+        }
+
+        let size_in = in_ref.inode.size();
+        if off_in >= size_in || len == 0 {
+            return Ok(0);
+        }
+        let len = min(len, size_in - off_in);
+
+        let block_size = self.super_block.block_size() as u64;
+        let end_out = match off_out.checked_add(len) {
+            Some(end) if end <= EXT_MAX_BLOCKS as u64 * block_size => end,
+            _ => return_errno_with_message!(Errno::EFBIG, "copy past the maximum file size"),
+        };
+        if inode_in == inode_out && off_in < end_out && off_out < off_in + len {
+            return_errno_with_message!(Errno::EINVAL, "overlapping copy within a file");
+        }
+
+        let extents = self.fiemap(inode_in, off_in, len, 0)?;
+
+        // every chunk is an update of its own, a copy larger than the
+        // journal commits as it goes
+        let end = off_in + len;
+        let mut pos = off_in;
+        for ex in extents.iter() {
+            // the hole before the extent
+            if ex.logical > pos {
+                let hole_end = min(ex.logical, end);
+                self.copy_zeros(inode_out, off_out + (pos - off_in), hole_end - pos, false)?;
+                pos = hole_end;
+            }
+
+            let ex_end = min(ex.logical + ex.length, end);
+            if pos >= ex_end {
+                continue;
+            }
+            if ex.flags & FIEMAP_EXTENT_UNWRITTEN != 0 {
+                self.copy_zeros(inode_out, off_out + (pos - off_in), ex_end - pos, true)?;
+            } else {
+                self.copy_data(ex, pos, ex_end, inode_out, off_out + (pos - off_in))?;
+            }
+            pos = ex_end;
+        }
+        if pos < end {
+            self.copy_zeros(inode_out, off_out + (pos - off_in), end - pos, false)?;
+        }
+
+        // a hole at the end grows the file too
+        self.journal_transaction(|| {
+            let mut out_ref = self.get_inode_ref(inode_out)?;
+            if end_out > out_ref.inode.size() {
+                out_ref.inode.set_size(end_out);
+                self.write_back_inode(&mut out_ref)?;
+            }
+            Ok(())
+        })?;
+
+        Ok(len)
+    }
+
+    /// Copy the bytes from `pos` to `end` of the data extent `ex` to the
+    /// destination file at `off_out`, `COPY_CHUNK_SIZE` bytes at a time.
+    fn copy_data(&self, ex: &Ext4FiemapExtent, pos: u64, end: u64, inode_out: u32, off_out: u64) -> Result<()> {
+        let block_size = self.super_block.block_size() as u64;
+        let chunk_size = COPY_CHUNK_SIZE as u64;
+
+        let mut cur = pos;
+        while cur < end {
+            // chunks end at multiples of the chunk size in the file, so that
+            // the writes after the first one are aligned
+            let chunk_end = min(end, (cur / chunk_size + 1) * chunk_size);
+            let physical = ex.physical + (cur - ex.logical);
+            let head = physical % block_size;
+            let count = chunk_end - cur;
+
+            let mut buf = vec![0u8; (head + count).div_ceil(block_size) as usize * block_size as usize];
+            self.block_device.read_blocks((physical - head) as usize, &mut buf)?;
+            let written = self.write_at(
+                inode_out,
+                (off_out + (cur - pos)) as usize,
+                &buf[head as usize..(head + count) as usize],
+            )?;
+            if written as u64 != count {
+                return_errno_with_message!(Errno::EIO, "short write while copying");
+            }
+
+            cur = chunk_end;
+        }
+        Ok(())
+    }
+
+    /// Make `len` bytes of the destination file at `offset` read as zeros,
+    /// without mapping new blocks unless `unwritten` asks for unwritten
+    /// extents.
+    fn copy_zeros(&self, inode_out: u32, offset: u64, len: u64, unwritten: bool) -> Result<()> {
+        let out_ref = self.get_inode_ref(inode_out)?;
+        if out_ref.inode.has_extents() {
+            let mode = if unwritten { FALLOC_FL_ZERO_RANGE } else { FALLOC_FL_PUNCH_HOLE };
+            self.fallocate(inode_out, mode | FALLOC_FL_KEEP_SIZE, offset, len)?;
+            return Ok(());
+        }
+
+        // the block map has no unwritten blocks and cannot punch, zero
+        // the blocks already there
+        let end = offset + len;
+        for ex in self.fiemap(inode_out, offset, len, 0)? {
+            let mut cur = ex.logical.max(offset);
+            let ex_end = min(ex.logical + ex.length, end);
+            while cur < ex_end {
+                let count = min(ex_end - cur, COPY_CHUNK_SIZE as u64);
+                self.write_at(inode_out, cur as usize, &vec![0u8; count as usize])?;
+                cur += count;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ext4_impls::test_utils::*;
+
+    #[test]
+    fn test_copy_data() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+        let (src, data) = create_fragmented_file(&ext4, "src", 20);
+        let dst = create_file(&ext4, "dst");
+
+        let (off_in, off_out, len) = (1000, 3333, data.len() as u64 - 2000);
+        let copied = ext4.copy_file_range(src, off_in, dst, off_out, len, 0).unwrap();
+
+        assert_eq!(copied, len);
+        let out = read_file(&ext4, dst);
+        assert_eq!(out.len() as u64, off_out + len);
+        assert!(out[..off_out as usize].iter().all(|&b| b == 0));
+        assert_eq!(out[off_out as usize..], data[off_in as usize..(off_in + len) as usize]);
+
+        // the copy is short at the end of the source
+        let copied = ext4.copy_file_range(src, off_in, dst, 0, u64::MAX / 2, 0).unwrap();
+        assert_eq!(copied, data.len() as u64 - off_in);
+    }
+
+    #[test]
+    fn test_copy_keeps_holes() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+        let block_size = ext4.super_block.block_size() as u64;
+        let src = create_file(&ext4, "src");
+        let data = pattern(2 * block_size as usize, 0);
+        ext4.write_at(src, 0, &data).unwrap();
+        ext4.write_at(src, 10 * block_size as usize, &data).unwrap();
+        // a hole at the end too
+        let mut src_ref = ext4.get_inode_ref(src).unwrap();
+        ext4.truncate_inode(&mut src_ref, 20 * block_size).unwrap();
+        let dst = create_file(&ext4, "dst");
+
+        let copied = ext4.copy_file_range(src, 0, dst, 0, 20 * block_size, 0).unwrap();
+
+        assert_eq!(copied, 20 * block_size);
+        let mapped: Vec<_> = file_extents(&ext4, dst)
+            .iter()
+            .map(|ex| (ex.first_block, ex.get_actual_len()))
+            .collect();
+        assert_eq!(mapped, [(0, 2), (10, 2)]);
+        assert_eq!(read_file(&ext4, dst), read_file(&ext4, src));
+    }
+
+    #[test]
+    fn test_copy_unwritten_range() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+        let block_size = ext4.super_block.block_size() as u64;
+        let src = create_file(&ext4, "src");
+        ext4.fallocate(src, 0, 0, 4 * block_size).unwrap();
+        ext4.write_at(src, 4 * block_size as usize, &pattern(block_size as usize, 0)).unwrap();
+        let dst = create_file(&ext4, "dst");
+        // the unwritten range replaces data already in the destination
+        ext4.write_at(dst, 0, &pattern(5 * block_size as usize, 1)).unwrap();
+
+        let copied = ext4.copy_file_range(src, 0, dst, 0, 5 * block_size, 0).unwrap();
+
+        assert_eq!(copied, 5 * block_size);
+        let extents = file_extents(&ext4, dst);
+        assert!(extents.iter().any(|ex| ex.is_unwritten() && ex.first_block == 0));
+        assert_eq!(read_file(&ext4, dst), read_file(&ext4, src));
+    }
+
+    #[test]
+    fn test_copy_overlapping_range() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+        let inode = create_file(&ext4, "file");
+        let data = pattern(10000, 0);
+        ext4.write_at(inode, 0, &data).unwrap();
+
+        let err = ext4.copy_file_range(inode, 0, inode, 5000, 6000, 0).unwrap_err();
+        assert_eq!(err.error(), Errno::EINVAL);
+        let err = ext4.copy_file_range(inode, 5000, inode, 2000, 4000, 0).unwrap_err();
+        assert_eq!(err.error(), Errno::EINVAL);
+
+        // disjoint ranges of the same file are fine
+        assert_eq!(ext4.copy_file_range(inode, 0, inode, 5000, 5000, 0).unwrap(), 5000);
+        let out = read_file(&ext4, inode);
+        assert_eq!(out[5000..], data[..5000]);
+    }
+}
@@ -20,9 +20,20 @@ impl Ext4 {
         result: &mut Ext4DirSearchResult,
     ) -> Result<usize> {
         // load parent inode
-        let parent = self.get_inode_ref(parent_inode);
+        let parent = self.get_inode_ref(parent_inode)?;
         assert!(parent.inode.is_dir());
 
+        // indexed directory, jump to the leaf holding the name.
+        // "." and ".." are not hashed, they always sit in block 0
+        if parent.inode.is_indexed() && self.super_block.has_dir_index() && name != "." && name != ".." {
+            match self.dx_find_entry(&parent, name, result) {
+                Err(e) if matches!(e.error(), Errno::EINVAL | Errno::ENOTSUP) => {
+                    log::warn!("inode {} htree index unusable, scanning linearly", parent_inode);
+                }
+                r => return r,
+            }
+        }
+
         // start from the first logical block
         let mut iblock = 0;
         // physical block id
@@ -30,32 +41,23 @@ impl Ext4 {
 
         // calculate total blocks
         let inode_size: u64 = parent.inode.size();
-        let total_blocks: u64 = inode_size / BLOCK_SIZE as u64;
+        let block_size = self.super_block.block_size() as usize;
+        let total_blocks: u64 = inode_size / block_size as u64;
 
         // iterate all blocks
         while iblock < total_blocks {
-            let search_path = self.find_extent(&parent, iblock as u32);
-
-            if let Ok(path) = search_path {
-                // get the last path
-                let path = path.path.last().unwrap();
-
-                // get physical block id
-                fblock = path.pblock;
+            // get physical block id
+            fblock = self.get_pblock_idx(&parent, iblock as u32)?;
 
-                // load physical block
-                let mut ext4block =
-                    Block::load(self.block_device.clone(), fblock as usize * BLOCK_SIZE);
+            // load physical block
+            let ext4block = self.dir_load_block(&parent, fblock)?;
 
-                // find entry in block
-                let r = self.dir_find_in_block(&ext4block, name, result);
+            // find entry in block
+            let r = self.dir_find_in_block(&ext4block, name, result);
 
-                if r.is_ok() {
-                    result.pblock_id = fblock as usize;
-                    return Ok(EOK);
-                }
-            } else {
-                return_errno_with_message!(Errno::ENOENT, "dir search fail")
+            if r.is_ok() {
+                result.pblock_id = fblock as usize;
+                return Ok(EOK);
             }
             // go to next block
             iblock += 1
@@ -82,7 +84,7 @@ impl Ext4 {
         let mut prev_de_offset = 0;
 
         // start from the first entry
-        while offset < BLOCK_SIZE - core::mem::size_of::<Ext4DirEntryTail>() {
+        while offset < block.data.len() - core::mem::size_of::<Ext4DirEntryTail>() {
             let de: Ext4DirEntry = block.read_offset_as(offset);
             if !de.unused() && de.compare_name(name) {
                 result.dentry = de;
@@ -104,17 +106,18 @@ impl Ext4 {
     /// inode: u32 - inode number of the directory
     ///
     /// Returns:
-    /// `Vec<Ext4DirEntry>` - list of directory entries
-    pub fn dir_get_entries(&self, inode: u32) -> Vec<Ext4DirEntry> {
+    /// `Result<Vec<Ext4DirEntry>>` - list of directory entries
+    pub fn dir_get_entries(&self, inode: u32) -> Result<Vec<Ext4DirEntry>> {
         let mut entries = Vec::new();
 
         // load inode
-        let inode_ref = self.get_inode_ref(inode);
+        let inode_ref = self.get_inode_ref(inode)?;
         assert!(inode_ref.inode.is_dir());
 
         // calculate total blocks
         let inode_size = inode_ref.inode.size();
-        let total_blocks = inode_size / BLOCK_SIZE as u64;
+        let block_size = self.super_block.block_size() as usize;
+        let total_blocks = inode_size / block_size as u64;
 
         // start from the first logical block
         let mut iblock = 0;
@@ -122,43 +125,67 @@ impl Ext4 {
         // iterate all blocks
         while iblock < total_blocks {
             // get physical block id of a logical block id
-            let search_path = self.find_extent(&inode_ref, iblock as u32);
-
-            if let Ok(path) = search_path {
-                // get the last path
-                let path = path.path.last().unwrap();
-
-                // get physical block id
-                let fblock = path.pblock;
-
-                // load physical block
-                let ext4block =
-                    Block::load(self.block_device.clone(), fblock as usize * BLOCK_SIZE);
-                let mut offset = 0;
-
-                // iterate all entries in a block
-                while offset < BLOCK_SIZE - core::mem::size_of::<Ext4DirEntryTail>() {
-                    let de: Ext4DirEntry = ext4block.read_offset_as(offset);
-                    if !de.unused() {
-                        entries.push(de);
-                    }
-                    offset += de.entry_len() as usize;
+            // get physical block id
+            let fblock = self.get_pblock_idx(&inode_ref, iblock as u32)?;
+
+            // load physical block
+            let ext4block = self.dir_load_block(&inode_ref, fblock)?;
+            let mut offset = 0;
+
+            // iterate all entries in a block
+            while offset < block_size - core::mem::size_of::<Ext4DirEntryTail>() {
+                let de: Ext4DirEntry = ext4block.read_offset_as(offset);
+                if !de.unused() {
+                    entries.push(de);
                 }
+                offset += de.entry_len() as usize;
             }
 
             // go ot next block
             iblock += 1;
         }
-        entries
+        Ok(entries)
     }
 
-    pub fn dir_set_csum(&self, dst_blk: &mut Block, ino_gen: u32) {
-        let parent_de: Ext4DirEntry = dst_blk.read_offset_as(0);
+    /// Load a directory block and verify its checksum tail.
+    ///
+    /// Params:
+    /// dir: &Ext4InodeRef - directory owning the block
+    /// pblock: Ext4Fsblk - physical block id
+    ///
+    /// Returns:
+    /// `Result<Block>` - the directory block
+    pub fn dir_load_block(&self, dir: &Ext4InodeRef, pblock: Ext4Fsblk) -> Result<Block> {
+        let block_size = self.super_block.block_size() as usize;
+        let block = Block::load(self.block_device.clone(), pblock as usize * block_size, block_size)?;
+
+        let tail_offset = block_size - size_of::<Ext4DirEntryTail>();
+        let tail: Ext4DirEntryTail = block.read_offset_as(tail_offset);
+        if tail.is_tail() {
+            self.verify_csum("directory block", pblock, || {
+                let csum = Ext4DirEntryTail::dir_csum(
+                    &self.super_block,
+                    dir.inode_num,
+                    dir.inode.generation(),
+                    &block.data,
+                );
+                csum == tail.checksum
+            })?;
+        }
+
+        Ok(block)
+    }
 
-        let tail_offset = BLOCK_SIZE - size_of::<Ext4DirEntryTail>();
+    pub fn dir_set_csum(&self, dir: &Ext4InodeRef, dst_blk: &mut Block) {
+        // without metadata_csum the last bytes may belong to an entry
+        if !self.super_block.has_metadata_csum() {
+            return;
+        }
+
+        let tail_offset = dst_blk.data.len() - size_of::<Ext4DirEntryTail>();
         let mut tail: Ext4DirEntryTail = *dst_blk.read_offset_as_mut(tail_offset);
 
-        tail.tail_set_csum(&self.super_block, &parent_de, &dst_blk.data[..], ino_gen);
+        tail.tail_set_csum(&self.super_block, dir.inode_num, &dst_blk.data[..], dir.inode.generation());
 
         tail.copy_to_slice(&mut dst_blk.data);
     }
@@ -178,9 +205,15 @@ impl Ext4 {
         child: &Ext4InodeRef,
         name: &str,
     ) -> Result<usize> {
+        self.check_writable()?;
+
+        if parent.inode.is_indexed() && self.super_block.has_dir_index() {
+            return self.dx_add_entry(parent, child, name);
+        }
+
         // calculate total blocks
         let inode_size: u64 = parent.inode.size();
-        let block_size = self.super_block.block_size();
+        let block_size = self.super_block.block_size() as usize;
         let total_blocks: u64 = inode_size / block_size as u64;
 
         // iterate all blocks
@@ -190,15 +223,14 @@ impl Ext4 {
             let pblock = self.get_pblock_idx(parent, iblock as u32)?;
 
             // load physical block
-            let mut ext4block =
-                Block::load(self.block_device.clone(), pblock as usize * BLOCK_SIZE);
+            let mut ext4block = self.dir_load_block(parent, pblock)?;
 
-            let result = self.try_insert_to_existing_block(&mut ext4block, name, child.inode_num);
+            let result = self.try_insert_to_existing_block(&mut ext4block, name, child);
 
             if result.is_ok() {
                 // set checksum
-                self.dir_set_csum(&mut ext4block, parent.inode.generation());
-                ext4block.sync_blk_to_disk(self.block_device.clone());
+                self.dir_set_csum(parent, &mut ext4block);
+                ext4block.sync_blk_to_disk(self.block_device.clone())?;
 
                 return Ok(EOK);
             }
@@ -207,21 +239,26 @@ impl Ext4 {
             iblock += 1;
         }
 
+        // a full single block directory gets an index instead of a second linear block
+        if total_blocks == 1 && self.super_block.has_dir_index() && self.dx_make_indexed(parent)? {
+            return self.dx_add_entry(parent, child, name);
+        }
+
         // no space in existing blocks, need to add new block
         let new_block = self.append_inode_pblk(parent)?;
 
         // load new block
         let mut new_ext4block =
-            Block::load(self.block_device.clone(), new_block as usize * BLOCK_SIZE);
+            Block::load(self.block_device.clone(), new_block as usize * block_size, block_size)?;
 
         // write new entry to the new block
         // must succeed, as we just allocated the block
-        let de_type = DirEntryType::EXT4_DE_DIR;
+        let de_type = child.inode.dir_entry_type();
         self.insert_to_new_block(&mut new_ext4block, child.inode_num, name, de_type);
 
         // set checksum
-        self.dir_set_csum(&mut new_ext4block, parent.inode.generation());
-        new_ext4block.sync_blk_to_disk(self.block_device.clone());
+        self.dir_set_csum(parent, &mut new_ext4block);
+        new_ext4block.sync_blk_to_disk(self.block_device.clone())?;
 
         Ok(EOK)
     }
@@ -231,7 +268,7 @@ impl Ext4 {
     /// Params:
     /// block: &mut Block - block to insert the new entry
     /// name: &str - name of the new entry
-    /// inode: u32 - inode number of the new entry
+    /// child: &Ext4InodeRef - inode the new entry points to
     ///
     /// Returns:
     /// `Result<usize>` - status of the operation
@@ -239,11 +276,14 @@ impl Ext4 {
         &self,
         block: &mut Block,
         name: &str,
-        child_inode: u32,
+        child: &Ext4InodeRef,
     ) -> Result<usize> {
+        let child_inode = child.inode_num;
+        let de_type = child.inode.dir_entry_type();
+
         // required length aligned to 4 bytes
         let required_len = {
-            let mut len = size_of::<Ext4DirEntry>() + name.len();
+            let mut len = size_of::<Ext4FakeDirEntry>() + name.len();
             if len % 4 != 0 {
                 len += 4 - (len % 4);
             }
@@ -253,10 +293,22 @@ impl Ext4 {
         let mut offset = 0;
 
         // Start from the first entry
-        while offset < BLOCK_SIZE - size_of::<Ext4DirEntryTail>() {
+        while offset < block.data.len() - size_of::<Ext4DirEntryTail>() {
             let mut de = Ext4DirEntry::try_from(&block.data[offset..]).unwrap();
+            if de.entry_len() == 0 {
+                break;
+            }
 
+            // an empty entry can be reused as a whole
             if de.unused() {
+                if de.entry_len() as usize >= required_len {
+                    let mut new_entry = Ext4DirEntry::default();
+                    new_entry.write_entry(de.entry_len(), child_inode, name, de_type);
+                    new_entry.copy_to_slice(&mut block.data, offset);
+                    block.sync_blk_to_disk(self.block_device.clone())?;
+                    return Ok(EOK);
+                }
+                offset += de.entry_len() as usize;
                 continue;
             }
 
@@ -279,7 +331,6 @@ impl Ext4 {
                 // Update existing entry length and copy both entries back to block data
                 de.entry_len = sz as u16;
 
-                let de_type = DirEntryType::EXT4_DE_DIR;
                 new_entry.write_entry(free_space as u16, child_inode, name, de_type);
 
                 // update parent_de and new_de to blk_data
@@ -287,7 +338,7 @@ impl Ext4 {
                 new_entry.copy_to_slice(&mut block.data, offset + sz);
 
                 // Sync to disk
-                block.sync_blk_to_disk(self.block_device.clone());
+                block.sync_blk_to_disk(self.block_device.clone())?;
 
                 return Ok(EOK);
             }
@@ -314,7 +365,7 @@ impl Ext4 {
     ) {
         // write new entry
         let mut new_entry = Ext4DirEntry::default();
-        let el = BLOCK_SIZE - size_of::<Ext4DirEntryTail>();
+        let el = block.data.len() - size_of::<Ext4DirEntryTail>();
         new_entry.write_entry(el as u16, inode, name, de_type);
         new_entry.copy_to_slice(&mut block.data, 0);
 
@@ -326,33 +377,36 @@ impl Ext4 {
     }
 
     pub fn dir_remove_entry(&self, parent: &mut Ext4InodeRef, path: &str) -> Result<usize> {
+        self.check_writable()?;
+
         // get remove_entry pos in parent and its prev entry
         let mut result = Ext4DirSearchResult::new(Ext4DirEntry::default());
 
         let r = self.dir_find_entry(parent.inode_num, path, &mut result)?;
 
-        let mut ext4block = Block::load(self.block_device.clone(), result.pblock_id * BLOCK_SIZE);
+        let mut ext4block = self.dir_load_block(parent, result.pblock_id as Ext4Fsblk)?;
 
         let de_del_entry_len = result.dentry.entry_len();
 
-        // prev entry
-        let pde: &mut Ext4DirEntry = ext4block.read_offset_as_mut(result.prev_offset);
-
-        pde.entry_len += de_del_entry_len;
+        // merge into the previous entry, the first entry of a block is only emptied
+        if result.offset != result.prev_offset {
+            let pde: &mut Ext4DirEntry = ext4block.read_offset_as_mut(result.prev_offset);
+            pde.entry_len += de_del_entry_len;
+        }
 
         let de_del: &mut Ext4DirEntry = ext4block.read_offset_as_mut(result.offset);
 
         de_del.inode = 0;
 
-        self.dir_set_csum(&mut ext4block, parent.inode.generation());
-        ext4block.sync_blk_to_disk(self.block_device.clone());
+        self.dir_set_csum(parent, &mut ext4block);
+        ext4block.sync_blk_to_disk(self.block_device.clone())?;
 
         Ok(EOK)
     }
 
-    pub fn dir_has_entry(&self, dir_inode: u32) -> bool {
+    pub fn dir_has_entry(&self, dir_inode: u32) -> Result<bool> {
         // load parent inode
-        let parent = self.get_inode_ref(dir_inode);
+        let parent = self.get_inode_ref(dir_inode)?;
         assert!(parent.inode.is_dir());
 
         // start from the first logical block
@@ -362,69 +416,66 @@ impl Ext4 {
 
         // calculate total blocks
         let inode_size: u64 = parent.inode.size();
-        let total_blocks: u64 = inode_size / BLOCK_SIZE as u64;
+        let block_size = self.super_block.block_size() as usize;
+        let total_blocks: u64 = inode_size / block_size as u64;
 
         // iterate all blocks
         while iblock < total_blocks {
-            let search_path = self.find_extent(&parent, iblock as u32);
-
-            if let Ok(path) = search_path {
-                // get the last path
-                let path = path.path.last().unwrap();
-
-                // get physical block id
-                fblock = path.pblock;
-
-                // load physical block
-                let ext4block =
-                    Block::load(self.block_device.clone(), fblock as usize * BLOCK_SIZE);
-
-                // start from the first entry
-                let mut offset = 0;
-                while offset < BLOCK_SIZE - core::mem::size_of::<Ext4DirEntryTail>() {
-                    let de: Ext4DirEntry = ext4block.read_offset_as(offset);
-                    offset += de.entry_len as usize;
-                    if de.inode == 0 {
-                        continue;
-                    }
-                    // skip . and ..
-                    if de.get_name() == "." || de.get_name() == ".." {
-                        continue;
-                    }
-                    return true;
+            // get physical block id
+            fblock = self.get_pblock_idx(&parent, iblock as u32)?;
+
+            // load physical block
+            let ext4block = self.dir_load_block(&parent, fblock)?;
+
+            // start from the first entry
+            let mut offset = 0;
+            while offset < block_size - core::mem::size_of::<Ext4DirEntryTail>() {
+                let de: Ext4DirEntry = ext4block.read_offset_as(offset);
+                offset += de.entry_len as usize;
+                if de.inode == 0 {
+                    continue;
                 }
+                // skip . and ..
+                if de.get_name() == "." || de.get_name() == ".." {
+                    continue;
+                }
+                return Ok(true);
             }
             // go to next block
             iblock += 1
         }
 
-        false
+        Ok(false)
     }
 
     pub fn dir_remove(&self, parent: u32, path: &str) -> Result<usize> {
-        let mut search_result = Ext4DirSearchResult::new(Ext4DirEntry::default());
+        self.check_writable()?;
 
-        let r = self.dir_find_entry(parent, path, &mut search_result)?;
+        self.journal_transaction(|| {
+            let mut search_result = Ext4DirSearchResult::new(Ext4DirEntry::default());
 
-        let mut parent_inode_ref = self.get_inode_ref(parent);
-        let mut child_inode_ref = self.get_inode_ref(search_result.dentry.inode);
+            let r = self.dir_find_entry(parent, path, &mut search_result)?;
 
-        if self.dir_has_entry(child_inode_ref.inode_num){
-            return_errno_with_message!(Errno::ENOTSUP, "rm dir with children not supported")
-        }
-        
-        self.truncate_inode(&mut child_inode_ref, 0)?;
+            let mut parent_inode_ref = self.get_inode_ref(parent)?;
+            let mut child_inode_ref = self.get_inode_ref(search_result.dentry.inode)?;
 
-        self.unlink(&mut parent_inode_ref, &mut child_inode_ref, path)?;
+            if self.dir_has_entry(child_inode_ref.inode_num)? {
+                return_errno_with_message!(Errno::ENOTSUP, "rm dir with children not supported")
+            }
 
-        self.write_back_inode(&mut parent_inode_ref);
+            self.truncate_inode(&mut child_inode_ref, 0)?;
 
-        // to do
-        // ext4_inode_set_del_time
-        // ext4_inode_set_links_cnt
-        // ext4_fs_free_inode(&child)
+            self.unlink(&mut parent_inode_ref, &mut child_inode_ref, path)?;
 
-        Ok(EOK)
+            self.write_back_inode(&mut parent_inode_ref)?;
+
+            // to do
+            // ext4_inode_set_del_time
+            // ext4_inode_set_links_cnt
+            // ext4_fs_free_inode(&child)
+
+            Ok(EOK)
+        })
     }
 }
 
@@ -436,3 +487,46 @@ pub fn copy_dir_entry_to_array(header: &Ext4DirEntry, array: &mut [u8], offset:
         core::ptr::copy_nonoverlapping(de_ptr, array_ptr.add(offset), count);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ext4_impls::test_utils::*;
+
+    #[test]
+    fn test_entry_file_types() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+        create_file(&ext4, "file");
+        ext4.dir_mk("dir").unwrap();
+        ext4.symlink(ROOT_INODE, "link", "file").unwrap();
+
+        for de in ext4.dir_get_entries(ROOT_INODE).unwrap() {
+            let inode_ref = ext4.get_inode_ref(de.inode).unwrap();
+            assert_eq!(de.get_de_type(), inode_ref.inode.dir_entry_type().bits(), "{}", de.get_name());
+        }
+    }
+
+    #[test]
+    fn test_reused_entry_file_type() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+        let block_size = ext4.super_block.block_size() as usize;
+        let link = ext4.symlink(ROOT_INODE, "link", "target").unwrap();
+
+        // a block holding one unused entry, like an emptied first entry
+        let mut block = Block {
+            disk_offset: 7000 * block_size,
+            data: vec![0u8; block_size],
+        };
+        let mut unused = Ext4DirEntry::default();
+        let len = (block_size - size_of::<Ext4DirEntryTail>()) as u16;
+        unused.write_entry(len, 0, "gone", DirEntryType::EXT4_DE_REG_FILE);
+        unused.copy_to_slice(&mut block.data, 0);
+
+        ext4.try_insert_to_existing_block(&mut block, "new", &link).unwrap();
+        let de = Ext4DirEntry::try_from(&block.data[..]).unwrap();
+        assert_eq!(de.inode, link.inode_num);
+        assert_eq!(de.get_de_type(), DirEntryType::EXT4_DE_SYMLINK.bits());
+    }
+}
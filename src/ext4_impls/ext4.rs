@@ -5,15 +5,125 @@ use crate::utils::*;
 use crate::ext4_defs::*;
 impl Ext4 {
     /// Opens and loads an Ext4 from the `block_device`.
-    pub fn open(block_device: Arc<dyn BlockDevice>) -> Self {
+    pub fn open(block_device: Arc<dyn BlockDevice>) -> Result<Self> {
+        Self::open_with_options(block_device, Ext4MountOptions::default())
+    }
+
+    /// Opens and loads an Ext4 from the `block_device` with mount `options`.
+    ///
+    /// The filesystem is mounted read-only when asked to, or when the
+    /// superblock has ro_compat features this implementation cannot write.
+    /// A read-only mount of a filesystem whose journal needs recovery fails
+    /// with `EROFS` unless `no_recovery` is set.
+    pub fn open_with_options(
+        block_device: Arc<dyn BlockDevice>,
+        mut options: Ext4MountOptions,
+    ) -> Result<Self> {
         // Load the superblock
-        let block = Block::load(block_device.clone(), SUPERBLOCK_OFFSET);
-        let super_block: Ext4Superblock = block.read_as();
+        let super_block = Ext4Superblock::load(block_device.clone())?;
+        super_block.validate()?;
+
+        if options.no_recovery && !options.read_only {
+            return_errno_with_message!(Errno::EINVAL, "no_recovery needs a read-only mount");
+        }
+
+        let unsupported = super_block.unsupported_ro_compat();
+        if !unsupported.is_empty() && !options.read_only {
+            log::warn!("unsupported ro_compat features {:?}, mounting read-only", unsupported);
+            options.read_only = true;
+        }
 
-        Ext4 {
+        let block_size = super_block.block_size() as usize;
+        let block_cache = Arc::new(BlockCache::new(
             block_device,
+            block_size,
+            BLOCK_CACHE_DEFAULT_CAPACITY,
+        ));
+
+        // the superblock and group descriptors are touched by every allocation
+        let first_meta_block = SUPERBLOCK_OFFSET / block_size;
+        let desc_per_block = block_size / super_block.desc_size() as usize;
+        let gdt_blocks = (super_block.block_group_count() as usize).div_ceil(desc_per_block);
+        let gdt_start = super_block.first_data_block() as usize + 1;
+        block_cache.pin(first_meta_block as u64)?;
+        for block in gdt_start..gdt_start + gdt_blocks {
+            block_cache.pin(block as u64)?;
+        }
+
+        let mut ext4 = Ext4 {
+            block_device: block_cache.clone(),
+            block_cache,
             super_block,
+            journal: None,
+            mount_options: options,
+        };
+        ext4.verify_csum("superblock", 0, || ext4.super_block.verify_checksum())?;
+
+        if options.read_only {
+            // the replay would write to the device
+            if ext4.super_block.has_journal() && ext4.super_block.needs_recovery() {
+                if !options.no_recovery {
+                    return_errno_with_message!(Errno::EROFS, "the journal needs recovery on a read-only mount");
+                }
+                log::warn!("read-only mount, the journal is not replayed");
+            }
+            ext4.block_cache.set_read_only(true);
+            return Ok(ext4);
+        }
+
+        // bring the filesystem back to a consistent state before anything reads it
+        ext4.journal_recover()?;
+        ext4.journal_init()?;
+
+        Ok(ext4)
+    }
+
+    /// Apply the checksum policy of the mount to one piece of metadata.
+    ///
+    /// `verify` is only called on `metadata_csum` filesystems when the policy
+    /// is not `Ignore`. A mismatch fails with `EIO` or is logged, depending on
+    /// the policy.
+    ///
+    /// Params:
+    /// what: &str - kind of metadata, for the log
+    /// id: u64 - inode, group or block number, for the log
+    /// verify: impl FnOnce() -> bool - returns true if the checksum matches
+    pub fn verify_csum(&self, what: &str, id: u64, verify: impl FnOnce() -> bool) -> Result<()> {
+        let policy = self.mount_options.checksum_policy;
+        if !self.super_block.has_metadata_csum() || policy == Ext4ChecksumPolicy::Ignore || verify() {
+            return Ok(());
+        }
+        if policy == Ext4ChecksumPolicy::Warn {
+            log::warn!("{} {} checksum mismatch", what, id);
+            return Ok(());
+        }
+        log::error!("{} {} checksum mismatch", what, id);
+        return_errno_with_message!(Errno::EIO, "metadata checksum mismatch");
+    }
+
+    /// Returns true if the filesystem was mounted read-only.
+    pub fn is_read_only(&self) -> bool {
+        self.mount_options.read_only
+    }
+
+    /// Fails with `EROFS` on a read-only mount, called first by every
+    /// operation that modifies the filesystem.
+    pub fn check_writable(&self) -> Result<()> {
+        if self.is_read_only() {
+            return_errno_with_message!(Errno::EROFS, "read-only file system");
+        }
+        Ok(())
+    }
+
+    /// Write all dirty cached blocks back to the device.
+    ///
+    /// With a journal the running transaction is committed first and the log
+    /// is checkpointed, leaving the filesystem clean.
+    pub fn sync(&self) -> Result<()> {
+        if self.journal.is_some() {
+            return self.journal_sync();
         }
+        self.block_cache.sync()
     }
 
     // with dir result search path offset
@@ -32,7 +142,9 @@ impl Ext4 {
         let mut search_path = path;
 
         let mut dir_search_result = Ext4DirSearchResult::new(Ext4DirEntry::default());
-        
+
+        let mut hops = 0;
+
         loop {
             while search_path.starts_with('/') {
                 *name_off += 1; // Skip the slash
@@ -53,7 +165,10 @@ impl Ext4 {
             
             // log::trace!("find in parent {:x?} r {:?} name {:?}", parent, r, current_path);
             if let Err(e) = r {
-                if e.error() != Errno::ENOENT || !create {
+                if e.error() != Errno::ENOENT {
+                    return Err(e);
+                }
+                if !create {
                     return_errno_with_message!(Errno::ENOENT, "No such file or directory");
                 }
 
@@ -79,8 +194,12 @@ impl Ext4 {
             if is_goal {
                 break;
             }else{
-                // update parent
-                *parent = dir_search_result.dentry.inode;
+                // update parent, through the target of a symlinked directory
+                let mut next = dir_search_result.dentry.inode;
+                if self.get_inode_ref(next)?.inode.is_link() {
+                    next = self.follow_link(*parent, next, &mut hops)?;
+                }
+                *parent = next;
             }
             *name_off += len as u32;
         }
@@ -94,17 +213,21 @@ impl Ext4 {
 
     #[allow(unused)]
     pub fn dir_mk(&self, path: &str) -> Result<usize> {
-        let mut nameoff = 0;
+        self.check_writable()?;
+
+        self.journal_transaction(|| {
+            let mut nameoff = 0;
+
+            let filetype = InodeFileType::S_IFDIR;
 
-        let filetype = InodeFileType::S_IFDIR;
-        
-        // todo get this path's parent
-        
-        // start from root
-        let mut parent = ROOT_INODE;
+            // todo get this path's parent
 
-        let r = self.generic_open(path, &mut parent, true, filetype.bits(), &mut nameoff);
-        Ok(EOK)
+            // start from root
+            let mut parent = ROOT_INODE;
+
+            self.generic_open(path, &mut parent, true, filetype.bits(), &mut nameoff)?;
+            Ok(EOK)
+        })
     }
 
     pub fn unlink(
@@ -113,12 +236,200 @@ impl Ext4 {
         child: &mut Ext4InodeRef,
         name: &str,
     ) -> Result<usize> {
-        self.dir_remove_entry(parent, name)?;
+        self.check_writable()?;
+
+        self.journal_transaction(|| {
+            self.dir_remove_entry(parent, name)?;
+
+            let is_dir = child.inode.is_dir();
+
+            // a directory loses "." and its ".." link to the parent with its name
+            let links = if is_dir {
+                let parent_links = parent.inode.links_count().saturating_sub(1);
+                parent.inode.set_links_count(parent_links);
+                0
+            } else {
+                child.inode.links_count().saturating_sub(1)
+            };
+            child.inode.set_links_count(links);
+            self.write_back_inode(child)?;
+
+            if links == 0 {
+                self.xattr_release(child)?;
+                self.ialloc_free_inode(child.inode_num, is_dir)?;
+            }
 
-        let is_dir = child.inode.is_dir();
+            Ok(EOK)
+        })
+    }
+}
+
+impl Drop for Ext4 {
+    /// Commit the running transaction and write everything back, so that
+    /// dropping the filesystem without `sync` leaves it clean.
+    fn drop(&mut self) {
+        if self.is_read_only() {
+            return;
+        }
+        if let Err(e) = self.sync() {
+            log::error!("sync failed on drop: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ext4_impls::test_utils::*;
+
+    #[test]
+    fn test_read_only_mount_needs_recovery() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+        create_file(&ext4, "file");
+        // crash, the transaction is left in memory
+        core::mem::forget(ext4);
+        let read_only = Ext4MountOptions { read_only: true, ..Default::default() };
+
+        let err = Ext4::open_with_options(disk.clone(), read_only).err().unwrap();
+        assert_eq!(err.error(), Errno::EROFS);
+        let options = Ext4MountOptions { no_recovery: true, ..Default::default() };
+        let err = Ext4::open_with_options(disk.clone(), options).err().unwrap();
+        assert_eq!(err.error(), Errno::EINVAL);
+
+        // nothing is written without recovery
+        let before = disk.bytes(0, EXT4_IMAGE.len());
+        let options = Ext4MountOptions { no_recovery: true, ..read_only };
+        let ext4 = Ext4::open_with_options(disk.clone(), options).unwrap();
+        assert!(ext4.super_block.needs_recovery());
+        drop(ext4);
+        assert!(disk.bytes(0, EXT4_IMAGE.len()) == before);
+
+        // a read-write mount recovers, then read-only mounts work again
+        drop(mount(&disk));
+        let ext4 = Ext4::open_with_options(disk.clone(), read_only).unwrap();
+        assert!(!ext4.super_block.needs_recovery());
+    }
+
+    #[test]
+    fn test_read_only_access() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let options = Ext4MountOptions { read_only: true, ..Default::default() };
+        let mut ext4 = Ext4::open_with_options(disk, options).unwrap();
+        let root = ROOT_INODE as u64;
+
+        // only asking for write access fails, whatever the umask
+        let err = ext4.fuse_access(root, 0, 0, W_OK as u16, 0).unwrap_err();
+        assert_eq!(err.error(), Errno::EROFS);
+        assert!(ext4.fuse_access(root, 0, 0, R_OK as u16, 0o022).unwrap());
+    }
+
+    #[test]
+    fn test_unlink_keeps_linked_inode() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let mut ext4 = mount(&disk);
+        let inode = create_file(&ext4, "a");
+        ext4.write_at(inode, 0, b"shared").unwrap();
+        ext4.fuse_link(inode as u64, ROOT_INODE as u64, "b").unwrap();
+        assert_eq!(ext4.get_inode_ref(inode).unwrap().inode.links_count(), 2);
+
+        // the first unlink only drops a link
+        ext4.file_remove("a").unwrap();
+        assert!(inode_in_use(&ext4, inode));
+        assert_eq!(ext4.get_inode_ref(inode).unwrap().inode.links_count(), 1);
+        assert_eq!(read_file(&ext4, lookup(&ext4, "b").unwrap()), b"shared");
+
+        ext4.file_remove("b").unwrap();
+        assert!(!inode_in_use(&ext4, inode));
+    }
+
+    /// Flip the bits of the byte at `offset` of the device.
+    fn corrupt(disk: &MemDisk, offset: usize) {
+        let byte = disk.bytes(offset, 1)[0];
+        disk.write_offset(offset, &[!byte]).unwrap();
+    }
+
+    /// Mount a copy of `disk` and run `op` under each checksum policy, it
+    /// must fail with `EIO` under `Error` only.
+    fn check_policies(disk: &MemDisk, op: impl Fn(&Ext4) -> Result<()>) {
+        let image = disk.bytes(0, EXT4_IMAGE.len());
+        for policy in [Ext4ChecksumPolicy::Error, Ext4ChecksumPolicy::Warn, Ext4ChecksumPolicy::Ignore] {
+            let options = Ext4MountOptions { checksum_policy: policy, ..Default::default() };
+            match Ext4::open_with_options(MemDisk::new(&image), options).and_then(|ext4| op(&ext4)) {
+                Err(err) if policy == Ext4ChecksumPolicy::Error => assert_eq!(err.error(), Errno::EIO),
+                result => assert!(result.is_ok() && policy != Ext4ChecksumPolicy::Error),
+            }
+        }
+    }
+
+    /// Create a file with one data block in the root directory.
+    fn create_data_file(ext4: &Ext4, name: &str) -> Result<()> {
+        let mode = InodeFileType::S_IFREG.bits() | 0o644;
+        let inode = ext4.create(ROOT_INODE, name, mode)?.inode_num;
+        ext4.write_at(inode, 0, &pattern(1024, 1))?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_corrupt_directory_block() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+        let block_size = ext4.super_block.block_size() as usize;
+        ext4.dir_mk("dir").unwrap();
+        let dir = lookup(&ext4, "dir").unwrap();
+        let mode = InodeFileType::S_IFREG.bits() | 0o644;
+        let file = ext4.create(dir, "file", mode).unwrap().inode_num;
+        let dir_ref = ext4.get_inode_ref(dir).unwrap();
+        let block = ext4.get_pblock_idx(&dir_ref, 0).unwrap() as usize;
+        drop(ext4);
+
+        // the checksum in the tail of the block, the entries stay readable
+        corrupt(&disk, (block + 1) * block_size - 1);
+        check_policies(&disk, |ext4| {
+            assert_eq!(lookup(ext4, "dir/file")?, file);
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_corrupt_block_bitmap() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+        let block_size = ext4.super_block.block_size() as usize;
+        let bg = ext4.load_block_group(0).unwrap();
+        let bitmap_block = bg.get_block_bitmap_block(&ext4.super_block) as usize;
+        drop(ext4);
+
+        // blocks near the end of the group, free or not
+        corrupt(&disk, bitmap_block * block_size + 1000);
+        check_policies(&disk, |ext4| create_data_file(ext4, "file"));
+    }
+
+    #[test]
+    fn test_corrupt_inode_bitmap() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+        let block_size = ext4.super_block.block_size() as usize;
+        let bg = ext4.load_block_group(0).unwrap();
+        let bitmap_block = bg.get_inode_bitmap_block(&ext4.super_block) as usize;
+        assert!(ext4.super_block.inodes_per_group() > 8 * 200);
+        drop(ext4);
+
+        // free inodes of the group
+        corrupt(&disk, bitmap_block * block_size + 200);
+        check_policies(&disk, |ext4| create_data_file(ext4, "file"));
+    }
 
-        self.ialloc_free_inode(child.inode_num, is_dir);
+    #[test]
+    fn test_corrupt_group_descriptor() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+        let block_size = ext4.super_block.block_size() as usize;
+        let gdt_start = ext4.super_block.first_data_block() as usize + 1;
+        drop(ext4);
 
-        Ok(EOK)
+        // bg_checksum of group 0
+        corrupt(&disk, gdt_start * block_size + 0x1e);
+        check_policies(&disk, |ext4| create_data_file(ext4, "file"));
     }
 }
@@ -43,10 +43,7 @@ impl Ext4 {
                 });
 
                 let next_block = search_path.path.last().unwrap().index.unwrap().leaf_lo;
-                let mut next_data = self
-                    .block_device
-                    .read_offset(next_block as usize * BLOCK_SIZE);
-                node = ExtentNode::load_from_data_mut(&mut next_data, false)?;
+                node = self.load_extent_node(inode_ref, next_block as Ext4Fsblk)?;
                 depth -= 1;
                 search_path.depth += 1;
                 pblock_of_node = next_block as usize;
@@ -56,137 +53,297 @@ impl Ext4 {
         }
 
         // Handle the case where depth is 0
-        if let Some((extent, pos)) = node.binsearch_extent(lblock) {
-            search_path.path.push(ExtentPathNode {
-                header: node.header,
-                index: None,
-                extent: Some(extent),
-                position: pos,
-                pblock: lblock as u64 - extent.get_first_block() as u64 + extent.get_pblock(),
-                pblock_of_node,
-            });
-            search_path.maxdepth = node.header.depth;
-
-            Ok(search_path)
-        } else {
-            search_path.path.push(ExtentPathNode {
-                header: node.header,
-                index: None,
-                extent: None,
-                position: 0,
-                pblock: 0,
-                pblock_of_node,
-            });
-            Ok(search_path)
-        }
+        // Use the closest extent so that callers inserting a new extent know where it goes
+        let pos = node.binsearch_extent_pos(lblock);
+        let extent = node.get_extent(pos).unwrap();
+        search_path.path.push(ExtentPathNode {
+            header: node.header,
+            index: None,
+            extent: Some(extent),
+            position: pos,
+            // 0 when lblock is in a hole
+            pblock: if node.header.entries_count > 0 && extent.contains(lblock) {
+                extent.pblock_of(lblock)
+            } else {
+                0
+            },
+            pblock_of_node,
+        });
+        search_path.maxdepth = node.header.depth;
+
+        Ok(search_path)
     }
 
     /// Insert an extent into the extent tree.
+    ///
+    /// The extent must not overlap the blocks already mapped. It is merged
+    /// with its neighbours in the leaf when they are contiguous. A full leaf
+    /// is split, and the tree grows in depth when the root is full.
+    ///
+    /// Params:
+    /// inode_ref: &mut Ext4InodeRef - inode reference, written back
+    /// newex: &mut Ext4Extent - the new extent
     pub fn insert_extent(
         &self,
         inode_ref: &mut Ext4InodeRef,
         newex: &mut Ext4Extent,
     ) -> Result<()> {
-        let newex_first_block = newex.first_block;
-        
-        let mut search_path = self.find_extent(inode_ref, newex_first_block)?;
-        
-        let depth = search_path.depth as usize;
-        let node = &search_path.path[depth]; // Get the node at the current depth
-
-        let at_root = node.pblock_of_node == 0;
-        let header = node.header;
-
-        // Node is empty (no extents)
-        if header.entries_count == 0 {
-            // If the node is empty, insert the new extent directly
-            self.insert_new_extent(inode_ref, &mut search_path, newex)?;
-            return Ok(());
-        }
-
-        // Insert to exsiting extent
-        if let Some(mut ex) = node.extent {
-            let pos = node.position;
-            let last_extent_pos = header.entries_count as usize - 1;
-
-            // Try to Insert to found_ext
-            // found_ext:   |<---found_ext--->|         |<---ext2--->|
-            //              20              30         50          60
-            // insert:      |<---found_ext---><---newex--->|         |<---ext2--->|
-            //              20              30            40         50          60
-            // merge:       |<---newex--->|      |<---ext2--->|
-            //              20           40      50          60
-            if self.can_merge(&ex, newex) {
-                self.merge_extent(&search_path, &mut ex, newex)?;
-
-                if at_root {
-                    // we are at root
-                    *inode_ref.inode.root_extent_mut_at(node.position) = ex;
+        self.check_writable()?;
+
+        loop {
+            let search_path = self.find_extent(inode_ref, newex.first_block)?;
+            let depth = search_path.depth as usize;
+            let leaf = &search_path.path[depth];
+            let node_pblock = leaf.pblock_of_node;
+            let count = leaf.header.entries_count as usize;
+            let mut node = self.ext_node_load(inode_ref, node_pblock)?;
+
+            // The closest extent starts before the new one, unless the new
+            // one goes first in the leaf
+            let mut pos = leaf.position;
+            if count > 0 && node.read_offset_as::<Ext4Extent>(ext_entry_offset(pos)).first_block < newex.first_block {
+                pos += 1;
+            }
+
+            // Merge with the left neighbour:
+            // |<---left--->|<---newex--->|
+            if pos > 0 {
+                let left: &mut Ext4Extent = node.read_offset_as_mut(ext_entry_offset(pos - 1));
+                if self.can_merge(left, newex) {
+                    ext_extend(left, newex.get_actual_len());
+                    return self.ext_node_store(inode_ref, node_pblock, &mut node);
                 }
-                return Ok(());
             }
 
-            // Insert right
-            // found_ext:   |<---found_ext--->|         |<---next_extent--->|
-            //              10               20         30                40
-            // insert:      |<---found_ext--->|<---newex---><---next_extent--->|
-            //              10               20            30                40
-            // merge:       |<---found_ext--->|<---newex--->|
-            //              10               20            40
-            if pos < last_extent_pos
-                && ((ex.first_block + ex.block_count as u32) < newex.first_block)
-            {
-                if let Some(next_extent) = self.get_extent_from_node(node, pos + 1) {
-                    if self.can_merge(&next_extent, newex) {
-                        self.merge_extent(&search_path, newex, &next_extent)?;
-                        return Ok(());
+            // Merge with the right neighbour, which then starts at the new extent:
+            // |<---newex--->|<---right--->|
+            if pos < count {
+                let right: &mut Ext4Extent = node.read_offset_as_mut(ext_entry_offset(pos));
+                if self.can_merge(newex, right) {
+                    let len = right.get_actual_len();
+                    *right = *newex;
+                    ext_extend(right, len);
+                    self.ext_node_store(inode_ref, node_pblock, &mut node)?;
+                    if pos == 0 {
+                        self.ext_update_keys(inode_ref, &search_path, depth, newex.first_block)?;
                     }
+                    return Ok(());
                 }
             }
 
-            // Insert left
-            //  found_ext:  |<---found_ext--->|         |<---ext2--->|
-            //              20              30         40          50
-            // insert:   |<---prev_extent---><---newex--->|<---found_ext--->|....|<---ext2--->|
-            //           0                  10          20                 30    40          50
-            // merge:    |<---newex--->|<---found_ext--->|....|<---ext2--->|
-            //           0            20                30    40          50
-            if pos > 0 && (newex.first_block + newex.block_count as u32) < ex.first_block {
-                if let Some(mut prev_extent) = self.get_extent_from_node(node, pos - 1) {
-                    if self.can_merge(&prev_extent, newex) {
-                        self.merge_extent(&search_path, &mut prev_extent, newex)?;
-                        return Ok(());
-                    }
+            if count < leaf.header.max_entries_count as usize {
+                ext_entry_make_room(&mut node, pos);
+                *node.read_offset_as_mut::<Ext4Extent>(ext_entry_offset(pos)) = *newex;
+                self.ext_node_store(inode_ref, node_pblock, &mut node)?;
+                if pos == 0 {
+                    self.ext_update_keys(inode_ref, &search_path, depth, newex.first_block)?;
                 }
+                return Ok(());
             }
+
+            // The leaf is full, make room and search again
+            self.ext_split_node(inode_ref, &search_path, depth, newex.first_block)?;
         }
+    }
 
-        // Check if there's space to insert the new extent
-        //                full         full
-        // Before:   |<---ext1--->|<---ext2--->|
-        //           10           20          30
+    /// Replace the extent starting at `first_block` with `ex`, which starts
+    /// at the same block or within the old extent. `ex` is merged into its
+    /// left neighbour when they are contiguous.
+    ///
+    /// Params:
+    /// inode_ref: &mut Ext4InodeRef - inode reference, written back
+    /// first_block: Ext4Lblk - first block of the extent to replace
+    /// ex: &Ext4Extent - the new extent
+    fn ext_replace(&self, inode_ref: &mut Ext4InodeRef, first_block: Ext4Lblk, ex: &Ext4Extent) -> Result<()> {
+        let search_path = self.find_extent(inode_ref, first_block)?;
+        let leaf = search_path.path.last().unwrap();
+        let pos = leaf.position;
+        let count = leaf.header.entries_count as usize;
+        let mut node = self.ext_node_load(inode_ref, leaf.pblock_of_node)?;
+
+        if pos > 0 {
+            let left: &mut Ext4Extent = node.read_offset_as_mut(ext_entry_offset(pos - 1));
+            if self.can_merge(left, ex) {
+                ext_extend(left, ex.get_actual_len());
+                // drop the replaced entry, the left one is still in the leaf
+                node.data.copy_within(ext_entry_offset(pos + 1)..ext_entry_offset(count), ext_entry_offset(pos));
+                node.read_offset_as_mut::<Ext4ExtentHeader>(0).entries_count -= 1;
+                return self.ext_node_store(inode_ref, leaf.pblock_of_node, &mut node);
+            }
+        }
 
-        //                full          full
-        // insert:   |<---ext1--->|<---ext2--->|<---newex--->|
-        //           10           20           30           35
-        if header.entries_count < header.max_entries_count {
-            self.insert_new_extent(inode_ref, &mut search_path, newex)?;
+        *node.read_offset_as_mut::<Ext4Extent>(ext_entry_offset(pos)) = *ex;
+        self.ext_node_store(inode_ref, leaf.pblock_of_node, &mut node)?;
+        if pos == 0 && ex.first_block != first_block {
+            self.ext_update_keys(inode_ref, &search_path, search_path.depth as usize, ex.first_block)?;
+        }
+        Ok(())
+    }
+
+    /// Mark blocks of an unwritten extent as written.
+    ///
+    /// Params:
+    /// inode_ref: &mut Ext4InodeRef - inode reference, written back
+    /// ex: &Ext4Extent - the unwritten extent
+    /// lblock: Ext4Lblk - first block to mark
+    /// count: u32 - number of blocks to mark, within `ex`
+    pub fn extent_mark_written(
+        &self,
+        inode_ref: &mut Ext4InodeRef,
+        ex: &Ext4Extent,
+        lblock: Ext4Lblk,
+        count: u32,
+    ) -> Result<()> {
+        self.ext_convert(inode_ref, ex, lblock, count, false)
+    }
+
+    /// Mark blocks of a written extent as unwritten, they read as zeros
+    /// from then on.
+    ///
+    /// Params:
+    /// inode_ref: &mut Ext4InodeRef - inode reference, written back
+    /// ex: &Ext4Extent - the written extent
+    /// lblock: Ext4Lblk - first block to mark
+    /// count: u32 - number of blocks to mark, within `ex` and at most the
+    /// length of an unwritten extent
+    pub fn extent_mark_unwritten(
+        &self,
+        inode_ref: &mut Ext4InodeRef,
+        ex: &Ext4Extent,
+        lblock: Ext4Lblk,
+        count: u32,
+    ) -> Result<()> {
+        self.ext_convert(inode_ref, ex, lblock, count, true)
+    }
+
+    /// Set the unwritten flag of `count` blocks from `lblock` in `ex`. The
+    /// extent is split into up to three extents: the blocks before, the
+    /// converted blocks and the blocks after.
+    fn ext_convert(
+        &self,
+        inode_ref: &mut Ext4InodeRef,
+        ex: &Ext4Extent,
+        lblock: Ext4Lblk,
+        count: u32,
+        unwritten: bool,
+    ) -> Result<()> {
+        self.check_writable()?;
+
+        let last = ex.get_last_block();
+        let mut converted = Ext4Extent::new(lblock, ex.pblock_of(lblock), count as u16);
+        if unwritten {
+            converted.mark_unwritten();
+        }
+
+        if lblock > ex.first_block {
+            self.ext_replace(inode_ref, ex.first_block, &ext_slice(ex, ex.first_block, lblock - 1))?;
+            self.insert_extent(inode_ref, &mut converted)?;
         } else {
-            // Create a new leaf node
-            self.create_new_leaf(inode_ref, &mut search_path, newex)?;
+            self.ext_replace(inode_ref, ex.first_block, &converted)?;
         }
 
+        if lblock + count - 1 < last {
+            self.insert_extent(inode_ref, &mut ext_slice(ex, lblock + count, last))?;
+        }
         Ok(())
     }
 
-    /// Get extent from the node at the given position.
-    fn get_extent_from_node(&self, node: &ExtentPathNode, pos: usize) -> Option<Ext4Extent> {
-        let data = self
-            .block_device
-            .read_offset(node.pblock as usize * BLOCK_SIZE);
-        let extent_node = ExtentNode::load_from_data(&data, false).unwrap();
+    /// Find the extent that maps a logical block.
+    ///
+    /// Params:
+    /// inode_ref: &Ext4InodeRef - inode reference
+    /// lblock: Ext4Lblk - logical block id
+    ///
+    /// Returns:
+    /// `Result<Option<Ext4Extent>>` - the extent, None if the block is in a hole
+    pub fn extent_at(&self, inode_ref: &Ext4InodeRef, lblock: Ext4Lblk) -> Result<Option<Ext4Extent>> {
+        let search_path = self.find_extent(inode_ref, lblock)?;
+        let leaf = search_path.path.last().unwrap();
+        if leaf.header.entries_count == 0 {
+            return Ok(None);
+        }
+        Ok(leaf.extent.filter(|ex| ex.contains(lblock)))
+    }
 
-        extent_node.get_extent(pos)
+    /// Find the extent that maps a logical block, or the first one after it.
+    ///
+    /// Params:
+    /// inode_ref: &Ext4InodeRef - inode reference
+    /// lblock: Ext4Lblk - logical block id
+    ///
+    /// Returns:
+    /// `Result<Option<Ext4Extent>>` - the extent, None if no block from `lblock` on is mapped
+    pub fn extent_find_next(&self, inode_ref: &Ext4InodeRef, lblock: Ext4Lblk) -> Result<Option<Ext4Extent>> {
+        let search_path = self.find_extent(inode_ref, lblock)?;
+        let depth = search_path.depth as usize;
+        let leaf = &search_path.path[depth];
+        let count = leaf.header.entries_count as usize;
+
+        if count > 0 {
+            let ex = leaf.extent.unwrap();
+            if lblock < ex.first_block || ex.contains(lblock) {
+                return Ok(Some(ex));
+            }
+            if leaf.position + 1 < count {
+                let node = self.ext_node_load(inode_ref, leaf.pblock_of_node)?;
+                return Ok(Some(node.read_offset_as(ext_entry_offset(leaf.position + 1))));
+            }
+        }
+
+        // The leaf has nothing after lblock, go on with the first leaf on the right
+        for level in (0..depth).rev() {
+            let parent = &search_path.path[level];
+            if parent.position + 1 >= parent.header.entries_count as usize {
+                continue;
+            }
+
+            let node = self.ext_node_load(inode_ref, parent.pblock_of_node)?;
+            let index: Ext4ExtentIndex = node.read_offset_as(ext_entry_offset(parent.position + 1));
+            let mut child = self.load_extent_node(inode_ref, index.get_pblock())?;
+            while child.header.depth > 0 {
+                let pblock = child.get_index(0)?.get_pblock();
+                child = self.load_extent_node(inode_ref, pblock)?;
+            }
+            if child.header.entries_count > 0 {
+                return Ok(child.get_extent(0));
+            }
+        }
+
+        Ok(None)
+    }
+    /// Load a non-root extent tree node and verify its tail checksum.
+    ///
+    /// Params:
+    /// inode_ref: &Ext4InodeRef - inode owning the extent tree
+    /// pblock: Ext4Fsblk - physical block of the node
+    ///
+    /// Returns:
+    /// `Result<ExtentNode>` - the extent node
+    pub fn load_extent_node(&self, inode_ref: &Ext4InodeRef, pblock: Ext4Fsblk) -> Result<ExtentNode> {
+        let block_size = self.super_block.block_size() as usize;
+        let data = Block::load(self.block_device.clone(), pblock as usize * block_size, block_size)?.data;
+        let node = ExtentNode::load_from_data(&data, false)?;
+        self.verify_csum("extent block", pblock, || {
+            node.verify_checksum(&self.super_block, inode_ref.inode_num, inode_ref.inode.generation())
+        })?;
+        Ok(node)
+    }
+
+    /// Write back a non-root extent tree node, updating its tail checksum.
+    ///
+    /// Params:
+    /// inode_ref: &Ext4InodeRef - inode owning the extent tree
+    /// block: &mut Block - the extent block
+    pub fn sync_extent_block(&self, inode_ref: &Ext4InodeRef, block: &mut Block) -> Result<()> {
+        if self.super_block.has_metadata_csum() {
+            Ext4ExtentTail::set_checksum(
+                &self.super_block,
+                inode_ref.inode_num,
+                inode_ref.inode.generation(),
+                &mut block.data,
+            );
+        }
+        block.sync_blk_to_disk(self.block_device.clone())
     }
 
     /// Check if two extents can be merged.
@@ -239,7 +396,12 @@ impl Ext4 {
         }
 
         // Check if the merged length would exceed the maximum allowed length
-        if ext1_ee_len + ext2_ee_len > EXT_INIT_MAX_LEN {
+        let max_len = if ex1.is_unwritten() {
+            EXT_UNWRITTEN_MAX_LEN - EXT_INIT_MAX_LEN
+        } else {
+            EXT_INIT_MAX_LEN
+        };
+        if ext1_ee_len as u32 + ext2_ee_len as u32 > max_len as u32 {
             return false;
         }
 
@@ -250,114 +412,125 @@ impl Ext4 {
         false
     }
 
-    fn merge_extent(
-        &self,
-        search_path: &SearchPath,
-        left_ext: &mut Ext4Extent,
-        right_ext: &Ext4Extent,
-    ) -> Result<()> {
-
-        let unwritten = left_ext.is_unwritten();
-        let len = left_ext.get_actual_len() + right_ext.get_actual_len();
-        left_ext.set_actual_len(len);
-        if unwritten {
-            left_ext.mark_unwritten();
+    /// Load an extent tree node to modify it.
+    ///
+    /// Params:
+    /// inode_ref: &Ext4InodeRef - inode owning the extent tree
+    /// pblock_of_node: usize - physical block of the node, 0 for the root in the inode
+    ///
+    /// Returns:
+    /// `Result<Block>` - the node
+    fn ext_node_load(&self, inode_ref: &Ext4InodeRef, pblock_of_node: usize) -> Result<Block> {
+        if pblock_of_node == 0 {
+            return Ok(Block::load_inode_root_block(&inode_ref.inode.block));
         }
-        let depth = search_path.depth as usize;
-
-        let header = search_path.path[depth].header;
-
-        if header.max_entries_count > 4 {
-            let node = &search_path.path[depth];
-            let block = node.pblock_of_node;
-            let new_ex_offset = core::mem::size_of::<Ext4ExtentHeader>() + core::mem::size_of::<Ext4Extent>() * (node.position);
-            let mut ext4block = Block::load(self.block_device.clone(), block * BLOCK_SIZE);
-            let left_ext:&mut Ext4Extent = ext4block.read_offset_as_mut(new_ex_offset);
-
-            let unwritten = left_ext.is_unwritten();
-            let len = left_ext.get_actual_len() + right_ext.get_actual_len();
-            left_ext.set_actual_len(len);
-            if unwritten {
-                left_ext.mark_unwritten();
-            }
+        let block_size = self.super_block.block_size() as usize;
+        Block::load(self.block_device.clone(), pblock_of_node * block_size, block_size)
+    }
 
-            ext4block.sync_blk_to_disk(self.block_device.clone());
+    /// Write back an extent tree node loaded by `ext_node_load`.
+    fn ext_node_store(&self, inode_ref: &mut Ext4InodeRef, pblock_of_node: usize, node: &mut Block) -> Result<()> {
+        if pblock_of_node != 0 {
+            return self.sync_extent_block(inode_ref, node);
         }
-
-
-        Ok(())
+        for (i, chunk) in node.data.chunks(4).enumerate() {
+            inode_ref.inode.block[i] = u32::from_le_bytes(chunk.try_into().unwrap());
+        }
+        self.write_back_inode(inode_ref)
     }
 
-    fn insert_new_extent(
+    /// Set the key of the indexes leading to the node at `level` of
+    /// `search_path`, after its first entry changed to start at `first_block`.
+    fn ext_update_keys(
         &self,
         inode_ref: &mut Ext4InodeRef,
-        search_path: &mut SearchPath,
-        new_extent: &mut Ext4Extent,
+        search_path: &SearchPath,
+        level: usize,
+        first_block: Ext4Lblk,
     ) -> Result<()> {
-        let depth = search_path.depth as usize;
-        let node = &mut search_path.path[depth]; // Get the node at the current depth
-        let header = node.header;
-
-        // insert at root
-        if depth == 0 {
-            // Node is empty (no extents)
-            if header.entries_count == 0 {
-                *inode_ref.inode.root_extent_mut_at(node.position) = *new_extent;
-                inode_ref.inode.root_extent_header_mut().entries_count += 1;
+        for parent in search_path.path[..level].iter().rev() {
+            let mut node = self.ext_node_load(inode_ref, parent.pblock_of_node)?;
+            node.read_offset_as_mut::<Ext4ExtentIndex>(ext_entry_offset(parent.position)).first_block = first_block;
+            self.ext_node_store(inode_ref, parent.pblock_of_node, &mut node)?;
 
-                self.write_back_inode(inode_ref);
-                return Ok(());
+            // the key of the parent itself only changes with its first entry
+            if parent.position != 0 {
+                break;
             }
-            // Not empty, insert at search result pos + 1
-            log::trace!("insert newex at pos {:x?} current entry_count {:x?} ex {:x?}", node.position + 1 , header.entries_count, new_extent);
-            *inode_ref.inode.root_extent_mut_at(node.position + 1) = *new_extent;
-            inode_ref.inode.root_extent_header_mut().entries_count += 1;
-            return Ok(());
-        }else{
-            // insert at nonroot
-            // log::trace!("insert newex at nonroot pos {:x?} current entry_count {:x?} ex {:x?}", node.position + 1 , header.entries_count, new_extent);
-
-            // load block
-            let node_block = node.pblock_of_node;
-            let mut ext4block =
-            Block::load(self.block_device.clone(), node_block * BLOCK_SIZE);
-            let new_ex_offset = core::mem::size_of::<Ext4ExtentHeader>() + core::mem::size_of::<Ext4Extent>() * (node.position + 1);
-
-            // insert new extent
-            let ex: &mut Ext4Extent = ext4block.read_offset_as_mut(new_ex_offset);
-            *ex = *new_extent;
-            let header: &mut Ext4ExtentHeader = ext4block.read_offset_as_mut(0);
-
-            // update entry count 
-            header.entries_count += 1;
-
-            // sync to disk
-            ext4block.sync_blk_to_disk(self.block_device.clone());
-
-            return Ok(());
         }
-
-        return_errno_with_message!(Errno::ENOTSUP, "Not supported insert extent at nonroot");
+        Ok(())
     }
 
-    // finds empty index and adds new leaf. if no free index is found, then it requests in-depth growing.
-    fn create_new_leaf(
+    /// Make room in the full node at `level` of `search_path`, on the way
+    /// to `lblock`.
+    ///
+    /// The root moves down into a new block. Other nodes move their upper
+    /// half to a new node next to them, except a leaf `lblock` is appended
+    /// to: it stays full and the new leaf starts empty, so that files
+    /// written in order end up with full leaves.
+    fn ext_split_node(
         &self,
         inode_ref: &mut Ext4InodeRef,
-        search_path: &mut SearchPath,
-        new_extent: &mut Ext4Extent,
+        search_path: &SearchPath,
+        level: usize,
+        lblock: Ext4Lblk,
     ) -> Result<()> {
-        // log::info!("search path {:x?}", search_path);
+        if level == 0 {
+            return self.ext_grow_indepth(inode_ref);
+        }
 
-        // tree is full, time to grow in depth
-        self.ext_grow_indepth(inode_ref);
+        // the parent needs room for the index of the new node first
+        let parent = &search_path.path[level - 1];
+        if parent.header.entries_count >= parent.header.max_entries_count {
+            return self.ext_split_node(inode_ref, search_path, level - 1, lblock);
+        }
 
-        // insert again
-        self.insert_extent(inode_ref, new_extent)
+        let path_node = &search_path.path[level];
+        let count = path_node.header.entries_count as usize;
+        let mut node = self.ext_node_load(inode_ref, path_node.pblock_of_node)?;
+        let last_first_block = node.read_offset_as::<Ext4Extent>(ext_entry_offset(count - 1)).first_block;
+        let split = if path_node.header.is_leaf() && lblock > last_first_block {
+            count
+        } else {
+            count / 2
+        };
 
-    }
+        let new_pblock = self.balloc_alloc_block(inode_ref, Some(path_node.pblock_of_node as Ext4Fsblk))?;
+        let block_size = self.super_block.block_size() as usize;
+        let mut new_node = Block {
+            disk_offset: new_pblock as usize * block_size,
+            data: vec![0u8; block_size],
+        };
 
-    
+        // the new node gets the entries from `split` on
+        new_node.data[..ext_entry_offset(0)].copy_from_slice(&node.data[..ext_entry_offset(0)]);
+        new_node.read_offset_as_mut::<Ext4ExtentHeader>(0).entries_count = (count - split) as u16;
+        new_node.data[ext_entry_offset(0)..ext_entry_offset(count - split)]
+            .copy_from_slice(&node.data[ext_entry_offset(split)..ext_entry_offset(count)]);
+        node.data[ext_entry_offset(split)..ext_entry_offset(count)].fill(0);
+        node.read_offset_as_mut::<Ext4ExtentHeader>(0).entries_count = split as u16;
+
+        let key = if split < count {
+            new_node.read_offset_as::<Ext4Extent>(ext_entry_offset(0)).first_block
+        } else {
+            lblock
+        };
+
+        self.sync_extent_block(inode_ref, &mut new_node)?;
+        self.ext_node_store(inode_ref, path_node.pblock_of_node, &mut node)?;
+
+        // index the new node right after the old one
+        let pos = parent.position + 1;
+        let mut parent_node = self.ext_node_load(inode_ref, parent.pblock_of_node)?;
+        ext_entry_make_room(&mut parent_node, pos);
+        let index: &mut Ext4ExtentIndex = parent_node.read_offset_as_mut(ext_entry_offset(pos));
+        *index = Ext4ExtentIndex {
+            first_block: key,
+            ..Default::default()
+        };
+        index.store_pblock(new_pblock);
+        self.ext_node_store(inode_ref, parent.pblock_of_node, &mut parent_node)
+    }
     // allocates new block
     // moves top-level data (index block or leaf) into the new block
     // initializes new top-level, creating index that points to the
@@ -367,8 +540,9 @@ impl Ext4 {
         let new_block = self.balloc_alloc_block(inode_ref, None)?;
 
         // load new block
+        let block_size = self.super_block.block_size() as usize;
         let mut new_ext4block =
-            Block::load(self.block_device.clone(), new_block as usize * BLOCK_SIZE);
+            Block::load(self.block_device.clone(), new_block as usize * block_size, block_size)?;
 
         // move top-level index/leaf into new block
         let data_to_copy = &inode_ref.inode.block;
@@ -381,7 +555,7 @@ impl Ext4 {
         // set new block header
         let mut new_header = Ext4ExtentHeader::load_from_u8_mut(&mut new_ext4block.data);
         new_header.set_magic();
-        let space = (BLOCK_SIZE - core::mem::size_of::<Ext4ExtentHeader>()) / core::mem::size_of::<Ext4Extent>();
+        let space = (block_size - core::mem::size_of::<Ext4ExtentHeader>()) / core::mem::size_of::<Ext4Extent>();
         new_header.set_max_entries_count(space as u16);
         log::info!("new_header max entries {:x?}", new_header.max_entries_count);
         
@@ -400,8 +574,8 @@ impl Ext4 {
         }
 
 
-        new_ext4block.sync_blk_to_disk(self.block_device.clone());
-        self.write_back_inode(inode_ref);
+        self.sync_extent_block(inode_ref, &mut new_ext4block)?;
+        self.write_back_inode(inode_ref)?;
 
 
         Ok(())
@@ -429,545 +603,449 @@ impl Ext4 {
     // +--------+...+--------+  +--------+...+--------+  ......
     // | ext1   |...| extn   |  | ext1   |...| extn   |  ......
     // +--------+...+--------+  +--------+...+--------+  ......
+    /// Unmap the logical blocks from `from` to `to` included and free them.
+    ///
+    /// Extents across the bounds are cut, one around the whole range is
+    /// split in two. Emptied tree nodes are freed.
+    ///
+    /// Params:
+    /// inode_ref: &mut Ext4InodeRef - inode reference, written back
+    /// from: u32 - first logical block to remove
+    /// to: u32 - last logical block to remove
+    ///
+    /// Returns:
+    /// `Result<usize>` - status of the operation
     pub fn extent_remove_space(
         &self,
         inode_ref: &mut Ext4InodeRef,
         from: u32,
         to: u32,
     ) -> Result<usize> {
-        // log::info!("Remove space from {:x?} to {:x?}", from, to);
-        let mut search_path = self.find_extent(inode_ref, from)?;
-
-        // for i in search_path.path.iter() {
-        //     log::info!("from Path: {:x?}", i);
-        // }
-
-        let depth = search_path.depth as usize;
+        self.check_writable()?;
 
-        /* If we do remove_space inside the range of an extent */
-        let mut ex = search_path.path[depth].extent.unwrap();
-        if ex.get_first_block() < from
-            && to < (ex.get_first_block() + ex.get_actual_len() as u32 - 1)
-        {
-            let mut newex = Ext4Extent::default();
-            let unwritten = ex.is_unwritten();
-            let ee_block = ex.first_block;
-            let block_count = ex.block_count;
-            let newblock = to + 1 - ee_block + ex.get_pblock() as u32;
-            ex.block_count = from as u16 - ee_block as u16;
-
-            if unwritten {
-                ex.mark_unwritten();
+        while let Some(ex) = self.extent_find_next(inode_ref, from)? {
+            if ex.first_block > to {
+                break;
             }
-            newex.first_block = to + 1;
-            newex.block_count = (ee_block + block_count as u32 - 1 - to) as u16;
-            newex.start_lo = newblock;
-            newex.start_hi = ((newblock as u64) >> 32) as u16;
-
-            self.insert_extent(inode_ref, &mut newex)?;
-
-            return Ok(EOK);
-        }
-
-        // log::warn!("Remove space in depth: {:x?}", depth);
-
-        let mut i = depth as isize;
-
-        while i >= 0 {
-            // we are at the leaf node
-            // depth 0 (root node)
-            // +--------+--------+--------+
-            // |  idx1  |  idx2  |  idx3  |
-            // +--------+--------+--------+
-            //              |path
-            //              v
-            //              idx2
-            // depth 1 (internal node)
-            // +--------+--------+--------+ ......
-            // |  idx1  |  idx2  |  idx3  | ......
-            // +--------+--------+--------+ ......
-            //              |path
-            //              v
-            //              ext2
-            // depth 2 (leaf nodes)
-            // +--------+--------+..+--------+
-            // | ext1   | ext2   |..|last_ext|
-            // +--------+--------+..+--------+
-            //            ^            ^
-            //            |            |
-            //            from         to(exceed last ext, rest of the extents will be removed)
-            if i as usize == depth {
-                let node_pblock = search_path.path[i as usize].pblock_of_node;
-
-                let header = search_path.path[i as usize].header;
-                let entries_count = header.entries_count;
-
-                // we are at root
-                if node_pblock == 0 {
-                    let first_ex = inode_ref.inode.root_extent_at(0);
-                    let last_ex = inode_ref.inode.root_extent_at(entries_count as usize - 1);
-
-                    let mut leaf_from = first_ex.first_block;
-                    let mut leaf_to = last_ex.first_block + last_ex.get_actual_len() as u32 - 1;
-                    if leaf_from < from {
-                        leaf_from = from;
-                    }
-                    if leaf_to > to {
-                        leaf_to = to;
-                    }
-                    // log::trace!("from {:x?} to {:x?} leaf_from {:x?} leaf_to {:x?}", from, to, leaf_from, leaf_to);
-                    self.ext_remove_leaf(inode_ref, &mut search_path, leaf_from, leaf_to)?;
 
-                    i -= 1;
-                    continue;
+            let last = ex.get_last_block();
+            let start = ex.first_block.max(from);
+            let end = last.min(to);
+
+            // Found:    |<-----------ex----------->|
+            // Remove:          |<---from..to--->|
+            // Keep:     |<---->|                |<->|
+            if start > ex.first_block {
+                // the tail goes in first, failing to insert it (the leaf may
+                // have to split) then leaves the extent untouched
+                if end < last {
+                    self.insert_extent(inode_ref, &mut ext_slice(&ex, end + 1, last))?;
                 }
-                let ext4block =
-                    Block::load(self.block_device.clone(), node_pblock * BLOCK_SIZE);
-
-                let header = search_path.path[i as usize].header;
-                let entries_count = header.entries_count;
-
-                let first_ex: Ext4Extent = ext4block.read_offset_as(size_of::<Ext4ExtentHeader>());
-                let last_ex: Ext4Extent = ext4block.read_offset_as(
-                    size_of::<Ext4ExtentHeader>()
-                        + size_of::<Ext4Extent>() * (entries_count - 1) as usize,
-                );
+                self.ext_replace(inode_ref, ex.first_block, &ext_slice(&ex, ex.first_block, start - 1))?;
+            } else if end < last {
+                self.ext_replace(inode_ref, ex.first_block, &ext_slice(&ex, end + 1, last))?;
+            } else {
+                self.ext_remove_entry(inode_ref, ex.first_block)?;
+            }
 
-                let mut leaf_from = first_ex.first_block;
-                let mut leaf_to = last_ex.first_block + last_ex.get_actual_len() as u32 - 1;
+            // the tree no longer maps the blocks, they can go
+            self.balloc_free_blocks(inode_ref, ex.pblock_of(start), end - start + 1)?;
+        }
 
-                if leaf_from < from {
-                    leaf_from = from;
-                }
-                if leaf_to > to {
-                    leaf_to = to;
-                }
-                // log::trace!(
-                //     "from {:x?} to {:x?} leaf_from {:x?} leaf_to {:x?}",
-                //     from,
-                //     to,
-                //     leaf_from,
-                //     leaf_to
-                // );
+        Ok(EOK)
+    }
 
-                self.ext_remove_leaf(inode_ref, &mut search_path, leaf_from, leaf_to)?;
+    /// Remove the logical blocks from `from` to `from + count - 1` and move
+    /// the extents after them `count` blocks to the left. The data blocks of
+    /// the range are freed, the others are not copied.
+    ///
+    /// Params:
+    /// inode_ref: &mut Ext4InodeRef - inode reference, written back
+    /// from: Ext4Lblk - first logical block to remove
+    /// count: u32 - number of blocks to remove
+    pub fn extent_collapse_range(&self, inode_ref: &mut Ext4InodeRef, from: Ext4Lblk, count: u32) -> Result<()> {
+        self.extent_remove_space(inode_ref, from, from + count - 1)?;
+        self.ext_shift_node(inode_ref, 0, from + count, &|lblock| lblock - count)?;
+        Ok(())
+    }
 
-                i -= 1;
-                continue;
+    /// Move the extents from the logical block `from` on `count` blocks to
+    /// the right, leaving a hole of `count` blocks at `from`. An extent
+    /// across `from` is split in two. No data block is copied.
+    ///
+    /// Params:
+    /// inode_ref: &mut Ext4InodeRef - inode reference, written back
+    /// from: Ext4Lblk - first logical block of the hole
+    /// count: u32 - number of blocks to insert
+    pub fn extent_insert_range(&self, inode_ref: &mut Ext4InodeRef, from: Ext4Lblk, count: u32) -> Result<()> {
+        // blocks preallocated past the end of file move too
+        if let Some(last) = self.ext_last_mapped_block(inode_ref)? {
+            if last >= from && last as u64 + count as u64 >= EXT_MAX_BLOCKS as u64 {
+                return_errno_with_message!(Errno::EFBIG, "insert range moves extents past the maximum file size");
             }
+        }
 
-            // log::trace!("---at level---{:?}\n", i);
-
-            // we are at index
-            // example i=1, depth=2
-            // depth 0 (root node) - 处理的索引节点
-            // +--------+--------+--------+
-            // |  idx1  |  idx2  |  idx3  |
-            // +--------+--------+--------+
-            //            |path     | 下一个要处理的节点(more_to_rm?)
-            //            v         v
-            //           idx2
-            //
-            // depth 1 (internal node)
-            // +--------++--------+...+--------+
-            // |  idx1  ||  idx2  |...|  idxn  |
-            // +--------++--------+...+--------+
-            //            |path
-            //            v
-            //            ext2
-            // depth 2 (leaf nodes)
-            // +--------+--------+..+--------+
-            // | ext1   | ext2   |..|last_ext|
-            // +--------+--------+..+--------+
-            let header = search_path.path[i as usize].header;
-            if self.more_to_rm(&search_path.path[i as usize], to) {
-                // todo
-                // load next idx
-
-                // go to this node's child
-                i += 1;
-            } else {
-                if i > 0 {
-                    // empty
-                    if header.entries_count == 0 {
-                        self.ext_remove_idx(inode_ref, &mut search_path, i as u16 - 1)?;
-                    }
-                }
-
-                let idx = i;
-                if idx - 1 < 0 {
-                    break;
-                }
-                i -= 1;
+        self.ext_shift_node(inode_ref, 0, from, &|lblock| lblock + count)?;
+
+        // Found:    |<-----ex----->|
+        // Split:    |<--->|        |<------->|
+        //                 from     from + count
+        if let Some(ex) = self.extent_at(inode_ref, from)? {
+            if ex.first_block < from {
+                let last = ex.get_last_block();
+                let mut tail = ext_slice(&ex, from, last);
+                tail.first_block = from + count;
+                self.insert_extent(inode_ref, &mut tail)?;
+                self.ext_replace(inode_ref, ex.first_block, &ext_slice(&ex, ex.first_block, from - 1))?;
             }
         }
+        Ok(())
+    }
 
-        Ok(EOK)
+    /// Last logical block mapped by the extent tree, None if it is empty.
+    fn ext_last_mapped_block(&self, inode_ref: &Ext4InodeRef) -> Result<Option<Ext4Lblk>> {
+        let mut pblock_of_node = 0;
+        loop {
+            let node = self.ext_node_load(inode_ref, pblock_of_node)?;
+            let header: Ext4ExtentHeader = node.read_offset_as(0);
+            let count = header.entries_count as usize;
+            if count == 0 {
+                return Ok(None);
+            }
+            if header.is_leaf() {
+                let ex: Ext4Extent = node.read_offset_as(ext_entry_offset(count - 1));
+                return Ok(Some(ex.get_last_block()));
+            }
+            let index: Ext4ExtentIndex = node.read_offset_as(ext_entry_offset(count - 1));
+            pblock_of_node = index.get_pblock() as usize;
+        }
     }
 
-    pub fn ext_remove_leaf(
+    /// Move the entries of the subtree at `pblock_of_node` starting from
+    /// the logical block `start` on to `shift(first_block)`, and fix the
+    /// keys of the indexes. The entries must keep their order.
+    ///
+    /// Returns:
+    /// `Result<Option<Ext4Lblk>>` - the first block of the node, None if it is empty
+    fn ext_shift_node(
         &self,
         inode_ref: &mut Ext4InodeRef,
-        path: &mut SearchPath,
-        from: u32,
-        to: u32,
-    ) -> Result<usize> {
-        // log::trace!("Remove leaf from {:x?} to {:x?}", from, to);
-
-        // depth 0 (root node)
-        // +--------+--------+--------+
-        // |  idx1  |  idx2  |  idx3  |
-        // +--------+--------+--------+
-        //     |         |         |
-        //     v         v         v
-        //     ^
-        //     Current position
-        let depth = inode_ref.inode.root_header_depth();
-        let mut header = path.path[depth as usize].header;
-
-        let mut new_entry_count = header.entries_count;
-        let mut ex2 = Ext4Extent::default();
-
-        /* find where to start removing */
-        let pos = path.path[depth as usize].position;
-        let entry_count = header.entries_count;
-
-        // depth 1 (internal node)
-        // +--------+...+--------+  +--------+...+--------+ ......
-        // |  idx1  |...|  idxn  |  |  idx1  |...|  idxn  | ......
-        // +--------+...+--------+  +--------+...+--------+ ......
-        //     |           |         |             |
-        //     v           v         v             v
-        //     ^
-        //     Current loaded node
-
-        // load node data
-        let node_disk_pos = path.path[depth as usize].pblock_of_node * BLOCK_SIZE;
-
-        let mut ext4block = if node_disk_pos == 0 {
-            // we are at root
-            Block::load_inode_root_block(&inode_ref.inode.block)
-        } else {
-            Block::load(self.block_device.clone(), node_disk_pos)
-        };
-
-        // depth 2 (leaf nodes)
-        // +--------+...+--------+  +--------+...+--------+  ......
-        // | ext1   |...| extn   |  | ext1   |...| extn   |  ......
-        // +--------+...+--------+  +--------+...+--------+  ......
-        //     ^
-        //     Current start extent
-
-        // start from pos
-        for i in pos..entry_count as usize {
-            let ex: &mut Ext4Extent = ext4block
-                .read_offset_as_mut(size_of::<Ext4ExtentHeader>() + i * size_of::<Ext4Extent>());
-
-            if ex.first_block > to {
-                break;
+        pblock_of_node: usize,
+        start: Ext4Lblk,
+        shift: &dyn Fn(Ext4Lblk) -> Ext4Lblk,
+    ) -> Result<Option<Ext4Lblk>> {
+        let mut node = self.ext_node_load(inode_ref, pblock_of_node)?;
+        let header: Ext4ExtentHeader = node.read_offset_as(0);
+        let count = header.entries_count as usize;
+        let mut changed = false;
+
+        for pos in 0..count {
+            if header.is_leaf() {
+                let ex: &mut Ext4Extent = node.read_offset_as_mut(ext_entry_offset(pos));
+                if ex.first_block >= start {
+                    ex.first_block = shift(ex.first_block);
+                    changed = true;
+                }
+                continue;
             }
 
-            let mut new_len = 0;
-            let mut start = ex.first_block;
-            let mut new_start = ex.first_block;
-
-            let mut len = ex.get_actual_len();
-            let mut newblock = ex.get_pblock();
-
-            // Initial state:
-            // +--------+...+--------+  +--------+...+--------+  ......
-            // | ext1   |...| ext2   |  | ext3   |...| extn   |  ......
-            // +--------+...+--------+  +--------+...+--------+  ......
-            //               ^                    ^
-            //              from                  to
-
-            // Case 1: Remove a portion within the extent
-            if start < from {
-                len -= from as u16 - start as u16;
-                new_len = from - start;
-                start = from;
-            } else {
-                // Case 2: Adjust extent that partially overlaps the 'to' boundary
-                if start + len as u32 - 1 > to {
-                    new_len = start + len as u32 - 1 - to;
-                    len -= new_len as u16;
-                    new_start = to + 1;
-                    newblock += (to + 1 - start) as u64;
-                    ex2 = *ex;
+            // the child only maps blocks before its right neighbour's key
+            if pos + 1 < count {
+                let next: Ext4ExtentIndex = node.read_offset_as(ext_entry_offset(pos + 1));
+                if next.first_block <= start {
+                    continue;
+                }
+            }
+            let index: Ext4ExtentIndex = node.read_offset_as(ext_entry_offset(pos));
+            if let Some(first) = self.ext_shift_node(inode_ref, index.get_pblock() as usize, start, shift)? {
+                if first != index.first_block {
+                    node.read_offset_as_mut::<Ext4ExtentIndex>(ext_entry_offset(pos)).first_block = first;
+                    changed = true;
                 }
             }
+        }
 
-            // After removing range from `from` to `to`:
-            // +--------+...+--------+  +--------+...+--------+  ......
-            // | ext1   |...[removed]|  |[removed]|...| extn   |  ......
-            // +--------+...+--------+  +--------+...+--------+  ......
-            //               ^                    ^
-            //              from                  to
-            //                                  new_start
+        if changed {
+            self.ext_node_store(inode_ref, pblock_of_node, &mut node)?;
+        }
+        if count == 0 {
+            return Ok(None);
+        }
+        // extents and indexes both start with their first block
+        Ok(Some(node.read_offset_as::<Ext4Extent>(ext_entry_offset(0)).first_block))
+    }
 
-            // Remove blocks within the extent
-            self.ext_remove_blocks(inode_ref, ex, start, start + len as u32 - 1);
+    /// Remove the extent starting at `first_block` from its leaf. A node
+    /// losing its last entry is freed and removed from its parent in turn.
+    fn ext_remove_entry(&self, inode_ref: &mut Ext4InodeRef, first_block: Ext4Lblk) -> Result<()> {
+        let search_path = self.find_extent(inode_ref, first_block)?;
 
-            ex.first_block = new_start;
-            // log::trace!("after remove leaf ex first_block {:x?}", ex.first_block);
+        for level in (0..=search_path.depth as usize).rev() {
+            let path_node = &search_path.path[level];
+            let pos = path_node.position;
+            let count = path_node.header.entries_count as usize;
 
-            if new_len == 0 {
-                new_entry_count -= 1;
-            } else {
-                let unwritten = ex.is_unwritten();
-                ex.store_pblock(newblock as u64);
-                ex.block_count = new_len as u16;
+            if count == 1 && level > 0 {
+                self.balloc_free_blocks(inode_ref, path_node.pblock_of_node as Ext4Fsblk, 1)?;
+                continue;
+            }
 
-                if unwritten {
-                    ex.mark_unwritten();
-                }
+            let mut node = self.ext_node_load(inode_ref, path_node.pblock_of_node)?;
+            node.data.copy_within(ext_entry_offset(pos + 1)..ext_entry_offset(count), ext_entry_offset(pos));
+            let header: &mut Ext4ExtentHeader = node.read_offset_as_mut(0);
+            header.entries_count -= 1;
+            if header.entries_count == 0 {
+                // an empty tree is an empty leaf in the inode
+                header.depth = 0;
             }
-        }
+            self.ext_node_store(inode_ref, path_node.pblock_of_node, &mut node)?;
 
-        // Move remaining extents to the start:
-        // Before:
-        // +--------+--------+...+--------+
-        // | ext3   | ext4   |...| extn   |
-        // +--------+--------+...+--------+
-        //      ^       ^
-        //      rm      rm
-        // After:
-        // +--------+.+--------+--------+...
-        // | ext1   |.| extn   | [empty]|...
-        // +--------+.+--------+--------+...
-
-        // Move any remaining extents to the starting position of the node.
-        if ex2.first_block > 0 {
-            let start_index = size_of::<Ext4ExtentHeader>() + pos * size_of::<Ext4Extent>();
-            let end_index =
-                size_of::<Ext4ExtentHeader>() + entry_count as usize * size_of::<Ext4Extent>();
-            let remaining_extents: Vec<u8> = ext4block.data[start_index..end_index].to_vec();
-            ext4block.data[size_of::<Ext4ExtentHeader>()
-                ..size_of::<Ext4ExtentHeader>() + remaining_extents.len()]
-                .copy_from_slice(&remaining_extents);
-        }
-
-        // Update the entries count in the header
-        header.entries_count = new_entry_count;
-
-        /*
-         * If the extent pointer is pointed to the first extent of the node, and
-         * there's still extents presenting, we may need to correct the indexes
-         * of the paths.
-         */
-        if pos == 0 && new_entry_count > 0 {
-            self.ext_correct_indexes(path)?;
-        }
-
-        /* if this leaf is free, then we should
-         * remove it from index block above */
-        if new_entry_count == 0 {
-            // if we are at root?
-            if path.path[depth as usize].pblock_of_node == 0 {
-                return Ok(EOK);
+            if pos == 0 && count > 1 {
+                // extents and indexes both start with their first block
+                let first = node.read_offset_as::<Ext4Extent>(ext_entry_offset(0)).first_block;
+                self.ext_update_keys(inode_ref, &search_path, level, first)?;
             }
-            self.ext_remove_idx(inode_ref, path, depth - 1)?;
-        } else if depth > 0 {
-            // go to next index
-            path.path[depth as usize - 1].position += 1;
+            break;
         }
 
-        Ok(EOK)
+        Ok(())
     }
+}
 
-    fn ext_remove_index_block(&self, inode_ref: &mut Ext4InodeRef, index: &mut Ext4ExtentIndex) {
-        let block_to_free = index.get_pblock();
+/// Offset of the entry `pos` in an extent tree node, extents and indexes
+/// have the same size.
+fn ext_entry_offset(pos: usize) -> usize {
+    size_of::<Ext4ExtentHeader>() + pos * size_of::<Ext4Extent>()
+}
 
-        // log::trace!("remove index's block {:x?}", block_to_free);
-        self.balloc_free_blocks(inode_ref, block_to_free as _, 1);
-    }
+/// Shift the entries of a node from `pos` on to the right, for a new entry at `pos`.
+fn ext_entry_make_room(node: &mut Block, pos: usize) {
+    let header: &mut Ext4ExtentHeader = node.read_offset_as_mut(0);
+    let count = header.entries_count as usize;
+    header.entries_count += 1;
+    node.data.copy_within(ext_entry_offset(pos)..ext_entry_offset(count), ext_entry_offset(pos + 1));
+}
 
-    fn ext_remove_idx(
-        &self,
-        inode_ref: &mut Ext4InodeRef,
-        path: &mut SearchPath,
-        depth: u16,
-    ) -> Result<usize> {
-        // log::trace!("Remove index at depth {:x?}", depth);
-
-        // Initial state:
-        // +--------+--------+--------+
-        // |  idx1  |  idx2  |  idx3  |
-        // +--------+--------+--------+
-        //           ^
-        // Current index to remove (pos=1)
-
-        // Removing index:
-        // +--------+--------+--------+
-        // |  idx1  |[empty] |  idx3  |
-        // +--------+--------+--------+
-        //           ^
-        // Current index to remove
-
-        // After moving remaining indexes:
-        // +--------+--------+--------+
-        // |  idx1  |  idx3  |[empty] |
-        // +--------+--------+--------+
-        //           ^
-        // Current index to remove
-
-        let mut i = depth as usize;
-        let mut header = path.path[i].header;
-
-        // 获取要删除的索引块
-        let leaf_block = path.path[i].index.unwrap().get_pblock();
-
-        // 如果当前索引不是最后一个索引，将后续的索引前移
-        if path.path[i].position != header.entries_count as usize - 1 {
-            let start_pos = size_of::<Ext4ExtentHeader>()
-                + path.path[i].position * size_of::<Ext4ExtentIndex>();
-            let end_pos = size_of::<Ext4ExtentHeader>()
-                + (header.entries_count as usize) * size_of::<Ext4ExtentIndex>();
-
-            let node_disk_pos = path.path[i].pblock_of_node * BLOCK_SIZE;
-            let mut ext4block = Block::load(self.block_device.clone(), node_disk_pos);
-
-            let remaining_indexes: Vec<u8> =
-                ext4block.data[start_pos + size_of::<Ext4ExtentIndex>()..end_pos].to_vec();
-            ext4block.data[start_pos..start_pos + remaining_indexes.len()]
-                .copy_from_slice(&remaining_indexes);
-            let remaining_size = remaining_indexes.len();
-
-            // 清空剩余位置
-            let empty_start = start_pos + remaining_size;
-            let empty_end = end_pos;
-            ext4block.data[empty_start..empty_end].fill(0);
-        }
-
-        // 更新头部的entries_count
-        header.entries_count -= 1;
-
-        // 释放索引块
-        self.ext_remove_index_block(inode_ref, &mut path.path[i].index.unwrap());
-
-        // Updating parent index if necessary:
-        // +--------+--------+--------+
-        // |  idx1  |  idx3  |[empty] |
-        // +--------+--------+--------+
-        //           ^
-        // Updated parent index if necessary
-
-        // 如果当前层不是根，需要检查是否需要更新父节点索引
-        while i > 0 {
-            if path.path[i].position != 0 {
-                break;
-            }
+/// Grow an extent by `len` blocks, keeping its unwritten flag.
+fn ext_extend(ex: &mut Ext4Extent, len: u16) {
+    let unwritten = ex.is_unwritten();
+    ex.set_actual_len(ex.get_actual_len() + len);
+    if unwritten {
+        ex.mark_unwritten();
+    }
+}
 
-            let parent_idx = i - 1;
-            let parent_index = &mut path.path[parent_idx].index.unwrap();
-            let current_index = &path.path[i].index.unwrap();
+/// The blocks of `ex` from `first` to `last` included, as an extent with
+/// the same unwritten flag.
+fn ext_slice(ex: &Ext4Extent, first: Ext4Lblk, last: Ext4Lblk) -> Ext4Extent {
+    let mut slice = Ext4Extent::new(first, ex.pblock_of(first), (last - first + 1) as u16);
+    if ex.is_unwritten() {
+        slice.mark_unwritten();
+    }
+    slice
+}
 
-            parent_index.first_block = current_index.first_block;
-            self.write_back_inode(inode_ref);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ext4_impls::test_utils::*;
+
+    #[test]
+    fn test_extent_block_checksum() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+        assert!(ext4.super_block.has_metadata_csum());
+        let block_size = ext4.super_block.block_size() as usize;
+        let (inode, data) = create_fragmented_file(&ext4, "file", 200);
+        // the last leaf is not full
+        let last = data.len() - block_size;
+        let leaf = ext4.fiemap(inode, last as u64, 1, FIEMAP_FLAG_METADATA).unwrap()[0];
+        assert_eq!(leaf.flags, FIEMAP_EXTENT_METADATA);
+        drop(ext4);
+
+        // a byte of an unused slot of the leaf
+        let offset = leaf.physical as usize + block_size - 8;
+        let byte = disk.bytes(offset, 1)[0];
+        disk.write_offset(offset, &[!byte]).unwrap();
+
+        let ext4 = mount(&disk);
+        let mut buf = vec![0u8; block_size];
+        let err = ext4.read_at(inode, last, &mut buf).unwrap_err();
+        assert_eq!(err.error(), Errno::EIO);
+        drop(ext4);
+
+        let options = Ext4MountOptions { checksum_policy: Ext4ChecksumPolicy::Warn, ..Default::default() };
+        let ext4 = Ext4::open_with_options(disk.clone(), options).unwrap();
+        assert_eq!(read_file(&ext4, inode), data);
+    }
 
-            i -= 1;
+    #[test]
+    fn test_remove_space_splits_extent() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+        let block_size = ext4.super_block.block_size() as usize;
+        let inode = create_file(&ext4, "file");
+        let mut data = pattern(20 * block_size, 0);
+        ext4.write_at(inode, 0, &data).unwrap();
+
+        let extents = file_extents(&ext4, inode);
+        assert_eq!(extents.len(), 1);
+        let ex = extents[0];
+        let free = free_blocks(&ext4);
+
+        let mut inode_ref = ext4.get_inode_ref(inode).unwrap();
+        ext4.extent_remove_space(&mut inode_ref, 5, 9).unwrap();
+
+        let extents = file_extents(&ext4, inode);
+        assert_eq!(extents, [ext_slice(&ex, 0, 4), ext_slice(&ex, 10, 19)]);
+        for lblock in 0..20 {
+            assert_eq!(block_in_use(&ext4, ex.pblock_of(lblock)), !(5..=9).contains(&lblock));
         }
+        assert_eq!(free_blocks(&ext4), free + 5);
 
-        Ok(EOK)
+        data[5 * block_size..10 * block_size].fill(0);
+        assert_eq!(read_file(&ext4, inode), data);
     }
 
-    /// Correct the first block of the parent index.
-    fn ext_correct_indexes(&self, path: &mut SearchPath) -> Result<usize> {
-        // if child get removed from parent, we need to update the parent's first_block
-        let mut depth = path.depth as usize;
-
-        // depth 2:
-        // +--------+--------+--------+
-        // |[empty] |  ext2  |  ext3  |
-        // +--------+--------+--------+
-        // ^
-        // pos=0, ext1_first_block=0(removed) parent index first block=0
-
-        // depth 2:
-        // +--------+--------+--------+
-        // |  ext2  |  ext3  |[empty] |
-        // +--------+--------+--------+
-        // ^
-        // pos=0, now first_block=ext2_first_block
-
-        // 更新父节点索引：
-        // depth 1:
-        // +-----------------------+
-        // | idx1_2 |...| idx1_n   |
-        // +-----------------------+
-        //     ^
-        //     更新父节点索引(first_block)
-
-        // depth 0:
-        // +--------+--------+--------+
-        // |  idx1  |  idx2  |  idx3  |
-        // +--------+--------+--------+
-        //     |
-        //     更新根节点索引(first_block)
+    #[test]
+    fn test_remove_space_frees_leaf() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+        let block_size = ext4.super_block.block_size() as usize;
+        let (inode, mut data) = create_fragmented_file(&ext4, "file", 200);
+
+        let inode_ref = ext4.get_inode_ref(inode).unwrap();
+        let before = file_extents(&ext4, inode);
+        assert_eq!(before.len(), 200);
+        let first_leaf = ext4.find_extent(&inode_ref, 0).unwrap();
+        assert_eq!(first_leaf.depth, 1);
+        let leaf = first_leaf.path[1].pblock_of_node as Ext4Fsblk;
+        let leaf_count = first_leaf.path[1].header.entries_count as usize;
+        let second_leaf = ext4.find_extent(&inode_ref, before[leaf_count].first_block).unwrap();
+        assert_ne!(second_leaf.path[1].pblock_of_node as Ext4Fsblk, leaf);
+
+        // from the start of the file to past the first leaf
+        let to = before[leaf_count + 1].get_last_block();
+        let mut inode_ref = ext4.get_inode_ref(inode).unwrap();
+        ext4.extent_remove_space(&mut inode_ref, 0, to).unwrap();
+
+        assert!(!block_in_use(&ext4, leaf));
+        for ex in before[..leaf_count + 2].iter() {
+            assert!(!block_in_use(&ext4, ex.get_pblock()));
+        }
+        assert_eq!(file_extents(&ext4, inode), before[leaf_count + 2..]);
+        let inode_ref = ext4.get_inode_ref(inode).unwrap();
+        let path = ext4.find_extent(&inode_ref, to + 1).unwrap();
+        assert_eq!(path.path[0].index.unwrap().first_block, before[leaf_count + 2].first_block);
 
-        while depth > 0 {
-            let parent_idx = depth - 1;
-
-            // 获取当前层的 extent
-            if let Some(child_extent) = path.path[depth].extent {
-                // 获取父节点
-                let parent_node = &mut path.path[parent_idx];
-                // 获取父节点的索引，并更新 first_block
-                if let Some(ref mut parent_index) = parent_node.index {
-                    parent_index.first_block = child_extent.first_block;
-                }
-            }
+        data[..(to as usize + 1) * block_size].fill(0);
+        assert_eq!(read_file(&ext4, inode), data);
+    }
 
-            depth -= 1;
+    #[test]
+    fn test_collapse_range_across_leaves() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+        let block_size = ext4.super_block.block_size() as usize;
+        let (inode, mut data) = create_fragmented_file(&ext4, "file", 200);
+
+        let inode_ref = ext4.get_inode_ref(inode).unwrap();
+        let before = file_extents(&ext4, inode);
+        let leaf_count = ext4.find_extent(&inode_ref, 0).unwrap().path[1].header.entries_count as usize;
+
+        // the last extent of the first leaf and the first one of the second leaf go
+        let from = before[leaf_count - 1].first_block;
+        let mut inode_ref = ext4.get_inode_ref(inode).unwrap();
+        ext4.extent_collapse_range(&mut inode_ref, from, 4).unwrap();
+
+        let mut expected = before[..leaf_count - 1].to_vec();
+        for ex in before[leaf_count + 1..].iter() {
+            let mut ex = *ex;
+            ex.first_block -= 4;
+            expected.push(ex);
+        }
+        assert_eq!(file_extents(&ext4, inode), expected);
+        assert!(!block_in_use(&ext4, before[leaf_count - 1].get_pblock()));
+        assert!(!block_in_use(&ext4, before[leaf_count].get_pblock()));
+
+        // every index key is the first block of its subtree
+        let inode_ref = ext4.get_inode_ref(inode).unwrap();
+        for ex in expected.iter() {
+            let path = ext4.find_extent(&inode_ref, ex.first_block).unwrap();
+            assert_eq!(path.path[1].extent.unwrap(), *ex);
         }
 
-        Ok(EOK)
+        let from = from as usize * block_size;
+        data.drain(from..from + 4 * block_size);
+        let mut inode_ref = ext4.get_inode_ref(inode).unwrap();
+        inode_ref.inode.set_size(data.len() as u64);
+        ext4.write_back_inode(&mut inode_ref).unwrap();
+        assert_eq!(read_file(&ext4, inode), data);
     }
 
-    fn ext_remove_blocks(
-        &self,
-        inode_ref: &mut Ext4InodeRef,
-        ex: &mut Ext4Extent,
-        from: u32,
-        to: u32,
-    ) {
-        let len = to - from + 1;
-        let num = from - ex.first_block;
-        let start: u32 = ex.get_pblock() as u32 + num;
-        self.balloc_free_blocks(inode_ref, start as _, len);
+    #[test]
+    fn test_insert_range_splits_extent() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+        let inode = create_file(&ext4, "file");
+        let block_size = ext4.super_block.block_size() as usize;
+        ext4.write_at(inode, 0, &pattern(20 * block_size, 0)).unwrap();
+        let ex = file_extents(&ext4, inode)[0];
+
+        let mut inode_ref = ext4.get_inode_ref(inode).unwrap();
+        ext4.extent_insert_range(&mut inode_ref, 5, 3).unwrap();
+
+        let mut tail = ext_slice(&ex, 5, 19);
+        tail.first_block = 8;
+        assert_eq!(file_extents(&ext4, inode), [ext_slice(&ex, 0, 4), tail]);
     }
 
-    pub fn more_to_rm(&self, path: &ExtentPathNode, to: u32) -> bool {
-        let header = path.header;
+    #[test]
+    fn test_insert_range_across_leaves() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+        let (inode, _) = create_fragmented_file(&ext4, "file", 200);
 
-        // No Sibling exists
-        if header.entries_count == 1 {
-            return false;
-        }
+        let inode_ref = ext4.get_inode_ref(inode).unwrap();
+        let before = file_extents(&ext4, inode);
+        let leaf_count = ext4.find_extent(&inode_ref, 0).unwrap().path[1].header.entries_count as usize;
 
-        let pos = path.position;
-        if pos > header.entries_count as usize - 1 {
-            return false;
-        }
-
-        // Check if index is out of bounds
-        if let Some(index) = path.index {
-            let last_index_pos = header.entries_count as usize - 1;
-            let node_disk_pos = path.pblock_of_node * BLOCK_SIZE;
-            let ext4block = Block::load(self.block_device.clone(), node_disk_pos);
-            let last_index: Ext4ExtentIndex =
-                ext4block.read_offset_as(size_of::<Ext4ExtentIndex>() * last_index_pos);
-
-            if path.position > last_index_pos || index.first_block > last_index.first_block {
-                return false;
-            }
+        // in the hole before the first extent of the second leaf
+        let from = before[leaf_count].first_block - 1;
+        let mut inode_ref = ext4.get_inode_ref(inode).unwrap();
+        ext4.extent_insert_range(&mut inode_ref, from, 5).unwrap();
 
-            // Check if index's first_block is greater than 'to'
-            if index.first_block > to {
-                return false;
-            }
+        let mut expected = before.clone();
+        for ex in expected[leaf_count..].iter_mut() {
+            ex.first_block += 5;
         }
+        assert_eq!(file_extents(&ext4, inode), expected);
+
+        let inode_ref = ext4.get_inode_ref(inode).unwrap();
+        let path = ext4.find_extent(&inode_ref, expected[leaf_count].first_block).unwrap();
+        assert_eq!(path.path[0].index.unwrap().first_block, expected[leaf_count].first_block);
+        assert_eq!(path.path[1].extent.unwrap(), expected[leaf_count]);
+    }
 
-        true
+    #[test]
+    fn test_insert_range_past_max_blocks() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+        let block_size = ext4.super_block.block_size() as u64;
+        let inode = create_file(&ext4, "file");
+        ext4.write_at(inode, 0, &pattern(4 * block_size as usize, 0)).unwrap();
+        // a block preallocated at the very end, past the end of file
+        let last = (EXT_MAX_BLOCKS - 1) as u64 * block_size;
+        ext4.fallocate(inode, FALLOC_FL_KEEP_SIZE, last, block_size).unwrap();
+        let before = file_extents(&ext4, inode);
+
+        let mut inode_ref = ext4.get_inode_ref(inode).unwrap();
+        let r = ext4.extent_insert_range(&mut inode_ref, 1, 1);
+        assert_eq!(r.unwrap_err().error(), Errno::EFBIG);
+        assert_eq!(file_extents(&ext4, inode), before);
+
+        let r = ext4.fallocate(inode, FALLOC_FL_INSERT_RANGE, 0, block_size);
+        assert_eq!(r.unwrap_err().error(), Errno::EFBIG);
+        assert_eq!(file_extents(&ext4, inode), before);
     }
 }
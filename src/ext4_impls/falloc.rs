@@ -0,0 +1,474 @@
+use crate::prelude::*;
+use crate::return_errno_with_message;
+
+use crate::ext4_defs::*;
+
+impl Ext4 {
+    /// Manipulate the allocated space of a file, like the fallocate syscall.
+    ///
+    /// Without flags the range is preallocated: the holes in it get blocks
+    /// mapped by unwritten extents, which read as zeros until they are
+    /// written, and the file grows to the end of the range.
+    ///
+    /// `FALLOC_FL_PUNCH_HOLE` frees the blocks inside the range and zeroes
+    /// the bytes of the range in the blocks at its edges. It must come with
+    /// `FALLOC_FL_KEEP_SIZE`.
+    ///
+    /// `FALLOC_FL_ZERO_RANGE` makes the range read as zeros: the blocks
+    /// inside it are marked unwritten, holes are preallocated and the edges
+    /// are zeroed.
+    ///
+    /// `FALLOC_FL_COLLAPSE_RANGE` removes the range from the file and
+    /// `FALLOC_FL_INSERT_RANGE` inserts a hole there, moving the rest of the
+    /// file without copying it. The range must be block aligned and the flag
+    /// used alone.
+    ///
+    /// Params:
+    /// inode: u32 - inode number of the file
+    /// mode: u32 - 0, `FALLOC_FL_PUNCH_HOLE` or `FALLOC_FL_ZERO_RANGE`, with
+    /// `FALLOC_FL_KEEP_SIZE` to leave the file size as is, or
+    /// `FALLOC_FL_COLLAPSE_RANGE` or `FALLOC_FL_INSERT_RANGE`
+    /// offset: u64 - start of the range
+    /// len: u64 - length of the range
+    ///
+    /// Returns:
+    /// `Result<usize>` - status of the operation
+    pub fn fallocate(&self, inode: u32, mode: u32, offset: u64, len: u64) -> Result<usize> {
+        self.check_writable()?;
+
+        let op = mode & !FALLOC_FL_KEEP_SIZE;
+        if ![0, FALLOC_FL_PUNCH_HOLE, FALLOC_FL_ZERO_RANGE, FALLOC_FL_COLLAPSE_RANGE, FALLOC_FL_INSERT_RANGE]
+            .contains(&op)
+        {
+            return_errno_with_message!(Errno::ENOTSUP, "unsupported fallocate mode");
+        }
+        if op == FALLOC_FL_PUNCH_HOLE && mode & FALLOC_FL_KEEP_SIZE == 0 {
+            return_errno_with_message!(Errno::ENOTSUP, "punch hole without FALLOC_FL_KEEP_SIZE");
+        }
+        let shifts = op == FALLOC_FL_COLLAPSE_RANGE || op == FALLOC_FL_INSERT_RANGE;
+        if shifts && mode != op {
+            return_errno_with_message!(Errno::EINVAL, "collapse or insert range with other flags");
+        }
+        if len == 0 {
+            return_errno_with_message!(Errno::EINVAL, "empty fallocate range");
+        }
+
+        let block_size = self.super_block.block_size() as u64;
+        if shifts && (offset % block_size != 0 || len % block_size != 0) {
+            return_errno_with_message!(Errno::EINVAL, "collapse or insert range not block aligned");
+        }
+        let end = match offset.checked_add(len) {
+            Some(end) if end <= EXT_MAX_BLOCKS as u64 * block_size => end,
+            _ => return_errno_with_message!(Errno::EFBIG, "fallocate range past the maximum file size"),
+        };
+
+        self.journal_transaction(|| {
+            let mut inode_ref = self.get_inode_ref(inode)?;
+            if inode_ref.inode.is_dir() {
+                return_errno_with_message!(Errno::EISDIR, "fallocate on a directory");
+            }
+            if !inode_ref.inode.is_file() {
+                return_errno_with_message!(Errno::ENODEV, "fallocate on a special file");
+            }
+            if !inode_ref.inode.has_extents() {
+                return_errno_with_message!(Errno::ENOTSUP, "fallocate needs an extent mapped file");
+            }
+
+            match op {
+                FALLOC_FL_PUNCH_HOLE => self.falloc_punch_hole(&mut inode_ref, offset, end)?,
+                FALLOC_FL_ZERO_RANGE => self.falloc_zero_range(&mut inode_ref, offset, end)?,
+                FALLOC_FL_COLLAPSE_RANGE => {
+                    let size = inode_ref.inode.size();
+                    if end >= size {
+                        return_errno_with_message!(Errno::EINVAL, "collapse range reaches the end of file");
+                    }
+                    let (from, count) = ((offset / block_size) as Ext4Lblk, (len / block_size) as u32);
+                    self.extent_collapse_range(&mut inode_ref, from, count)?;
+                    inode_ref.inode.set_size(size - len);
+                    self.write_back_inode(&mut inode_ref)?;
+                }
+                FALLOC_FL_INSERT_RANGE => {
+                    let size = inode_ref.inode.size();
+                    if offset >= size {
+                        return_errno_with_message!(Errno::EINVAL, "insert range past the end of file");
+                    }
+                    if size + len > EXT_MAX_BLOCKS as u64 * block_size {
+                        return_errno_with_message!(Errno::EFBIG, "insert range past the maximum file size");
+                    }
+                    let (from, count) = ((offset / block_size) as Ext4Lblk, (len / block_size) as u32);
+                    self.extent_insert_range(&mut inode_ref, from, count)?;
+                    inode_ref.inode.set_size(size + len);
+                    self.write_back_inode(&mut inode_ref)?;
+                }
+                _ => {
+                    let from = (offset / block_size) as Ext4Lblk;
+                    let to = end.div_ceil(block_size) as Ext4Lblk;
+                    self.falloc_prealloc(&mut inode_ref, from, to)?;
+                }
+            }
+
+            if !shifts && mode & FALLOC_FL_KEEP_SIZE == 0 && end > inode_ref.inode.size() {
+                inode_ref.inode.set_size(end);
+                self.write_back_inode(&mut inode_ref)?;
+            }
+
+            Ok(EOK)
+        })
+    }
+
+    /// Map the holes between the logical blocks `from` and `to` with new
+    /// unwritten extents, leaving the mapped blocks as they are.
+    fn falloc_prealloc(&self, inode_ref: &mut Ext4InodeRef, from: Ext4Lblk, to: Ext4Lblk) -> Result<()> {
+        let max_len = (EXT_UNWRITTEN_MAX_LEN - EXT_INIT_MAX_LEN) as u32;
+        let mut goal = None;
+        let mut lblock = from;
+
+        while lblock < to {
+            let hole_end = match self.extent_find_next(inode_ref, lblock)? {
+                Some(ex) if ex.contains(lblock) => {
+                    let last = ex.get_last_block();
+                    goal = Some(ex.pblock_of(last) + 1);
+                    lblock = last + 1;
+                    continue;
+                }
+                Some(ex) => min(ex.first_block, to),
+                None => to,
+            };
+
+            while lblock < hole_end {
+                let (pblock, count) =
+                    self.balloc_alloc_blocks(inode_ref, goal, min(hole_end - lblock, max_len))?;
+                let mut ex = Ext4Extent::new(lblock, pblock, count as u16);
+                ex.mark_unwritten();
+                self.insert_extent(inode_ref, &mut ex)?;
+
+                lblock += count;
+                goal = Some(pblock + count as Ext4Fsblk);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Free the blocks inside the byte range from `offset` to `end` and
+    /// zero the rest of the range. Nothing past the end of the file is
+    /// punched, but the block holding its end is freed as a whole.
+    fn falloc_punch_hole(&self, inode_ref: &mut Ext4InodeRef, offset: u64, end: u64) -> Result<()> {
+        let block_size = self.super_block.block_size() as u64;
+        let size = inode_ref.inode.size();
+        if offset >= size {
+            return Ok(());
+        }
+        let end = if end >= size { size.div_ceil(block_size) * block_size } else { end };
+
+        let from = offset.div_ceil(block_size);
+        let to = end / block_size;
+        if from > to {
+            return self.falloc_zero_partial(inode_ref, offset, end);
+        }
+
+        self.falloc_zero_partial(inode_ref, offset, from * block_size)?;
+        self.falloc_zero_partial(inode_ref, to * block_size, end)?;
+        if from < to {
+            self.extent_remove_space(inode_ref, from as Ext4Lblk, to as Ext4Lblk - 1)?;
+        }
+        Ok(())
+    }
+
+    /// Make the byte range from `offset` to `end` read as zeros: the
+    /// written blocks inside it become unwritten, the holes are
+    /// preallocated and the rest of the range is zeroed.
+    fn falloc_zero_range(&self, inode_ref: &mut Ext4InodeRef, offset: u64, end: u64) -> Result<()> {
+        let block_size = self.super_block.block_size() as u64;
+        let from = offset.div_ceil(block_size);
+        let to = end / block_size;
+        if from > to {
+            return self.falloc_zero_partial(inode_ref, offset, end);
+        }
+
+        self.falloc_zero_partial(inode_ref, offset, from * block_size)?;
+        self.falloc_zero_partial(inode_ref, to * block_size, end)?;
+
+        let (from, to) = (from as Ext4Lblk, to as Ext4Lblk);
+        let max_len = (EXT_UNWRITTEN_MAX_LEN - EXT_INIT_MAX_LEN) as u32;
+        let mut lblock = from;
+        while lblock < to {
+            let ex = match self.extent_find_next(inode_ref, lblock)? {
+                Some(ex) if ex.first_block < to => ex,
+                _ => break,
+            };
+            let start = ex.first_block.max(lblock);
+            let count = min(ex.get_last_block() - start + 1, to - start);
+            if ex.is_unwritten() {
+                lblock = start + count;
+                continue;
+            }
+
+            let count = min(count, max_len);
+            self.extent_mark_unwritten(inode_ref, &ex, start, count)?;
+            lblock = start + count;
+        }
+
+        self.falloc_prealloc(inode_ref, from, to)
+    }
+
+    /// Zero the bytes from `offset` to `end`, both within one block of the
+    /// file. Holes and unwritten blocks already read as zeros and are left
+    /// alone.
+    fn falloc_zero_partial(&self, inode_ref: &Ext4InodeRef, offset: u64, end: u64) -> Result<()> {
+        if offset >= end {
+            return Ok(());
+        }
+
+        let block_size = self.super_block.block_size() as u64;
+        let lblock = (offset / block_size) as Ext4Lblk;
+        let pblock = match self.extent_at(inode_ref, lblock)? {
+            Some(ex) if !ex.is_unwritten() => ex.pblock_of(lblock),
+            _ => return Ok(()),
+        };
+
+        let disk_offset = (pblock * block_size) as usize;
+        let mut block = Block::load(self.block_device.clone(), disk_offset, block_size as usize)?;
+        let start = (offset % block_size) as usize;
+        block.data[start..start + (end - offset) as usize].fill(0);
+        block.sync_blk_to_disk(self.block_device.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ext4_impls::test_utils::*;
+
+    #[test]
+    fn test_punch_hole() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+        let block_size = ext4.super_block.block_size() as u64;
+        let inode = create_file(&ext4, "file");
+        let mut data = pattern(10 * block_size as usize, 0);
+        ext4.write_at(inode, 0, &data).unwrap();
+        let ex = file_extents(&ext4, inode)[0];
+        let slice = |first, last| Ext4Extent::new(first, ex.pblock_of(first), (last - first + 1) as u16);
+        let free = free_blocks(&ext4);
+
+        // blocks 3 to 5 are freed, the partial blocks around them are zeroed
+        let (offset, len) = (2 * block_size + 100, 4 * block_size);
+        ext4.fallocate(inode, FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE, offset, len).unwrap();
+
+        assert_eq!(file_extents(&ext4, inode), [slice(0, 2), slice(6, 9)]);
+        assert_eq!(free_blocks(&ext4), free + 3);
+        data[offset as usize..(offset + len) as usize].fill(0);
+        assert_eq!(read_file(&ext4, inode), data);
+    }
+
+    #[test]
+    fn test_zero_range_written() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+        let block_size = ext4.super_block.block_size() as u64;
+        let inode = create_file(&ext4, "file");
+        let mut data = pattern(10 * block_size as usize, 0);
+        ext4.write_at(inode, 0, &data).unwrap();
+        let ex = file_extents(&ext4, inode)[0];
+        let slice = |first, last| Ext4Extent::new(first, ex.pblock_of(first), (last - first + 1) as u16);
+        let free = free_blocks(&ext4);
+
+        let (offset, len) = (2 * block_size + 100, 4 * block_size);
+        ext4.fallocate(inode, FALLOC_FL_ZERO_RANGE, offset, len).unwrap();
+
+        // the whole blocks in the range become unwritten, nothing is freed
+        let mut unwritten = slice(3, 5);
+        unwritten.mark_unwritten();
+        assert_eq!(file_extents(&ext4, inode), [slice(0, 2), unwritten, slice(6, 9)]);
+        assert_eq!(free_blocks(&ext4), free);
+        data[offset as usize..(offset + len) as usize].fill(0);
+        assert_eq!(read_file(&ext4, inode), data);
+    }
+
+    #[test]
+    fn test_zero_range_unwritten() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+        let block_size = ext4.super_block.block_size() as u64;
+        let inode = create_file(&ext4, "file");
+        ext4.fallocate(inode, 0, 0, 10 * block_size).unwrap();
+        let before = file_extents(&ext4, inode);
+        assert_eq!(before.len(), 1);
+        assert!(before[0].is_unwritten());
+
+        // unwritten blocks already read as zeros, the range past the end is preallocated
+        ext4.fallocate(inode, FALLOC_FL_ZERO_RANGE, 3 * block_size, 12 * block_size).unwrap();
+
+        // the first blocks keep their mapping
+        let after = file_extents(&ext4, inode);
+        assert_eq!(after[0].get_pblock(), before[0].get_pblock());
+        assert!(after[0].get_actual_len() >= 10);
+        assert!(after.iter().all(|ex| ex.is_unwritten()));
+        assert_eq!(after.last().unwrap().get_last_block(), 14);
+        assert_eq!(ext4.get_inode_ref(inode).unwrap().inode.size(), 15 * block_size);
+        assert_eq!(read_file(&ext4, inode), vec![0u8; 15 * block_size as usize]);
+    }
+
+    /// Logical block, physical block and unwritten flag of every mapped
+    /// block of a file.
+    fn block_map(ext4: &Ext4, inode: u32) -> Vec<(Ext4Lblk, Ext4Fsblk, bool)> {
+        file_extents(ext4, inode)
+            .iter()
+            .flat_map(|ex| {
+                (ex.first_block..=ex.get_last_block())
+                    .map(|lblock| (lblock, ex.pblock_of(lblock), ex.is_unwritten()))
+            })
+            .collect()
+    }
+
+    /// Where the blocks of a `block_map` are, whether written or not.
+    fn pblocks(map: &[(Ext4Lblk, Ext4Fsblk, bool)]) -> Vec<(Ext4Lblk, Ext4Fsblk)> {
+        map.iter().map(|&(lblock, pblock, _)| (lblock, pblock)).collect()
+    }
+
+    #[test]
+    fn test_prealloc() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+        let block_size = ext4.super_block.block_size() as u64;
+        let inode = create_file(&ext4, "file");
+        let free = free_blocks(&ext4);
+
+        // the range rounds out to whole blocks 1 to 9
+        ext4.fallocate(inode, 0, block_size + 100, 8 * block_size).unwrap();
+
+        let extents = file_extents(&ext4, inode);
+        assert_eq!(extents.len(), 1);
+        assert!(extents[0].is_unwritten());
+        assert_eq!((extents[0].first_block, extents[0].get_actual_len()), (1, 9));
+        assert_eq!(free_blocks(&ext4), free - 9);
+        let inode_ref = ext4.get_inode_ref(inode).unwrap();
+        assert_eq!(inode_ref.inode.size(), 9 * block_size + 100);
+        assert_eq!(inode_ref.inode.blocks_count(), 9 * block_size / 512);
+        assert_eq!(read_file(&ext4, inode), vec![0u8; 9 * block_size as usize + 100]);
+
+        // preallocating again only fills the holes
+        ext4.fallocate(inode, 0, 0, 12 * block_size).unwrap();
+        assert_eq!(block_map(&ext4, inode).len(), 12);
+        assert_eq!(free_blocks(&ext4), free - 12);
+        assert_eq!(ext4.get_inode_ref(inode).unwrap().inode.size(), 12 * block_size);
+    }
+
+    #[test]
+    fn test_prealloc_keep_size() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+        let block_size = ext4.super_block.block_size() as u64;
+        let inode = create_file(&ext4, "file");
+        let data = pattern(1000, 0);
+        ext4.write_at(inode, 0, &data).unwrap();
+
+        ext4.fallocate(inode, FALLOC_FL_KEEP_SIZE, 0, 8 * block_size).unwrap();
+
+        // the blocks past the end of the file are mapped, the size is the same
+        let inode_ref = ext4.get_inode_ref(inode).unwrap();
+        assert_eq!(inode_ref.inode.size(), 1000);
+        assert_eq!(inode_ref.inode.blocks_count(), 8 * block_size / 512);
+        assert_eq!(read_file(&ext4, inode), data);
+        let map = block_map(&ext4, inode);
+        assert_eq!(map.len(), 8);
+        assert!(!map[0].2 && map[1..].iter().all(|&(_, _, unwritten)| unwritten));
+
+        // writing past the end into them grows the file without allocating
+        let free = free_blocks(&ext4);
+        ext4.write_at(inode, 5 * block_size as usize, &data).unwrap();
+        assert_eq!(free_blocks(&ext4), free);
+        assert_eq!(ext4.get_inode_ref(inode).unwrap().inode.size(), 5 * block_size + 1000);
+        let mut expected = vec![0u8; 5 * block_size as usize + 1000];
+        expected[..1000].copy_from_slice(&data);
+        expected[5 * block_size as usize..].copy_from_slice(&data);
+        assert_eq!(read_file(&ext4, inode), expected);
+    }
+
+    #[test]
+    fn test_write_into_prealloc() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+        let block_size = ext4.super_block.block_size() as usize;
+        let inode = create_file(&ext4, "file");
+        ext4.fallocate(inode, 0, 0, 10 * block_size as u64).unwrap();
+        let prealloc = block_map(&ext4, inode);
+        let free = free_blocks(&ext4);
+        let mut expected = vec![0u8; 10 * block_size];
+
+        // an unaligned write over blocks 2 to 5 splits the extent in three
+        let data = pattern(3 * block_size, 1);
+        let offset = 2 * block_size + 100;
+        ext4.write_at(inode, offset, &data).unwrap();
+        expected[offset..offset + data.len()].copy_from_slice(&data);
+
+        let extents = file_extents(&ext4, inode);
+        let ranges: Vec<_> = extents
+            .iter()
+            .map(|ex| (ex.first_block, ex.get_last_block(), ex.is_unwritten()))
+            .collect();
+        assert_eq!(ranges, [(0, 1, true), (2, 5, false), (6, 9, true)]);
+        // the untouched parts of the written blocks read as zeros
+        assert_eq!(read_file(&ext4, inode), expected);
+
+        // single blocks and partial blocks in the middle of unwritten extents
+        let writes = [(0, block_size, 2), (7 * block_size + 10, 20, 3), (9 * block_size, 1, 4)];
+        for (offset, len, seed) in writes {
+            let data = pattern(len, seed);
+            ext4.write_at(inode, offset, &data).unwrap();
+            expected[offset..offset + len].copy_from_slice(&data);
+        }
+        let map = block_map(&ext4, inode);
+        let written: Vec<_> = map
+            .iter()
+            .filter(|&&(_, _, unwritten)| !unwritten)
+            .map(|&(lblock, _, _)| lblock)
+            .collect();
+        assert_eq!(written, [0, 2, 3, 4, 5, 7, 9]);
+        assert_eq!(read_file(&ext4, inode), expected);
+
+        // every block stays where it was preallocated, only a leaf is
+        // allocated for the seven extents that no longer fit in the inode
+        assert_eq!(pblocks(&map), pblocks(&prealloc));
+        assert_eq!(file_extents(&ext4, inode).len(), 7);
+        assert_eq!(free_blocks(&ext4), free - 1);
+        assert_eq!(ext4.get_inode_ref(inode).unwrap().inode.blocks_count(), 11 * block_size as u64 / 512);
+
+        // the data is still there after a remount
+        drop(ext4);
+        let ext4 = mount(&disk);
+        assert_eq!(read_file(&ext4, inode), expected);
+    }
+
+    #[test]
+    fn test_write_across_prealloc_end() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+        let block_size = ext4.super_block.block_size() as usize;
+        let inode = create_file(&ext4, "file");
+        ext4.fallocate(inode, 0, 0, 4 * block_size as u64).unwrap();
+        let prealloc = block_map(&ext4, inode);
+        let free = free_blocks(&ext4);
+
+        // blocks 2 and 3 are preallocated, 4 and 5 are not
+        let data = pattern(4 * block_size, 5);
+        ext4.write_at(inode, 2 * block_size, &data).unwrap();
+
+        assert_eq!(free_blocks(&ext4), free - 2);
+        let map = block_map(&ext4, inode);
+        assert_eq!(map.len(), 6);
+        assert_eq!(pblocks(&map[..4]), pblocks(&prealloc));
+        assert!(map[2..].iter().all(|&(_, _, unwritten)| !unwritten));
+        // no block is mapped twice, in the file or in the bitmap
+        let mut pblocks: Vec<_> = map.iter().map(|&(_, p, _)| p).collect();
+        pblocks.sort();
+        pblocks.dedup();
+        assert_eq!(pblocks.len(), 6);
+        assert!(pblocks.iter().all(|&p| block_in_use(&ext4, p)));
+
+        let mut expected = vec![0u8; 2 * block_size];
+        expected.extend(&data);
+        assert_eq!(read_file(&ext4, inode), expected);
+    }
+}
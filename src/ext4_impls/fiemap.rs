@@ -0,0 +1,308 @@
+use crate::prelude::*;
+use crate::return_errno_with_message;
+
+use crate::ext4_defs::*;
+
+impl Ext4 {
+    /// Get the extents mapping a byte range of a file, like the FIEMAP ioctl.
+    ///
+    /// Every extent overlapping the range is returned whole, in file order.
+    /// Extents of unwritten blocks have `FIEMAP_EXTENT_UNWRITTEN`, and the
+    /// last extent of the file has `FIEMAP_EXTENT_LAST`. Files mapped by
+    /// indirect blocks get one `FIEMAP_EXTENT_MERGED` extent per run of
+    /// physically contiguous blocks.
+    ///
+    /// With `FIEMAP_FLAG_METADATA`, a flag of this crate only, the blocks of the extent tree on the way
+    /// to the range are returned too, before the extents they hold, flagged
+    /// `FIEMAP_EXTENT_METADATA` and at the first byte they map.
+    ///
+    /// Params:
+    /// inode: u32 - inode number of the file
+    /// start: u64 - start of the range
+    /// len: u64 - length of the range
+    /// flags: u32 - `FIEMAP_FLAG_SYNC` and `FIEMAP_FLAG_METADATA`
+    ///
+    /// Returns:
+    /// `Result<Vec<Ext4FiemapExtent>>` - the extents, `EBADR` for unsupported flags
+    pub fn fiemap(&self, inode: u32, start: u64, len: u64, flags: u32) -> Result<Vec<Ext4FiemapExtent>> {
+        if flags & !(FIEMAP_FLAG_SYNC | FIEMAP_FLAG_METADATA) != 0 {
+            return_errno_with_message!(Errno::EBADR, "unsupported fiemap flags");
+        }
+        if len == 0 {
+            return_errno_with_message!(Errno::EINVAL, "empty fiemap range");
+        }
+        if flags & FIEMAP_FLAG_SYNC != 0 {
+            self.sync()?;
+        }
+
+        let inode_ref = self.get_inode_ref(inode)?;
+        let block_size = self.super_block.block_size() as u64;
+        let from = min(start / block_size, EXT_MAX_BLOCKS as u64 - 1) as Ext4Lblk;
+        let to = min(start.saturating_add(len - 1) / block_size, EXT_MAX_BLOCKS as u64 - 1) as Ext4Lblk;
+
+        let mut extents = Vec::new();
+        if !inode_ref.inode.has_extents() {
+            self.fiemap_indirect(&inode_ref, from, to, &mut extents)?;
+            return Ok(extents);
+        }
+
+        let metadata = flags & FIEMAP_FLAG_METADATA != 0;
+        self.fiemap_node(&inode_ref, 0, from, to, metadata, &mut extents)?;
+
+        if let Some(last) = extents.iter_mut().rev().find(|ex| ex.flags & FIEMAP_EXTENT_METADATA == 0) {
+            let next = ((last.logical + last.length) / block_size) as Ext4Lblk;
+            if self.extent_find_next(&inode_ref, next)?.is_none() {
+                last.flags |= FIEMAP_EXTENT_LAST;
+            }
+        }
+
+        Ok(extents)
+    }
+
+    /// Collect the extents of the subtree at `pblock_of_node` (0 for the
+    /// root in the inode) overlapping the logical blocks `from` to `to`.
+    fn fiemap_node(
+        &self,
+        inode_ref: &Ext4InodeRef,
+        pblock_of_node: Ext4Fsblk,
+        from: Ext4Lblk,
+        to: Ext4Lblk,
+        metadata: bool,
+        extents: &mut Vec<Ext4FiemapExtent>,
+    ) -> Result<()> {
+        let block_size = self.super_block.block_size() as u64;
+        let node = if pblock_of_node == 0 {
+            let root_data: &[u8; 60] =
+                unsafe { core::mem::transmute::<&[u32; 15], &[u8; 60]>(&inode_ref.inode.block) };
+            ExtentNode::load_from_data(root_data, true)?
+        } else {
+            self.load_extent_node(inode_ref, pblock_of_node)?
+        };
+        let count = node.header.entries_count as usize;
+
+        if node.header.is_leaf() {
+            for ex in (0..count).filter_map(|pos| node.get_extent(pos)) {
+                if ex.get_last_block() < from {
+                    continue;
+                }
+                if ex.first_block > to {
+                    break;
+                }
+                extents.push(Ext4FiemapExtent {
+                    logical: ex.first_block as u64 * block_size,
+                    physical: ex.get_pblock() * block_size,
+                    length: ex.get_actual_len() as u64 * block_size,
+                    flags: if ex.is_unwritten() { FIEMAP_EXTENT_UNWRITTEN } else { 0 },
+                });
+            }
+            return Ok(());
+        }
+
+        for pos in 0..count {
+            let index = node.get_index(pos)?;
+            if index.first_block > to {
+                break;
+            }
+            // the child only maps blocks before its right neighbour's key
+            if pos + 1 < count && node.get_index(pos + 1)?.first_block <= from {
+                continue;
+            }
+
+            if metadata {
+                extents.push(Ext4FiemapExtent {
+                    logical: index.first_block as u64 * block_size,
+                    physical: index.get_pblock() * block_size,
+                    length: block_size,
+                    flags: FIEMAP_EXTENT_METADATA,
+                });
+            }
+            self.fiemap_node(inode_ref, index.get_pblock(), from, to, metadata, extents)?;
+        }
+
+        Ok(())
+    }
+
+    /// Collect the runs of physically contiguous blocks of a file mapped by
+    /// indirect blocks, between the logical blocks `from` and `to`.
+    fn fiemap_indirect(
+        &self,
+        inode_ref: &Ext4InodeRef,
+        from: Ext4Lblk,
+        to: Ext4Lblk,
+        extents: &mut Vec<Ext4FiemapExtent>,
+    ) -> Result<()> {
+        let block_size = self.super_block.block_size() as u64;
+        let blocks = inode_ref.inode.size().div_ceil(block_size);
+
+        // a run across `from` is reported from its start
+        let mut lblock = from as u64;
+        let mut pblock = self.indirect_get_pblock(inode_ref, lblock as Ext4Lblk)?;
+        while pblock != 0 && lblock > 0 {
+            let prev = self.indirect_get_pblock(inode_ref, lblock as Ext4Lblk - 1)?;
+            if prev + 1 != pblock {
+                break;
+            }
+            lblock -= 1;
+            pblock = prev;
+        }
+
+        while lblock < blocks {
+            let pblock = self.indirect_get_pblock(inode_ref, lblock as Ext4Lblk)?;
+            if pblock == 0 {
+                if lblock > to as u64 {
+                    break;
+                }
+                lblock += 1;
+                continue;
+            }
+            if lblock > to as u64 {
+                // the previous run is not the last one
+                break;
+            }
+
+            let mut count = 1;
+            while lblock + count < blocks
+                && self.indirect_get_pblock(inode_ref, (lblock + count) as Ext4Lblk)? == pblock + count
+            {
+                count += 1;
+            }
+            extents.push(Ext4FiemapExtent {
+                logical: lblock * block_size,
+                physical: pblock * block_size,
+                length: count * block_size,
+                flags: FIEMAP_EXTENT_MERGED,
+            });
+            lblock += count;
+        }
+
+        // nothing is mapped after the runs found
+        if lblock >= blocks {
+            if let Some(last) = extents.last_mut() {
+                last.flags |= FIEMAP_EXTENT_LAST;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ext4_impls::test_utils::*;
+
+    #[test]
+    fn test_fiemap_extents() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+        let block_size = ext4.super_block.block_size() as u64;
+        let inode = create_file(&ext4, "file");
+        ext4.write_at(inode, 0, &pattern(3 * block_size as usize, 0)).unwrap();
+        ext4.fallocate(inode, 0, 5 * block_size, 2 * block_size).unwrap();
+        ext4.write_at(inode, 10 * block_size as usize, &pattern(100, 1)).unwrap();
+        let mapped = file_extents(&ext4, inode);
+
+        let extents = ext4.fiemap(inode, 0, u64::MAX, 0).unwrap();
+        let found: Vec<_> = extents.iter().map(|ex| (ex.logical, ex.physical, ex.length, ex.flags)).collect();
+        assert_eq!(
+            found,
+            [
+                (0, mapped[0].get_pblock() * block_size, 3 * block_size, 0),
+                (5 * block_size, mapped[1].get_pblock() * block_size, 2 * block_size, FIEMAP_EXTENT_UNWRITTEN),
+                (10 * block_size, mapped[2].get_pblock() * block_size, block_size, FIEMAP_EXTENT_LAST),
+            ]
+        );
+
+        // extents overlapping the range are returned whole, the last one of
+        // the range is not the last one of the file
+        let extents = ext4.fiemap(inode, block_size + 1, 5 * block_size, 0).unwrap();
+        let found: Vec<_> = extents.iter().map(|ex| (ex.logical, ex.length, ex.flags)).collect();
+        assert_eq!(found, [(0, 3 * block_size, 0), (5 * block_size, 2 * block_size, FIEMAP_EXTENT_UNWRITTEN)]);
+
+        // a hole maps nothing
+        assert!(ext4.fiemap(inode, 3 * block_size, 2 * block_size, 0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_fiemap_flags() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+        let inode = create_file(&ext4, "file");
+        ext4.write_at(inode, 0, &pattern(100, 0)).unwrap();
+
+        // extended attributes are not mapped
+        let err = ext4.fiemap(inode, 0, 100, 0x02).unwrap_err();
+        assert_eq!(err.error(), Errno::EBADR);
+        let err = ext4.fiemap(inode, 0, 0, 0).unwrap_err();
+        assert_eq!(err.error(), Errno::EINVAL);
+
+        let extents = ext4.fiemap(inode, 0, 100, FIEMAP_FLAG_SYNC).unwrap();
+        assert_eq!(extents.len(), 1);
+        assert_eq!(extents[0].flags, FIEMAP_EXTENT_LAST);
+    }
+
+    #[test]
+    fn test_fiemap_metadata() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+        let block_size = ext4.super_block.block_size() as u64;
+        let (inode, _) = create_fragmented_file(&ext4, "file", 200);
+
+        let extents = ext4.fiemap(inode, 0, u64::MAX, FIEMAP_FLAG_METADATA).unwrap();
+
+        let (leaves, data): (Vec<&Ext4FiemapExtent>, Vec<_>) =
+            extents.iter().partition(|ex| ex.flags & FIEMAP_EXTENT_METADATA != 0);
+        assert_eq!(data.len(), 200);
+        assert!(leaves.len() > 1);
+        for leaf in leaves.iter() {
+            assert_eq!(leaf.length, block_size);
+            assert!(block_in_use(&ext4, leaf.physical / block_size));
+        }
+        // a leaf comes right before the first extent it holds
+        for (i, ex) in extents.iter().enumerate() {
+            if ex.flags & FIEMAP_EXTENT_METADATA != 0 {
+                assert_eq!(extents[i + 1].logical, ex.logical);
+                assert_eq!(extents[i + 1].flags & FIEMAP_EXTENT_METADATA, 0);
+            }
+        }
+        assert_eq!(extents.last().unwrap().flags, FIEMAP_EXTENT_LAST);
+
+        // only the leaves on the way to the range
+        let extents = ext4.fiemap(inode, 0, block_size, FIEMAP_FLAG_METADATA).unwrap();
+        let found: Vec<_> = extents.iter().map(|ex| (ex.logical, ex.flags)).collect();
+        assert_eq!(found, [(0, FIEMAP_EXTENT_METADATA), (0, 0)]);
+    }
+
+    #[test]
+    fn test_fiemap_indirect() {
+        let disk = MemDisk::new(INDIRECT_IMAGE);
+        let ext4 = mount(&disk);
+        let block_size = ext4.super_block.block_size() as u64;
+
+        for name in ["direct", "indirect", "dindirect", "holey"] {
+            let inode = lookup(&ext4, name).unwrap();
+            let data = image_file(name);
+            let extents = ext4.fiemap(inode, 0, u64::MAX, 0).unwrap();
+
+            // the runs hold the file content
+            let mut mapped = vec![0u8; data.len()];
+            for ex in extents.iter() {
+                assert_eq!(ex.flags & FIEMAP_EXTENT_MERGED, FIEMAP_EXTENT_MERGED);
+                let start = ex.logical as usize;
+                let end = min(data.len(), (ex.logical + ex.length) as usize);
+                mapped[start..end].copy_from_slice(&disk.bytes(ex.physical as usize, end - start));
+            }
+            assert_eq!(mapped, data, "{}", name);
+            assert_eq!(extents.last().unwrap().flags, FIEMAP_EXTENT_MERGED | FIEMAP_EXTENT_LAST);
+        }
+
+        let inode = lookup(&ext4, "holey").unwrap();
+        let extents = ext4.fiemap(inode, 0, u64::MAX, 0).unwrap();
+        let found: Vec<_> = extents.iter().map(|ex| ex.logical / block_size).collect();
+        assert_eq!(found, [0, 97, 292]);
+
+        // a range in the middle of a run gets the whole run, not the last one
+        let extents = ext4.fiemap(inode, 98 * block_size, 1, 0).unwrap();
+        let found: Vec<_> = extents.iter().map(|ex| (ex.logical / block_size, ex.flags)).collect();
+        assert_eq!(found, [(97, FIEMAP_EXTENT_MERGED)]);
+    }
+}
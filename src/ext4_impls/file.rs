@@ -19,38 +19,42 @@ impl Ext4 {
         child: &mut Ext4InodeRef,
         name: &str,
     ) -> Result<usize> {
-        // Add a directory entry in the parent directory pointing to the child inode
+        self.check_writable()?;
 
-        // at this point should insert to existing block
-        self.dir_add_entry(parent, child, name)?;
-        self.write_back_inode_without_csum(parent);
+        self.journal_transaction(|| {
+            // Add a directory entry in the parent directory pointing to the child inode
 
-        // If this is the first link. add '.' and '..' entries
-        if child.inode.is_dir() {
-            // let child_ref = child.clone();
-            let new_child_ref = Ext4InodeRef {
-                inode_num: child.inode_num,
-                inode: child.inode,
-            };
+            // at this point should insert to existing block
+            self.dir_add_entry(parent, child, name)?;
+            self.write_back_inode(parent)?;
+
+            // If this is the first link. add '.' and '..' entries
+            if child.inode.is_dir() {
+                // let child_ref = child.clone();
+                let new_child_ref = Ext4InodeRef {
+                    inode_num: child.inode_num,
+                    inode: child.inode,
+                };
 
-            // at this point child need a new block
-            self.dir_add_entry(child, &new_child_ref, ".")?;
+                // at this point child need a new block
+                self.dir_add_entry(child, &new_child_ref, ".")?;
 
-            // at this point should insert to existing block
-            self.dir_add_entry(child, &new_child_ref, "..")?;
+                // at this point should insert to existing block
+                self.dir_add_entry(child, parent, "..")?;
 
-            child.inode.set_links_count(2);
-            let link_cnt = parent.inode.links_count() + 1;
-            parent.inode.set_links_count(link_cnt);
+                child.inode.set_links_count(2);
+                let link_cnt = parent.inode.links_count() + 1;
+                parent.inode.set_links_count(link_cnt);
 
-            return Ok(EOK);
-        }
+                return Ok(EOK);
+            }
 
-        // Increment the link count of the child inode
-        let link_cnt = child.inode.links_count() + 1;
-        child.inode.set_links_count(link_cnt);
+            // Increment the link count of the child inode
+            let link_cnt = child.inode.links_count() + 1;
+            child.inode.set_links_count(link_cnt);
 
-        Ok(EOK)
+            Ok(EOK)
+        })
     }
 
     /// create a new inode and link it to the parent directory
@@ -62,24 +66,30 @@ impl Ext4 {
     ///
     /// Returns:
     pub fn create(&self, parent: u32, name: &str, inode_mode: u16) -> Result<Ext4InodeRef> {
-        let mut parent_inode_ref = self.get_inode_ref(parent);
+        self.check_writable()?;
+
+        self.journal_transaction(|| {
+            let mut parent_inode_ref = self.get_inode_ref(parent)?;
 
-        // let mut child_inode_ref = self.create_inode(inode_mode)?;
-        let init_child_ref = self.create_inode(inode_mode)?;
+            // let mut child_inode_ref = self.create_inode(inode_mode)?;
+            let mut init_child_ref = self.create_inode(inode_mode)?;
 
-        self.write_back_inode_without_csum(&init_child_ref);
-        // load new
-        let mut child_inode_ref = self.get_inode_ref(init_child_ref.inode_num);
+            self.write_back_inode(&mut init_child_ref)?;
+            self.acl_inherit(parent, init_child_ref.inode_num)?;
+            // load new
+            let mut child_inode_ref = self.get_inode_ref(init_child_ref.inode_num)?;
 
-        self.link(&mut parent_inode_ref, &mut child_inode_ref, name)?;
+            self.link(&mut parent_inode_ref, &mut child_inode_ref, name)?;
 
-        self.write_back_inode(&mut parent_inode_ref);
-        self.write_back_inode(&mut child_inode_ref);
+            self.write_back_inode(&mut parent_inode_ref)?;
+            self.write_back_inode(&mut child_inode_ref)?;
 
-        Ok(child_inode_ref)
+            Ok(child_inode_ref)
+        })
     }
 
     pub fn create_inode(&self, inode_mode: u16) -> Result<Ext4InodeRef> {
+        self.check_writable()?;
 
         let inode_file_type = match InodeFileType::from_bits(inode_mode) {
             Some(file_type) => file_type,
@@ -91,6 +101,10 @@ impl Ext4 {
         // allocate inode
         let inode_num = self.alloc_inode(is_dir)?;
 
+        // a reused record may still hold the in-inode xattrs of its last owner
+        let record = vec![0u8; self.super_block.inode_size() as usize];
+        self.block_device.write_offset(self.inode_disk_pos(inode_num)?, &record)?;
+
         // initialize inode
         let mut inode = Ext4Inode::default();
 
@@ -104,9 +118,11 @@ impl Ext4 {
             inode.set_i_extra_isize(extra_size);
         }
 
-        // set extent
-        inode.set_flags(EXT4_INODE_FLAG_EXTENTS as u32);
-        inode.extent_tree_init();
+        // set extent, filesystems without them use the indirect block map
+        if self.super_block.has_extents() {
+            inode.set_flags(EXT4_INODE_FLAG_EXTENTS as u32);
+            inode.extent_tree_init();
+        }
 
         let inode_ref = Ext4InodeRef {
             inode_num,
@@ -128,24 +144,29 @@ impl Ext4 {
     ///
     /// Returns:
     pub fn create_with_attr(&self, parent: u32, name: &str, inode_mode: u16, uid:u16, gid: u16) -> Result<Ext4InodeRef> {
-        let mut parent_inode_ref = self.get_inode_ref(parent);
+        self.check_writable()?;
+
+        self.journal_transaction(|| {
+            let mut parent_inode_ref = self.get_inode_ref(parent)?;
 
-        // let mut child_inode_ref = self.create_inode(inode_mode)?;
-        let mut init_child_ref = self.create_inode(inode_mode)?;
+            // let mut child_inode_ref = self.create_inode(inode_mode)?;
+            let mut init_child_ref = self.create_inode(inode_mode)?;
 
-        init_child_ref.inode.set_uid(uid);
-        init_child_ref.inode.set_gid(gid);
+            init_child_ref.inode.set_uid(uid);
+            init_child_ref.inode.set_gid(gid);
 
-        self.write_back_inode_without_csum(&init_child_ref);
-        // load new
-        let mut child_inode_ref = self.get_inode_ref(init_child_ref.inode_num);
+            self.write_back_inode(&mut init_child_ref)?;
+            self.acl_inherit(parent, init_child_ref.inode_num)?;
+            // load new
+            let mut child_inode_ref = self.get_inode_ref(init_child_ref.inode_num)?;
 
-        self.link(&mut parent_inode_ref, &mut child_inode_ref, name)?;
+            self.link(&mut parent_inode_ref, &mut child_inode_ref, name)?;
 
-        self.write_back_inode(&mut parent_inode_ref);
-        self.write_back_inode(&mut child_inode_ref);
+            self.write_back_inode(&mut parent_inode_ref)?;
+            self.write_back_inode(&mut child_inode_ref)?;
 
-        Ok(child_inode_ref)
+            Ok(child_inode_ref)
+        })
     }
 
     /// Read data from a file at a given offset
@@ -165,7 +186,7 @@ impl Ext4 {
         }
 
         // get the inode reference
-        let inode_ref = self.get_inode_ref(inode);
+        let inode_ref = self.get_inode_ref(inode)?;
 
         // get the file size
         let file_size = inode_ref.inode.size();
@@ -184,9 +205,10 @@ impl Ext4 {
         let size_to_read = min(read_buf_len, file_size as usize - offset);
 
         // calculate the start block and unaligned size
-        let iblock_start = offset / BLOCK_SIZE;
-        let iblock_last = (offset + size_to_read + BLOCK_SIZE - 1) / BLOCK_SIZE; // round up to include the last partial block
-        let unaligned_start_offset = offset % BLOCK_SIZE;
+        let block_size = self.super_block.block_size() as usize;
+        let iblock_start = offset / block_size;
+        let iblock_last = (offset + size_to_read + block_size - 1) / block_size; // round up to include the last partial block
+        let unaligned_start_offset = offset % block_size;
 
         // Buffer to keep track of read bytes
         let mut cursor = 0;
@@ -195,15 +217,13 @@ impl Ext4 {
 
         // Unaligned read at the beginning
         if unaligned_start_offset > 0 {
-            let adjust_read_size = min(BLOCK_SIZE - unaligned_start_offset, size_to_read);
+            let adjust_read_size = min(block_size - unaligned_start_offset, size_to_read);
 
             // get iblock physical block id
-            let pblock_idx = self.get_pblock_idx(&inode_ref, iblock as u32)?;
+            let pblock_idx = self.get_data_pblock(&inode_ref, iblock as u32)?;
 
             // read data
-            let data = self
-                .block_device
-                .read_offset(pblock_idx as usize * BLOCK_SIZE);
+            let data = self.read_file_block(pblock_idx)?;
 
             // copy data to read buffer
             read_buf[cursor..cursor + adjust_read_size].copy_from_slice(
@@ -216,30 +236,104 @@ impl Ext4 {
             iblock += 1;
         }
 
-        // Continue with full block reads
-        while total_bytes_read < size_to_read {
-            let read_length = core::cmp::min(BLOCK_SIZE, size_to_read - total_bytes_read);
+        // Full blocks, one device request per run of physically contiguous blocks
+        let aligned_end = cursor + (size_to_read - cursor) / block_size * block_size;
+        while cursor < aligned_end {
+            let fblock_start = self.get_data_pblock(&inode_ref, iblock as u32)?;
+
+            // holes and unwritten blocks read as zeros
+            if fblock_start == 0 {
+                let max_count = (aligned_end - cursor) / block_size;
+                let len = self.get_hole_len(&inode_ref, iblock as u32, max_count)? * block_size;
+                read_buf[cursor..cursor + len].fill(0);
+                cursor += len;
+                total_bytes_read += len;
+                iblock += len / block_size;
+                continue;
+            }
+
+            let mut fblock_count = 1;
+            while cursor + fblock_count * block_size < aligned_end {
+                let pblock_idx = self.get_data_pblock(&inode_ref, (iblock + fblock_count) as u32)?;
+                if pblock_idx != fblock_start + fblock_count as u64 {
+                    break;
+                }
+                fblock_count += 1;
+            }
+
+            let len = fblock_count * block_size;
+            self.block_device.read_blocks(
+                fblock_start as usize * block_size,
+                &mut read_buf[cursor..cursor + len],
+            )?;
+
+            cursor += len;
+            total_bytes_read += len;
+            iblock += fblock_count;
+        }
+
+        // Final partial block
+        if total_bytes_read < size_to_read {
+            let read_length = size_to_read - total_bytes_read;
 
             // get iblock physical block id
-            let pblock_idx = self.get_pblock_idx(&inode_ref, iblock as u32)?;
+            let pblock_idx = self.get_data_pblock(&inode_ref, iblock as u32)?;
 
             // read data
-            let data = self
-                .block_device
-                .read_offset(pblock_idx as usize * BLOCK_SIZE);
+            let data = self.read_file_block(pblock_idx)?;
 
             // copy data to read buffer
             read_buf[cursor..cursor + read_length].copy_from_slice(&data[..read_length]);
 
-            // update cursor and total bytes read
             cursor += read_length;
             total_bytes_read += read_length;
-            iblock += 1;
         }
 
         Ok(min(total_bytes_read, size_to_read))
     }
 
+    /// Get the physical block holding the data of a file block.
+    ///
+    /// Params:
+    /// inode_ref: &Ext4InodeRef - inode reference
+    /// lblock: Ext4Lblk - logical block id
+    ///
+    /// Returns:
+    /// `Result<Ext4Fsblk>` - physical block id, 0 for holes and unwritten blocks which read as zeros
+    fn get_data_pblock(&self, inode_ref: &Ext4InodeRef, lblock: Ext4Lblk) -> Result<Ext4Fsblk> {
+        if !inode_ref.inode.has_extents() {
+            return self.get_pblock_idx(inode_ref, lblock);
+        }
+        match self.extent_at(inode_ref, lblock)? {
+            Some(ex) if !ex.is_unwritten() => Ok(ex.pblock_of(lblock)),
+            _ => Ok(0),
+        }
+    }
+
+    /// Get the number of blocks reading as zeros from a file block that
+    /// `get_data_pblock` found unmapped, up to `max_count`.
+    fn get_hole_len(&self, inode_ref: &Ext4InodeRef, lblock: Ext4Lblk, max_count: usize) -> Result<usize> {
+        if !inode_ref.inode.has_extents() {
+            return Ok(1);
+        }
+        let end = match self.extent_find_next(inode_ref, lblock)? {
+            // the rest of an unwritten extent
+            Some(ex) if ex.contains(lblock) => ex.get_last_block() as usize + 1,
+            Some(ex) => ex.first_block as usize,
+            None => usize::MAX,
+        };
+        Ok(min(end - lblock as usize, max_count))
+    }
+
+    /// Read one data block of a file, block 0 being a hole.
+    fn read_file_block(&self, pblock_idx: Ext4Fsblk) -> Result<Vec<u8>> {
+        let block_size = self.super_block.block_size() as usize;
+        if pblock_idx == 0 {
+            return Ok(vec![0u8; block_size]);
+        }
+        Ok(Block::load(self.block_device.clone(), pblock_idx as usize * block_size, block_size)?.data)
+    }
+
     /// Write data to a file at a given offset
     ///
     /// Params:
@@ -250,135 +344,182 @@ impl Ext4 {
     /// Returns:
     /// `Result<usize>` - number of bytes written
     pub fn write_at(&self, inode: u32, offset: usize, write_buf: &[u8]) -> Result<usize> {
-        // write buf is empty, return 0
-        let write_buf_len = write_buf.len();
-        if write_buf_len == 0 {
-            return Ok(0);
-        }
+        self.check_writable()?;
 
-        // get the inode reference
-        let mut inode_ref = self.get_inode_ref(inode);
+        self.journal_transaction(|| {
+            // write buf is empty, return 0
+            let write_buf_len = write_buf.len();
+            if write_buf_len == 0 {
+                return Ok(0);
+            }
 
-        // Get the file size
-        let file_size = inode_ref.inode.size();
+            // get the inode reference
+            let mut inode_ref = self.get_inode_ref(inode)?;
 
-        // Calculate the start and end block index
-        let iblock_start = offset / BLOCK_SIZE;
-        let iblock_last = (offset + write_buf_len + BLOCK_SIZE - 1) / BLOCK_SIZE; // round up to include the last partial block
+            // Get the file size
+            let file_size = inode_ref.inode.size();
 
-        // start block index
-        let mut iblk_idx = iblock_start;
-        let ifile_blocks = (file_size + BLOCK_SIZE as u64 - 1) / BLOCK_SIZE as u64;
+            // Calculate the start and end block index
+            let block_size = self.super_block.block_size() as usize;
+            if (offset + write_buf_len).div_ceil(block_size) > EXT_MAX_BLOCKS as usize {
+                return_errno_with_message!(Errno::EFBIG, "write past the maximum file size");
+            }
+            let iblock_start = offset / block_size;
+            let iblock_last = (offset + write_buf_len + block_size - 1) / block_size; // round up to include the last partial block
 
-        // Calculate the unaligned size
-        let unaligned = offset % BLOCK_SIZE;
+            // start block index
+            let mut iblk_idx = iblock_start;
 
-        // Buffer to keep track of written bytes
-        let mut written = 0;
+            // Calculate the unaligned size
+            let unaligned = offset % block_size;
 
-        // Start bgid
-        let mut start_bgid = 1;
+            // Buffer to keep track of written bytes
+            let mut written = 0;
 
-        // Unaligned write
-        if unaligned > 0 {
-            let len = min(write_buf_len, BLOCK_SIZE - unaligned);
-            // Get the physical block id, if the block is not present, append a new block
-            let pblock_idx = if iblk_idx < ifile_blocks as usize {
-                self.get_pblock_idx(&inode_ref, iblk_idx as u32)?
-            } else {
-                // physical block not exist, append a new block
-                self.append_inode_pblk_from(&mut inode_ref, &mut start_bgid)?
-            };
+            // Start bgid
+            let mut start_bgid = 1;
 
-            let mut block =
-                Block::load(self.block_device.clone(), pblock_idx as usize * BLOCK_SIZE);
+            // Unaligned write
+            if unaligned > 0 {
+                let len = min(write_buf_len, block_size - unaligned);
+                let (pblock_idx, fresh) =
+                    self.get_or_alloc_pblock(&mut inode_ref, iblk_idx, 1, &mut start_bgid)?;
+                self.write_partial_block(pblock_idx, unaligned, &write_buf[..len], fresh)?;
 
-            block.write_offset(unaligned, &write_buf[..len], len);
-            block.sync_blk_to_disk(self.block_device.clone());
-            drop(block);
+                written += len;
+                iblk_idx += 1;
+            }
 
+            // Aligned write, one device request per run of physically contiguous blocks
+            let aligned_end = written + (write_buf_len - written) / block_size * block_size;
+            while written < aligned_end {
+                let (fblock_start, _) = self.get_or_alloc_pblock(
+                    &mut inode_ref,
+                    iblk_idx,
+                    (aligned_end - written) / block_size,
+                    &mut start_bgid,
+                )?;
+                let mut fblock_count = 1;
+                while written + fblock_count * block_size < aligned_end {
+                    // a block that breaks the run stays mapped and starts the next one
+                    let (pblock_idx, _) = self.get_or_alloc_pblock(
+                        &mut inode_ref,
+                        iblk_idx + fblock_count,
+                        (aligned_end - written) / block_size - fblock_count,
+                        &mut start_bgid,
+                    )?;
+                    if pblock_idx != fblock_start + fblock_count as u64 {
+                        break;
+                    }
+                    fblock_count += 1;
+                }
 
-            written += len;
-            iblk_idx += 1;
-        }
+                let len = fblock_count * block_size;
+                self.block_device.write_blocks(
+                    fblock_start as usize * block_size,
+                    &write_buf[written..written + len],
+                )?;
 
-        // Aligned write
-        let mut fblock_start = 0;
-        let mut fblock_count = 0;
-
-        while written < write_buf_len {
-            while iblk_idx < iblock_last && written < write_buf_len {
-                // Get the physical block id, if the block is not present, append a new block
-                let pblock_idx = if iblk_idx < ifile_blocks as usize {
-                    self.get_pblock_idx(&inode_ref, iblk_idx as u32)?
-                } else {
-                    // physical block not exist, append a new block
-                    self.append_inode_pblk_from(&mut inode_ref, &mut start_bgid)?
-                };
-                if fblock_start == 0 {
-                    fblock_start = pblock_idx;
-                }
+                written += len;
+                iblk_idx += fblock_count;
+            }
 
-                // Check if the block is contiguous
-                if fblock_start + fblock_count != pblock_idx {
-                    break;
-                }
+            // Final unaligned write if any
+            if written < write_buf_len {
+                let (pblock_idx, fresh) =
+                    self.get_or_alloc_pblock(&mut inode_ref, iblk_idx, 1, &mut start_bgid)?;
+                self.write_partial_block(pblock_idx, 0, &write_buf[written..], fresh)?;
 
-                fblock_count += 1;
-                iblk_idx += 1;
+                written = write_buf_len;
             }
 
-            // Write contiguous blocks at once
-            let len = min(
-                fblock_count as usize * BLOCK_SIZE,
-                write_buf_len - written,
-            );
+            // Update file size if necessary
+            if offset + write_buf_len > file_size as usize {
+                // log::trace!("set file size {:x}", offset + write_buf_len);
+                inode_ref
+                    .inode
+                    .set_size((offset + write_buf_len) as u64);
 
-            for i in 0..fblock_count {
-                let block_offset = fblock_start as usize * BLOCK_SIZE + i as usize * BLOCK_SIZE;
-                let mut block = Block::load(self.block_device.clone(), block_offset);
-                let write_size = min(BLOCK_SIZE, write_buf_len - written);
-                block.write_offset(0, &write_buf[written..written + write_size], write_size);
-                block.sync_blk_to_disk(self.block_device.clone());
-                drop(block);
-                written += write_size;
+                self.write_back_inode(&mut inode_ref)?;
             }
 
-            fblock_start = 0;
-            fblock_count = 0;
-        }
+            Ok(written)
+        })
+    }
 
-        // Final unaligned write if any
-        if written < write_buf_len {
-            let len = write_buf_len - written;
-            // Get the physical block id, if the block is not present, append a new block
-            let pblock_idx = if iblk_idx < ifile_blocks as usize {
-                self.get_pblock_idx(&inode_ref, iblk_idx as u32)?
-            } else {
-                // physical block not exist, append a new block
-                self.append_inode_pblk(&mut inode_ref)?
-            };
-
-            let mut block =
-                Block::load(self.block_device.clone(), pblock_idx as usize * BLOCK_SIZE);
-            block.write_offset(0, &write_buf[written..], len);
-            block.sync_blk_to_disk(self.block_device.clone());
-            drop(block);
-
-            written += len;
+    /// Get the physical block of a file block about to be written, allocating
+    /// a new block if it is a hole.
+    ///
+    /// A block of an unwritten extent is marked as written, along with the
+    /// blocks after it up to `count`, so that a run of full blocks takes a
+    /// single extent update.
+    ///
+    /// Params:
+    /// inode_ref: &mut Ext4InodeRef - inode reference
+    /// iblock: usize - logical block id
+    /// count: usize - number of blocks about to be written from `iblock`
+    /// start_bgid: &mut u32 - start bgid of free block search
+    ///
+    /// Returns:
+    /// `Result<(Ext4Fsblk, bool)>` - physical block id and whether its content is not file data yet
+    fn get_or_alloc_pblock(
+        &self,
+        inode_ref: &mut Ext4InodeRef,
+        iblock: usize,
+        count: usize,
+        start_bgid: &mut u32,
+    ) -> Result<(Ext4Fsblk, bool)> {
+        let lblock = iblock as Ext4Lblk;
+        if inode_ref.inode.has_extents() {
+            if let Some(ex) = self.extent_at(inode_ref, lblock)? {
+                if ex.is_unwritten() {
+                    let left = ex.first_block + ex.get_actual_len() as u32 - lblock;
+                    self.extent_mark_written(inode_ref, &ex, lblock, min(count as u32, left))?;
+                }
+                return Ok((ex.pblock_of(lblock), ex.is_unwritten()));
+            }
+        } else {
+            let pblock_idx = self.get_pblock_idx(inode_ref, lblock)?;
+            if pblock_idx != 0 {
+                return Ok((pblock_idx, false));
+            }
         }
 
-        // Update file size if necessary
-        if offset + write_buf_len > file_size as usize {
-            // log::trace!("set file size {:x}", offset + write_buf_len);
-            inode_ref
-                .inode
-                .set_size((offset + write_buf_len) as u64);
-
-            self.write_back_inode(&mut inode_ref);
+        // fill the hole with a new block
+        let pblock_idx = self.balloc_alloc_block_from(inode_ref, start_bgid)?;
+        if inode_ref.inode.has_extents() {
+            self.insert_extent(inode_ref, &mut Ext4Extent::new(lblock, pblock_idx, 1))?;
+        } else {
+            self.indirect_map_block(inode_ref, lblock, pblock_idx)?;
         }
+        Ok((pblock_idx, true))
+    }
 
-        Ok(written)
+    /// Write part of a data block through the block cache.
+    ///
+    /// A freshly allocated block is not read from the disk, the bytes around
+    /// `data` are zeroed instead.
+    fn write_partial_block(
+        &self,
+        pblock_idx: Ext4Fsblk,
+        offset: usize,
+        data: &[u8],
+        fresh: bool,
+    ) -> Result<()> {
+        let block_size = self.super_block.block_size() as usize;
+        let disk_offset = pblock_idx as usize * block_size;
+
+        let mut block = if fresh {
+            Block {
+                disk_offset,
+                data: vec![0u8; block_size],
+            }
+        } else {
+            Block::load(self.block_device.clone(), disk_offset, block_size)?
+        };
+
+        block.write_offset(offset, data, data.len());
+        block.sync_blk_to_disk(self.block_device.clone())
     }
 
     /// File remove
@@ -389,34 +530,38 @@ impl Ext4 {
     /// Returns:
     /// `Result<usize>` - status of the operation
     pub fn file_remove(&self, path: &str) -> Result<usize> {
-        // start from root
-        let mut parent_inode_num = ROOT_INODE;
+        self.check_writable()?;
 
-        let mut nameoff = 0;
-        let child_inode = self.generic_open(path, &mut parent_inode_num, false, 0, &mut nameoff)?;
+        self.journal_transaction(|| {
+            // start from root
+            let mut parent_inode_num = ROOT_INODE;
 
-        let mut child_inode_ref = self.get_inode_ref(child_inode);
-        let child_link_cnt = child_inode_ref.inode.links_count();
-        if child_link_cnt == 1 {
-            self.truncate_inode(&mut child_inode_ref, 0)?;
-        }
+            let mut nameoff = 0;
+            let child_inode = self.generic_open(path, &mut parent_inode_num, false, 0, &mut nameoff)?;
 
-        // get child name
-        let mut is_goal = false;
-        let p = &path[nameoff as usize..];
-        let len = path_check(p, &mut is_goal);
+            let mut child_inode_ref = self.get_inode_ref(child_inode)?;
+            let child_link_cnt = child_inode_ref.inode.links_count();
+            if child_link_cnt == 1 {
+                self.truncate_inode(&mut child_inode_ref, 0)?;
+            }
 
-        // load parent
-        let mut parent_inode_ref = self.get_inode_ref(parent_inode_num);
+            // get child name
+            let mut is_goal = false;
+            let p = &path[nameoff as usize..];
+            let len = path_check(p, &mut is_goal);
 
-        let r = self.unlink(
-            &mut parent_inode_ref,
-            &mut child_inode_ref,
-            &p[..len],
-        )?;
+            // load parent
+            let mut parent_inode_ref = self.get_inode_ref(parent_inode_num)?;
 
+            let r = self.unlink(
+                &mut parent_inode_ref,
+                &mut child_inode_ref,
+                &p[..len],
+            )?;
 
-        Ok(EOK)
+
+            Ok(EOK)
+        })
     }
 
     /// File truncate
@@ -428,26 +573,148 @@ impl Ext4 {
     /// Returns:
     /// `Result<usize>` - status of the operation
     pub fn truncate_inode(&self, inode_ref: &mut Ext4InodeRef, new_size: u64) -> Result<usize> {
-        let old_size = inode_ref.inode.size();
+        self.check_writable()?;
 
-        assert!(old_size > new_size);
+        self.journal_transaction(|| {
+            let old_size = inode_ref.inode.size();
 
-        if old_size == new_size {
-            return Ok(EOK);
-        }
+            // growing only sets the size, blocks preallocated past it stay
+            if new_size > old_size {
+                inode_ref.inode.set_size(new_size);
+                self.write_back_inode(inode_ref)?;
+                return Ok(EOK);
+            }
+
+            // the target of a fast symlink lives in the inode, not in blocks
+            if inode_ref.inode.is_fast_symlink() {
+                inode_ref.inode.set_block([0; 15]);
+                inode_ref.inode.set_size(new_size);
+                self.write_back_inode(inode_ref)?;
+                return Ok(EOK);
+            }
 
-        let block_size = BLOCK_SIZE as u64;
-        let new_blocks_cnt = ((new_size + block_size - 1) / block_size) as u32;
-        let old_blocks_cnt = ((old_size + block_size - 1) / block_size) as u32;
-        let diff_blocks_cnt = old_blocks_cnt - new_blocks_cnt;
+            let block_size = self.super_block.block_size() as u64;
+            let new_blocks_cnt = ((new_size + block_size - 1) / block_size) as u32;
+            let old_blocks_cnt = ((old_size + block_size - 1) / block_size) as u32;
+            let diff_blocks_cnt = old_blocks_cnt - new_blocks_cnt;
 
-        if diff_blocks_cnt > 0{
-            self.extent_remove_space(inode_ref, new_blocks_cnt, EXT_MAX_BLOCKS)?;
+            if !inode_ref.inode.has_extents() {
+                if diff_blocks_cnt > 0 {
+                    self.indirect_remove_space(inode_ref, new_blocks_cnt)?;
+                }
+            } else if self.extent_find_next(inode_ref, new_blocks_cnt)?.is_some() {
+                // this also frees the blocks preallocated past the old size
+                self.extent_remove_space(inode_ref, new_blocks_cnt, EXT_MAX_BLOCKS)?;
+            }
+
+            inode_ref.inode.set_size(new_size);
+            self.write_back_inode(inode_ref)?;
+
+            Ok(EOK)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ext4_impls::test_utils::*;
+
+    #[test]
+    fn test_write_past_eof_leaves_hole() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+        let block_size = ext4.super_block.block_size() as usize;
+        let inode = create_file(&ext4, "file");
+        ext4.write_at(inode, 0, &pattern(100, 0)).unwrap();
+        let free = free_blocks(&ext4);
+
+        let offset = 10 * block_size + 100;
+        ext4.write_at(inode, offset, &pattern(200, 1)).unwrap();
+
+        // only the block written is allocated
+        assert_eq!(free_blocks(&ext4), free - 1);
+        let mapped: Vec<_> = file_extents(&ext4, inode)
+            .iter()
+            .map(|ex| (ex.first_block, ex.get_actual_len()))
+            .collect();
+        assert_eq!(mapped, [(0, 1), (10, 1)]);
+
+        let mut data = vec![0u8; offset + 200];
+        data[..100].copy_from_slice(&pattern(100, 0));
+        data[offset..].copy_from_slice(&pattern(200, 1));
+        assert_eq!(read_file(&ext4, inode), data);
+    }
+
+    #[test]
+    fn test_read_holes() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+        let block_size = ext4.super_block.block_size() as usize;
+        let inode = create_file(&ext4, "file");
+        // hole, data, hole, unwritten, hole up to the size
+        ext4.write_at(inode, 3 * block_size, &pattern(block_size, 0)).unwrap();
+        ext4.fallocate(inode, FALLOC_FL_KEEP_SIZE, 6 * block_size as u64, 2 * block_size as u64).unwrap();
+        let mut inode_ref = ext4.get_inode_ref(inode).unwrap();
+        ext4.truncate_inode(&mut inode_ref, 10 * block_size as u64).unwrap();
+
+        let mut data = vec![0u8; 10 * block_size];
+        data[3 * block_size..4 * block_size].copy_from_slice(&pattern(block_size, 0));
+        assert_eq!(read_file(&ext4, inode), data);
+
+        // unaligned reads starting and ending in holes
+        for (offset, len) in [(100, 3 * block_size), (3 * block_size + 10, 4 * block_size), (5 * block_size, 5 * block_size)] {
+            let mut buf = vec![0xffu8; len];
+            assert_eq!(ext4.read_at(inode, offset, &mut buf).unwrap(), len);
+            assert_eq!(buf, data[offset..offset + len], "{} {}", offset, len);
         }
+    }
 
-        inode_ref.inode.set_size(new_size);
-        self.write_back_inode(inode_ref);
+    #[test]
+    fn test_truncate_grow() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+        let block_size = ext4.super_block.block_size() as u64;
+        let inode = create_file(&ext4, "file");
+        ext4.write_at(inode, 0, &pattern(100, 0)).unwrap();
+        ext4.fallocate(inode, FALLOC_FL_KEEP_SIZE, 5 * block_size, block_size).unwrap();
+        let free = free_blocks(&ext4);
+
+        // growing maps nothing and keeps the blocks preallocated past the size
+        let mut inode_ref = ext4.get_inode_ref(inode).unwrap();
+        ext4.truncate_inode(&mut inode_ref, 20 * block_size).unwrap();
+        assert_eq!(free_blocks(&ext4), free);
+        assert_eq!(file_extents(&ext4, inode).len(), 2);
+        let mut data = vec![0u8; 20 * block_size as usize];
+        data[..100].copy_from_slice(&pattern(100, 0));
+        assert_eq!(read_file(&ext4, inode), data);
+
+        // shrinking back into the first block frees the preallocated one
+        let mut inode_ref = ext4.get_inode_ref(inode).unwrap();
+        ext4.truncate_inode(&mut inode_ref, 50).unwrap();
+        assert_eq!(free_blocks(&ext4), free + 1);
+        assert_eq!(read_file(&ext4, inode), data[..50]);
+    }
 
-        Ok(EOK)
+    #[test]
+    fn test_write_past_max_file_size() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+        let block_size = ext4.super_block.block_size() as usize;
+        let inode = create_file(&ext4, "file");
+        let max_size = EXT_MAX_BLOCKS as usize * block_size;
+        let free = free_blocks(&ext4);
+
+        let err = ext4.write_at(inode, max_size - 10, &pattern(20, 0)).unwrap_err();
+        assert_eq!(err.error(), Errno::EFBIG);
+        assert_eq!(free_blocks(&ext4), free);
+        assert_eq!(ext4.get_inode_ref(inode).unwrap().inode.size(), 0);
+
+        // the last byte can be written
+        ext4.write_at(inode, max_size - 10, &pattern(10, 0)).unwrap();
+        assert_eq!(ext4.get_inode_ref(inode).unwrap().inode.size(), max_size as u64);
+        let mut buf = vec![0u8; 10];
+        ext4.read_at(inode, max_size - 10, &mut buf).unwrap();
+        assert_eq!(buf, pattern(10, 0));
     }
 }
@@ -0,0 +1,764 @@
+use crate::prelude::*;
+use crate::return_errno_with_message;
+use crate::utils::*;
+
+use crate::ext4_defs::*;
+
+impl Ext4 {
+    /// Get the hash version of an indexed directory.
+    ///
+    /// The root only records legacy, half_md4 or tea, the superblock flags
+    /// tell whether names are hashed as signed or unsigned chars.
+    fn dx_hash_version(&self, root: &Ext4DxFrame) -> u8 {
+        let info: Ext4DxRootInfo = root.block.read_offset_as(EXT4_DX_ROOT_INFO_OFFSET);
+        let version = info.hash_version;
+        if version <= EXT4_DX_HASH_TEA && self.super_block.has_unsigned_hash() {
+            version + 3
+        } else {
+            version
+        }
+    }
+
+    /// Hash a name with the hash version of an indexed directory.
+    fn dx_name_hash(&self, version: u8, name: &[u8]) -> Result<u32> {
+        match ext4_dx_hash(name, version, &self.super_block.hash_seed()) {
+            Some((hash, _)) => Ok(hash),
+            None => return_errno_with_message!(Errno::ENOTSUP, "unsupported htree hash"),
+        }
+    }
+
+    /// Load an index block of a directory and check its count/limit header
+    /// and checksum.
+    ///
+    /// Params:
+    /// dir: &Ext4InodeRef - indexed directory
+    /// lblock: Ext4Lblk - logical block of the index block
+    /// entries_offset: usize - offset of the count/limit header in the block
+    ///
+    /// Returns:
+    /// `Result<Ext4DxFrame>` - the index block, positioned on its first entry
+    fn dx_load_frame(
+        &self,
+        dir: &Ext4InodeRef,
+        lblock: Ext4Lblk,
+        entries_offset: usize,
+    ) -> Result<Ext4DxFrame> {
+        let block_size = self.super_block.block_size() as usize;
+        let pblock = self.get_pblock_idx(dir, lblock)?;
+        let block = Block::load(self.block_device.clone(), pblock as usize * block_size, block_size)?;
+
+        let frame = Ext4DxFrame {
+            lblock,
+            block,
+            entries_offset,
+            position: 0,
+        };
+
+        let cl = frame.count_limit();
+        let max_entries = (block_size - entries_offset) / size_of::<Ext4DxEntry>();
+        if cl.count == 0 || cl.count > cl.limit || cl.limit as usize > max_entries {
+            return_errno_with_message!(Errno::EINVAL, "bad htree index block");
+        }
+
+        if self.super_block.has_metadata_csum() {
+            self.verify_csum("htree index", pblock, || {
+                let csum = Ext4DxTail::dx_csum(
+                    &self.super_block,
+                    dir.inode_num,
+                    dir.inode.generation(),
+                    &frame.block,
+                    entries_offset,
+                );
+                csum == Some(Ext4DxTail::stored_csum(&frame.block, entries_offset))
+            })?;
+        }
+
+        Ok(frame)
+    }
+
+    /// Walk the htree index of a directory down to the leaf that may hold `name`.
+    ///
+    /// Params:
+    /// dir: &Ext4InodeRef - indexed directory
+    /// name: &str - name to look up
+    ///
+    /// Returns:
+    /// `Result<(Vec<Ext4DxFrame>, u32)>` - index blocks from the root down, each
+    /// positioned on the entry followed, and the hash of `name`
+    pub fn dx_probe(&self, dir: &Ext4InodeRef, name: &str) -> Result<(Vec<Ext4DxFrame>, u32)> {
+        let root = self.dx_load_frame(dir, 0, EXT4_DX_ROOT_ENTRIES_OFFSET)?;
+        let info: Ext4DxRootInfo = root.block.read_offset_as(EXT4_DX_ROOT_INFO_OFFSET);
+        if info.info_length as usize != size_of::<Ext4DxRootInfo>()
+            || info.indirect_levels >= EXT4_HTREE_LEVEL
+        {
+            return_errno_with_message!(Errno::EINVAL, "bad htree root");
+        }
+
+        let hash = self.dx_name_hash(self.dx_hash_version(&root), name.as_bytes())?;
+
+        let mut frame = root;
+        let mut frames = Vec::new();
+        for level in 0..=info.indirect_levels {
+            frame.position = frame.binsearch(hash);
+            let next = frame.entry(frame.position).block();
+            frames.push(frame);
+            if level == info.indirect_levels {
+                break;
+            }
+            frame = self.dx_load_frame(dir, next, EXT4_DX_NODE_ENTRIES_OFFSET)?;
+        }
+
+        Ok((frames, hash))
+    }
+
+    /// Move an htree path to the next leaf block.
+    ///
+    /// Params:
+    /// dir: &Ext4InodeRef - indexed directory
+    /// frames: &mut [Ext4DxFrame] - path returned by `dx_probe`
+    ///
+    /// Returns:
+    /// `Result<Option<u32>>` - the starting hash of the next leaf, with the
+    /// collision bit, or `None` after the last leaf
+    pub fn dx_next_leaf(&self, dir: &Ext4InodeRef, frames: &mut [Ext4DxFrame]) -> Result<Option<u32>> {
+        // find the deepest level that has an entry left
+        let mut level = frames.len();
+        loop {
+            if level == 0 {
+                return Ok(None);
+            }
+            level -= 1;
+            let frame = &mut frames[level];
+            if frame.position + 1 < frame.count_limit().count as usize {
+                frame.position += 1;
+                break;
+            }
+        }
+        let next_hash = frames[level].entry(frames[level].position).hash;
+
+        // and reload the levels below it from their first entry
+        for i in level + 1..frames.len() {
+            let lblock = frames[i - 1].entry(frames[i - 1].position).block();
+            frames[i] = self.dx_load_frame(dir, lblock, EXT4_DX_NODE_ENTRIES_OFFSET)?;
+        }
+
+        Ok(Some(next_hash))
+    }
+
+    /// Find a directory entry through the htree index.
+    ///
+    /// Params:
+    /// dir: &Ext4InodeRef - indexed directory
+    /// name: &str - name of the entry to find
+    /// result: &mut Ext4DirSearchResult - result of the search
+    ///
+    /// Returns:
+    /// `Result<usize>` - status of the search, `EINVAL` or `ENOTSUP` if the
+    /// index cannot be used
+    pub fn dx_find_entry(
+        &self,
+        dir: &Ext4InodeRef,
+        name: &str,
+        result: &mut Ext4DirSearchResult,
+    ) -> Result<usize> {
+        let (mut frames, hash) = self.dx_probe(dir, name)?;
+
+        loop {
+            let leaf = frames.last().unwrap();
+            let pblock = self.get_pblock_idx(dir, leaf.entry(leaf.position).block())?;
+            let block = self.dir_load_block(dir, pblock)?;
+            if self.dir_find_in_block(&block, name, result).is_ok() {
+                result.pblock_id = pblock as usize;
+                return Ok(EOK);
+            }
+
+            // names with the same hash may continue in the next leaf,
+            // its index entry then has the collision bit set
+            match self.dx_next_leaf(dir, &mut frames)? {
+                Some(next_hash) if next_hash & 1 != 0 && next_hash & !1 == hash => continue,
+                _ => break,
+            }
+        }
+
+        return_errno_with_message!(Errno::ENOENT, "dir search fail");
+    }
+
+    /// Size of the checksum tail at the end of a directory leaf block.
+    fn dir_tail_size(&self) -> usize {
+        if self.super_block.has_metadata_csum() {
+            size_of::<Ext4DirEntryTail>()
+        } else {
+            0
+        }
+    }
+
+    /// Maximum number of entries in an index block.
+    fn dx_limit(&self, entries_offset: usize) -> u16 {
+        let block_size = self.super_block.block_size() as usize;
+        let mut space = block_size - entries_offset;
+        if self.super_block.has_metadata_csum() {
+            space -= size_of::<Ext4DxTail>();
+        }
+        (space / size_of::<Ext4DxEntry>()) as u16
+    }
+
+    /// Update the checksum of an index block and write it back.
+    fn dx_sync_frame(&self, dir: &Ext4InodeRef, frame: &mut Ext4DxFrame) -> Result<()> {
+        if self.super_block.has_metadata_csum() {
+            Ext4DxTail::set_dx_csum(
+                &self.super_block,
+                dir.inode_num,
+                dir.inode.generation(),
+                &mut frame.block,
+                frame.entries_offset,
+            );
+        }
+        frame.block.sync_blk_to_disk(self.block_device.clone())
+    }
+
+    /// Collect the live entries of a leaf block with their name hashes.
+    ///
+    /// Returns:
+    /// `Result<Vec<(u32, Vec<u8>)>>` - hash and raw bytes (header and name) of each entry
+    fn dx_leaf_entries(&self, block: &Block, hash_version: u8) -> Result<Vec<(u32, Vec<u8>)>> {
+        let end = block.data.len() - self.dir_tail_size();
+        let mut entries = Vec::new();
+
+        let mut offset = 0;
+        while offset + size_of::<Ext4FakeDirEntry>() <= end {
+            let de: Ext4DirEntry = block.read_offset_as(offset);
+            if de.entry_len() == 0 {
+                return_errno_with_message!(Errno::EINVAL, "bad directory entry");
+            }
+            if !de.unused() {
+                let hash = self.dx_name_hash(hash_version, &de.name[..de.get_name_len()])?;
+                entries.push((hash, block.data[offset..offset + de.actual_len()].to_vec()));
+            }
+            offset += de.entry_len() as usize;
+        }
+
+        Ok(entries)
+    }
+
+    /// Lay out entries in a leaf block, the last one takes the remaining space.
+    ///
+    /// Params:
+    /// dir: &Ext4InodeRef - directory owning the block
+    /// block: &mut Block - leaf block, fully rewritten
+    /// entries: &[(u32, Vec<u8>)] - entries as returned by `dx_leaf_entries`
+    fn dx_fill_leaf(&self, dir: &Ext4InodeRef, block: &mut Block, entries: &[(u32, Vec<u8>)]) {
+        let end = block.data.len() - self.dir_tail_size();
+        block.data.fill(0);
+
+        let mut offset = 0;
+        let mut last = None;
+        for (_, raw) in entries {
+            let rec_len = raw.len().next_multiple_of(4);
+            block.data[offset..offset + raw.len()].copy_from_slice(raw);
+            // drop any slack the entry covered in its old block
+            let de: &mut Ext4DirEntry = block.read_offset_as_mut(offset);
+            de.entry_len = rec_len as u16;
+            last = Some(offset);
+            offset += rec_len;
+        }
+
+        // the last entry, or an empty one, covers the rest of the block
+        let last = last.unwrap_or(0);
+        let de: &mut Ext4DirEntry = block.read_offset_as_mut(last);
+        de.entry_len = (end - last) as u16;
+
+        if self.super_block.has_metadata_csum() {
+            Ext4DirEntryTail::new().copy_to_slice(&mut block.data);
+            self.dir_set_csum(dir, block);
+        }
+    }
+
+    /// Make room for one more entry in the last index block of a path,
+    /// splitting an index node or adding an index level as needed.
+    ///
+    /// Params:
+    /// dir: &mut Ext4InodeRef - indexed directory
+    /// frames: &mut Vec<Ext4DxFrame> - path returned by `dx_probe`, updated
+    /// to go through the block now holding the leaf entry
+    fn dx_make_room(&self, dir: &mut Ext4InodeRef, frames: &mut Vec<Ext4DxFrame>) -> Result<()> {
+        let leaf_frame = frames.last().unwrap();
+        let cl = leaf_frame.count_limit();
+        if cl.count < cl.limit {
+            return Ok(());
+        }
+
+        let block_size = self.super_block.block_size() as usize;
+        let node_limit = self.dx_limit(EXT4_DX_NODE_ENTRIES_OFFSET);
+
+        if frames.len() == 1 {
+            // the root is full: move its entries into a new node and
+            // add an index level
+            let new_lblock = (dir.inode.size() / block_size as u64) as Ext4Lblk;
+            let new_pblock = self.append_inode_pblk(dir)?;
+
+            let root = &mut frames[0];
+            let count = root.count_limit().count as usize;
+            let mut node = Ext4DxFrame {
+                lblock: new_lblock,
+                block: Block {
+                    disk_offset: new_pblock as usize * block_size,
+                    data: vec![0u8; block_size],
+                },
+                entries_offset: EXT4_DX_NODE_ENTRIES_OFFSET,
+                position: root.position,
+            };
+
+            // an empty entry covering the whole block hides the index from linear scans
+            let fake: &mut Ext4DirEntry = node.block.read_offset_as_mut(0);
+            fake.entry_len = block_size as u16;
+
+            let entries_len = count * size_of::<Ext4DxEntry>();
+            let src = root.entries_offset;
+            let dst = node.entries_offset;
+            node.block.data[dst..dst + entries_len].copy_from_slice(&root.block.data[src..src + entries_len]);
+            let node_cl: &mut Ext4DxCountLimit = node.block.read_offset_as_mut(dst);
+            node_cl.limit = node_limit;
+            node_cl.count = count as u16;
+
+            root.set_count(1);
+            root.set_entry(0, Ext4DxEntry { hash: 0, block: new_lblock });
+            root.position = 0;
+            let info: &mut Ext4DxRootInfo = root.block.read_offset_as_mut(EXT4_DX_ROOT_INFO_OFFSET);
+            info.indirect_levels += 1;
+
+            self.dx_sync_frame(dir, &mut node)?;
+            self.dx_sync_frame(dir, &mut frames[0])?;
+            frames.push(node);
+
+            return Ok(());
+        }
+
+        // an index node is full: split it in two, which needs room in its parent
+        let parent_cl = frames[frames.len() - 2].count_limit();
+        if parent_cl.count >= parent_cl.limit {
+            return_errno_with_message!(Errno::ENOSPC, "directory index is full");
+        }
+
+        let new_lblock = (dir.inode.size() / block_size as u64) as Ext4Lblk;
+        let new_pblock = self.append_inode_pblk(dir)?;
+
+        let mut node = frames.pop().unwrap();
+        let count = node.count_limit().count as usize;
+        let keep = count / 2;
+        let moved = count - keep;
+
+        let mut new_node = Ext4DxFrame {
+            lblock: new_lblock,
+            block: Block {
+                disk_offset: new_pblock as usize * block_size,
+                data: vec![0u8; block_size],
+            },
+            entries_offset: EXT4_DX_NODE_ENTRIES_OFFSET,
+            position: 0,
+        };
+        let fake: &mut Ext4DirEntry = new_node.block.read_offset_as_mut(0);
+        fake.entry_len = block_size as u16;
+
+        let src = node.entries_offset + keep * size_of::<Ext4DxEntry>();
+        let dst = new_node.entries_offset;
+        let len = moved * size_of::<Ext4DxEntry>();
+        new_node.block.data[dst..dst + len].copy_from_slice(&node.block.data[src..src + len]);
+        let new_cl: &mut Ext4DxCountLimit = new_node.block.read_offset_as_mut(dst);
+        new_cl.limit = node_limit;
+        new_cl.count = moved as u16;
+
+        let split_hash = node.entry(keep).hash;
+        node.set_count(keep as u16);
+
+        let parent = frames.last_mut().unwrap();
+        let parent_pos = parent.position;
+        parent.insert_entry(parent_pos + 1, Ext4DxEntry { hash: split_hash, block: new_lblock });
+
+        self.dx_sync_frame(dir, &mut node)?;
+        self.dx_sync_frame(dir, &mut new_node)?;
+        self.dx_sync_frame(dir, frames.last_mut().unwrap())?;
+
+        // keep following the half that holds the leaf
+        if node.position >= keep {
+            new_node.position = node.position - keep;
+            frames.last_mut().unwrap().position += 1;
+            frames.push(new_node);
+        } else {
+            frames.push(node);
+        }
+
+        Ok(())
+    }
+
+    /// Add an entry to an indexed directory, splitting the leaf if it is full.
+    ///
+    /// Params:
+    /// dir: &mut Ext4InodeRef - indexed directory
+    /// child: &Ext4InodeRef - inode the new entry points to
+    /// name: &str - name of the new entry
+    ///
+    /// Returns:
+    /// `Result<usize>` - status of the operation
+    pub fn dx_add_entry(&self, dir: &mut Ext4InodeRef, child: &Ext4InodeRef, name: &str) -> Result<usize> {
+        let (mut frames, hash) = self.dx_probe(dir, name)?;
+
+        let leaf_frame = frames.last().unwrap();
+        let leaf_lblock = leaf_frame.entry(leaf_frame.position).block();
+        let pblock = self.get_pblock_idx(dir, leaf_lblock)?;
+        let mut leaf = self.dir_load_block(dir, pblock)?;
+        if self.try_insert_to_existing_block(&mut leaf, name, child).is_ok() {
+            self.dir_set_csum(dir, &mut leaf);
+            leaf.sync_blk_to_disk(self.block_device.clone())?;
+            return Ok(EOK);
+        }
+
+        // the leaf is full, split it by hash into a new block
+        let hash_version = self.dx_hash_version(&frames[0]);
+        self.dx_make_room(dir, &mut frames)?;
+
+        let mut entries = self.dx_leaf_entries(&leaf, hash_version)?;
+        if entries.len() < 2 {
+            return_errno_with_message!(Errno::ENOSPC, "No space in block for new entry");
+        }
+        entries.sort_by_key(|(hash, _)| *hash);
+
+        // move about half of the bytes to the new leaf
+        let total: usize = entries.iter().map(|(_, raw)| raw.len()).sum();
+        let mut split = 0;
+        let mut size = 0;
+        while split < entries.len() - 1 && size < total / 2 {
+            size += entries[split].1.len();
+            split += 1;
+        }
+        let split = split.max(1);
+        let mut split_hash = entries[split].0;
+        // names with the same hash end up in both leaves, flag the collision
+        if split_hash == entries[split - 1].0 {
+            split_hash |= 1;
+        }
+
+        let block_size = self.super_block.block_size() as usize;
+        let new_lblock = (dir.inode.size() / block_size as u64) as Ext4Lblk;
+        let new_pblock = self.append_inode_pblk(dir)?;
+        let mut new_leaf = Block {
+            disk_offset: new_pblock as usize * block_size,
+            data: vec![0u8; block_size],
+        };
+
+        self.dx_fill_leaf(dir, &mut leaf, &entries[..split]);
+        self.dx_fill_leaf(dir, &mut new_leaf, &entries[split..]);
+
+        let index = frames.last_mut().unwrap();
+        let pos = index.position;
+        index.insert_entry(pos + 1, Ext4DxEntry { hash: split_hash, block: new_lblock });
+        self.dx_sync_frame(dir, index)?;
+
+        let target = if hash >= (split_hash & !1) { &mut new_leaf } else { &mut leaf };
+        self.try_insert_to_existing_block(target, name, child)?;
+        self.dir_set_csum(dir, target);
+
+        leaf.sync_blk_to_disk(self.block_device.clone())?;
+        new_leaf.sync_blk_to_disk(self.block_device.clone())?;
+
+        Ok(EOK)
+    }
+
+    /// Turn a full single block directory into an indexed one, the way
+    /// Linux does when the directory grows past its first block.
+    ///
+    /// The entries after "." and ".." move to a new leaf block and block 0
+    /// becomes the dx_root pointing to it.
+    ///
+    /// Params:
+    /// dir: &mut Ext4InodeRef - directory to index
+    ///
+    /// Returns:
+    /// `Result<bool>` - false if block 0 does not start with "." and ".."
+    pub fn dx_make_indexed(&self, dir: &mut Ext4InodeRef) -> Result<bool> {
+        let block_size = self.super_block.block_size() as usize;
+        let pblock = self.get_pblock_idx(dir, 0)?;
+        let mut root = self.dir_load_block(dir, pblock)?;
+
+        let dot: Ext4DirEntry = root.read_offset_as(0);
+        let dotdot: Ext4DirEntry = root.read_offset_as(12);
+        if dot.entry_len() != 12 || !dot.compare_name(".") || !dotdot.compare_name("..") {
+            return Ok(false);
+        }
+
+        // entries after ".." go to the new leaf
+        let end = block_size - self.dir_tail_size();
+        let mut entries = Vec::new();
+        let mut offset = 12 + dotdot.entry_len() as usize;
+        while offset + size_of::<Ext4FakeDirEntry>() <= end {
+            let de: Ext4DirEntry = root.read_offset_as(offset);
+            if de.entry_len() == 0 {
+                return_errno_with_message!(Errno::EINVAL, "bad directory entry");
+            }
+            if !de.unused() {
+                entries.push((0, root.data[offset..offset + de.actual_len()].to_vec()));
+            }
+            offset += de.entry_len() as usize;
+        }
+
+        let leaf_lblock = (dir.inode.size() / block_size as u64) as Ext4Lblk;
+        let leaf_pblock = self.append_inode_pblk(dir)?;
+        let mut leaf = Block {
+            disk_offset: leaf_pblock as usize * block_size,
+            data: vec![0u8; block_size],
+        };
+        self.dx_fill_leaf(dir, &mut leaf, &entries);
+        leaf.sync_blk_to_disk(self.block_device.clone())?;
+
+        // rewrite block 0 as dx_root: "." and ".." then the index
+        root.data[24..].fill(0);
+        let dotdot: &mut Ext4DirEntry = root.read_offset_as_mut(12);
+        dotdot.entry_len = (block_size - 12) as u16;
+        let info: &mut Ext4DxRootInfo = root.read_offset_as_mut(EXT4_DX_ROOT_INFO_OFFSET);
+        info.hash_version = self.super_block.default_hash_version();
+        info.info_length = size_of::<Ext4DxRootInfo>() as u8;
+
+        let mut frame = Ext4DxFrame {
+            lblock: 0,
+            block: root,
+            entries_offset: EXT4_DX_ROOT_ENTRIES_OFFSET,
+            position: 0,
+        };
+        let cl: &mut Ext4DxCountLimit = frame.block.read_offset_as_mut(EXT4_DX_ROOT_ENTRIES_OFFSET);
+        cl.limit = self.dx_limit(EXT4_DX_ROOT_ENTRIES_OFFSET);
+        cl.count = 1;
+        frame.set_entry(0, Ext4DxEntry { hash: 0, block: leaf_lblock });
+        self.dx_sync_frame(dir, &mut frame)?;
+
+        let flags = dir.inode.flags() | EXT4_INODE_FLAG_INDEX;
+        dir.inode.set_flags(flags);
+        self.write_back_inode(dir)?;
+
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ext4_impls::test_utils::*;
+    use alloc::format;
+
+    /// Name `i` of a test directory, zero padded to `len` bytes.
+    fn entry_name(i: usize, len: usize) -> String {
+        format!("{:0>len$}", i, len = len)
+    }
+
+    fn create_in(ext4: &Ext4, dir: u32, name: &str) -> u32 {
+        let mode = InodeFileType::S_IFREG.bits() | 0o644;
+        ext4.create(dir, name, mode).unwrap().inode_num
+    }
+
+    fn indirect_levels(ext4: &Ext4, dir: u32) -> u8 {
+        let dir_ref = ext4.get_inode_ref(dir).unwrap();
+        let root = ext4.dx_load_frame(&dir_ref, 0, EXT4_DX_ROOT_ENTRIES_OFFSET).unwrap();
+        let info: Ext4DxRootInfo = root.block.read_offset_as(EXT4_DX_ROOT_INFO_OFFSET);
+        info.indirect_levels
+    }
+
+    /// Walk the leaves of an indexed directory in hash order, checking that
+    /// the names of each leaf hash into the range of its index entry.
+    /// Returns the names of each leaf.
+    fn dx_walk(ext4: &Ext4, dir: u32) -> Vec<Vec<String>> {
+        let dir_ref = ext4.get_inode_ref(dir).unwrap();
+        assert!(dir_ref.inode.is_indexed());
+        let root = ext4.dx_load_frame(&dir_ref, 0, EXT4_DX_ROOT_ENTRIES_OFFSET).unwrap();
+        let hash_version = ext4.dx_hash_version(&root);
+        let mut frames = vec![root];
+        for _ in 0..indirect_levels(ext4, dir) {
+            let lblock = frames.last().unwrap().entry(0).block();
+            frames.push(ext4.dx_load_frame(&dir_ref, lblock, EXT4_DX_NODE_ENTRIES_OFFSET).unwrap());
+        }
+
+        let mut leaves = Vec::new();
+        let mut start = 0;
+        loop {
+            let frame = frames.last().unwrap();
+            let pblock = ext4.get_pblock_idx(&dir_ref, frame.entry(frame.position).block()).unwrap();
+            let leaf = ext4.dir_load_block(&dir_ref, pblock).unwrap();
+            let next = ext4.dx_next_leaf(&dir_ref, &mut frames).unwrap();
+
+            let mut names = Vec::new();
+            for (hash, raw) in ext4.dx_leaf_entries(&leaf, hash_version).unwrap() {
+                assert!(hash >= start & !1);
+                if let Some(next) = next {
+                    // a name may reach the next leaf only with the collision bit
+                    assert!(hash < next & !1 || (hash == next & !1 && next & 1 != 0));
+                }
+                let name_len = raw[6] as usize;
+                names.push(String::from_utf8(raw[8..8 + name_len].to_vec()).unwrap());
+            }
+            leaves.push(names);
+            match next {
+                Some(next) => start = next,
+                None => return leaves,
+            }
+        }
+    }
+
+    /// All names of an indexed directory, sorted.
+    fn dx_names(ext4: &Ext4, dir: u32) -> Vec<String> {
+        let mut names: Vec<String> = dx_walk(ext4, dir).into_iter().flatten().collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_dx_make_indexed() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+        let block_size = ext4.super_block.block_size() as u64;
+        ext4.dir_mk("d").unwrap();
+        let dir = lookup(&ext4, "d").unwrap();
+
+        // fill the first block, the directory stays linear
+        let mut names = Vec::new();
+        let mut inodes = Vec::new();
+        loop {
+            let name = entry_name(names.len(), 12);
+            inodes.push(create_in(&ext4, dir, &name));
+            names.push(name);
+            let dir_ref = ext4.get_inode_ref(dir).unwrap();
+            if dir_ref.inode.is_indexed() {
+                break;
+            }
+            assert_eq!(dir_ref.inode.size(), block_size);
+        }
+
+        // the entry that did not fit made block 0 the root of one leaf
+        // holding all the entries
+        assert_eq!(indirect_levels(&ext4, dir), 0);
+        assert_eq!(dx_walk(&ext4, dir).len(), 1);
+        assert_eq!(ext4.get_inode_ref(dir).unwrap().inode.size(), 2 * block_size);
+        names.sort();
+        assert_eq!(dx_names(&ext4, dir), names);
+        drop(ext4);
+
+        let ext4 = mount(&disk);
+        for (i, inode) in inodes.iter().enumerate() {
+            assert_eq!(lookup(&ext4, &format!("d/{}", entry_name(i, 12))).unwrap(), *inode);
+        }
+        assert_eq!(lookup(&ext4, "d/.").unwrap(), dir);
+        assert_eq!(lookup(&ext4, "d/..").unwrap(), ROOT_INODE);
+        assert_eq!(lookup(&ext4, "d/missing").unwrap_err().error(), Errno::ENOENT);
+    }
+
+    #[test]
+    fn test_dx_second_level() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+        ext4.dir_mk("d").unwrap();
+        let dir = lookup(&ext4, "d").unwrap();
+        let root_limit = ext4.dx_limit(EXT4_DX_ROOT_ENTRIES_OFFSET) as usize;
+
+        // long names fill leaves fast, every split adds a root entry until
+        // the root is full and moves to an index node
+        let mut count = 0;
+        while count < 1000 {
+            create_in(&ext4, dir, &entry_name(count, 200));
+            count += 1;
+            if ext4.get_inode_ref(dir).unwrap().inode.is_indexed() && indirect_levels(&ext4, dir) == 1 {
+                break;
+            }
+        }
+        assert_eq!(indirect_levels(&ext4, dir), 1);
+        let leaves = dx_walk(&ext4, dir).len();
+        assert_eq!(leaves, root_limit + 1);
+
+        // and the index node splits once full too
+        for i in count..count + 300 {
+            create_in(&ext4, dir, &entry_name(i, 200));
+        }
+        count += 300;
+        let dir_ref = ext4.get_inode_ref(dir).unwrap();
+        let root = ext4.dx_load_frame(&dir_ref, 0, EXT4_DX_ROOT_ENTRIES_OFFSET).unwrap();
+        assert_eq!(root.count_limit().count, 2);
+        assert_eq!(indirect_levels(&ext4, dir), 1);
+
+        let mut names: Vec<String> = (0..count).map(|i| entry_name(i, 200)).collect();
+        names.sort();
+        assert_eq!(dx_names(&ext4, dir), names);
+        drop(ext4);
+
+        let ext4 = mount(&disk);
+        for name in &names {
+            lookup(&ext4, &format!("d/{}", name)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_dx_remove_lookup() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+        ext4.dir_mk("d").unwrap();
+        let dir = lookup(&ext4, "d").unwrap();
+        for i in 0..300 {
+            create_in(&ext4, dir, &entry_name(i, 16));
+        }
+        assert!(dx_walk(&ext4, dir).len() > 2);
+
+        for i in (0..300).step_by(2) {
+            ext4.file_remove(&format!("d/{}", entry_name(i, 16))).unwrap();
+        }
+        for i in 0..300 {
+            let found = lookup(&ext4, &format!("d/{}", entry_name(i, 16)));
+            if i % 2 == 0 {
+                assert_eq!(found.unwrap_err().error(), Errno::ENOENT);
+            } else {
+                found.unwrap();
+            }
+        }
+        let mut names: Vec<String> = (1..300).step_by(2).map(|i| entry_name(i, 16)).collect();
+        names.sort();
+        assert_eq!(dx_names(&ext4, dir), names);
+
+        // the freed room is reused
+        let size = ext4.get_inode_ref(dir).unwrap().inode.size();
+        for i in (0..300).step_by(2) {
+            create_in(&ext4, dir, &entry_name(i, 16));
+        }
+        assert_eq!(ext4.get_inode_ref(dir).unwrap().inode.size(), size);
+        drop(ext4);
+
+        let ext4 = mount(&disk);
+        for i in 0..300 {
+            lookup(&ext4, &format!("d/{}", entry_name(i, 16))).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_linux_htree() {
+        let disk = MemDisk::new(LINUX_IMAGE);
+        let ext4 = mount(&disk);
+        let dir = lookup(&ext4, "htree").unwrap();
+        let mut names: Vec<String> = (0..500).map(|i| format!("file{:04}", i)).collect();
+        assert_eq!(dx_names(&ext4, dir), names);
+        assert!(dx_walk(&ext4, dir).len() > 1);
+        for name in &names {
+            lookup(&ext4, &format!("htree/{}", name)).unwrap();
+        }
+        assert_eq!(lookup(&ext4, "htree/file0500").unwrap_err().error(), Errno::ENOENT);
+
+        // the leaves made by Linux take new entries and splits
+        for i in 500..1000 {
+            let name = format!("file{:04}", i);
+            create_in(&ext4, dir, &name);
+            names.push(name);
+        }
+        for name in names.drain(..100) {
+            ext4.file_remove(&format!("htree/{}", name)).unwrap();
+        }
+        drop(ext4);
+
+        let ext4 = mount(&disk);
+        assert_eq!(dx_names(&ext4, dir), names);
+        for name in &names {
+            lookup(&ext4, &format!("htree/{}", name)).unwrap();
+        }
+        assert_eq!(lookup(&ext4, "htree/file0000").unwrap_err().error(), Errno::ENOENT);
+    }
+}
@@ -4,10 +4,55 @@ use crate::return_errno_with_message;
 use crate::utils::bitmap::*;
 
 impl Ext4 {
+    /// Load the inode bitmap of a block group.
+    ///
+    /// A group flagged INODE_UNINIT has no bitmap on disk yet, so an empty
+    /// one is returned and the flag is cleared. The caller is responsible
+    /// for writing back both the bitmap and the descriptor.
+    ///
+    /// Params:
+    /// `bg` - Block group descriptor.
+    /// `bgid` - Block group index.
+    ///
+    /// Returns:
+    /// `Result<Block>` - The inode bitmap.
+    pub fn load_inode_bitmap(&self, bg: &mut Ext4BlockGroup, bgid: u32) -> Result<Block> {
+        let super_block = &self.super_block;
+        let block_size = super_block.block_size() as usize;
+        let bmp_blk_adr = bg.get_inode_bitmap_block(super_block);
+
+        if bg.is_inode_uninit() {
+            bg.clear_inode_uninit();
+            let mut data = vec![0u8; block_size];
+            // padding past the inodes of the group, like ext4_mark_bitmap_end
+            let inodes_per_group = super_block.inodes_per_group();
+            let bitmap_bits = (block_size * 8) as u32;
+            if inodes_per_group < bitmap_bits {
+                ext4_bmap_bits_set(&mut data, inodes_per_group, bitmap_bits - 1);
+            }
+            return Ok(Block {
+                disk_offset: bmp_blk_adr as usize * block_size,
+                data,
+            });
+        }
+
+        let bitmap_block = Block::load(
+            self.block_device.clone(),
+            bmp_blk_adr as usize * block_size,
+            block_size,
+        )?;
+        self.verify_csum("inode bitmap", bgid as u64, || {
+            bg.verify_inode_bitmap_csum(super_block, &bitmap_block.data)
+        })?;
+        Ok(bitmap_block)
+    }
+
     pub fn ialloc_alloc_inode(&self, is_dir: bool) -> Result<u32> {
+        self.check_writable()?;
+
         let mut bgid = 0;
         let bg_count = self.super_block.block_group_count();
-        let mut super_block = self.super_block;
+        let mut super_block = Ext4Superblock::load(self.block_device.clone())?;
 
         while bgid <= bg_count {
             if bgid == bg_count {
@@ -15,17 +60,15 @@ impl Ext4 {
                 continue;
             }
 
-            let mut bg =
-                Ext4BlockGroup::load_new(self.block_device.clone(), &super_block, bgid as usize);
+            let mut bg = self.load_block_group(bgid)?;
 
             let mut free_inodes = bg.get_free_inodes_count();
 
             if free_inodes > 0 {
                 let inode_bitmap_block = bg.get_inode_bitmap_block(&super_block);
 
-                let mut raw_data = self
-                    .block_device
-                    .read_offset(inode_bitmap_block as usize * BLOCK_SIZE);
+                let block_size = super_block.block_size() as usize;
+                let mut raw_data = self.load_inode_bitmap(&mut bg, bgid)?.data;
 
                 let inodes_in_bg = super_block.get_inodes_in_group_cnt(bgid);
 
@@ -38,7 +81,7 @@ impl Ext4 {
 
                 // update bitmap in disk
                 self.block_device
-                    .write_offset(inode_bitmap_block as usize * BLOCK_SIZE, bitmap_data);
+                    .write_offset(inode_bitmap_block as usize * block_size, bitmap_data)?;
 
                 bg.set_block_group_ialloc_bitmap_csum(&super_block, bitmap_data);
 
@@ -60,11 +103,11 @@ impl Ext4 {
                     bg.set_itable_unused(&super_block, unused);
                 }
 
-                bg.sync_to_disk_with_csum(self.block_device.clone(), bgid as usize, &super_block);
+                bg.sync_to_disk_with_csum(self.block_device.clone(), bgid as usize, &super_block)?;
 
                 /* Update superblock */
                 super_block.decrease_free_inodes_count();
-                super_block.sync_to_disk_with_csum(self.block_device.clone());
+                super_block.sync_to_disk_with_csum(self.block_device.clone())?;
 
                 /* Compute the absolute i-nodex number */
                 let inodes_per_group = super_block.inodes_per_group();
@@ -79,20 +122,20 @@ impl Ext4 {
         return_errno_with_message!(Errno::ENOSPC, "alloc inode fail");
     }
 
-    pub fn ialloc_free_inode(&self, index: u32, is_dir: bool) {
+    pub fn ialloc_free_inode(&self, index: u32, is_dir: bool) -> Result<()> {
+        self.check_writable()?;
+
         // Compute index of block group
         let bgid = self.get_bgid_of_inode(index);
         let block_device = self.block_device.clone();
 
-        let mut super_block = self.super_block;
-        let mut bg =
-            Ext4BlockGroup::load_new(self.block_device.clone(), &super_block, bgid as usize);
+        let mut super_block = Ext4Superblock::load(self.block_device.clone())?;
+        let mut bg = self.load_block_group(bgid)?;
 
         // Load inode bitmap block
         let inode_bitmap_block = bg.get_inode_bitmap_block(&self.super_block);
-        let mut bitmap_data = self
-            .block_device
-            .read_offset(inode_bitmap_block as usize * BLOCK_SIZE);
+        let block_size = super_block.block_size() as usize;
+        let mut bitmap_data = self.load_inode_bitmap(&mut bg, bgid)?.data;
 
         // Find index within group and clear bit
         let index_in_group = self.inode_to_bgidx(index);
@@ -101,7 +144,7 @@ impl Ext4 {
         // Set new checksum after modification
         // update bitmap in disk
         self.block_device
-            .write_offset(inode_bitmap_block as usize * BLOCK_SIZE, &bitmap_data);
+            .write_offset(inode_bitmap_block as usize * block_size, &bitmap_data)?;
         bg.set_block_group_ialloc_bitmap_csum(&super_block, &bitmap_data);
 
         // Update free inodes count in block group
@@ -114,9 +157,52 @@ impl Ext4 {
             bg.set_used_dirs_count(&self.super_block, used_dirs);
         }
 
-        bg.sync_to_disk_with_csum(block_device.clone(), bgid as usize, &super_block);
+        bg.sync_to_disk_with_csum(block_device.clone(), bgid as usize, &super_block)?;
+
+        super_block.increase_free_inodes_count();
+        super_block.sync_to_disk_with_csum(self.block_device.clone())
+    }
+}
 
-        super_block.decrease_free_inodes_count();
-        super_block.sync_to_disk_with_csum(self.block_device.clone());
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ext4_impls::test_utils::*;
+    use alloc::format;
+
+    #[test]
+    fn test_uninit_inode_bitmap_marks_end() {
+        let disk = MemDisk::new(GROUPS_IMAGE);
+        let ext4 = mount(&disk);
+        let super_block = &ext4.super_block;
+        let block_size = super_block.block_size() as usize;
+        let inodes_per_group = super_block.inodes_per_group();
+        assert!(inodes_per_group < block_size as u32 * 8);
+        assert!(ext4.load_block_group(1).unwrap().is_inode_uninit());
+
+        // fill group 0, the next inode is the first of group 1
+        let mut inode = 0;
+        for i in 0..inodes_per_group {
+            inode = create_file(&ext4, &format!("f{}", i));
+            if inode > inodes_per_group {
+                break;
+            }
+        }
+        assert_eq!(inode, inodes_per_group + 1);
+
+        let bg = ext4.load_block_group(1).unwrap();
+        assert!(!bg.is_inode_uninit());
+        let bitmap_block = bg.get_inode_bitmap_block(super_block) as usize;
+        let bitmap = ext4.block_device.read_offset(bitmap_block * block_size).unwrap();
+        let used = inodes_per_group as usize / 8;
+        assert_eq!(bitmap[0], 1);
+        assert!(bitmap[1..used].iter().all(|&b| b == 0));
+        assert!(bitmap[used..block_size].iter().all(|&b| b == 0xff));
+        drop(ext4);
+
+        // the checksum only covers the inodes of the group
+        let ext4 = mount(&disk);
+        assert!(inode_in_use(&ext4, inode));
+        assert_eq!(create_file(&ext4, "next"), inode + 1);
     }
 }
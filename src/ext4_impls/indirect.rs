@@ -0,0 +1,385 @@
+use crate::prelude::*;
+use crate::return_errno_with_message;
+
+use crate::ext4_defs::*;
+
+/// Legacy block map of inodes without `EXT4_INODE_FLAG_EXTENTS`.
+///
+/// `i_block` holds `EXT4_NDIR_BLOCKS` direct pointers, then the single,
+/// double and triple indirect blocks. An indirect block is an array of
+/// little endian block numbers, 0 marks a hole.
+impl Ext4 {
+    /// Number of block pointers in one indirect block.
+    fn indirect_per_block(&self) -> u64 {
+        self.super_block.block_size() as u64 / 4
+    }
+
+    /// Compute the slots leading to a logical block: the `i_block` slot,
+    /// then one index per indirect level.
+    ///
+    /// Params:
+    /// lblock: Ext4Lblk - logical block id
+    ///
+    /// Returns:
+    /// `Result<Vec<usize>>` - the slots, `EFBIG` past the triple indirect block
+    fn indirect_path(&self, lblock: Ext4Lblk) -> Result<Vec<usize>> {
+        let apb = self.indirect_per_block();
+        let mut l = lblock as u64;
+
+        if l < EXT4_NDIR_BLOCKS as u64 {
+            return Ok(vec![l as usize]);
+        }
+        l -= EXT4_NDIR_BLOCKS as u64;
+        if l < apb {
+            return Ok(vec![EXT4_IND_BLOCK, l as usize]);
+        }
+        l -= apb;
+        if l < apb * apb {
+            return Ok(vec![EXT4_DIND_BLOCK, (l / apb) as usize, (l % apb) as usize]);
+        }
+        l -= apb * apb;
+        if l < apb * apb * apb {
+            return Ok(vec![
+                EXT4_TIND_BLOCK,
+                (l / (apb * apb)) as usize,
+                ((l / apb) % apb) as usize,
+                (l % apb) as usize,
+            ]);
+        }
+
+        return_errno_with_message!(Errno::EFBIG, "block beyond the indirect block map");
+    }
+
+    fn indirect_load(&self, pblock: Ext4Fsblk) -> Result<Block> {
+        let block_size = self.super_block.block_size() as usize;
+        Block::load(self.block_device.clone(), pblock as usize * block_size, block_size)
+    }
+
+    /// Get the physical block of a logical block through the block map.
+    ///
+    /// Params:
+    /// inode_ref: &Ext4InodeRef - inode reference
+    /// lblock: Ext4Lblk - logical block id
+    ///
+    /// Returns:
+    /// `Result<Ext4Fsblk>` - physical block id, 0 for a hole
+    pub fn indirect_get_pblock(&self, inode_ref: &Ext4InodeRef, lblock: Ext4Lblk) -> Result<Ext4Fsblk> {
+        let path = self.indirect_path(lblock)?;
+
+        let mut pblock = inode_ref.inode.block[path[0]] as Ext4Fsblk;
+        for &idx in &path[1..] {
+            if pblock == 0 {
+                return Ok(0);
+            }
+            pblock = self.indirect_load(pblock)?.read_offset_as::<u32>(idx * 4) as Ext4Fsblk;
+        }
+
+        Ok(pblock)
+    }
+
+    /// Map a logical block to a physical block, allocating the indirect
+    /// blocks on the way.
+    ///
+    /// Params:
+    /// inode_ref: &mut Ext4InodeRef - inode reference, written back
+    /// lblock: Ext4Lblk - logical block id
+    /// pblock: Ext4Fsblk - physical block id
+    pub fn indirect_map_block(
+        &self,
+        inode_ref: &mut Ext4InodeRef,
+        lblock: Ext4Lblk,
+        pblock: Ext4Fsblk,
+    ) -> Result<()> {
+        self.check_writable()?;
+
+        let path = self.indirect_path(lblock)?;
+        if path.len() == 1 {
+            inode_ref.inode.block[path[0]] = pblock as u32;
+            return self.write_back_inode(inode_ref);
+        }
+
+        let mut node = inode_ref.inode.block[path[0]] as Ext4Fsblk;
+        if node == 0 {
+            node = self.indirect_alloc_node(inode_ref, pblock)?;
+            inode_ref.inode.block[path[0]] = node as u32;
+            self.write_back_inode(inode_ref)?;
+        }
+
+        for (level, &idx) in path[1..].iter().enumerate() {
+            let mut block = self.indirect_load(node)?;
+            if level == path.len() - 2 {
+                *block.read_offset_as_mut::<u32>(idx * 4) = pblock as u32;
+                block.sync_blk_to_disk(self.block_device.clone())?;
+                break;
+            }
+
+            let mut next = block.read_offset_as::<u32>(idx * 4) as Ext4Fsblk;
+            if next == 0 {
+                next = self.indirect_alloc_node(inode_ref, pblock)?;
+                *block.read_offset_as_mut::<u32>(idx * 4) = next as u32;
+                block.sync_blk_to_disk(self.block_device.clone())?;
+            }
+            node = next;
+        }
+
+        Ok(())
+    }
+
+    /// Allocate a zeroed indirect block near `goal`.
+    fn indirect_alloc_node(&self, inode_ref: &mut Ext4InodeRef, goal: Ext4Fsblk) -> Result<Ext4Fsblk> {
+        let pblock = self.balloc_alloc_block(inode_ref, Some(goal))?;
+        let block_size = self.super_block.block_size() as usize;
+        let mut block = Block {
+            disk_offset: pblock as usize * block_size,
+            data: vec![0u8; block_size],
+        };
+        block.sync_blk_to_disk(self.block_device.clone())?;
+        Ok(pblock)
+    }
+
+    /// Free all blocks mapped at or past `from`, and the indirect blocks
+    /// left empty.
+    ///
+    /// Params:
+    /// inode_ref: &mut Ext4InodeRef - inode reference, written back
+    /// from: Ext4Lblk - first logical block to free
+    pub fn indirect_remove_space(&self, inode_ref: &mut Ext4InodeRef, from: Ext4Lblk) -> Result<()> {
+        self.check_writable()?;
+
+        let from = from as u64;
+        let apb = self.indirect_per_block();
+
+        for slot in 0..EXT4_NDIR_BLOCKS {
+            let pblock = inode_ref.inode.block[slot];
+            if slot as u64 >= from && pblock != 0 {
+                inode_ref.inode.block[slot] = 0;
+                self.balloc_free_blocks(inode_ref, pblock as Ext4Fsblk, 1)?;
+            }
+        }
+
+        let mut first = EXT4_NDIR_BLOCKS as u64;
+        let mut span = apb;
+        for (level, slot) in [EXT4_IND_BLOCK, EXT4_DIND_BLOCK, EXT4_TIND_BLOCK].into_iter().enumerate() {
+            let pblock = inode_ref.inode.block[slot] as Ext4Fsblk;
+            if pblock != 0 && self.indirect_free_tree(inode_ref, pblock, level as u32 + 1, first, from)? {
+                inode_ref.inode.block[slot] = 0;
+                self.balloc_free_blocks(inode_ref, pblock, 1)?;
+            }
+            first += span;
+            span *= apb;
+        }
+
+        self.write_back_inode(inode_ref)
+    }
+
+    /// Free the blocks at or past `from` below one indirect block.
+    ///
+    /// Params:
+    /// pblock: Ext4Fsblk - the indirect block
+    /// level: u32 - 1 if it points to data blocks, 2 or 3 for deeper trees
+    /// first: u64 - first logical block it covers
+    /// from: u64 - first logical block to free
+    ///
+    /// Returns:
+    /// `Result<bool>` - true if the indirect block is left empty, the caller frees it
+    fn indirect_free_tree(
+        &self,
+        inode_ref: &mut Ext4InodeRef,
+        pblock: Ext4Fsblk,
+        level: u32,
+        first: u64,
+        from: u64,
+    ) -> Result<bool> {
+        let apb = self.indirect_per_block();
+        let span = apb.pow(level - 1);
+
+        let mut block = self.indirect_load(pblock)?;
+        let mut changed = false;
+        let mut empty = true;
+
+        for idx in 0..apb as usize {
+            let child = block.read_offset_as::<u32>(idx * 4) as Ext4Fsblk;
+            if child == 0 {
+                continue;
+            }
+
+            let child_first = first + idx as u64 * span;
+            let freed = if child_first + span <= from {
+                false
+            } else if level == 1 {
+                true
+            } else {
+                self.indirect_free_tree(inode_ref, child, level - 1, child_first, from)?
+            };
+
+            if freed {
+                *block.read_offset_as_mut::<u32>(idx * 4) = 0;
+                self.balloc_free_blocks(inode_ref, child, 1)?;
+                changed = true;
+            } else {
+                empty = false;
+            }
+        }
+
+        if changed && !empty {
+            block.sync_blk_to_disk(self.block_device.clone())?;
+        }
+        Ok(empty)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ext4_impls::test_utils::*;
+
+    #[test]
+    fn test_indirect_path() {
+        let disk = MemDisk::new(INDIRECT_IMAGE);
+        let ext4 = mount(&disk);
+        let apb = ext4.indirect_per_block() as u32;
+        let ndir = EXT4_NDIR_BLOCKS as u32;
+
+        assert_eq!(ext4.indirect_path(0).unwrap(), [0]);
+        assert_eq!(ext4.indirect_path(ndir - 1).unwrap(), [EXT4_NDIR_BLOCKS - 1]);
+        assert_eq!(ext4.indirect_path(ndir).unwrap(), [EXT4_IND_BLOCK, 0]);
+        assert_eq!(ext4.indirect_path(ndir + apb - 1).unwrap(), [EXT4_IND_BLOCK, apb as usize - 1]);
+        assert_eq!(ext4.indirect_path(ndir + apb).unwrap(), [EXT4_DIND_BLOCK, 0, 0]);
+        assert_eq!(ext4.indirect_path(ndir + 2 * apb + 3).unwrap(), [EXT4_DIND_BLOCK, 1, 3]);
+        let tind = ndir + apb + apb * apb;
+        assert_eq!(ext4.indirect_path(tind).unwrap(), [EXT4_TIND_BLOCK, 0, 0, 0]);
+        let err = ext4.indirect_path(tind + apb * apb * apb).unwrap_err();
+        assert_eq!(err.error(), Errno::EFBIG);
+    }
+
+    #[test]
+    fn test_read_indirect_files() {
+        let disk = MemDisk::new(INDIRECT_IMAGE);
+        let ext4 = mount(&disk);
+
+        // direct blocks only, then through the single and the double
+        // indirect block
+        for name in ["direct", "indirect", "dindirect"] {
+            let inode = lookup(&ext4, name).unwrap();
+            assert!(!ext4.get_inode_ref(inode).unwrap().inode.has_extents());
+            assert_eq!(read_file(&ext4, inode), image_file(name), "{}", name);
+        }
+
+        // unaligned reads across the boundaries of the mapping levels
+        let block_size = ext4.super_block.block_size() as usize;
+        let apb = block_size / 4;
+        let inode = lookup(&ext4, "dindirect").unwrap();
+        let data = image_file("dindirect");
+        for boundary in [EXT4_NDIR_BLOCKS, EXT4_NDIR_BLOCKS + apb] {
+            let offset = boundary * block_size - 100;
+            let mut buf = vec![0u8; block_size + 300];
+            assert_eq!(ext4.read_at(inode, offset, &mut buf).unwrap(), buf.len());
+            assert_eq!(buf, data[offset..offset + buf.len()]);
+        }
+
+        // a read is short at the end of the file
+        let mut buf = vec![0u8; 1000];
+        assert_eq!(ext4.read_at(inode, data.len() - 10, &mut buf).unwrap(), 10);
+        assert_eq!(buf[..10], data[data.len() - 10..]);
+    }
+
+    #[test]
+    fn test_read_indirect_holes() {
+        let disk = MemDisk::new(INDIRECT_IMAGE);
+        let ext4 = mount(&disk);
+        let block_size = ext4.super_block.block_size() as usize;
+        let inode = lookup(&ext4, "holey").unwrap();
+        let inode_ref = ext4.get_inode_ref(inode).unwrap();
+        let data = image_file("holey");
+
+        assert_eq!(read_file(&ext4, inode), data);
+
+        // data in direct, single and double indirect blocks, holes between
+        for (lblock, mapped) in [(0, true), (5, false), (50, false), (98, true), (200, false), (293, true), (341, false)] {
+            let pblock = ext4.indirect_get_pblock(&inode_ref, lblock).unwrap();
+            assert_eq!(pblock != 0, mapped, "block {}", lblock);
+        }
+
+        // a read from a hole into data
+        let offset = 90 * block_size;
+        let mut buf = vec![0xffu8; 20 * block_size];
+        assert_eq!(ext4.read_at(inode, offset, &mut buf).unwrap(), buf.len());
+        assert_eq!(buf, data[offset..offset + buf.len()]);
+        assert!(buf[..100000 - offset].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_write_indirect_hole() {
+        let disk = MemDisk::new(INDIRECT_IMAGE);
+        let block_size = {
+            let ext4 = mount(&disk);
+            let inode = lookup(&ext4, "holey").unwrap();
+            ext4.write_at(inode, 200 * ext4.super_block.block_size() as usize, &pattern(2000, 9)).unwrap();
+            ext4.super_block.block_size() as usize
+        };
+
+        let ext4 = mount(&disk);
+        let inode = lookup(&ext4, "holey").unwrap();
+        let mut data = image_file("holey");
+        data[200 * block_size..200 * block_size + 2000].copy_from_slice(&pattern(2000, 9));
+        assert_eq!(read_file(&ext4, inode), data);
+        let inode_ref = ext4.get_inode_ref(inode).unwrap();
+        let pblock = ext4.indirect_get_pblock(&inode_ref, 201).unwrap();
+        assert!(pblock != 0 && block_in_use(&ext4, pblock));
+    }
+
+    #[test]
+    fn test_truncate_indirect() {
+        let disk = MemDisk::new(INDIRECT_IMAGE);
+        let ext4 = mount(&disk);
+        let block_size = ext4.super_block.block_size() as u64;
+        let sectors = block_size / EXT4_INODE_BLOCK_SIZE as u64;
+        let inode = lookup(&ext4, "dindirect").unwrap();
+        let mut inode_ref = ext4.get_inode_ref(inode).unwrap();
+        let data = image_file("dindirect");
+        let blocks = data.len().div_ceil(block_size as usize) as Ext4Lblk;
+        let pblocks: Vec<Ext4Fsblk> = (0..blocks)
+            .map(|lblock| ext4.indirect_get_pblock(&inode_ref, lblock).unwrap())
+            .collect();
+        let ind = inode_ref.inode.block[EXT4_IND_BLOCK] as Ext4Fsblk;
+        let dind = inode_ref.inode.block[EXT4_DIND_BLOCK] as Ext4Fsblk;
+        let dind_child = ext4.indirect_load(dind).unwrap().read_offset_as::<u32>(0) as Ext4Fsblk;
+        // the data blocks, the single and the double indirect blocks and
+        // the one indirect block below the double one
+        assert_eq!(inode_ref.inode.blocks_count(), (blocks as u64 + 3) * sectors);
+        let free = free_blocks(&ext4);
+
+        // into the single indirect range, the double indirect tree goes
+        let size: u64 = 100000;
+        let kept = size.div_ceil(block_size) as usize;
+        ext4.truncate_inode(&mut inode_ref, size).unwrap();
+        assert_eq!(inode_ref.inode.block[EXT4_DIND_BLOCK], 0);
+        assert!(!block_in_use(&ext4, dind) && !block_in_use(&ext4, dind_child));
+        assert!(block_in_use(&ext4, ind));
+        assert!(pblocks[..kept].iter().all(|&pblock| block_in_use(&ext4, pblock)));
+        assert!(pblocks[kept..].iter().all(|&pblock| !block_in_use(&ext4, pblock)));
+        assert_eq!(free_blocks(&ext4), free + (pblocks.len() - kept) as u64 + 2);
+        assert_eq!(inode_ref.inode.blocks_count(), (kept as u64 + 1) * sectors);
+        assert_eq!(read_file(&ext4, inode), data[..size as usize]);
+        drop(ext4);
+
+        // then to the direct blocks, the single indirect block goes
+        let ext4 = mount(&disk);
+        let mut inode_ref = ext4.get_inode_ref(inode).unwrap();
+        assert_eq!(read_file(&ext4, inode), data[..size as usize]);
+        let size: u64 = 5000;
+        let direct = size.div_ceil(block_size) as usize;
+        ext4.truncate_inode(&mut inode_ref, size).unwrap();
+        assert_eq!(inode_ref.inode.block[EXT4_IND_BLOCK], 0);
+        assert!(inode_ref.inode.block[direct..EXT4_NDIR_BLOCKS].iter().all(|&b| b == 0));
+        assert!(!block_in_use(&ext4, ind));
+        assert!(pblocks[..direct].iter().all(|&pblock| block_in_use(&ext4, pblock)));
+        assert!(pblocks[direct..].iter().all(|&pblock| !block_in_use(&ext4, pblock)));
+        assert_eq!(free_blocks(&ext4), free + (pblocks.len() - direct) as u64 + 3);
+        assert_eq!(inode_ref.inode.blocks_count(), direct as u64 * sectors);
+        drop(ext4);
+
+        let ext4 = mount(&disk);
+        assert_eq!(read_file(&ext4, inode), data[..size as usize]);
+    }
+}
@@ -6,62 +6,87 @@ use crate::return_errno_with_message;
 use crate::utils::bitmap::*;
 
 impl Ext4 {
+    // inode numbers start at 1
     pub fn get_bgid_of_inode(&self, inode_num: u32) -> u32 {
-        inode_num / self.super_block.inodes_per_group()
+        (inode_num - 1) / self.super_block.inodes_per_group()
     }
 
     pub fn inode_to_bgidx(&self, inode_num: u32) -> u32 {
-        inode_num % self.super_block.inodes_per_group()
+        (inode_num - 1) % self.super_block.inodes_per_group()
     }
 
     /// Get inode disk position.
-    pub fn inode_disk_pos(&self, inode_num: u32) -> usize {
+    pub fn inode_disk_pos(&self, inode_num: u32) -> Result<usize> {
         let super_block = self.super_block;
         let inodes_per_group = super_block.inodes_per_group;
         let inode_size = super_block.inode_size as u64;
         let group = (inode_num - 1) / inodes_per_group;
         let index = (inode_num - 1) % inodes_per_group;
-        let block_group =
-            Ext4BlockGroup::load_new(self.block_device.clone(), &super_block, group as usize);
+        let block_group = self.load_block_group(group)?;
         let inode_table_blk_num = block_group.get_inode_table_blk_num();
 
-        inode_table_blk_num as usize * BLOCK_SIZE + index as usize * inode_size as usize
+        let block_size = super_block.block_size() as usize;
+        Ok(inode_table_blk_num as usize * block_size + index as usize * inode_size as usize)
     }
 
     /// Load the inode reference from the disk.
-    pub fn get_inode_ref(&self, inode_num: u32) -> Ext4InodeRef {
-        let offset = self.inode_disk_pos(inode_num);
+    pub fn get_inode_ref(&self, inode_num: u32) -> Result<Ext4InodeRef> {
+        let offset = self.inode_disk_pos(inode_num)?;
 
-        let mut ext4block = Block::load(self.block_device.clone(), offset);
+        let inode_size = self.super_block.inode_size() as usize;
+        let ext4block = Block::load(self.block_device.clone(), offset, inode_size)?;
+        self.verify_csum("inode", inode_num as u64, || {
+            Ext4Inode::verify_checksum(&ext4block.data, inode_num, &self.super_block)
+        })?;
 
-        let inode: &mut Ext4Inode = ext4block.read_as_mut();
+        // small (128 bytes) inodes have no extra fields, they stay zeroed
+        let inode: Ext4Inode = ext4block.read_offset_as(0);
 
-        Ext4InodeRef {
+        Ok(Ext4InodeRef {
             inode_num,
-            inode: *inode,
-        }
+            inode,
+        })
     }
 
     /// write back inode with checksum
-    pub fn write_back_inode(&self, inode_ref: &mut Ext4InodeRef) {
-        let inode_pos = self.inode_disk_pos(inode_ref.inode_num);
+    pub fn write_back_inode(&self, inode_ref: &mut Ext4InodeRef) -> Result<()> {
+        self.check_writable()?;
 
-        // make sure self.super_block is up-to-date
-        inode_ref
-            .inode
-            .set_inode_checksum(&self.super_block, inode_ref.inode_num);
-        inode_ref
-            .inode
-            .sync_inode_to_disk(self.block_device.clone(), inode_pos);
+        // the checksum covers the whole on-disk record, in-inode xattrs included
+        let mut raw = self.inode_load_raw(inode_ref.inode_num)?;
+        self.write_back_inode_raw(inode_ref, &mut raw)
+    }
+
+    /// Load the whole on-disk inode record, in-inode xattrs included.
+    pub fn inode_load_raw(&self, inode_num: u32) -> Result<Vec<u8>> {
+        let inode_pos = self.inode_disk_pos(inode_num)?;
+        let inode_size = self.super_block.inode_size() as usize;
+        Ok(Block::load(self.block_device.clone(), inode_pos, inode_size)?.data)
+    }
+
+    /// write back inode with checksum, over the on-disk record `raw`
+    pub fn write_back_inode_raw(&self, inode_ref: &mut Ext4InodeRef, raw: &mut [u8]) -> Result<()> {
+        self.check_writable()?;
+
+        let inode_pos = self.inode_disk_pos(inode_ref.inode_num)?;
+        inode_ref.inode.copy_to_raw(raw);
+        if self.super_block.has_metadata_csum() {
+            inode_ref
+                .inode
+                .set_inode_checksum(raw, inode_ref.inode_num, &self.super_block);
+        }
+        self.block_device.write_offset(inode_pos, raw)
     }
 
     /// write back inode with checksum
-    pub fn write_back_inode_without_csum(&self, inode_ref: &Ext4InodeRef) {
-        let inode_pos = self.inode_disk_pos(inode_ref.inode_num);
+    pub fn write_back_inode_without_csum(&self, inode_ref: &Ext4InodeRef) -> Result<()> {
+        self.check_writable()?;
+
+        let inode_pos = self.inode_disk_pos(inode_ref.inode_num)?;
 
         inode_ref
             .inode
-            .sync_inode_to_disk(self.block_device.clone(), inode_pos);
+            .sync_inode_to_disk(self.block_device.clone(), inode_pos, &self.super_block)
     }
 
     /// Get physical block id of a logical block.
@@ -71,8 +96,12 @@ impl Ext4 {
     /// lblock: Ext4Lblk - logical block id
     ///
     /// Returns:
-    /// `Result<Ext4Fsblk>` - physical block id
+    /// `Result<Ext4Fsblk>` - physical block id, 0 for a hole
     pub fn get_pblock_idx(&self, inode_ref: &Ext4InodeRef, lblock: Ext4Lblk) -> Result<Ext4Fsblk> {
+        if !inode_ref.inode.has_extents() {
+            return self.indirect_get_pblock(inode_ref, lblock);
+        }
+
         let search_path = self.find_extent(inode_ref, lblock);
         if let Ok(path) = search_path {
             // get the last path
@@ -89,47 +118,47 @@ impl Ext4 {
 
     /// Allocate a new block
     pub fn allocate_new_block(&self, inode_ref: &mut Ext4InodeRef) -> Result<Ext4Fsblk> {
-        let mut super_block = self.super_block;
+        self.check_writable()?;
+
+        let mut super_block = Ext4Superblock::load(self.block_device.clone())?;
         let inodes_per_group = super_block.inodes_per_group();
         let bgid = (inode_ref.inode_num - 1) / inodes_per_group;
         let index = (inode_ref.inode_num - 1) % inodes_per_group;
 
         // load block group
-        let mut block_group =
-            Ext4BlockGroup::load_new(self.block_device.clone(), &super_block, bgid as usize);
+        let mut block_group = self.load_block_group(bgid)?;
 
         let block_bitmap_block = block_group.get_block_bitmap_block(&super_block);
 
-        let mut block_bmap_raw_data = self
-            .block_device
-            .read_offset(block_bitmap_block as usize * BLOCK_SIZE);
+        let block_size = super_block.block_size() as usize;
+        let mut block_bmap_raw_data = self.load_block_bitmap(&mut block_group, bgid)?.data;
         let mut data: &mut Vec<u8> = &mut block_bmap_raw_data;
         let mut rel_blk_idx = 0;
 
-        ext4_bmap_bit_find_clr(data, index, 0x8000, &mut rel_blk_idx);
+        ext4_bmap_bit_find_clr(data, index, super_block.blocks_per_group(), &mut rel_blk_idx);
         ext4_bmap_bit_set(data, rel_blk_idx);
 
         block_group.set_block_group_balloc_bitmap_csum(&super_block, data);
         self.block_device
-            .write_offset(block_bitmap_block as usize * BLOCK_SIZE, data);
+            .write_offset(block_bitmap_block as usize * block_size, data)?;
 
         /* Update superblock free blocks count */
         let mut super_blk_free_blocks = super_block.free_blocks_count();
         super_blk_free_blocks -= 1;
         super_block.set_free_blocks_count(super_blk_free_blocks);
-        super_block.sync_to_disk_with_csum(self.block_device.clone());
+        super_block.sync_to_disk_with_csum(self.block_device.clone())?;
 
         /* Update inode blocks (different block size!) count */
         let mut inode_blocks = inode_ref.inode.blocks_count();
-        inode_blocks += (BLOCK_SIZE / EXT4_INODE_BLOCK_SIZE) as u64;
+        inode_blocks += (block_size / EXT4_INODE_BLOCK_SIZE) as u64;
         inode_ref.inode.set_blocks_count(inode_blocks);
-        self.write_back_inode(inode_ref);
+        self.write_back_inode(inode_ref)?;
 
         /* Update block group free blocks count */
         let mut fb_cnt = block_group.get_free_blocks_count();
         fb_cnt -= 1;
         block_group.set_free_blocks_count(fb_cnt as u32);
-        block_group.sync_to_disk_with_csum(self.block_device.clone(), bgid as usize, &super_block);
+        block_group.sync_to_disk_with_csum(self.block_device.clone(), bgid as usize, &super_block)?;
 
         Ok(rel_blk_idx as Ext4Fsblk)
     }
@@ -143,24 +172,31 @@ impl Ext4 {
     /// Returns:
     /// `Result<Ext4Fsblk>` - physical block id of the new block
     pub fn append_inode_pblk(&self, inode_ref: &mut Ext4InodeRef) -> Result<Ext4Fsblk> {
+        self.check_writable()?;
+
         let inode_size = inode_ref.inode.size();
-        let iblock = ((inode_size as usize + BLOCK_SIZE - 1) / BLOCK_SIZE) as u32;
+        let block_size = self.super_block.block_size() as usize;
+        let iblock = ((inode_size as usize + block_size - 1) / block_size) as u32;
 
         let mut newex: Ext4Extent = Ext4Extent::default();
 
         let new_block = self.balloc_alloc_block(inode_ref, None)?;
 
-        newex.first_block = iblock;
-        newex.store_pblock(new_block);
-        newex.block_count = min(1, EXT_MAX_BLOCKS - iblock) as u16;
+        if inode_ref.inode.has_extents() {
+            newex.first_block = iblock;
+            newex.store_pblock(new_block);
+            newex.block_count = min(1, EXT_MAX_BLOCKS - iblock) as u16;
 
-        self.insert_extent(inode_ref, &mut newex)?;
+            self.insert_extent(inode_ref, &mut newex)?;
+        } else {
+            self.indirect_map_block(inode_ref, iblock, new_block)?;
+        }
 
         // Update the inode size
         let mut inode_size = inode_ref.inode.size();
-        inode_size += BLOCK_SIZE as u64;
+        inode_size += block_size as u64;
         inode_ref.inode.set_size(inode_size);
-        self.write_back_inode(inode_ref);
+        self.write_back_inode(inode_ref)?;
 
         Ok(new_block)
     }
@@ -174,24 +210,31 @@ impl Ext4 {
     /// Returns:
     /// `Result<Ext4Fsblk>` - physical block id of the new block
     pub fn append_inode_pblk_from(&self, inode_ref: &mut Ext4InodeRef, start_bgid: &mut u32) -> Result<Ext4Fsblk> {
+        self.check_writable()?;
+
         let inode_size = inode_ref.inode.size();
-        let iblock = ((inode_size as usize + BLOCK_SIZE - 1) / BLOCK_SIZE) as u32;
+        let block_size = self.super_block.block_size() as usize;
+        let iblock = ((inode_size as usize + block_size - 1) / block_size) as u32;
 
         let mut newex: Ext4Extent = Ext4Extent::default();
 
         let new_block = self.balloc_alloc_block_from(inode_ref, start_bgid)?;
 
-        newex.first_block = iblock;
-        newex.store_pblock(new_block);
-        newex.block_count = min(1, EXT_MAX_BLOCKS - iblock) as u16;
+        if inode_ref.inode.has_extents() {
+            newex.first_block = iblock;
+            newex.store_pblock(new_block);
+            newex.block_count = min(1, EXT_MAX_BLOCKS - iblock) as u16;
 
-        self.insert_extent(inode_ref, &mut newex)?;
+            self.insert_extent(inode_ref, &mut newex)?;
+        } else {
+            self.indirect_map_block(inode_ref, iblock, new_block)?;
+        }
 
         // Update the inode size
         let mut inode_size = inode_ref.inode.size();
-        inode_size += BLOCK_SIZE as u64;
+        inode_size += block_size as u64;
         inode_ref.inode.set_size(inode_size);
-        self.write_back_inode(inode_ref);
+        self.write_back_inode(inode_ref)?;
 
         Ok(new_block)
     }
@@ -204,6 +247,8 @@ impl Ext4 {
     /// Returns:
     /// `Result<u32>` - inode number
     pub fn alloc_inode(&self, is_dir: bool) -> Result<u32> {
+        self.check_writable()?;
+
         // Allocate inode
         let inode_num = self.ialloc_alloc_inode(is_dir)?;
 
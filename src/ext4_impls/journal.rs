@@ -0,0 +1,801 @@
+use crate::prelude::*;
+use crate::return_errno_with_message;
+
+use crate::ext4_defs::*;
+
+/// The three passes of journal recovery, same as jbd2.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RecoveryPass {
+    /// Find the end of the log: the first transaction without a valid commit block.
+    Scan,
+    /// Collect revoke records of the committed transactions.
+    Revoke,
+    /// Write the logged blocks of the committed transactions to their home location.
+    Replay,
+}
+
+/// State shared by the recovery passes.
+#[derive(Debug, Default)]
+struct RecoveryInfo {
+    /// First transaction that is not committed.
+    end_transaction: u32,
+    /// Revoked filesystem block -> latest transaction that revoked it.
+    revoked: BTreeMap<u64, u32>,
+    /// Number of blocks written back.
+    replayed: usize,
+    /// A logged block failed its checksum and was not written back.
+    block_error: bool,
+}
+
+impl Ext4 {
+    /// Load the journal superblock from the journal inode.
+    ///
+    /// Returns:
+    /// `Result<Journal>` - the journal, or ENOTSUP for an external journal
+    pub fn journal_load(&self) -> Result<Journal> {
+        let journal_inode = self.super_block.journal_inode_number();
+        if journal_inode == 0 {
+            return_errno_with_message!(Errno::ENOTSUP, "external journal devices are not supported");
+        }
+
+        let inode_ref = self.get_inode_ref(journal_inode)?;
+        let block_size = self.super_block.block_size() as usize;
+        let pblock = self.get_pblock_idx(&inode_ref, 0)?;
+        let block = Block::load(self.block_device.clone(), pblock as usize * block_size, block_size)?;
+        let super_block: JournalSuperblock = block.read_as();
+        super_block.validate(self.super_block.block_size())?;
+
+        Ok(Journal::new(inode_ref, super_block))
+    }
+
+    /// Read journal block `lblock` bypassing the block cache.
+    pub fn journal_read_block(&self, journal: &Journal, lblock: u32) -> Result<Vec<u8>> {
+        let block_size = journal.block_size();
+        let pblock = self.get_pblock_idx(&journal.inode_ref, lblock)?;
+        let mut data = vec![0u8; block_size];
+        self.block_device
+            .read_blocks(pblock as usize * block_size, &mut data)?;
+        Ok(data)
+    }
+
+    /// Write `data`, a whole number of blocks, to the log starting at journal
+    /// block `lblock`, with one device request per physically contiguous run.
+    pub fn journal_write_blocks(&self, journal: &Journal, lblock: u32, data: &[u8]) -> Result<()> {
+        let block_size = journal.block_size();
+        let count = data.len() / block_size;
+
+        let mut i = 0;
+        while i < count {
+            let pblock_start = self.get_pblock_idx(&journal.inode_ref, lblock + i as u32)?;
+            let mut run = 1;
+            while i + run < count
+                && self.get_pblock_idx(&journal.inode_ref, lblock + (i + run) as u32)?
+                    == pblock_start + run as u64
+            {
+                run += 1;
+            }
+            self.block_device.write_blocks(
+                pblock_start as usize * block_size,
+                &data[i * block_size..(i + run) * block_size],
+            )?;
+            i += run;
+        }
+        Ok(())
+    }
+
+    /// Write the journal superblock back to the first journal block.
+    pub fn journal_write_super(&self, journal: &mut Journal) -> Result<()> {
+        journal.super_block.set_checksum();
+        let mut data = vec![0u8; journal.block_size()];
+        let sb = unsafe {
+            core::slice::from_raw_parts(
+                &journal.super_block as *const _ as *const u8,
+                size_of::<JournalSuperblock>(),
+            )
+        };
+        data[..sb.len()].copy_from_slice(sb);
+        self.journal_write_blocks(journal, 0, &data)
+    }
+
+    /// Load the journal used to log metadata updates.
+    ///
+    /// Called once the journal has been recovered, so the log is empty and the
+    /// features this crate writes can be turned on.
+    pub fn journal_init(&mut self) -> Result<()> {
+        if !self.super_block.has_journal() {
+            return Ok(());
+        }
+
+        let mut journal = match self.journal_load() {
+            Ok(journal) => journal,
+            Err(e) if e.error() == Errno::ENOTSUP => {
+                log::warn!("metadata updates are not journaled: {:?}", e);
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        if journal.super_block.header().blocktype() != JBD2_SUPERBLOCK_V2 {
+            log::warn!("metadata updates are not journaled: v1 journal superblock");
+            return Ok(());
+        }
+
+        let old_features = journal.super_block.incompat_features();
+        journal.super_block.set_incompat(JBD2_FEATURE_INCOMPAT_REVOKE);
+        if self.super_block.is_64bit() {
+            journal.super_block.set_incompat(JBD2_FEATURE_INCOMPAT_64BIT);
+        }
+        if self.super_block.has_metadata_csum() && !journal.super_block.has_csum_v2or3() {
+            journal.super_block.set_incompat(JBD2_FEATURE_INCOMPAT_CSUM_V3);
+            journal.super_block.set_checksum_type(JBD2_CRC32C_CHKSUM);
+        }
+
+        // a log left behind without the needs_recovery flag is stale
+        let wipe = journal.super_block.start() != 0;
+        if wipe {
+            log::warn!("discarding journal contents, the filesystem was not marked for recovery");
+            journal.super_block.set_start(0);
+        }
+        if wipe || journal.super_block.incompat_features() != old_features {
+            self.journal_write_super(&mut journal)?;
+        }
+
+        self.journal = Some(Mutex::new(journal));
+        Ok(())
+    }
+
+    /// Open a handle on the running transaction, starting one if needed.
+    ///
+    /// Every metadata block written through the block cache until the matching
+    /// `journal_stop` belongs to the transaction. Handles nest, the outermost
+    /// handle and the handles nested in it make up one update.
+    pub fn journal_start(&self) -> Result<()> {
+        let Some(journal) = &self.journal else {
+            return Ok(());
+        };
+        let mut journal = journal.lock();
+
+        if journal.handles == 0 && !self.block_cache.transaction_running() {
+            if !journal.recovery_flag_set {
+                // must be on disk before the first commit block is
+                let mut super_block = Ext4Superblock::load(self.block_device.clone())?;
+                super_block.set_needs_recovery(true);
+                super_block.sync_to_disk_with_csum(self.block_device.clone())?;
+                self.block_cache.sync()?;
+                journal.recovery_flag_set = true;
+            }
+            self.block_cache.start_transaction();
+        }
+        if journal.handles == 0 {
+            journal.revoked_before_update = journal.revoked.clone();
+            self.block_cache.start_update();
+        }
+        journal.handles += 1;
+        Ok(())
+    }
+
+    /// Close a handle opened by `journal_start`.
+    ///
+    /// Closing the last handle of an update aborts it if one of its handles
+    /// failed or if the transaction no longer fits in the journal: every block
+    /// it wrote gets back its previous content. Otherwise the running
+    /// transaction is committed once it holds enough blocks, or later
+    /// operations join it.
+    pub fn journal_stop(&self) -> Result<()> {
+        let Some(journal) = &self.journal else {
+            return Ok(());
+        };
+        let mut journal = journal.lock();
+
+        journal.handles -= 1;
+        if journal.handles > 0 {
+            return Ok(());
+        }
+
+        let len = journal.transaction_len(self.block_cache.transaction_blocks(), journal.revoked.len());
+        let too_large = len > journal.capacity() as usize;
+        if journal.aborted || too_large {
+            self.block_cache.abort_update();
+            journal.revoked = core::mem::take(&mut journal.revoked_before_update);
+            if core::mem::replace(&mut journal.aborted, false) {
+                return_errno_with_message!(Errno::EIO, "update aborted by a failed handle");
+            }
+            return_errno_with_message!(Errno::ENOSPC, "update does not fit in the journal");
+        }
+        self.block_cache.finish_update();
+
+        let batch = min(
+            journal.capacity() as usize / 4,
+            self.block_cache.capacity() / 2,
+        );
+        if self.block_cache.transaction_blocks() >= batch.max(1) {
+            self.journal_commit(&mut journal)?;
+        }
+        Ok(())
+    }
+
+    /// Run `f` as one atomic update of the filesystem.
+    ///
+    /// If `f` fails, the update it is part of is aborted.
+    pub fn journal_transaction<T>(&self, f: impl FnOnce() -> Result<T>) -> Result<T> {
+        self.journal_start()?;
+        let r = f();
+        if r.is_err() {
+            self.journal_abort();
+        }
+        let stop = self.journal_stop();
+        let value = r?;
+        stop?;
+        Ok(value)
+    }
+
+    /// Mark the current update as failed, it is aborted when its last handle
+    /// is closed.
+    pub fn journal_abort(&self) {
+        if let Some(journal) = &self.journal {
+            journal.lock().aborted = true;
+        }
+    }
+
+    /// Tell the journal that blocks `start..start + count` were freed.
+    ///
+    /// Copies of them in the log are revoked so that replay cannot overwrite
+    /// whatever the blocks are reused for.
+    pub fn journal_revoke(&self, start: Ext4Fsblk, count: u64) {
+        let Some(journal) = &self.journal else {
+            return;
+        };
+        let mut journal = journal.lock();
+
+        // a freed block needs no copy in the running transaction
+        self.block_cache.release_from_transaction(start, count);
+
+        let logged: Vec<u64> = journal.logged.range(start..start + count).copied().collect();
+        journal.revoked.extend(logged);
+    }
+
+    /// Commit the running transaction to the log.
+    ///
+    /// Descriptor blocks, block copies and revoke blocks are written first,
+    /// the commit block last: replay only trusts a transaction once its commit
+    /// block is on disk.
+    fn journal_commit(&self, journal: &mut Journal) -> Result<()> {
+        let block_size = journal.block_size();
+        let count = self.block_cache.transaction_blocks();
+        let len = journal.transaction_len(count, journal.revoked.len()) as u32;
+
+        if len > journal.capacity() {
+            // journal_stop aborts the updates that would get here
+            return_errno_with_message!(Errno::ENOSPC, "transaction does not fit in the journal");
+        }
+        if journal.head + len > journal.super_block.maxlen() {
+            // blocks of the running transaction stay in memory
+            self.journal_checkpoint(journal)?;
+        }
+
+        let blocks = self.block_cache.take_transaction();
+        // a block written again after it was freed is logged, not revoked
+        let revoked: Vec<u64> = core::mem::take(&mut journal.revoked)
+            .into_iter()
+            .filter(|b| !blocks.iter().any(|(blk, _)| blk == b))
+            .collect();
+        if blocks.is_empty() && revoked.is_empty() {
+            return Ok(());
+        }
+
+        let len = journal.transaction_len(blocks.len(), revoked.len()) as u32;
+        let sequence = journal.sequence;
+        let start = journal.head;
+
+        if journal.super_block.start() == 0 {
+            // the log is no longer empty, recovery starts at this transaction
+            journal.super_block.set_start(start);
+            journal.super_block.set_sequence(sequence);
+            self.journal_write_super(journal)?;
+        }
+
+        let mut log = Vec::with_capacity((len as usize - 1) * block_size);
+        for chunk in blocks.chunks(journal.tags_per_descriptor()) {
+            let tags: Vec<(u64, &[u8])> = chunk.iter().map(|(b, d)| (*b, d.as_slice())).collect();
+            log.extend(journal.build_descriptor(sequence, &tags));
+            for (_, data) in chunk {
+                log.extend(Journal::escape_block(data));
+            }
+        }
+        for chunk in revoked.chunks(journal.records_per_revoke()) {
+            log.extend(journal.build_revoke(sequence, chunk));
+        }
+        self.journal_write_blocks(journal, start, &log)?;
+        self.journal_write_blocks(journal, start + len - 1, &journal.build_commit(sequence))?;
+
+        journal.head = start + len;
+        journal.sequence = sequence.wrapping_add(1);
+        journal.logged.extend(blocks.iter().map(|(b, _)| *b));
+        Ok(())
+    }
+
+    /// Write every committed block to its home location and empty the log.
+    fn journal_checkpoint(&self, journal: &mut Journal) -> Result<()> {
+        self.block_cache.sync()?;
+        if journal.super_block.start() != 0 {
+            journal.super_block.set_start(0);
+            journal.super_block.set_sequence(journal.sequence);
+            self.journal_write_super(journal)?;
+        }
+        journal.head = journal.super_block.first();
+        journal.logged.clear();
+        Ok(())
+    }
+
+    /// Commit the running transaction, checkpoint the log and mark the
+    /// filesystem clean.
+    pub fn journal_sync(&self) -> Result<()> {
+        let Some(journal) = &self.journal else {
+            return self.block_cache.sync();
+        };
+        let mut journal = journal.lock();
+
+        if journal.handles > 0 {
+            // in the middle of an update, only committed blocks may go to disk
+            return self.block_cache.sync();
+        }
+
+        self.journal_commit(&mut journal)?;
+        self.journal_checkpoint(&mut journal)?;
+
+        if journal.recovery_flag_set {
+            let mut super_block = Ext4Superblock::load(self.block_device.clone())?;
+            super_block.set_needs_recovery(false);
+            super_block.sync_to_disk_with_csum(self.block_device.clone())?;
+            self.block_cache.sync()?;
+            journal.recovery_flag_set = false;
+        }
+        Ok(())
+    }
+
+    /// Replay the journal if the filesystem was not cleanly unmounted.
+    ///
+    /// Committed transactions are written to their home location, the journal
+    /// is then marked empty and the in-memory superblock reloaded since the
+    /// log may have contained it.
+    pub fn journal_recover(&mut self) -> Result<()> {
+        if !self.super_block.has_journal() || !self.super_block.needs_recovery() {
+            return Ok(());
+        }
+
+        let mut journal = self.journal_load()?;
+        if journal.super_block.has_incompat(JBD2_FEATURE_INCOMPAT_FAST_COMMIT) {
+            return_errno_with_message!(Errno::ENOTSUP, "fast commit journal replay is not supported");
+        }
+
+        let mut info = RecoveryInfo::default();
+        if journal.super_block.start() != 0 {
+            self.journal_do_pass(&journal, RecoveryPass::Scan, &mut info)?;
+            self.journal_do_pass(&journal, RecoveryPass::Revoke, &mut info)?;
+            self.journal_do_pass(&journal, RecoveryPass::Replay, &mut info)?;
+            log::info!(
+                "journal recovery: {} transactions, {} blocks replayed",
+                info.end_transaction.wrapping_sub(journal.super_block.sequence()),
+                info.replayed
+            );
+
+            if info.block_error {
+                self.block_cache.sync()?;
+                return_errno_with_message!(Errno::EIO, "journal block checksum mismatch");
+            }
+
+            // skip a transaction id so a partially written one is never mistaken for a new one
+            journal
+                .super_block
+                .set_sequence(info.end_transaction.wrapping_add(1));
+            journal.super_block.set_start(0);
+        }
+
+        // replayed blocks must be on disk before the journal is marked empty
+        self.block_cache.sync()?;
+        self.journal_write_super(&mut journal)?;
+        self.block_cache.sync()?;
+
+        let mut super_block = Ext4Superblock::load(self.block_device.clone())?;
+        super_block.set_needs_recovery(false);
+        super_block.sync_to_disk_with_csum(self.block_device.clone())?;
+        self.block_cache.sync()?;
+        self.super_block = super_block;
+
+        Ok(())
+    }
+
+    /// Walk the log from its start, doing the work of one recovery pass.
+    fn journal_do_pass(
+        &self,
+        journal: &Journal,
+        pass: RecoveryPass,
+        info: &mut RecoveryInfo,
+    ) -> Result<()> {
+        let block_size = journal.block_size();
+        let mut next_commit_id = journal.super_block.sequence();
+        let mut next_log_block = journal.super_block.start();
+
+        loop {
+            if pass != RecoveryPass::Scan && tid_geq(next_commit_id, info.end_transaction) {
+                break;
+            }
+
+            let data = self.journal_read_block(journal, next_log_block)?;
+            next_log_block = journal.next_block(next_log_block);
+
+            let header: JournalHeader = unsafe { core::ptr::read_unaligned(data.as_ptr() as *const _) };
+            if header.magic() != JBD2_MAGIC_NUMBER || header.sequence() != next_commit_id {
+                break;
+            }
+
+            match header.blocktype() {
+                JBD2_DESCRIPTOR_BLOCK => {
+                    if !journal.verify_block_tail(&data) {
+                        if pass == RecoveryPass::Scan {
+                            // the transaction was never fully written
+                            break;
+                        }
+                        return_errno_with_message!(Errno::EIO, "journal descriptor checksum mismatch");
+                    }
+
+                    let tags = journal.parse_tags(&data);
+                    if pass != RecoveryPass::Replay {
+                        next_log_block = journal.wrap(next_log_block + tags.len() as u32);
+                        continue;
+                    }
+
+                    for tag in tags {
+                        let log_block = next_log_block;
+                        next_log_block = journal.next_block(next_log_block);
+
+                        if info
+                            .revoked
+                            .get(&tag.blocknr)
+                            .is_some_and(|&seq| tid_geq(seq, next_commit_id))
+                        {
+                            continue;
+                        }
+
+                        let mut block = self.journal_read_block(journal, log_block)?;
+                        if !journal.verify_data(&tag, next_commit_id, &block) {
+                            log::error!(
+                                "journal: bad checksum for block {} in transaction {}",
+                                tag.blocknr,
+                                next_commit_id
+                            );
+                            info.block_error = true;
+                            continue;
+                        }
+                        if tag.flags & JBD2_FLAG_ESCAPE != 0 {
+                            block[..4].copy_from_slice(&JBD2_MAGIC_NUMBER.to_be_bytes());
+                        }
+
+                        self.block_device
+                            .write_offset(tag.blocknr as usize * block_size, &block)?;
+                        info.replayed += 1;
+                    }
+                }
+                JBD2_COMMIT_BLOCK => {
+                    if pass == RecoveryPass::Scan && !journal.verify_commit(&data) {
+                        break;
+                    }
+                    next_commit_id = next_commit_id.wrapping_add(1);
+                }
+                JBD2_REVOKE_BLOCK => {
+                    if !journal.verify_block_tail(&data) {
+                        if pass == RecoveryPass::Scan {
+                            break;
+                        }
+                        return_errno_with_message!(Errno::EIO, "journal revoke checksum mismatch");
+                    }
+                    if pass != RecoveryPass::Revoke {
+                        continue;
+                    }
+                    for block in journal.parse_revoke(&data)? {
+                        let seq = info.revoked.entry(block).or_insert(next_commit_id);
+                        if tid_gt(next_commit_id, *seq) {
+                            *seq = next_commit_id;
+                        }
+                    }
+                }
+                _ => break,
+            }
+        }
+
+        if pass == RecoveryPass::Scan {
+            info.end_transaction = next_commit_id;
+        } else if next_commit_id != info.end_transaction {
+            return_errno_with_message!(Errno::EIO, "journal changed between recovery passes");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ext4_impls::test_utils::*;
+    use alloc::format;
+
+    #[test]
+    fn test_drop_commits_running_transaction() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+        let inode = create_file(&ext4, "file");
+        ext4.write_at(inode, 0, b"not synced").unwrap();
+        drop(ext4);
+
+        let ext4 = mount(&disk);
+        let inode = lookup(&ext4, "file").unwrap();
+        assert_eq!(read_file(&ext4, inode), b"not synced");
+        let super_block = Ext4Superblock::load(disk.clone()).unwrap();
+        assert!(!super_block.needs_recovery());
+    }
+
+    #[test]
+    fn test_failed_update_is_aborted() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+        create_file(&ext4, "kept");
+        let free = free_inodes(&ext4);
+
+        let r: Result<()> = ext4.journal_transaction(|| {
+            create_file(&ext4, "dropped");
+            return_errno_with_message!(Errno::EIO, "failing on purpose");
+        });
+        assert_eq!(r.unwrap_err().error(), Errno::EIO);
+
+        // the earlier update in the same transaction is kept
+        assert!(lookup(&ext4, "kept").is_ok());
+        assert_eq!(lookup(&ext4, "dropped").unwrap_err().error(), Errno::ENOENT);
+        assert_eq!(free_inodes(&ext4), free);
+
+        drop(ext4);
+        let ext4 = mount(&disk);
+        assert!(lookup(&ext4, "kept").is_ok());
+        assert_eq!(lookup(&ext4, "dropped").unwrap_err().error(), Errno::ENOENT);
+    }
+
+    #[test]
+    fn test_failed_nested_handle_aborts_update() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+
+        let r = ext4.journal_transaction(|| {
+            create_file(&ext4, "outer");
+            // the caller ignores the failure, the update is aborted anyway
+            let inner: Result<()> = ext4.journal_transaction(|| {
+                return_errno_with_message!(Errno::EIO, "failing on purpose");
+            });
+            assert!(inner.is_err());
+            Ok(())
+        });
+        assert_eq!(r.unwrap_err().error(), Errno::EIO);
+        assert_eq!(lookup(&ext4, "outer").unwrap_err().error(), Errno::ENOENT);
+    }
+
+    #[test]
+    fn test_oversized_update_is_not_written() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+        let block_size = ext4.super_block.block_size() as usize;
+        let capacity = ext4.journal.as_ref().unwrap().lock().capacity() as usize;
+        let first = 8192 - capacity - 100;
+
+        let r = ext4.journal_transaction(|| {
+            for block in first..first + capacity {
+                ext4.block_device.write_offset(block * block_size, &[0xaa; 16])?;
+            }
+            Ok(())
+        });
+        assert_eq!(r.unwrap_err().error(), Errno::ENOSPC);
+
+        ext4.sync().unwrap();
+        let data = disk.bytes(first * block_size, capacity * block_size);
+        assert!(data.iter().all(|&b| b != 0xaa));
+    }
+
+    #[test]
+    fn test_dir_remove() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+        let links = ext4.get_inode_ref(ROOT_INODE).unwrap().inode.links_count();
+        ext4.dir_mk("dir").unwrap();
+        assert_eq!(ext4.get_inode_ref(ROOT_INODE).unwrap().inode.links_count(), links + 1);
+
+        ext4.dir_remove(ROOT_INODE, "dir").unwrap();
+        drop(ext4);
+
+        let ext4 = mount(&disk);
+        assert_eq!(lookup(&ext4, "dir").unwrap_err().error(), Errno::ENOENT);
+        assert_eq!(ext4.get_inode_ref(ROOT_INODE).unwrap().inode.links_count(), links);
+    }
+
+    /// Blocks logged with their content and blocks revoked by a transaction.
+    type Transaction = (Vec<(u64, Vec<u8>)>, Vec<u64>);
+
+    /// Write `transactions` to the log as `journal_commit` lays them out and
+    /// mark the filesystem for recovery, then crash.
+    fn crash_with_log(ext4: Ext4, transactions: &[Transaction]) {
+        ext4.sync().unwrap();
+        let mut journal = ext4.journal_load().unwrap();
+        let first = journal.super_block.first();
+        let sequence = journal.super_block.sequence();
+
+        let mut log = Vec::new();
+        for (i, (blocks, revoked)) in transactions.iter().enumerate() {
+            let sequence = sequence + i as u32;
+            if !blocks.is_empty() {
+                let tags: Vec<(u64, &[u8])> = blocks.iter().map(|(b, d)| (*b, d.as_slice())).collect();
+                log.extend(journal.build_descriptor(sequence, &tags));
+                for (_, data) in blocks {
+                    log.extend(Journal::escape_block(data));
+                }
+            }
+            if !revoked.is_empty() {
+                log.extend(journal.build_revoke(sequence, revoked));
+            }
+            log.extend(journal.build_commit(sequence));
+        }
+        ext4.journal_write_blocks(&journal, first, &log).unwrap();
+        journal.super_block.set_start(first);
+        ext4.journal_write_super(&mut journal).unwrap();
+
+        let mut super_block = Ext4Superblock::load(ext4.block_device.clone()).unwrap();
+        super_block.set_needs_recovery(true);
+        super_block.sync_to_disk_with_csum(ext4.block_device.clone()).unwrap();
+        ext4.block_cache.sync().unwrap();
+        core::mem::forget(ext4);
+    }
+
+    #[test]
+    fn test_crash_after_commit_is_replayed() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+        let sequence = ext4.journal.as_ref().unwrap().lock().sequence;
+
+        // enough updates for journal_stop to commit on its own, every
+        // directory brings a block of its own
+        let mut files = Vec::new();
+        for i in 0..300 {
+            let dir = ext4.create(ROOT_INODE, &format!("d{}", i), InodeFileType::S_IFDIR.bits() | 0o755);
+            let mode = InodeFileType::S_IFREG.bits() | 0o644;
+            let inode = ext4.create(dir.unwrap().inode_num, "f", mode).unwrap().inode_num;
+            ext4.write_at(inode, 0, &pattern(3000, i)).unwrap();
+            files.push(inode);
+        }
+        let journal = ext4.journal.as_ref().unwrap();
+        assert_ne!(journal.lock().sequence, sequence);
+        // commit the rest and crash before the checkpoint
+        ext4.journal_commit(&mut journal.lock()).unwrap();
+        let blocks: Vec<u64> = files
+            .iter()
+            .flat_map(|&inode| file_extents(&ext4, inode))
+            .flat_map(|ex| ex.get_pblock()..ex.get_pblock() + ex.get_actual_len() as u64)
+            .collect();
+        let (free_i, free_b) = (free_inodes(&ext4), free_blocks(&ext4));
+        core::mem::forget(ext4);
+
+        // the committed transactions are only in the log
+        let options = Ext4MountOptions { read_only: true, no_recovery: true, ..Default::default() };
+        let ext4 = Ext4::open_with_options(disk.clone(), options).unwrap();
+        assert_ne!(ext4.journal_load().unwrap().super_block.start(), 0);
+        assert_eq!(lookup(&ext4, "d299").unwrap_err().error(), Errno::ENOENT);
+        drop(ext4);
+
+        let ext4 = mount(&disk);
+        assert!(!ext4.super_block.needs_recovery());
+        for (i, &inode) in files.iter().enumerate() {
+            assert_eq!(lookup(&ext4, &format!("d{}/f", i)).unwrap(), inode);
+            assert_eq!(read_file(&ext4, inode), pattern(3000, i));
+            assert!(inode_in_use(&ext4, inode));
+        }
+        assert!(blocks.iter().all(|&b| block_in_use(&ext4, b)));
+        assert_eq!((free_inodes(&ext4), free_blocks(&ext4)), (free_i, free_b));
+    }
+
+    #[test]
+    fn test_replay_skips_revoked_blocks() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+        let block_size = ext4.super_block.block_size() as usize;
+        // unused blocks at the end of the filesystem
+        let (a, b, c) = (8191u64, 8190, 8189);
+        assert!([a, b, c].iter().all(|&blk| !block_in_use(&ext4, blk)));
+        let block = |byte: u8| vec![byte; block_size];
+        // a logged block starting with the journal magic number is escaped
+        let mut magic = block(4);
+        magic[..4].copy_from_slice(&JBD2_MAGIC_NUMBER.to_be_bytes());
+
+        crash_with_log(
+            ext4,
+            &[
+                (vec![(a, block(1)), (b, block(1)), (c, block(1))], vec![]),
+                // a revoke hides the copies of its own and earlier transactions
+                (vec![(b, block(2))], vec![a, b]),
+                // but not the copies of later ones
+                (vec![(b, block(3)), (c, magic.clone())], vec![]),
+            ],
+        );
+
+        let ext4 = mount(&disk);
+        assert!(!ext4.super_block.needs_recovery());
+        assert_eq!(disk.bytes(a as usize * block_size, block_size), block(0));
+        assert_eq!(disk.bytes(b as usize * block_size, block_size), block(3));
+        assert_eq!(disk.bytes(c as usize * block_size, block_size), magic);
+    }
+
+    #[test]
+    fn test_replay_checks_csum_v3_tags() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+        let block_size = ext4.super_block.block_size() as usize;
+        // the image has metadata_csum, so the journal tags carry checksums
+        let journal = ext4.journal_load().unwrap();
+        assert!(journal.super_block.has_incompat(JBD2_FEATURE_INCOMPAT_CSUM_V3));
+        assert_eq!(journal.super_block.tag_bytes(), 16);
+        // the copy of the only logged block follows the descriptor
+        let copy = ext4.get_pblock_idx(&journal.inode_ref, journal.super_block.first() + 1).unwrap();
+        let home = 8191u64;
+        crash_with_log(ext4, &[(vec![(home, vec![1u8; block_size])], vec![])]);
+        let image = disk.bytes(0, EXT4_IMAGE.len());
+
+        drop(mount(&disk));
+        assert_eq!(disk.bytes(home as usize * block_size, block_size), vec![1u8; block_size]);
+
+        // a copy that does not match its tag is not written back
+        let disk = MemDisk::new(&image);
+        disk.write_offset(copy as usize * block_size, &[0xff]).unwrap();
+        let err = Ext4::open(disk.clone()).err().unwrap();
+        assert_eq!(err.error(), Errno::EIO);
+        assert_eq!(disk.bytes(home as usize * block_size, block_size), vec![0u8; block_size]);
+    }
+
+    #[test]
+    fn test_replay_linux_journal() {
+        let disk = MemDisk::new(JOURNAL_IMAGE);
+        let ext4 = mount(&disk);
+        assert!(!ext4.super_block.needs_recovery());
+        assert_eq!(ext4.journal_load().unwrap().super_block.start(), 0);
+
+        let replayed = lookup(&ext4, "replayed").unwrap();
+        assert_eq!(read_file(&ext4, replayed), vec![0x5a; 1024]);
+        let revoked = lookup(&ext4, "revoked").unwrap();
+        assert_eq!(read_file(&ext4, revoked), pattern(1024, 2));
+        let uncommitted = lookup(&ext4, "uncommitted").unwrap();
+        assert_eq!(read_file(&ext4, uncommitted), pattern(1024, 3));
+
+        // the log is empty once replayed
+        drop(ext4);
+        let before = disk.bytes(0, JOURNAL_IMAGE.len());
+        drop(mount(&disk));
+        assert!(disk.bytes(0, JOURNAL_IMAGE.len()) == before);
+    }
+
+    #[test]
+    fn test_crash_after_revoke_keeps_reused_block() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+        let block_size = ext4.super_block.block_size() as usize;
+
+        // the block of the directory is logged, then freed
+        ext4.create(ROOT_INODE, "dir", InodeFileType::S_IFDIR.bits() | 0o755).unwrap();
+        let dir = lookup(&ext4, "dir").unwrap();
+        let dir_block = file_extents(&ext4, dir)[0].get_pblock();
+        let journal = ext4.journal.as_ref().unwrap();
+        ext4.journal_commit(&mut journal.lock()).unwrap();
+        ext4.dir_remove(ROOT_INODE, "dir").unwrap();
+
+        // and reused for file data
+        let inode = create_file(&ext4, "file");
+        let data = pattern(block_size, 7);
+        ext4.write_at(inode, 0, &data).unwrap();
+        assert_eq!(file_extents(&ext4, inode)[0].get_pblock(), dir_block);
+        ext4.journal_commit(&mut journal.lock()).unwrap();
+        core::mem::forget(ext4);
+
+        let ext4 = mount(&disk);
+        assert_eq!(lookup(&ext4, "dir").unwrap_err().error(), Errno::ENOENT);
+        assert_eq!(read_file(&ext4, lookup(&ext4, "file").unwrap()), data);
+    }
+}
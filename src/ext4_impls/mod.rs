@@ -1,15 +1,37 @@
 pub mod extents;
+pub mod indirect;
 pub mod ext4;
 pub mod inode;
 pub mod dir;
+pub mod htree;
 pub mod file;
+pub mod falloc;
+pub mod seek;
+pub mod fiemap;
+pub mod copy;
+pub mod rename;
+pub mod symlink;
+pub mod xattr;
+pub mod acl;
 pub mod ialloc;
 pub mod balloc;
+pub mod journal;
+
+#[cfg(test)]
+pub mod test_utils;
 
 pub use extents::*;
+pub use indirect::*;
 pub use ext4::*;
 pub use inode::*;
 pub use dir::*;
+pub use htree::*;
 pub use file::*;
+pub use falloc::*;
+pub use rename::*;
+pub use symlink::*;
+pub use xattr::*;
+pub use acl::*;
 pub use ialloc::*;
-pub use balloc::*;
\ No newline at end of file
+pub use balloc::*;
+pub use journal::*;
\ No newline at end of file
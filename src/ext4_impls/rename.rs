@@ -0,0 +1,387 @@
+use crate::prelude::*;
+use crate::return_errno_with_message;
+
+use crate::ext4_defs::*;
+
+impl Ext4 {
+    /// Rename a directory entry, within a directory or across directories.
+    ///
+    /// An existing target is replaced by pointing its entry at the source
+    /// inode, so the target name never disappears. With `RENAME_NOREPLACE`
+    /// an existing target fails with `EEXIST`, with `RENAME_EXCHANGE` both
+    /// entries swap their inodes and the target must exist.
+    ///
+    /// Params:
+    /// parent: u32 - inode number of the source directory
+    /// name: &str - name of the entry to move
+    /// new_parent: u32 - inode number of the target directory
+    /// new_name: &str - new name of the entry
+    /// flags: u32 - 0, `RENAME_NOREPLACE` or `RENAME_EXCHANGE`
+    ///
+    /// Returns:
+    /// `Result<usize>` - status of the operation
+    pub fn rename(
+        &self,
+        parent: u32,
+        name: &str,
+        new_parent: u32,
+        new_name: &str,
+        flags: u32,
+    ) -> Result<usize> {
+        self.check_writable()?;
+
+        if flags & !(RENAME_NOREPLACE | RENAME_EXCHANGE) != 0
+            || (flags & RENAME_NOREPLACE != 0 && flags & RENAME_EXCHANGE != 0)
+        {
+            return_errno_with_message!(Errno::EINVAL, "invalid rename flags");
+        }
+        for n in [name, new_name] {
+            if n.is_empty() || n == "." || n == ".." || n.contains('/') {
+                return_errno_with_message!(Errno::EINVAL, "invalid rename name");
+            }
+        }
+        if new_name.len() > EXT4_NAME_LEN {
+            return_errno_with_message!(Errno::ENAMETOOLONG, "file name too long");
+        }
+
+        self.journal_transaction(|| {
+            for dir in [parent, new_parent] {
+                if !self.get_inode_ref(dir)?.inode.is_dir() {
+                    return_errno_with_message!(Errno::ENOTDIR, "rename parent is not a directory");
+                }
+            }
+
+            let mut src = Ext4DirSearchResult::new(Ext4DirEntry::default());
+            self.dir_find_entry(parent, name, &mut src)?;
+            let src_ino = src.dentry.inode;
+
+            let mut dst = Ext4DirSearchResult::new(Ext4DirEntry::default());
+            let dst_ino = match self.dir_find_entry(new_parent, new_name, &mut dst) {
+                Ok(_) => Some(dst.dentry.inode),
+                Err(e) if e.error() == Errno::ENOENT => None,
+                Err(e) => return Err(e),
+            };
+
+            if dst_ino.is_some() && flags & RENAME_NOREPLACE != 0 {
+                return_errno_with_message!(Errno::EEXIST, "rename target exists");
+            }
+            if dst_ino.is_none() && flags & RENAME_EXCHANGE != 0 {
+                return_errno_with_message!(Errno::ENOENT, "rename exchange target missing");
+            }
+
+            // both names already refer to the same inode
+            if dst_ino == Some(src_ino) {
+                return Ok(EOK);
+            }
+
+            let src_ref = self.get_inode_ref(src_ino)?;
+            let src_is_dir = src_ref.inode.is_dir();
+            if src_is_dir && parent != new_parent && self.dir_is_ancestor(src_ino, new_parent)? {
+                return_errno_with_message!(Errno::EINVAL, "cannot move a directory into itself");
+            }
+
+            if flags & RENAME_EXCHANGE != 0 {
+                return self.rename_exchange(parent, &src, new_parent, &dst);
+            }
+
+            let mut target = None;
+            if let Some(dst_ino) = dst_ino {
+                let dst_ref = self.get_inode_ref(dst_ino)?;
+                match (src_is_dir, dst_ref.inode.is_dir()) {
+                    (true, false) => {
+                        return_errno_with_message!(Errno::ENOTDIR, "rename target is not a directory")
+                    }
+                    (false, true) => {
+                        return_errno_with_message!(Errno::EISDIR, "rename target is a directory")
+                    }
+                    (true, true) if self.dir_has_entry(dst_ino)? => {
+                        return_errno_with_message!(Errno::ENOTEMPTY, "rename target is not empty")
+                    }
+                    _ => {}
+                }
+
+                self.dir_set_entry_inode(new_parent, &dst, src_ino, src.dentry.get_de_type())?;
+                target = Some(dst_ref);
+            } else {
+                let mut new_parent_ref = self.get_inode_ref(new_parent)?;
+                self.dir_add_entry(&mut new_parent_ref, &src_ref, new_name)?;
+                self.write_back_inode(&mut new_parent_ref)?;
+            }
+
+            let mut parent_ref = self.get_inode_ref(parent)?;
+            self.dir_remove_entry(&mut parent_ref, name)?;
+
+            // a moved directory hangs off its new parent
+            if src_is_dir && parent != new_parent {
+                self.dir_set_dotdot(src_ino, new_parent)?;
+                self.inode_add_links(parent, -1)?;
+                self.inode_add_links(new_parent, 1)?;
+            }
+
+            if let Some(target) = target {
+                self.rename_release_target(new_parent, target)?;
+            }
+
+            Ok(EOK)
+        })
+    }
+
+    /// Swap the inodes of two existing entries, for `RENAME_EXCHANGE`.
+    fn rename_exchange(
+        &self,
+        parent: u32,
+        src: &Ext4DirSearchResult,
+        new_parent: u32,
+        dst: &Ext4DirSearchResult,
+    ) -> Result<usize> {
+        let src_ino = src.dentry.inode;
+        let dst_ino = dst.dentry.inode;
+        let src_is_dir = self.get_inode_ref(src_ino)?.inode.is_dir();
+        let dst_is_dir = self.get_inode_ref(dst_ino)?.inode.is_dir();
+
+        if dst_is_dir && parent != new_parent && self.dir_is_ancestor(dst_ino, parent)? {
+            return_errno_with_message!(Errno::EINVAL, "cannot move a directory into itself");
+        }
+
+        self.dir_set_entry_inode(parent, src, dst_ino, dst.dentry.get_de_type())?;
+        self.dir_set_entry_inode(new_parent, dst, src_ino, src.dentry.get_de_type())?;
+
+        if parent != new_parent {
+            if src_is_dir {
+                self.dir_set_dotdot(src_ino, new_parent)?;
+            }
+            if dst_is_dir {
+                self.dir_set_dotdot(dst_ino, parent)?;
+            }
+
+            let delta = src_is_dir as i32 - dst_is_dir as i32;
+            if delta != 0 {
+                self.inode_add_links(parent, -delta)?;
+                self.inode_add_links(new_parent, delta)?;
+            }
+        }
+
+        Ok(EOK)
+    }
+
+    /// Drop the link of an inode replaced by a rename, freeing it with its
+    /// last link.
+    fn rename_release_target(&self, new_parent: u32, mut target: Ext4InodeRef) -> Result<()> {
+        let is_dir = target.inode.is_dir();
+        let links = if is_dir {
+            // the replaced directory's ".." no longer counts for the parent
+            self.inode_add_links(new_parent, -1)?;
+            0
+        } else {
+            target.inode.links_count().saturating_sub(1)
+        };
+
+        target.inode.set_links_count(links);
+        if links == 0 {
+            if target.inode.size() > 0 {
+                self.truncate_inode(&mut target, 0)?;
+            }
+            self.xattr_release(&mut target)?;
+            self.ialloc_free_inode(target.inode_num, is_dir)?;
+        }
+        self.write_back_inode(&mut target)
+    }
+
+    /// Point an existing directory entry at another inode.
+    ///
+    /// Params:
+    /// dir: u32 - inode number of the directory holding the entry
+    /// entry: &Ext4DirSearchResult - location of the entry, from `dir_find_entry`
+    /// inode: u32 - new inode number of the entry
+    /// de_type: u8 - new file type of the entry
+    pub fn dir_set_entry_inode(
+        &self,
+        dir: u32,
+        entry: &Ext4DirSearchResult,
+        inode: u32,
+        de_type: u8,
+    ) -> Result<()> {
+        let dir_ref = self.get_inode_ref(dir)?;
+        let mut block = self.dir_load_block(&dir_ref, entry.pblock_id as Ext4Fsblk)?;
+
+        let de: &mut Ext4DirEntry = block.read_offset_as_mut(entry.offset);
+        de.inode = inode;
+        de.inner.inode_type = de_type;
+
+        self.dir_set_csum(&dir_ref, &mut block);
+        block.sync_blk_to_disk(self.block_device.clone())?;
+        Ok(())
+    }
+
+    /// Point the ".." entry of a directory at a new parent.
+    fn dir_set_dotdot(&self, dir: u32, parent: u32) -> Result<()> {
+        let mut dotdot = Ext4DirSearchResult::new(Ext4DirEntry::default());
+        self.dir_find_entry(dir, "..", &mut dotdot)?;
+        self.dir_set_entry_inode(dir, &dotdot, parent, DirEntryType::EXT4_DE_DIR.bits())
+    }
+
+    /// Returns true if `ancestor` is `dir` or one of its parents.
+    ///
+    /// Params:
+    /// ancestor: u32 - inode number of the candidate ancestor
+    /// dir: u32 - inode number of the directory to start from
+    pub fn dir_is_ancestor(&self, ancestor: u32, dir: u32) -> Result<bool> {
+        let mut cur = dir;
+        loop {
+            if cur == ancestor {
+                return Ok(true);
+            }
+            if cur == ROOT_INODE {
+                return Ok(false);
+            }
+
+            let mut dotdot = Ext4DirSearchResult::new(Ext4DirEntry::default());
+            self.dir_find_entry(cur, "..", &mut dotdot)?;
+            if dotdot.dentry.inode == cur {
+                return Ok(false);
+            }
+            cur = dotdot.dentry.inode;
+        }
+    }
+
+    /// Add `delta` to the link count of an inode.
+    fn inode_add_links(&self, inode: u32, delta: i32) -> Result<()> {
+        let mut inode_ref = self.get_inode_ref(inode)?;
+        let links = (inode_ref.inode.links_count() as i32 + delta).max(0);
+        inode_ref.inode.set_links_count(links as u16);
+        self.write_back_inode(&mut inode_ref)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ext4_impls::test_utils::*;
+
+    fn links(ext4: &Ext4, inode: u32) -> u16 {
+        ext4.get_inode_ref(inode).unwrap().inode.links_count()
+    }
+
+    #[test]
+    fn test_rename() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+        let inode = create_file(&ext4, "a");
+        ext4.write_at(inode, 0, b"content").unwrap();
+
+        ext4.rename(ROOT_INODE, "a", ROOT_INODE, "b", 0).unwrap();
+        drop(ext4);
+
+        let ext4 = mount(&disk);
+        assert_eq!(lookup(&ext4, "a").unwrap_err().error(), Errno::ENOENT);
+        assert_eq!(lookup(&ext4, "b").unwrap(), inode);
+        assert_eq!(read_file(&ext4, inode), b"content");
+        assert_eq!(links(&ext4, inode), 1);
+    }
+
+    #[test]
+    fn test_rename_noreplace() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+        let a = create_file(&ext4, "a");
+        let b = create_file(&ext4, "b");
+
+        let r = ext4.rename(ROOT_INODE, "a", ROOT_INODE, "b", RENAME_NOREPLACE);
+        assert_eq!(r.unwrap_err().error(), Errno::EEXIST);
+        assert_eq!(lookup(&ext4, "a").unwrap(), a);
+        assert_eq!(lookup(&ext4, "b").unwrap(), b);
+    }
+
+    #[test]
+    fn test_rename_parent_not_dir() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+        let a = create_file(&ext4, "a");
+        let file = create_file(&ext4, "file");
+
+        for flags in [0, RENAME_NOREPLACE, RENAME_EXCHANGE] {
+            let r = ext4.rename(file, "a", ROOT_INODE, "b", flags);
+            assert_eq!(r.unwrap_err().error(), Errno::ENOTDIR);
+            let r = ext4.rename(ROOT_INODE, "a", file, "b", flags);
+            assert_eq!(r.unwrap_err().error(), Errno::ENOTDIR);
+        }
+        assert_eq!(lookup(&ext4, "a").unwrap(), a);
+        assert_eq!(links(&ext4, a), 1);
+    }
+
+    #[test]
+    fn test_rename_exchange() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+        let file = create_file(&ext4, "file");
+        ext4.dir_mk("dir").unwrap();
+        ext4.dir_mk("other").unwrap();
+        let dir = lookup(&ext4, "dir").unwrap();
+        let other = lookup(&ext4, "other").unwrap();
+        let root_links = links(&ext4, ROOT_INODE);
+
+        // a file in the root swaps with a directory in "other"
+        ext4.rename(ROOT_INODE, "dir", other, "x", 0).unwrap();
+        ext4.rename(ROOT_INODE, "file", other, "x", RENAME_EXCHANGE).unwrap();
+
+        assert_eq!(lookup(&ext4, "file").unwrap(), dir);
+        assert_eq!(lookup(&ext4, "other/x").unwrap(), file);
+        assert_eq!(lookup(&ext4, "file/..").unwrap(), ROOT_INODE);
+        assert_eq!(links(&ext4, ROOT_INODE), root_links);
+        assert_eq!(links(&ext4, other), 2);
+
+        let r = ext4.rename(ROOT_INODE, "file", ROOT_INODE, "missing", RENAME_EXCHANGE);
+        assert_eq!(r.unwrap_err().error(), Errno::ENOENT);
+    }
+
+    #[test]
+    fn test_rename_over_existing_file() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+        let a = create_file(&ext4, "a");
+        let b = create_file(&ext4, "b");
+        ext4.write_at(b, 0, b"replaced").unwrap();
+        let b_block = ext4.get_pblock_idx(&ext4.get_inode_ref(b).unwrap(), 0).unwrap();
+        let free = free_inodes(&ext4);
+
+        ext4.rename(ROOT_INODE, "a", ROOT_INODE, "b", 0).unwrap();
+
+        assert_eq!(lookup(&ext4, "b").unwrap(), a);
+        assert_eq!(lookup(&ext4, "a").unwrap_err().error(), Errno::ENOENT);
+        assert!(!inode_in_use(&ext4, b));
+        assert!(!block_in_use(&ext4, b_block));
+        assert_eq!(free_inodes(&ext4), free + 1);
+    }
+
+    #[test]
+    fn test_rename_into_descendant() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+        ext4.dir_mk("a").unwrap();
+        ext4.dir_mk("a/b").unwrap();
+        let b = lookup(&ext4, "a/b").unwrap();
+
+        let r = ext4.rename(ROOT_INODE, "a", b, "a", 0);
+        assert_eq!(r.unwrap_err().error(), Errno::EINVAL);
+        assert!(lookup(&ext4, "a/b").is_ok());
+    }
+
+    #[test]
+    fn test_rename_directory_links() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+        ext4.dir_mk("p1").unwrap();
+        ext4.dir_mk("p2").unwrap();
+        ext4.dir_mk("p1/c").unwrap();
+        let (p1, p2) = (lookup(&ext4, "p1").unwrap(), lookup(&ext4, "p2").unwrap());
+        let c = lookup(&ext4, "p1/c").unwrap();
+        assert_eq!((links(&ext4, p1), links(&ext4, p2)), (3, 2));
+
+        ext4.rename(p1, "c", p2, "c", 0).unwrap();
+
+        assert_eq!((links(&ext4, p1), links(&ext4, p2)), (2, 3));
+        assert_eq!(lookup(&ext4, "p2/c").unwrap(), c);
+        assert_eq!(lookup(&ext4, "p2/c/..").unwrap(), p2);
+        assert_eq!(links(&ext4, c), 2);
+    }
+}
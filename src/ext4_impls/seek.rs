@@ -0,0 +1,200 @@
+use crate::prelude::*;
+use crate::return_errno_with_message;
+
+use crate::ext4_defs::*;
+
+impl Ext4 {
+    /// Compute a new file offset, like the lseek syscall.
+    ///
+    /// `SEEK_DATA` and `SEEK_HOLE` walk the block mapping of the file:
+    /// unwritten extents count as holes and the end of the file is a hole.
+    ///
+    /// Params:
+    /// inode: u32 - inode number of the file
+    /// pos: u64 - current file offset, for `SEEK_CUR`
+    /// offset: i64 - offset relative to `whence`
+    /// whence: u32 - `SEEK_SET`, `SEEK_CUR`, `SEEK_END`, `SEEK_DATA` or `SEEK_HOLE`
+    ///
+    /// Returns:
+    /// `Result<u64>` - the new file offset
+    pub fn lseek(&self, inode: u32, pos: u64, offset: i64, whence: u32) -> Result<u64> {
+        let inode_ref = self.get_inode_ref(inode)?;
+        let size = inode_ref.inode.size();
+        let block_size = self.super_block.block_size() as u64;
+
+        let new_pos = match whence {
+            SEEK_SET => Some(offset as u64).filter(|_| offset >= 0),
+            SEEK_CUR => pos.checked_add_signed(offset),
+            SEEK_END => size.checked_add_signed(offset),
+            SEEK_DATA | SEEK_HOLE => {
+                if offset < 0 || offset as u64 >= size {
+                    return_errno_with_message!(Errno::ENXIO, "seek for data or hole past the end of file");
+                }
+                let offset = offset as u64;
+                let lblock = (offset / block_size) as Ext4Lblk;
+
+                let found = if whence == SEEK_DATA {
+                    match self.seek_next_data(&inode_ref, lblock)? {
+                        Some((start, _)) if start as u64 * block_size < size => start as u64 * block_size,
+                        _ => return_errno_with_message!(Errno::ENXIO, "no data past the offset"),
+                    }
+                } else {
+                    // skip the runs of data following each other
+                    let mut lblock = lblock;
+                    while (lblock as u64) * block_size < size {
+                        match self.seek_next_data(&inode_ref, lblock)? {
+                            Some((start, end)) if start == lblock => lblock = end,
+                            _ => break,
+                        }
+                    }
+                    min(lblock as u64 * block_size, size)
+                };
+                Some(found.max(offset))
+            }
+            _ => return_errno_with_message!(Errno::EINVAL, "invalid whence"),
+        };
+
+        match new_pos {
+            Some(new_pos) if new_pos <= EXT_MAX_BLOCKS as u64 * block_size => Ok(new_pos),
+            _ => return_errno_with_message!(Errno::EINVAL, "invalid file offset"),
+        }
+    }
+
+    /// Find the first run of data blocks from a logical block on.
+    ///
+    /// Returns:
+    /// `Result<Option<(Ext4Lblk, Ext4Lblk)>>` - first block of the run and
+    /// the block after it, None if there is no data after `lblock`
+    fn seek_next_data(&self, inode_ref: &Ext4InodeRef, lblock: Ext4Lblk) -> Result<Option<(Ext4Lblk, Ext4Lblk)>> {
+        if !inode_ref.inode.has_extents() {
+            // the block map has no cheap way over holes, go block by block
+            let block_size = self.super_block.block_size() as u64;
+            let last = inode_ref.inode.size().div_ceil(block_size) as Ext4Lblk;
+            let mut start = lblock;
+            while start < last && self.indirect_get_pblock(inode_ref, start)? == 0 {
+                start += 1;
+            }
+            if start >= last {
+                return Ok(None);
+            }
+            let mut end = start + 1;
+            while end < last && self.indirect_get_pblock(inode_ref, end)? != 0 {
+                end += 1;
+            }
+            return Ok(Some((start, end)));
+        }
+
+        let mut lblock = lblock;
+        while let Some(ex) = self.extent_find_next(inode_ref, lblock)? {
+            let end = ex.get_last_block() + 1;
+            if !ex.is_unwritten() {
+                return Ok(Some((ex.first_block.max(lblock), end)));
+            }
+            lblock = end;
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ext4_impls::test_utils::*;
+
+    /// The offsets `SEEK_DATA` and `SEEK_HOLE` find from each of `offsets`.
+    fn seek_all(ext4: &Ext4, inode: u32, offsets: &[u64], whence: u32) -> Vec<Option<u64>> {
+        offsets
+            .iter()
+            .map(|&offset| match ext4.lseek(inode, 0, offset as i64, whence) {
+                Ok(pos) => Some(pos),
+                Err(err) => {
+                    assert_eq!(err.error(), Errno::ENXIO);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_lseek_set_cur_end() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+        let inode = create_file(&ext4, "file");
+        ext4.write_at(inode, 0, &pattern(5000, 0)).unwrap();
+
+        assert_eq!(ext4.lseek(inode, 300, 100, SEEK_SET).unwrap(), 100);
+        assert_eq!(ext4.lseek(inode, 300, 100, SEEK_CUR).unwrap(), 400);
+        assert_eq!(ext4.lseek(inode, 300, -300, SEEK_CUR).unwrap(), 0);
+        assert_eq!(ext4.lseek(inode, 300, 100, SEEK_END).unwrap(), 5100);
+        for (offset, whence) in [(-1, SEEK_SET), (-301, SEEK_CUR), (-5001, SEEK_END), (0, 5)] {
+            let err = ext4.lseek(inode, 300, offset, whence).unwrap_err();
+            assert_eq!(err.error(), Errno::EINVAL);
+        }
+    }
+
+    #[test]
+    fn test_seek_data_hole() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+        let bs = ext4.super_block.block_size() as u64;
+        // hole, data, hole, data, hole up to the end of the file
+        let inode = create_file(&ext4, "file");
+        ext4.write_at(inode, 2 * bs as usize, &pattern(2 * bs as usize, 0)).unwrap();
+        ext4.write_at(inode, 6 * bs as usize, &pattern(bs as usize, 0)).unwrap();
+        let mut inode_ref = ext4.get_inode_ref(inode).unwrap();
+        ext4.truncate_inode(&mut inode_ref, 10 * bs).unwrap();
+
+        let offsets = [0, 2 * bs + 1, 4 * bs, 6 * bs, 8 * bs, 10 * bs];
+        assert_eq!(
+            seek_all(&ext4, inode, &offsets, SEEK_DATA),
+            [Some(2 * bs), Some(2 * bs + 1), Some(6 * bs), Some(6 * bs), None, None]
+        );
+        assert_eq!(
+            seek_all(&ext4, inode, &offsets, SEEK_HOLE),
+            [Some(0), Some(4 * bs), Some(4 * bs), Some(7 * bs), Some(8 * bs), None]
+        );
+    }
+
+    #[test]
+    fn test_seek_unwritten() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+        let bs = ext4.super_block.block_size() as u64;
+        // data, unwritten, data, unwritten at the end of the file
+        let inode = create_file(&ext4, "file");
+        ext4.write_at(inode, 0, &pattern(bs as usize, 0)).unwrap();
+        ext4.fallocate(inode, 0, bs, 2 * bs).unwrap();
+        ext4.write_at(inode, 3 * bs as usize, &pattern(bs as usize, 0)).unwrap();
+        ext4.fallocate(inode, 0, 4 * bs, 2 * bs).unwrap();
+        assert_eq!(ext4.get_inode_ref(inode).unwrap().inode.size(), 6 * bs);
+
+        let offsets = [0, bs, 3 * bs, 4 * bs];
+        assert_eq!(
+            seek_all(&ext4, inode, &offsets, SEEK_DATA),
+            [Some(0), Some(3 * bs), Some(3 * bs), None]
+        );
+        assert_eq!(
+            seek_all(&ext4, inode, &offsets, SEEK_HOLE),
+            [Some(bs), Some(bs), Some(4 * bs), Some(4 * bs)]
+        );
+    }
+
+    #[test]
+    fn test_seek_indirect() {
+        let disk = MemDisk::new(INDIRECT_IMAGE);
+        let ext4 = mount(&disk);
+        let bs = ext4.super_block.block_size() as u64;
+        let inode = lookup(&ext4, "holey").unwrap();
+
+        // data in the blocks 0 to 2, 97 to 100 and 292 to 295 of 342
+        let offsets = [0, 10 * bs, 98 * bs, 200 * bs, 296 * bs];
+        assert_eq!(
+            seek_all(&ext4, inode, &offsets, SEEK_DATA),
+            [Some(0), Some(97 * bs), Some(98 * bs), Some(292 * bs), None]
+        );
+        assert_eq!(
+            seek_all(&ext4, inode, &offsets, SEEK_HOLE),
+            [Some(3 * bs), Some(10 * bs), Some(101 * bs), Some(200 * bs), Some(296 * bs)]
+        );
+    }
+}
@@ -0,0 +1,389 @@
+use crate::prelude::*;
+use crate::return_errno_with_message;
+
+use crate::ext4_defs::*;
+
+impl Ext4 {
+    /// Create a symbolic link in a directory.
+    ///
+    /// Targets of at most `EXT4_FAST_SYMLINK_MAX` bytes are stored in the
+    /// inode itself, longer ones in a data block, like Linux does.
+    ///
+    /// Params:
+    /// parent: u32 - inode number of the parent directory
+    /// name: &str - name of the link
+    /// target: &str - path the link points to
+    ///
+    /// Returns:
+    /// `Result<Ext4InodeRef>` - the new link inode
+    pub fn symlink(&self, parent: u32, name: &str, target: &str) -> Result<Ext4InodeRef> {
+        self.check_writable()?;
+
+        if target.is_empty() {
+            return_errno_with_message!(Errno::ENOENT, "empty symlink target");
+        }
+        // the target has to fit in one block with its terminating NUL
+        if target.len() >= self.super_block.block_size() as usize {
+            return_errno_with_message!(Errno::ENAMETOOLONG, "symlink target too long");
+        }
+
+        self.journal_transaction(|| {
+            let mut parent_ref = self.get_inode_ref(parent)?;
+            if !parent_ref.inode.is_dir() {
+                return_errno_with_message!(Errno::ENOTDIR, "not a directory");
+            }
+            let mut search_result = Ext4DirSearchResult::new(Ext4DirEntry::default());
+            match self.dir_find_entry(parent, name, &mut search_result) {
+                Ok(_) => return_errno_with_message!(Errno::EEXIST, "file exists"),
+                Err(e) if e.error() != Errno::ENOENT => return Err(e),
+                Err(_) => {}
+            }
+
+            let mut link_ref = self.create_inode(InodeFileType::S_IFLNK.bits() | 0o777)?;
+            if target.len() <= EXT4_FAST_SYMLINK_MAX {
+                link_ref.inode.set_fast_symlink_target(target.as_bytes());
+            }
+            self.write_back_inode(&mut link_ref)?;
+
+            self.link(&mut parent_ref, &mut link_ref, name)?;
+            self.write_back_inode(&mut parent_ref)?;
+            self.write_back_inode(&mut link_ref)?;
+
+            // slow symlink, the target goes to the first data block
+            if target.len() > EXT4_FAST_SYMLINK_MAX {
+                self.write_at(link_ref.inode_num, 0, target.as_bytes())?;
+                link_ref = self.get_inode_ref(link_ref.inode_num)?;
+            }
+
+            Ok(link_ref)
+        })
+    }
+
+    /// Read the target of a symbolic link.
+    ///
+    /// Params:
+    /// inode: u32 - inode number of the link
+    ///
+    /// Returns:
+    /// `Result<Vec<u8>>` - the target, without a terminating NUL
+    pub fn readlink(&self, inode: u32) -> Result<Vec<u8>> {
+        let inode_ref = self.get_inode_ref(inode)?;
+        if !inode_ref.inode.is_link() {
+            return_errno_with_message!(Errno::EINVAL, "not a symbolic link");
+        }
+
+        if inode_ref.inode.is_fast_symlink() {
+            return Ok(inode_ref.inode.fast_symlink_target());
+        }
+
+        let mut target = vec![0u8; inode_ref.inode.size() as usize];
+        let read_size = self.read_at(inode, 0, &mut target)?;
+        target.truncate(read_size);
+        Ok(target)
+    }
+
+    /// Resolve a path to an inode, following symbolic links.
+    ///
+    /// Relative paths start at `dir`, absolute ones at the root, and so do
+    /// link targets, relative to the directory holding the link. Links in
+    /// intermediate components are always followed, the last one only
+    /// without `O_NOFOLLOW`. Following more than `EXT4_MAX_SYMLINK_HOPS`
+    /// links fails with `ELOOP`.
+    ///
+    /// Params:
+    /// dir: u32 - inode number of the directory relative paths start from
+    /// path: &str - path to resolve
+    /// flags: i32 - open flags, only `O_NOFOLLOW` is looked at
+    ///
+    /// Returns:
+    /// `Result<u32>` - inode number the path leads to
+    pub fn resolve_path(&self, dir: u32, path: &str, flags: i32) -> Result<u32> {
+        let mut hops = 0;
+        self.resolve_path_from(dir, path, flags & O_NOFOLLOW == 0, &mut hops)
+    }
+
+    /// Find the directory and the name an `O_CREAT` open of a path creates.
+    ///
+    /// A last component that is a link to a missing file is followed, so
+    /// that the file the link points to gets created like Linux does, or
+    /// fails with `ELOOP` under `O_NOFOLLOW`. An existing last component
+    /// that is not a link fails with `EEXIST`.
+    ///
+    /// Params:
+    /// dir: u32 - inode number of the directory relative paths start from
+    /// path: &str - path to create
+    /// flags: i32 - open flags, only `O_NOFOLLOW` is looked at
+    ///
+    /// Returns:
+    /// `Result<(u32, String)>` - the parent directory and the name to create in it
+    pub fn resolve_create_path(&self, dir: u32, path: &str, flags: i32) -> Result<(u32, String)> {
+        let mut hops = 0;
+        let mut dir = dir;
+        let mut path = String::from(path);
+
+        loop {
+            if path.ends_with('/') {
+                return_errno_with_message!(Errno::EISDIR, "cannot create a directory with open");
+            }
+            let (parent_path, name) = path.split_at(path.rfind('/').map_or(0, |pos| pos + 1));
+            let parent = self.resolve_path_from(dir, parent_path, true, &mut hops)?;
+            if !self.get_inode_ref(parent)?.inode.is_dir() {
+                return_errno_with_message!(Errno::ENOTDIR, "not a directory");
+            }
+
+            let mut search_result = Ext4DirSearchResult::new(Ext4DirEntry::default());
+            match self.dir_find_entry(parent, name, &mut search_result) {
+                Err(e) if e.error() == Errno::ENOENT => return Ok((parent, String::from(name))),
+                Err(e) => return Err(e),
+                Ok(_) => {}
+            }
+
+            let link = search_result.dentry.inode;
+            if !self.get_inode_ref(link)?.inode.is_link() {
+                return_errno_with_message!(Errno::EEXIST, "file exists");
+            }
+            if flags & O_NOFOLLOW != 0 {
+                return_errno_with_message!(Errno::ELOOP, "last component is a symbolic link");
+            }
+            hops += 1;
+            if hops > EXT4_MAX_SYMLINK_HOPS {
+                return_errno_with_message!(Errno::ELOOP, "too many levels of symbolic links");
+            }
+
+            // the link target is created, relative to the directory of the link
+            let target = self.readlink(link)?;
+            path = String::from(core::str::from_utf8(&target)?);
+            dir = parent;
+        }
+    }
+
+    fn resolve_path_from(&self, dir: u32, path: &str, follow: bool, hops: &mut usize) -> Result<u32> {
+        let mut cur = if path.starts_with('/') { ROOT_INODE } else { dir };
+        let mut rest = path;
+
+        loop {
+            rest = rest.trim_start_matches('/');
+            if rest.is_empty() {
+                return Ok(cur);
+            }
+            let (name, tail) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+            rest = tail;
+            // a trailing slash asks for a directory, so the link is followed
+            let last = rest.is_empty();
+
+            if !self.get_inode_ref(cur)?.inode.is_dir() {
+                return_errno_with_message!(Errno::ENOTDIR, "not a directory");
+            }
+            let mut search_result = Ext4DirSearchResult::new(Ext4DirEntry::default());
+            self.dir_find_entry(cur, name, &mut search_result)?;
+
+            let mut next = search_result.dentry.inode;
+            if self.get_inode_ref(next)?.inode.is_link() && (follow || !last) {
+                next = self.follow_link(cur, next, hops)?;
+            }
+            if last {
+                return Ok(next);
+            }
+            cur = next;
+        }
+    }
+
+    /// Resolve the target of the link `link` found in directory `dir`.
+    ///
+    /// Params:
+    /// dir: u32 - directory holding the link, relative targets start there
+    /// link: u32 - inode number of the link
+    /// hops: &mut usize - links followed so far in this lookup
+    ///
+    /// Returns:
+    /// `Result<u32>` - inode number the target leads to
+    pub(crate) fn follow_link(&self, dir: u32, link: u32, hops: &mut usize) -> Result<u32> {
+        *hops += 1;
+        if *hops > EXT4_MAX_SYMLINK_HOPS {
+            return_errno_with_message!(Errno::ELOOP, "too many levels of symbolic links");
+        }
+
+        let target = self.readlink(link)?;
+        let target = core::str::from_utf8(&target)?;
+        self.resolve_path_from(dir, target, true, hops)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ext4_impls::test_utils::*;
+    use alloc::format;
+
+    /// Make the directory `/d` holding the file `/d/f`.
+    fn make_tree(ext4: &Ext4) -> (u32, u32) {
+        ext4.dir_mk("d").unwrap();
+        let dir = lookup(ext4, "d").unwrap();
+        let file = ext4.create(dir, "f", InodeFileType::S_IFREG.bits() | 0o644).unwrap().inode_num;
+        (dir, file)
+    }
+
+    #[test]
+    fn test_symlink_targets() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+        let (dir, file) = make_tree(&ext4);
+
+        // relative targets start in the directory of the link
+        ext4.symlink(dir, "rel", "f").unwrap();
+        ext4.symlink(dir, "up", "../d/./f").unwrap();
+        ext4.symlink(ROOT_INODE, "abs", "/d/f").unwrap();
+        let long = format!("{}f", "./".repeat(EXT4_FAST_SYMLINK_MAX));
+        ext4.symlink(dir, "slow", &long).unwrap();
+
+        for path in ["d/rel", "d/up", "abs", "/abs", "d/slow"] {
+            assert_eq!(ext4.resolve_path(ROOT_INODE, path, 0).ok(), Some(file), "{}", path);
+        }
+        // an absolute target does not depend on the directory of the lookup
+        assert_eq!(ext4.resolve_path(dir, "../abs", 0).unwrap(), file);
+        let slow = ext4.resolve_path(dir, "slow", O_NOFOLLOW).unwrap();
+        assert_eq!(ext4.readlink(slow).unwrap(), long.as_bytes());
+    }
+
+    #[test]
+    fn test_symlink_intermediate_component() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+        let (dir, file) = make_tree(&ext4);
+        ext4.symlink(ROOT_INODE, "dl", "d").unwrap();
+        ext4.symlink(dir, "rel", "f").unwrap();
+
+        // a link in the middle of a path is followed even with O_NOFOLLOW
+        assert_eq!(ext4.resolve_path(ROOT_INODE, "dl/f", O_NOFOLLOW).unwrap(), file);
+        assert_eq!(ext4.resolve_path(ROOT_INODE, "dl/", O_NOFOLLOW).unwrap(), dir);
+        assert_eq!(ext4.resolve_path(ROOT_INODE, "dl/rel", 0).unwrap(), file);
+
+        // the last one only without O_NOFOLLOW
+        let link = ext4.resolve_path(ROOT_INODE, "dl/rel", O_NOFOLLOW).unwrap();
+        assert!(ext4.get_inode_ref(link).unwrap().inode.is_link());
+        assert_eq!(ext4.readlink(link).unwrap(), b"f");
+
+        // a link to a file is not a directory
+        let err = ext4.resolve_path(ROOT_INODE, "dl/rel/x", 0).unwrap_err();
+        assert_eq!(err.error(), Errno::ENOTDIR);
+        let err = ext4.symlink(file, "x", "f").err().unwrap();
+        assert_eq!(err.error(), Errno::ENOTDIR);
+    }
+
+    #[test]
+    fn test_symlink_loop() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+        let file = create_file(&ext4, "l0");
+
+        // l<n> takes n hops to reach the file
+        for n in 1..=EXT4_MAX_SYMLINK_HOPS + 1 {
+            ext4.symlink(ROOT_INODE, &format!("l{}", n), &format!("l{}", n - 1)).unwrap();
+        }
+        let path = format!("l{}", EXT4_MAX_SYMLINK_HOPS);
+        assert_eq!(ext4.resolve_path(ROOT_INODE, &path, 0).unwrap(), file);
+        let path = format!("l{}", EXT4_MAX_SYMLINK_HOPS + 1);
+        let err = ext4.resolve_path(ROOT_INODE, &path, 0).unwrap_err();
+        assert_eq!(err.error(), Errno::ELOOP);
+
+        // the hops of intermediate components count too
+        ext4.dir_mk("d").unwrap();
+        ext4.symlink(ROOT_INODE, "m0", "d").unwrap();
+        for n in 1..=EXT4_MAX_SYMLINK_HOPS {
+            ext4.symlink(ROOT_INODE, &format!("m{}", n), &format!("m{}", n - 1)).unwrap();
+        }
+        let path = format!("m{}/", EXT4_MAX_SYMLINK_HOPS - 1);
+        assert_eq!(ext4.resolve_path(ROOT_INODE, &path, 0).unwrap(), lookup(&ext4, "d").unwrap());
+        let path = format!("m{}/", EXT4_MAX_SYMLINK_HOPS);
+        let err = ext4.resolve_path(ROOT_INODE, &path, 0).unwrap_err();
+        assert_eq!(err.error(), Errno::ELOOP);
+
+        // a cycle
+        ext4.symlink(ROOT_INODE, "a", "b").unwrap();
+        ext4.symlink(ROOT_INODE, "b", "a").unwrap();
+        let err = ext4.resolve_path(ROOT_INODE, "a", 0).unwrap_err();
+        assert_eq!(err.error(), Errno::ELOOP);
+        let a = ext4.resolve_path(ROOT_INODE, "a", O_NOFOLLOW).unwrap();
+        assert!(ext4.get_inode_ref(a).unwrap().inode.is_link());
+    }
+
+    #[test]
+    fn test_open_create_dangling_link() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+        let (dir, _) = make_tree(&ext4);
+        ext4.symlink(ROOT_INODE, "dang", "nothere").unwrap();
+        ext4.symlink(ROOT_INODE, "dang_dir", "d/new").unwrap();
+        ext4.symlink(dir, "dang_abs", "/abs_new").unwrap();
+
+        // the file the link points to is created, relative to the link
+        let inode = ext4.ext4_file_open("/dang", "w").unwrap();
+        assert!(ext4.get_inode_ref(inode).unwrap().inode.is_file());
+        assert_eq!(lookup(&ext4, "nothere").unwrap(), inode);
+        assert_eq!(ext4.write_at(inode, 0, b"data").unwrap(), 4);
+        assert_eq!(ext4.readlink(lookup(&ext4, "dang").unwrap()).unwrap(), b"nothere");
+        assert_eq!(read_file(&ext4, ext4.ext4_file_open("dang", "r").unwrap()), b"data");
+
+        let inode = ext4.ext4_file_open("dang_dir", "a").unwrap();
+        assert_eq!(ext4.resolve_path(dir, "new", 0).unwrap(), inode);
+        let inode = ext4.ext4_file_open("d/dang_abs", "w+").unwrap();
+        assert_eq!(lookup(&ext4, "abs_new").unwrap(), inode);
+
+        // a target in a missing directory is not created
+        ext4.symlink(ROOT_INODE, "dang_missing", "nodir/f").unwrap();
+        let err = ext4.ext4_file_open("dang_missing", "w").unwrap_err();
+        assert_eq!(err.error(), Errno::ENOENT);
+
+        // nor is one behind O_NOFOLLOW or a loop of links
+        let err = ext4.resolve_create_path(ROOT_INODE, "dang", O_NOFOLLOW).unwrap_err();
+        assert_eq!(err.error(), Errno::ELOOP);
+        ext4.symlink(ROOT_INODE, "a", "b").unwrap();
+        ext4.symlink(ROOT_INODE, "b", "a").unwrap();
+        let err = ext4.ext4_file_open("a", "w").unwrap_err();
+        assert_eq!(err.error(), Errno::ELOOP);
+    }
+
+    #[test]
+    fn test_linux_symlinks() {
+        let disk = MemDisk::new(LINUX_IMAGE);
+        let ext4 = mount(&disk);
+        let file = lookup(&ext4, "dir/file").unwrap();
+        assert_eq!(read_file(&ext4, file), pattern(3000, 1));
+
+        let fast = ext4.resolve_path(ROOT_INODE, "fast", O_NOFOLLOW).unwrap();
+        assert!(ext4.get_inode_ref(fast).unwrap().inode.is_fast_symlink());
+        assert_eq!(ext4.readlink(fast).unwrap(), b"dir/file");
+        let slow = ext4.resolve_path(ROOT_INODE, "slow", O_NOFOLLOW).unwrap();
+        let slow_ref = ext4.get_inode_ref(slow).unwrap();
+        assert!(!slow_ref.inode.is_fast_symlink() && slow_ref.inode.has_extents());
+        let target = format!("{}dir/file", "./".repeat(40));
+        assert_eq!(ext4.readlink(slow).unwrap(), target.as_bytes());
+
+        for path in ["fast", "slow", "dirlink/file", "/dirlink/../fast"] {
+            assert_eq!(ext4.resolve_path(ROOT_INODE, path, 0).ok(), Some(file), "{}", path);
+        }
+        let dir = lookup(&ext4, "dir").unwrap();
+        assert_eq!(ext4.resolve_path(ROOT_INODE, "dirlink", 0).unwrap(), dir);
+    }
+
+    #[test]
+    fn test_linux_symlinks_indirect() {
+        let disk = MemDisk::new(INDIRECT_IMAGE);
+        let ext4 = mount(&disk);
+
+        let fast = ext4.resolve_path(ROOT_INODE, "fastlink", O_NOFOLLOW).unwrap();
+        assert!(ext4.get_inode_ref(fast).unwrap().inode.is_fast_symlink());
+        assert_eq!(ext4.readlink(fast).unwrap(), b"direct");
+        // the target of the slow link is in a block mapped by i_block
+        let slow = ext4.resolve_path(ROOT_INODE, "slowlink", O_NOFOLLOW).unwrap();
+        let slow_ref = ext4.get_inode_ref(slow).unwrap();
+        assert!(!slow_ref.inode.is_fast_symlink() && !slow_ref.inode.has_extents());
+        let target = format!("{}indirect", "./".repeat(40));
+        assert_eq!(ext4.readlink(slow).unwrap(), target.as_bytes());
+
+        let file = ext4.resolve_path(ROOT_INODE, "fastlink", 0).unwrap();
+        assert_eq!(read_file(&ext4, file), image_file("direct"));
+        let file = ext4.resolve_path(ROOT_INODE, "slowlink", 0).unwrap();
+        assert_eq!(read_file(&ext4, file), image_file("indirect"));
+    }
+}
@@ -0,0 +1,175 @@
+//! Filesystem images and helpers shared by the tests of the filesystem code.
+//!
+//! The images are made by `tests/images/gen_images.sh`.
+
+use crate::prelude::*;
+
+use crate::ext4_defs::*;
+
+/// 8M, 1k blocks, default ext4 features with a journal, empty.
+pub const EXT4_IMAGE: &[u8] = include_bytes!("../../tests/images/ext4.img");
+
+/// 4M, 1k blocks, no extents and no journal. Holds the files `direct`,
+/// `indirect`, `dindirect` and `holey`, see `image_file`, and the links
+/// `fastlink` to `direct` and `slowlink` to `indirect`.
+pub const INDIRECT_IMAGE: &[u8] = include_bytes!("../../tests/images/indirect.img");
+
+/// 8M, 1k blocks, like `EXT4_IMAGE` but with a journal to replay, logged
+/// with csum v3 tags. Holds the one block files `replayed`, `revoked` and
+/// `uncommitted`, made with `pattern` seeds 1, 2 and 3. The log rewrites
+/// the first two with 0x5a bytes, then revokes the block of `revoked`, then
+/// rewrites `uncommitted` in a transaction without a commit block.
+pub const JOURNAL_IMAGE: &[u8] = include_bytes!("../../tests/images/journal.img");
+
+/// 8M, 1k blocks, like `EXT4_IMAGE` with files made by Linux: `dir/file`
+/// of 3000 bytes made with `pattern` seed 1, the fast link `fast` and the
+/// slow link `slow` to it, and the link `dirlink` to `/dir`. `dir/file`
+/// has the xattr `user.small` "hello" in the inode and `user.big`, 200
+/// bytes made with `pattern` seed 2, in an xattr block. The directory
+/// `htree` holds the empty files `file0000` to `file0499` and was indexed
+/// by `e2fsck -D`.
+pub const LINUX_IMAGE: &[u8] = include_bytes!("../../tests/images/linux.img");
+
+/// 4M, 1k blocks, four block groups of 128 inodes, empty. Groups 1 to 3
+/// are still flagged INODE_UNINIT.
+pub const GROUPS_IMAGE: &[u8] = include_bytes!("../../tests/images/groups.img");
+
+/// In-memory block device, a filesystem can be dropped and mounted again
+/// from the same device.
+pub struct MemDisk {
+    data: Mutex<Vec<u8>>,
+}
+
+impl MemDisk {
+    pub fn new(image: &[u8]) -> Arc<Self> {
+        Arc::new(MemDisk {
+            data: Mutex::new(image.to_vec()),
+        })
+    }
+
+    /// `len` bytes of the device at `offset`.
+    pub fn bytes(&self, offset: usize, len: usize) -> Vec<u8> {
+        self.data.lock()[offset..offset + len].to_vec()
+    }
+}
+
+impl BlockDevice for MemDisk {
+    fn read_offset(&self, offset: usize) -> Result<Vec<u8>> {
+        let data = self.data.lock();
+        let end = min(offset + 4096, data.len());
+        Ok(data[offset.min(end)..end].to_vec())
+    }
+
+    fn write_offset(&self, offset: usize, data: &[u8]) -> Result<()> {
+        self.data.lock()[offset..offset + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+}
+
+pub fn mount(disk: &Arc<MemDisk>) -> Ext4 {
+    Ext4::open(disk.clone()).unwrap()
+}
+
+/// Create an empty regular file in the root directory.
+pub fn create_file(ext4: &Ext4, name: &str) -> u32 {
+    let mode = InodeFileType::S_IFREG.bits() | 0o644;
+    ext4.create(ROOT_INODE, name, mode).unwrap().inode_num
+}
+
+pub fn lookup(ext4: &Ext4, path: &str) -> Result<u32> {
+    ext4.generic_open(path, &mut ROOT_INODE.clone(), false, 0, &mut 0)
+}
+
+/// Read a whole file.
+pub fn read_file(ext4: &Ext4, inode: u32) -> Vec<u8> {
+    let size = ext4.get_inode_ref(inode).unwrap().inode.size() as usize;
+    let mut buf = vec![0u8; size];
+    let read = ext4.read_at(inode, 0, &mut buf).unwrap();
+    assert_eq!(read, size);
+    buf
+}
+
+/// Deterministic file content, byte `i` of a file made with `seed`.
+pub fn pattern(len: usize, seed: usize) -> Vec<u8> {
+    (0..len).map(|i| ((i * 7 + seed) % 251) as u8).collect()
+}
+
+/// Content of a file of `INDIRECT_IMAGE`.
+pub fn image_file(name: &str) -> Vec<u8> {
+    match name {
+        "direct" => pattern(5000, 1),
+        "indirect" => pattern(200000, 2),
+        "dindirect" => pattern(400000, 3),
+        "holey" => {
+            let mut data = vec![0u8; 350000];
+            for off in [0, 100000, 300000] {
+                data[off..off + 3000].copy_from_slice(&pattern(3000, off % 251));
+            }
+            data
+        }
+        _ => panic!("no file {} in the image", name),
+    }
+}
+
+/// Free inode count of the superblock as the filesystem sees it.
+pub fn free_inodes(ext4: &Ext4) -> u32 {
+    Ext4Superblock::load(ext4.block_device.clone()).unwrap().free_inodes_count()
+}
+
+/// Free block count of the superblock as the filesystem sees it.
+pub fn free_blocks(ext4: &Ext4) -> u64 {
+    Ext4Superblock::load(ext4.block_device.clone()).unwrap().free_blocks_count()
+}
+
+/// Whether the filesystem block `pblock` is marked used in its bitmap.
+pub fn block_in_use(ext4: &Ext4, pblock: Ext4Fsblk) -> bool {
+    let super_block = &ext4.super_block;
+    let blocks_per_group = super_block.blocks_per_group() as u64;
+    let first = super_block.first_data_block() as u64;
+    let bgid = ((pblock - first) / blocks_per_group) as u32;
+    let bit = ((pblock - first) % blocks_per_group) as usize;
+
+    let bg = Ext4BlockGroup::load_new(ext4.block_device.clone(), super_block, bgid as usize).unwrap();
+    let bitmap_block = bg.get_block_bitmap_block(super_block) as usize;
+    let bitmap = ext4.block_device.read_offset(bitmap_block * super_block.block_size() as usize).unwrap();
+    bitmap[bit / 8] & (1 << (bit % 8)) != 0
+}
+
+/// The extents of a file, in logical order.
+pub fn file_extents(ext4: &Ext4, inode: u32) -> Vec<Ext4Extent> {
+    let inode_ref = ext4.get_inode_ref(inode).unwrap();
+    let mut extents = Vec::new();
+    let mut lblock = 0;
+    while let Some(ex) = ext4.extent_find_next(&inode_ref, lblock).unwrap() {
+        lblock = ex.get_last_block() + 1;
+        extents.push(ex);
+    }
+    extents
+}
+
+/// Create a file mapping every other block up to block `2 * count`, one
+/// extent per written block. Returns the inode and the content.
+pub fn create_fragmented_file(ext4: &Ext4, name: &str, count: usize) -> (u32, Vec<u8>) {
+    let block_size = ext4.super_block.block_size() as usize;
+    let inode = create_file(ext4, name);
+    let mut data = vec![0u8; (2 * count - 1) * block_size];
+    for i in 0..count {
+        let offset = 2 * i * block_size;
+        let block = pattern(block_size, i);
+        ext4.write_at(inode, offset, &block).unwrap();
+        data[offset..offset + block_size].copy_from_slice(&block);
+    }
+    (inode, data)
+}
+
+/// Whether inode `inode` is marked used in its bitmap.
+pub fn inode_in_use(ext4: &Ext4, inode: u32) -> bool {
+    let super_block = &ext4.super_block;
+    let bgid = (inode - 1) / super_block.inodes_per_group;
+    let bit = ((inode - 1) % super_block.inodes_per_group) as usize;
+
+    let bg = Ext4BlockGroup::load_new(ext4.block_device.clone(), super_block, bgid as usize).unwrap();
+    let bitmap_block = bg.get_inode_bitmap_block(super_block) as usize;
+    let bitmap = ext4.block_device.read_offset(bitmap_block * super_block.block_size() as usize).unwrap();
+    bitmap[bit / 8] & (1 << (bit % 8)) != 0
+}
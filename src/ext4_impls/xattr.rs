@@ -0,0 +1,532 @@
+use core::ops::Range;
+
+use crate::prelude::*;
+use crate::return_errno_with_message;
+
+use crate::ext4_defs::*;
+
+/// Extended attributes live in the inode record after `i_extra_isize`,
+/// then in one external block referenced by `file_acl`. New attributes
+/// go to the inode while they fit, like Linux does.
+impl Ext4 {
+    /// Get the value of an extended attribute.
+    ///
+    /// Params:
+    /// inode: u32 - inode number
+    /// name: &str - full attribute name, e.g. "user.mime_type"
+    ///
+    /// Returns:
+    /// `Result<Vec<u8>>` - the value, `ENODATA` if the attribute is missing
+    pub fn getxattr(&self, inode: u32, name: &str) -> Result<Vec<u8>> {
+        let probe = Ext4Xattr::new(name, &[])?;
+        let inode_ref = self.get_inode_ref(inode)?;
+
+        let mut xattrs = self.xattr_ibody_load(&inode_ref)?;
+        xattrs.extend(self.xattr_block_load(&inode_ref)?);
+        match xattrs.into_iter().find(|x| x.same_name(&probe)) {
+            Some(xattr) => Ok(xattr.value),
+            None => return_errno_with_message!(Errno::ENODATA, "no such xattr"),
+        }
+    }
+
+    /// List the extended attributes of an inode.
+    ///
+    /// Params:
+    /// inode: u32 - inode number
+    ///
+    /// Returns:
+    /// `Result<Vec<u8>>` - full names, each ending with a NUL like listxattr(2)
+    pub fn listxattr(&self, inode: u32) -> Result<Vec<u8>> {
+        let inode_ref = self.get_inode_ref(inode)?;
+
+        let mut xattrs = self.xattr_ibody_load(&inode_ref)?;
+        xattrs.extend(self.xattr_block_load(&inode_ref)?);
+
+        let mut list = Vec::new();
+        for name in xattrs.iter().filter_map(|x| x.full_name()) {
+            list.extend_from_slice(&name);
+            list.push(0);
+        }
+        Ok(list)
+    }
+
+    /// Set an extended attribute.
+    ///
+    /// Params:
+    /// inode: u32 - inode number
+    /// name: &str - full attribute name, e.g. "user.mime_type"
+    /// value: &[u8] - attribute value
+    /// flags: u32 - 0, `XATTR_CREATE` or `XATTR_REPLACE`
+    ///
+    /// Returns:
+    /// `Result<usize>` - status of the operation, `ENOSPC` if the attribute
+    /// fits neither in the inode nor in the xattr block
+    pub fn setxattr(&self, inode: u32, name: &str, value: &[u8], flags: u32) -> Result<usize> {
+        self.check_writable()?;
+
+        if flags & !(XATTR_CREATE | XATTR_REPLACE) != 0 {
+            return_errno_with_message!(Errno::EINVAL, "invalid xattr flags");
+        }
+        let xattr = Ext4Xattr::new(name, value)?;
+        // permission checks interpret ACLs, refuse malformed ones
+        if matches!(
+            xattr.name_index,
+            EXT4_XATTR_INDEX_POSIX_ACL_ACCESS | EXT4_XATTR_INDEX_POSIX_ACL_DEFAULT
+        ) {
+            Ext4Acl::from_disk(value)?;
+        }
+
+        self.journal_transaction(|| {
+            let mut inode_ref = self.get_inode_ref(inode)?;
+            let mut ibody = self.xattr_ibody_load(&inode_ref)?;
+            let mut block = self.xattr_block_load(&inode_ref)?;
+
+            let in_ibody = ibody.iter().position(|x| x.same_name(&xattr));
+            let in_block = block.iter().position(|x| x.same_name(&xattr));
+            let exists = in_ibody.is_some() || in_block.is_some();
+            if exists && flags & XATTR_CREATE != 0 {
+                return_errno_with_message!(Errno::EEXIST, "xattr exists");
+            }
+            if !exists && flags & XATTR_REPLACE != 0 {
+                return_errno_with_message!(Errno::ENODATA, "no such xattr");
+            }
+
+            if let Some(pos) = in_ibody {
+                ibody.remove(pos);
+            }
+            if let Some(pos) = in_block {
+                block.remove(pos);
+            }
+
+            ibody.push(xattr.clone());
+            if ext4_xattr_fits(&ibody, self.xattr_ibody_space(&inode_ref.inode)) {
+                if in_block.is_some() {
+                    self.xattr_block_store(&mut inode_ref, &mut block)?;
+                }
+                self.xattr_ibody_store(&mut inode_ref, &ibody)?;
+                return Ok(EOK);
+            }
+
+            ibody.pop();
+            block.push(xattr.clone());
+            if !ext4_xattr_fits(&block, self.xattr_block_space()) {
+                return_errno_with_message!(Errno::ENOSPC, "no space for xattr");
+            }
+            self.xattr_block_store(&mut inode_ref, &mut block)?;
+            if in_ibody.is_some() {
+                self.xattr_ibody_store(&mut inode_ref, &ibody)?;
+            }
+            Ok(EOK)
+        })
+    }
+
+    /// Remove an extended attribute.
+    ///
+    /// Params:
+    /// inode: u32 - inode number
+    /// name: &str - full attribute name
+    ///
+    /// Returns:
+    /// `Result<usize>` - status of the operation, `ENODATA` if the attribute is missing
+    pub fn removexattr(&self, inode: u32, name: &str) -> Result<usize> {
+        self.check_writable()?;
+
+        let probe = Ext4Xattr::new(name, &[])?;
+
+        self.journal_transaction(|| {
+            let mut inode_ref = self.get_inode_ref(inode)?;
+
+            let mut ibody = self.xattr_ibody_load(&inode_ref)?;
+            if let Some(pos) = ibody.iter().position(|x| x.same_name(&probe)) {
+                ibody.remove(pos);
+                self.xattr_ibody_store(&mut inode_ref, &ibody)?;
+                return Ok(EOK);
+            }
+
+            let mut block = self.xattr_block_load(&inode_ref)?;
+            if let Some(pos) = block.iter().position(|x| x.same_name(&probe)) {
+                block.remove(pos);
+                self.xattr_block_store(&mut inode_ref, &mut block)?;
+                return Ok(EOK);
+            }
+
+            return_errno_with_message!(Errno::ENODATA, "no such xattr")
+        })
+    }
+
+    /// Drop all extended attributes of an inode being freed.
+    ///
+    /// Params:
+    /// inode_ref: &mut Ext4InodeRef - inode reference, written back
+    pub fn xattr_release(&self, inode_ref: &mut Ext4InodeRef) -> Result<()> {
+        let pblock = inode_ref.inode.file_acl_block();
+        if pblock != 0 {
+            self.xattr_block_release(inode_ref, pblock)?;
+            inode_ref.inode.set_file_acl_block(0);
+        }
+        self.xattr_ibody_store(inode_ref, &[])
+    }
+
+    /// Range of the in-inode xattr area in the on-disk inode record, None
+    /// for inodes without room for it.
+    fn xattr_ibody_range(&self, inode: &Ext4Inode) -> Option<Range<usize>> {
+        let inode_size = self.super_block.inode_size() as usize;
+        if inode_size <= EXT4_GOOD_OLD_INODE_SIZE as usize {
+            return None;
+        }
+        let start = EXT4_GOOD_OLD_INODE_SIZE as usize + inode.i_extra_isize() as usize;
+        // the magic and the end of the entry list
+        if start + 8 > inode_size {
+            return None;
+        }
+        Some(start..inode_size)
+    }
+
+    /// Bytes for entries and values in the in-inode area.
+    fn xattr_ibody_space(&self, inode: &Ext4Inode) -> usize {
+        self.xattr_ibody_range(inode).map_or(0, |range| range.len() - 4)
+    }
+
+    /// Bytes for entries and values in an xattr block.
+    fn xattr_block_space(&self) -> usize {
+        self.super_block.block_size() as usize - size_of::<Ext4XattrHeader>()
+    }
+
+    fn xattr_ibody_load(&self, inode_ref: &Ext4InodeRef) -> Result<Vec<Ext4Xattr>> {
+        let range = match self.xattr_ibody_range(&inode_ref.inode) {
+            Some(range) => range,
+            None => return Ok(Vec::new()),
+        };
+
+        let raw = self.inode_load_raw(inode_ref.inode_num)?;
+        let area = &raw[range];
+        if u32::from_le_bytes(area[..4].try_into().unwrap()) != EXT4_XATTR_MAGIC {
+            return Ok(Vec::new());
+        }
+        // in-inode value offsets start at the first entry
+        ext4_xattr_parse(&area[4..], 0)
+    }
+
+    /// Rewrite the in-inode area, the caller checked the attributes fit.
+    fn xattr_ibody_store(&self, inode_ref: &mut Ext4InodeRef, xattrs: &[Ext4Xattr]) -> Result<()> {
+        let range = match self.xattr_ibody_range(&inode_ref.inode) {
+            Some(range) => range,
+            None => return self.write_back_inode(inode_ref),
+        };
+
+        let mut raw = self.inode_load_raw(inode_ref.inode_num)?;
+        let area = &mut raw[range];
+        area.fill(0);
+        if !xattrs.is_empty() {
+            area[..4].copy_from_slice(&EXT4_XATTR_MAGIC.to_le_bytes());
+            ext4_xattr_build(xattrs, &mut area[4..], 0);
+        }
+        self.write_back_inode_raw(inode_ref, &mut raw)
+    }
+
+    /// Load an xattr block, checking its header and checksum.
+    fn xattr_block_read(&self, pblock: Ext4Fsblk) -> Result<Block> {
+        let block_size = self.super_block.block_size() as usize;
+        let block = Block::load(self.block_device.clone(), pblock as usize * block_size, block_size)?;
+
+        let header = Ext4XattrHeader::load(&block.data);
+        if header.magic != EXT4_XATTR_MAGIC || header.blocks != 1 {
+            return_errno_with_message!(Errno::EIO, "bad xattr block header");
+        }
+        self.verify_csum("xattr block", pblock, || {
+            Ext4XattrHeader::verify_checksum(&self.super_block, pblock, &block.data)
+        })?;
+        Ok(block)
+    }
+
+    fn xattr_block_load(&self, inode_ref: &Ext4InodeRef) -> Result<Vec<Ext4Xattr>> {
+        let pblock = inode_ref.inode.file_acl_block();
+        if pblock == 0 {
+            return Ok(Vec::new());
+        }
+        let block = self.xattr_block_read(pblock)?;
+        ext4_xattr_parse(&block.data, size_of::<Ext4XattrHeader>())
+    }
+
+    /// Rewrite the xattr block of an inode, the caller checked the
+    /// attributes fit. A block shared with other inodes is copied first, an
+    /// empty one is released.
+    fn xattr_block_store(&self, inode_ref: &mut Ext4InodeRef, xattrs: &mut [Ext4Xattr]) -> Result<()> {
+        let old = inode_ref.inode.file_acl_block();
+        let mut pblock = old;
+        if old != 0 {
+            let refcount = Ext4XattrHeader::load(&self.xattr_block_read(old)?.data).refcount;
+            if refcount > 1 || xattrs.is_empty() {
+                self.xattr_block_release(inode_ref, old)?;
+                pblock = 0;
+            }
+        }
+
+        if xattrs.is_empty() {
+            inode_ref.inode.set_file_acl_block(0);
+            return self.write_back_inode(inode_ref);
+        }
+
+        if pblock == 0 {
+            let goal = if old != 0 { Some(old) } else { None };
+            pblock = self.balloc_alloc_block(inode_ref, goal)?;
+            inode_ref.inode.set_file_acl_block(pblock);
+            self.write_back_inode(inode_ref)?;
+        }
+
+        let block_size = self.super_block.block_size() as usize;
+        let mut block = Block {
+            disk_offset: pblock as usize * block_size,
+            data: vec![0u8; block_size],
+        };
+        Ext4XattrHeader::sort(xattrs);
+        ext4_xattr_build(xattrs, &mut block.data, size_of::<Ext4XattrHeader>());
+        let header = Ext4XattrHeader {
+            magic: EXT4_XATTR_MAGIC,
+            refcount: 1,
+            blocks: 1,
+            hash: Ext4XattrHeader::block_hash(xattrs),
+            ..Default::default()
+        };
+        header.store(&mut block.data);
+        if self.super_block.has_metadata_csum() {
+            Ext4XattrHeader::set_checksum(&self.super_block, pblock, &mut block.data);
+        }
+        block.sync_blk_to_disk(self.block_device.clone())
+    }
+
+    /// Drop the reference of an inode to an xattr block, freeing the block
+    /// with its last reference. `file_acl` is left to the caller.
+    fn xattr_block_release(&self, inode_ref: &mut Ext4InodeRef, pblock: Ext4Fsblk) -> Result<()> {
+        let mut block = self.xattr_block_read(pblock)?;
+        let mut header = Ext4XattrHeader::load(&block.data);
+        if header.refcount <= 1 {
+            return self.balloc_free_blocks(inode_ref, pblock, 1);
+        }
+
+        header.refcount -= 1;
+        header.store(&mut block.data);
+        if self.super_block.has_metadata_csum() {
+            Ext4XattrHeader::set_checksum(&self.super_block, pblock, &mut block.data);
+        }
+        block.sync_blk_to_disk(self.block_device.clone())?;
+
+        // the shared block no longer counts for this inode
+        let block_size = self.super_block.block_size() as usize;
+        let blocks = inode_ref.inode.blocks_count();
+        inode_ref
+            .inode
+            .set_blocks_count(blocks.saturating_sub((block_size / EXT4_INODE_BLOCK_SIZE) as u64));
+        self.write_back_inode(inode_ref)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ext4_impls::test_utils::*;
+
+    /// The xattr block of an inode as on the device, and its number.
+    fn xattr_block(ext4: &Ext4, inode: u32) -> (Ext4Fsblk, Vec<u8>) {
+        let pblock = ext4.get_inode_ref(inode).unwrap().inode.file_acl_block();
+        assert_ne!(pblock, 0);
+        let block_size = ext4.super_block.block_size() as usize;
+        let data = ext4.block_device.read_offset(pblock as usize * block_size).unwrap();
+        (pblock, data[..block_size].to_vec())
+    }
+
+    /// Let inode `inode` share the xattr block of inode `owner`.
+    fn share_xattr_block(ext4: &Ext4, owner: u32, inode: u32) {
+        let (pblock, mut data) = xattr_block(ext4, owner);
+        let mut header = Ext4XattrHeader::load(&data);
+        header.refcount += 1;
+        header.store(&mut data);
+        Ext4XattrHeader::set_checksum(&ext4.super_block, pblock, &mut data);
+        let block_size = ext4.super_block.block_size() as usize;
+        ext4.block_device.write_offset(pblock as usize * block_size, &data).unwrap();
+
+        let mut inode_ref = ext4.get_inode_ref(inode).unwrap();
+        inode_ref.inode.set_file_acl_block(pblock);
+        let blocks = inode_ref.inode.blocks_count() + (block_size / EXT4_INODE_BLOCK_SIZE) as u64;
+        inode_ref.inode.set_blocks_count(blocks);
+        ext4.write_back_inode(&mut inode_ref).unwrap();
+    }
+
+    fn blocks_count(ext4: &Ext4, inode: u32) -> u64 {
+        ext4.get_inode_ref(inode).unwrap().inode.blocks_count()
+    }
+
+    #[test]
+    fn test_xattr_set_get_list_remove() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+        let inode = create_file(&ext4, "file");
+
+        ext4.setxattr(inode, "user.a", b"1", 0).unwrap();
+        assert_eq!(ext4.getxattr(inode, "user.a").unwrap(), b"1");
+        let err = ext4.setxattr(inode, "user.a", b"2", XATTR_CREATE).unwrap_err();
+        assert_eq!(err.error(), Errno::EEXIST);
+        let err = ext4.setxattr(inode, "user.b", b"2", XATTR_REPLACE).unwrap_err();
+        assert_eq!(err.error(), Errno::ENODATA);
+        ext4.setxattr(inode, "user.a", b"replaced", XATTR_REPLACE).unwrap();
+        ext4.setxattr(inode, "trusted.b", b"2", XATTR_CREATE).unwrap();
+        assert_eq!(ext4.getxattr(inode, "user.a").unwrap(), b"replaced");
+        assert_eq!(ext4.listxattr(inode).unwrap(), b"user.a\0trusted.b\0");
+
+        let err = ext4.setxattr(inode, "user.c", b"", XATTR_CREATE | 4).unwrap_err();
+        assert_eq!(err.error(), Errno::EINVAL);
+        let err = ext4.setxattr(inode, "unknown.c", b"", 0).unwrap_err();
+        assert_eq!(err.error(), Errno::ENOTSUP);
+
+        ext4.removexattr(inode, "user.a").unwrap();
+        assert_eq!(ext4.getxattr(inode, "user.a").unwrap_err().error(), Errno::ENODATA);
+        assert_eq!(ext4.removexattr(inode, "user.a").unwrap_err().error(), Errno::ENODATA);
+        assert_eq!(ext4.listxattr(inode).unwrap(), b"trusted.b\0");
+        // everything fit in the inode
+        assert_eq!(ext4.get_inode_ref(inode).unwrap().inode.file_acl_block(), 0);
+        drop(ext4);
+
+        let ext4 = mount(&disk);
+        assert_eq!(ext4.getxattr(inode, "trusted.b").unwrap(), b"2");
+    }
+
+    #[test]
+    fn test_xattr_spill_to_block() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+        let inode = create_file(&ext4, "file");
+        let free = free_blocks(&ext4);
+        let block_size = ext4.super_block.block_size() as usize;
+        let block_sectors = (block_size / EXT4_INODE_BLOCK_SIZE) as u64;
+
+        // the second value does not fit in the inode
+        ext4.setxattr(inode, "user.small", b"small", 0).unwrap();
+        assert_eq!(ext4.get_inode_ref(inode).unwrap().inode.file_acl_block(), 0);
+        ext4.setxattr(inode, "user.big", &pattern(200, 1), 0).unwrap();
+        let (pblock, data) = xattr_block(&ext4, inode);
+        assert!(block_in_use(&ext4, pblock));
+        assert_eq!(free_blocks(&ext4), free - 1);
+        assert_eq!(blocks_count(&ext4, inode), block_sectors);
+        assert_eq!(Ext4XattrHeader::load(&data).refcount, 1);
+        assert!(Ext4XattrHeader::verify_checksum(&ext4.super_block, pblock, &data));
+        assert_eq!(ext4.getxattr(inode, "user.small").unwrap(), b"small");
+        assert_eq!(ext4.getxattr(inode, "user.big").unwrap(), pattern(200, 1));
+        assert_eq!(ext4.listxattr(inode).unwrap(), b"user.small\0user.big\0");
+        drop(ext4);
+
+        // a smaller value moves back to the inode and the block is freed
+        let ext4 = mount(&disk);
+        assert_eq!(ext4.getxattr(inode, "user.big").unwrap(), pattern(200, 1));
+        ext4.setxattr(inode, "user.big", b"big", XATTR_REPLACE).unwrap();
+        assert_eq!(ext4.get_inode_ref(inode).unwrap().inode.file_acl_block(), 0);
+        assert!(!block_in_use(&ext4, pblock));
+        assert_eq!(free_blocks(&ext4), free);
+        assert_eq!(blocks_count(&ext4, inode), 0);
+        assert_eq!(ext4.getxattr(inode, "user.big").unwrap(), b"big");
+        assert_eq!(ext4.getxattr(inode, "user.small").unwrap(), b"small");
+
+        // and removing the last attribute of a block frees it too
+        ext4.setxattr(inode, "user.big", &pattern(200, 1), 0).unwrap();
+        let (pblock, _) = xattr_block(&ext4, inode);
+        ext4.removexattr(inode, "user.big").unwrap();
+        assert_eq!(ext4.get_inode_ref(inode).unwrap().inode.file_acl_block(), 0);
+        assert!(!block_in_use(&ext4, pblock));
+        assert_eq!(free_blocks(&ext4), free);
+    }
+
+    #[test]
+    fn test_xattr_shared_block() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let mut ext4 = mount(&disk);
+        let block_size = ext4.super_block.block_size() as usize;
+        let block_sectors = (block_size / EXT4_INODE_BLOCK_SIZE) as u64;
+        let owner = create_file(&ext4, "owner");
+        ext4.setxattr(owner, "user.big", &pattern(200, 1), 0).unwrap();
+        let (shared, _) = xattr_block(&ext4, owner);
+        let free = free_blocks(&ext4);
+
+        // a write to a shared block goes to a copy
+        let inode = create_file(&ext4, "file");
+        share_xattr_block(&ext4, owner, inode);
+        assert_eq!(ext4.getxattr(inode, "user.big").unwrap(), pattern(200, 1));
+        ext4.setxattr(inode, "user.big", &pattern(200, 2), 0).unwrap();
+        let (pblock, data) = xattr_block(&ext4, inode);
+        assert_ne!(pblock, shared);
+        assert_eq!(Ext4XattrHeader::load(&data).refcount, 1);
+        let (_, data) = xattr_block(&ext4, owner);
+        assert_eq!(Ext4XattrHeader::load(&data).refcount, 1);
+        assert!(Ext4XattrHeader::verify_checksum(&ext4.super_block, shared, &data));
+        assert_eq!(ext4.getxattr(owner, "user.big").unwrap(), pattern(200, 1));
+        assert_eq!(ext4.getxattr(inode, "user.big").unwrap(), pattern(200, 2));
+        assert_eq!(blocks_count(&ext4, inode), block_sectors);
+        assert_eq!(free_blocks(&ext4), free - 1);
+
+        // unlinking a sharer only drops its reference
+        let sharer = create_file(&ext4, "sharer");
+        share_xattr_block(&ext4, owner, sharer);
+        ext4.file_remove("sharer").unwrap();
+        assert!(block_in_use(&ext4, shared));
+        let (_, data) = xattr_block(&ext4, owner);
+        assert_eq!(Ext4XattrHeader::load(&data).refcount, 1);
+        assert_eq!(ext4.getxattr(owner, "user.big").unwrap(), pattern(200, 1));
+        assert_eq!(blocks_count(&ext4, owner), block_sectors);
+
+        // the last reference frees it
+        ext4.file_remove("owner").unwrap();
+        assert!(!block_in_use(&ext4, shared));
+        assert_eq!(free_blocks(&ext4), free);
+    }
+
+    #[test]
+    fn test_xattr_block_checksum() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let ext4 = mount(&disk);
+        let block_size = ext4.super_block.block_size() as usize;
+        let inode = create_file(&ext4, "file");
+        ext4.setxattr(inode, "user.big", &pattern(200, 1), 0).unwrap();
+        let (pblock, _) = xattr_block(&ext4, inode);
+        drop(ext4);
+
+        // the last byte of the value, values are at the end of the block
+        let offset = (pblock as usize + 1) * block_size - 1;
+        let byte = disk.bytes(offset, 1)[0];
+        disk.write_offset(offset, &[!byte]).unwrap();
+
+        let ext4 = mount(&disk);
+        let err = ext4.getxattr(inode, "user.big").unwrap_err();
+        assert_eq!(err.error(), Errno::EIO);
+        drop(ext4);
+
+        let options = Ext4MountOptions { checksum_policy: Ext4ChecksumPolicy::Warn, ..Default::default() };
+        let ext4 = Ext4::open_with_options(disk.clone(), options).unwrap();
+        let mut value = pattern(200, 1);
+        value[199] = !value[199];
+        assert_eq!(ext4.getxattr(inode, "user.big").unwrap(), value);
+    }
+
+    #[test]
+    fn test_linux_xattrs() {
+        let disk = MemDisk::new(LINUX_IMAGE);
+        let ext4 = mount(&disk);
+        let inode = lookup(&ext4, "dir/file").unwrap();
+        let free = free_blocks(&ext4);
+
+        assert_eq!(ext4.getxattr(inode, "user.small").unwrap(), b"hello");
+        assert_eq!(ext4.getxattr(inode, "user.big").unwrap(), pattern(200, 2));
+        assert_eq!(ext4.listxattr(inode).unwrap(), b"user.small\0user.big\0");
+        let (pblock, data) = xattr_block(&ext4, inode);
+        assert!(Ext4XattrHeader::verify_checksum(&ext4.super_block, pblock, &data));
+
+        // attributes written by Linux can be changed
+        ext4.setxattr(inode, "user.small", b"world", XATTR_REPLACE).unwrap();
+        ext4.removexattr(inode, "user.big").unwrap();
+        assert_eq!(ext4.get_inode_ref(inode).unwrap().inode.file_acl_block(), 0);
+        assert_eq!(free_blocks(&ext4), free + 1);
+        drop(ext4);
+
+        let ext4 = mount(&disk);
+        assert_eq!(ext4.listxattr(inode).unwrap(), b"user.small\0");
+        assert_eq!(ext4.getxattr(inode, "user.small").unwrap(), b"world");
+        assert_eq!(read_file(&ext4, inode), pattern(3000, 1));
+    }
+}
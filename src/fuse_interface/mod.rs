@@ -1,3 +1,5 @@
+#![allow(clippy::too_many_arguments)]
+
 use crate::prelude::*;
 
 use crate::ext4_defs::*;
@@ -21,16 +23,16 @@ impl Ext4 {
 
         let inode_num = search_result.dentry.inode;
 
-        let inode_ref = self.get_inode_ref(inode_num);
-        let file_attr = FileAttr::from_inode_ref(&inode_ref);
+        let inode_ref = self.get_inode_ref(inode_num)?;
+        let file_attr = FileAttr::from_inode_ref(&inode_ref, self.super_block.block_size());
 
         Ok(file_attr)
     }
 
     /// Get file attributes.
     pub fn fuse_getattr(&self, ino: u64) -> Result<FileAttr> {
-        let inode_ref = self.get_inode_ref(ino as u32);
-        let file_attr = FileAttr::from_inode_ref(&inode_ref);
+        let inode_ref = self.get_inode_ref(ino as u32)?;
+        let file_attr = FileAttr::from_inode_ref(&inode_ref, self.super_block.block_size());
         Ok(file_attr)
     }
 
@@ -50,8 +52,10 @@ impl Ext4 {
         chgtime: Option<u32>,
         bkuptime: Option<u32>,
         flags: Option<u32>,
-    ) {
-        let mut inode_ref = self.get_inode_ref(ino as u32);
+    ) -> Result<()> {
+        self.check_writable()?;
+
+        let mut inode_ref = self.get_inode_ref(ino as u32)?;
 
         let mut attr = FileAttr::default();
 
@@ -105,16 +109,12 @@ impl Ext4 {
 
         inode_ref.set_attr(&attr);
 
-        self.write_back_inode(&mut inode_ref);
+        self.journal_transaction(|| self.write_back_inode(&mut inode_ref))
     }
 
     /// Read symbolic link.
-    fn fuse_readlink(&mut self, ino: u64) -> Result<Vec<u8>> {
-        let inode_ref = self.get_inode_ref(ino as u32);
-        let file_size = inode_ref.inode.size();
-        let mut read_buf = vec![0; file_size as usize];
-        let read_size = self.read_at(ino as u32, 0, &mut read_buf)?;
-        Ok(read_buf)
+    pub fn fuse_readlink(&mut self, ino: u64) -> Result<Vec<u8>> {
+        self.readlink(ino as u32)
     }
 
 
@@ -127,8 +127,15 @@ impl Ext4 {
         umask: u32,
         rdev: u32,
     ) -> Result<Ext4InodeRef> {
+        self.check_writable()?;
+
         let mut search_result = Ext4DirSearchResult::new(Ext4DirEntry::default());
         let r = self.dir_find_entry(parent as u32, name, &mut search_result);
+        if let Err(e) = r {
+            if e.error() != Errno::ENOENT {
+                return Err(e);
+            }
+        }
         if r.is_ok() {
             return_errno!(Errno::EEXIST);
         }
@@ -148,8 +155,15 @@ impl Ext4 {
         uid: u32, 
         gid: u32,
     ) -> Result<Ext4InodeRef> {
+        self.check_writable()?;
+
         let mut search_result = Ext4DirSearchResult::new(Ext4DirEntry::default());
         let r = self.dir_find_entry(parent as u32, name, &mut search_result);
+        if let Err(e) = r {
+            if e.error() != Errno::ENOENT {
+                return Err(e);
+            }
+        }
         if r.is_ok() {
             return_errno!(Errno::EEXIST);
         }
@@ -159,8 +173,15 @@ impl Ext4 {
 
     /// Create a directory.
     pub fn fuse_mkdir(&mut self, parent: u64, name: &str, mode: u32, umask: u32) -> Result<usize> {
+        self.check_writable()?;
+
         let mut search_result = Ext4DirSearchResult::new(Ext4DirEntry::default());
         let r = self.dir_find_entry(parent as u32, name, &mut search_result);
+        if let Err(e) = r {
+            if e.error() != Errno::ENOENT {
+                return Err(e);
+            }
+        }
         if r.is_ok() {
             return_errno!(Errno::EEXIST);
         }
@@ -175,9 +196,15 @@ impl Ext4 {
 
     /// Create a directory.
     pub fn fuse_mkdir_with_attr(&mut self, parent: u64, name: &str, mode: u32, umask: u32, uid:u32, gid:u32) -> Result<Ext4InodeRef> {
+        self.check_writable()?;
 
         let mut search_result = Ext4DirSearchResult::new(Ext4DirEntry::default());
         let r = self.dir_find_entry(parent as u32, name, &mut search_result);
+        if let Err(e) = r {
+            if e.error() != Errno::ENOENT {
+                return Err(e);
+            }
+        }
         if r.is_ok() {
             return_errno!(Errno::EEXIST);
         }
@@ -195,70 +222,68 @@ impl Ext4 {
 
     /// Remove a file.
     pub fn fuse_unlink(&self, parent: u64, name: &str) -> Result<usize> {
-        // unlink actual remove a file
+        self.check_writable()?;
 
-        // get child inode num
-        let mut parent_inode = parent as u32;
-        let mut nameoff = 0;
-        let child_inode = self.generic_open(name, &mut parent_inode, false, 0, &mut nameoff)?;
+        self.journal_transaction(|| {
+            // unlink actual remove a file
 
-        let mut child_inode_ref = self.get_inode_ref(child_inode);
-        let child_link_cnt = child_inode_ref.inode.links_count();
-        if child_link_cnt == 1 {
-            self.truncate_inode(&mut child_inode_ref, 0)?;
-        }
+            // get child inode num
+            let mut parent_inode = parent as u32;
+            let mut nameoff = 0;
+            let child_inode = self.generic_open(name, &mut parent_inode, false, 0, &mut nameoff)?;
 
-        // get child name
-        let mut is_goal = false;
-        let p = &name[nameoff as usize..];
-        let len = path_check(p, &mut is_goal);
+            let mut child_inode_ref = self.get_inode_ref(child_inode)?;
+            let child_link_cnt = child_inode_ref.inode.links_count();
+            if child_link_cnt == 1 {
+                self.truncate_inode(&mut child_inode_ref, 0)?;
+            }
 
-        // load parent
-        let mut parent_inode_ref = self.get_inode_ref(parent_inode);
+            // get child name
+            let mut is_goal = false;
+            let p = &name[nameoff as usize..];
+            let len = path_check(p, &mut is_goal);
 
-        let r = self.unlink(
-            &mut parent_inode_ref,
-            &mut child_inode_ref,
-            &p[..len],
-        )?;
+            // load parent
+            let mut parent_inode_ref = self.get_inode_ref(parent_inode)?;
 
-        Ok(EOK)
+            let r = self.unlink(
+                &mut parent_inode_ref,
+                &mut child_inode_ref,
+                &p[..len],
+            )?;
+
+            Ok(EOK)
+        })
     }
     /// Remove a directory.
     pub fn fuse_rmdir(&mut self, parent: u64, name: &str) -> Result<usize> {
-        let mut search_result = Ext4DirSearchResult::new(Ext4DirEntry::default());
+        self.check_writable()?;
 
-        let r = self.dir_find_entry(parent as u32, name, &mut search_result)?;
+        self.journal_transaction(|| {
+            let mut search_result = Ext4DirSearchResult::new(Ext4DirEntry::default());
 
-        let mut parent_inode_ref = self.get_inode_ref(parent as u32);
-        let mut child_inode_ref = self.get_inode_ref(search_result.dentry.inode);
+            let r = self.dir_find_entry(parent as u32, name, &mut search_result)?;
 
-        self.truncate_inode(&mut child_inode_ref, 0)?;
+            let mut parent_inode_ref = self.get_inode_ref(parent as u32)?;
+            let mut child_inode_ref = self.get_inode_ref(search_result.dentry.inode)?;
 
-        self.unlink(&mut parent_inode_ref, &mut child_inode_ref, name)?;
+            self.truncate_inode(&mut child_inode_ref, 0)?;
 
-        self.write_back_inode(&mut parent_inode_ref);
+            self.unlink(&mut parent_inode_ref, &mut child_inode_ref, name)?;
 
-        // to do
-        // ext4_inode_set_del_time
-        // ext4_inode_set_links_cnt
-        // ext4_fs_free_inode(&child)
+            self.write_back_inode(&mut parent_inode_ref)?;
 
-        Ok(EOK)
+            // to do
+            // ext4_inode_set_del_time
+            // ext4_inode_set_links_cnt
+            // ext4_fs_free_inode(&child)
+
+            Ok(EOK)
+        })
     }
     /// Create a symbolic link.
     pub fn fuse_symlink(&mut self, parent: u64, link_name: &str, target: &str) -> Result<usize> {
-        let mut search_result = Ext4DirSearchResult::new(Ext4DirEntry::default());
-        let r = self.dir_find_entry(parent as u32, link_name, &mut search_result);
-        if r.is_ok() {
-            return_errno!(Errno::EEXIST);
-        }
-
-        let mut mode = 0o777;
-        let file_type = InodeFileType::S_IFLNK;
-        mode |= file_type.bits();
-
-        let inode_ref = self.create(parent as u32, link_name, mode)?;
+        self.symlink(parent as u32, link_name, target)?;
         Ok(EOK)
     }
     /// Create a hard link.
@@ -269,13 +294,18 @@ impl Ext4 {
     ///
     ///
     pub fn fuse_link(&mut self, ino: u64, newparent: u64, newname: &str) -> Result<usize> {
-        let mut parent_inode_ref = self.get_inode_ref(newparent as u32);
-        let mut child_inode_ref = self.get_inode_ref(ino as u32);
+        self.check_writable()?;
 
-        // to do if child already exists we should not add . and .. in child directory
-        self.link(&mut parent_inode_ref, &mut child_inode_ref, newname)?;
+        self.journal_transaction(|| {
+            let mut parent_inode_ref = self.get_inode_ref(newparent as u32)?;
+            let mut child_inode_ref = self.get_inode_ref(ino as u32)?;
 
-        Ok(EOK)
+            // to do if child already exists we should not add . and .. in child directory
+            self.link(&mut parent_inode_ref, &mut child_inode_ref, newname)?;
+            self.write_back_inode(&mut child_inode_ref)?;
+
+            Ok(EOK)
+        })
     }
 
     /// Open a file.
@@ -287,7 +317,11 @@ impl Ext4 {
     /// filesystem may set, to change the way the file is opened. See fuse_file_info
     /// structure in <fuse_common.h> for more details.
     pub fn fuse_open(&mut self, ino: u64, flags: i32) -> Result<usize> {
-        let inode_ref = self.get_inode_ref(ino as u32);
+        if flags & (O_WRONLY | O_RDWR | O_TRUNC) != 0 {
+            self.check_writable()?;
+        }
+
+        let inode_ref = self.get_inode_ref(ino as u32)?;
 
         // check permission
         let file_type = inode_ref.inode.file_type();
@@ -302,7 +336,7 @@ impl Ext4 {
             return_errno_with_message!(Errno::EACCES, "Permission denied can not write");
         }
         // If trying to open the file in read mode, check for read permissions
-        if ((flags & O_RDONLY != 0) || (flags & O_RDWR != 0)) && !can_read {
+        if ((flags & O_ACCMODE == O_RDONLY) || (flags & O_RDWR != 0)) && !can_read {
             return_errno_with_message!(Errno::EACCES, "Permission denied can not read");
         }
 
@@ -361,6 +395,8 @@ impl Ext4 {
         flags: i32,
         lock_owner: Option<u64>,
     ) -> Result<usize> {
+        self.check_writable()?;
+
         let write_size = self.write_at(ino as u32, offset as usize, data)?;
         Ok(write_size)
     }
@@ -373,7 +409,7 @@ impl Ext4 {
     /// directory stream operations in case the contents of the directory can change
     /// between opendir and releasedir.
     pub fn fuse_opendir(&mut self, ino: u64, flags: i32) -> Result<usize> {
-        let inode_ref = self.get_inode_ref(ino as u32);
+        let inode_ref = self.get_inode_ref(ino as u32)?;
 
         // 检查是否为目录
         if !inode_ref.inode.is_dir() {
@@ -396,7 +432,7 @@ impl Ext4 {
     /// value set by the opendir method, or will be undefined if the opendir method
     /// didn't set any value.
     pub fn fuse_readdir(&self, ino: u64, fh: u64, offset: i64) -> Result<Vec<Ext4DirEntry>> {
-        let mut entries = self.dir_get_entries(ino as u32);
+        let mut entries = self.dir_get_entries(ino as u32)?;
         entries = entries[offset as usize..].to_vec();
         Ok(entries)
     }
@@ -422,8 +458,17 @@ impl Ext4 {
         // check file exist
         let mut search_result = Ext4DirSearchResult::new(Ext4DirEntry::default());
         let r = self.dir_find_entry(parent as u32, name, &mut search_result);
+        if let Err(e) = r {
+            if e.error() != Errno::ENOENT {
+                return Err(e);
+            }
+        }
         if r.is_ok() {
-            let inode_ref = self.get_inode_ref(search_result.dentry.inode);
+            if flags & (O_WRONLY | O_RDWR | O_TRUNC) != 0 {
+                self.check_writable()?;
+            }
+
+            let inode_ref = self.get_inode_ref(search_result.dentry.inode)?;
 
             // check permission
             let file_perm = inode_ref.inode.file_perm();
@@ -438,7 +483,7 @@ impl Ext4 {
             }
 
             // If trying to open the file in read mode, check for read permissions
-            if (flags & O_RDONLY != 0) || (flags & O_RDWR != 0) && !can_read{
+            if ((flags & O_ACCMODE == O_RDONLY) || (flags & O_RDWR != 0)) && !can_read {
                 return_errno_with_message!(Errno::EACCES, "Permission denied can not read");
             }
 
@@ -464,10 +509,12 @@ impl Ext4 {
     /// int faccessat(int dirfd, const char *pathname, int mode, int flags);
     /// 
     /// uid and gid come from request
-    pub fn fuse_access(&mut self, ino: u64, uid: u16, gid: u16, mode: u16, mask: i32) -> bool {
-        let inode_ref = self.get_inode_ref(ino as u32);
+    pub fn fuse_access(&mut self, ino: u64, uid: u16, gid: u16, mode: u16, mask: i32) -> Result<bool> {
+        if mode & W_OK as u16 != 0 {
+            self.check_writable()?;
+        }
 
-        inode_ref.inode.check_access(uid, gid, mode, mask as u16)
+        self.inode_check_access(ino as u32, uid, gid, mode, mask as u16)
     }
 
     /// Get file system statistics.
@@ -475,8 +522,8 @@ impl Ext4 {
     /// int stat(const char *restrict pathname, struct stat *restrict statbuf);
     /// int fstatat(int dirfd, const char *restrict pathname, struct stat *restrict statbuf, int flags);
     pub fn fuse_statfs(&mut self, ino: u64) -> Result<LinuxStat> {
-        let inode_ref = self.get_inode_ref(ino as u32);
-        let linux_stat = LinuxStat::from_inode_ref(&inode_ref);
+        let inode_ref = self.get_inode_ref(ino as u32)?;
+        let linux_stat = LinuxStat::from_inode_ref(&inode_ref, self.super_block.block_size());
         Ok(linux_stat)
     }
 
@@ -490,12 +537,14 @@ impl Ext4 {
     /// Clean up filesystem.
     /// Called on filesystem exit.
     pub fn fuse_destroy(&mut self) -> Result<usize> {
+        self.sync()?;
         Ok(EOK)
     }
 
     /// Rename a file.
-    fn fuse_rename(&mut self, parent: u64, name: &str, newparent: u64, newname: &str, flags: u32) {
-        unimplemented!();
+    /// flags may hold RENAME_NOREPLACE or RENAME_EXCHANGE.
+    pub fn fuse_rename(&mut self, parent: u64, name: &str, newparent: u64, newname: &str, flags: u32) -> Result<usize> {
+        self.rename(parent as u32, name, newparent as u32, newname, flags)
     }
 
     /// Flush method.
@@ -534,8 +583,9 @@ impl Ext4 {
     /// Synchronize file contents.
     /// If the datasync parameter is non-zero, then only the user data should be flushed,
     /// not the meta data.
-    fn fuse_fsync(&mut self, ino: u64, fh: u64, datasync: bool) {
-        unimplemented!();
+    pub fn fuse_fsync(&mut self, ino: u64, fh: u64, datasync: bool) -> Result<usize> {
+        self.sync()?;
+        Ok(EOK)
     }
 
     /// Read directory.
@@ -559,34 +609,47 @@ impl Ext4 {
     /// If the datasync parameter is set, then only the directory contents should
     /// be flushed, not the meta data. fh will contain the value set by the opendir
     /// method, or will be undefined if the opendir method didn't set any value.
-    fn fuse_fsyncdir(&mut self, ino: u64, fh: u64, datasync: bool) {
-        unimplemented!();
+    pub fn fuse_fsyncdir(&mut self, ino: u64, fh: u64, datasync: bool) -> Result<usize> {
+        self.sync()?;
+        Ok(EOK)
     }
 
     /// Set an extended attribute.
-    fn fuse_setxattr(&mut self, ino: u64, name: &str, _value: &[u8], flags: i32, position: u32) {
-        unimplemented!();
+    /// flags may hold XATTR_CREATE or XATTR_REPLACE, position is only used on macOS.
+    pub fn fuse_setxattr(&mut self, ino: u64, name: &str, value: &[u8], flags: i32, position: u32) -> Result<usize> {
+        if position != 0 {
+            return_errno_with_message!(Errno::EINVAL, "xattr position is not supported");
+        }
+        self.setxattr(ino as u32, name, value, flags as u32)
     }
 
     /// Get an extended attribute.
     /// If `size` is 0, the size of the value should be sent with `reply.size()`.
     /// If `size` is not 0, and the value fits, send it with `reply.data()`, or
     /// `reply.error(ERANGE)` if it doesn't.
-    fn fuse_getxattr(&mut self, ino: u64, name: &str, size: u32) {
-        unimplemented!();
+    pub fn fuse_getxattr(&mut self, ino: u64, name: &str, size: u32) -> Result<Vec<u8>> {
+        let value = self.getxattr(ino as u32, name)?;
+        if size != 0 && value.len() > size as usize {
+            return_errno_with_message!(Errno::ERANGE, "xattr value larger than the buffer");
+        }
+        Ok(value)
     }
 
     /// List extended attribute names.
     /// If `size` is 0, the size of the value should be sent with `reply.size()`.
     /// If `size` is not 0, and the value fits, send it with `reply.data()`, or
     /// `reply.error(ERANGE)` if it doesn't.
-    fn fuse_listxattr(&mut self, ino: u64, size: u32) {
-        unimplemented!();
+    pub fn fuse_listxattr(&mut self, ino: u64, size: u32) -> Result<Vec<u8>> {
+        let list = self.listxattr(ino as u32)?;
+        if size != 0 && list.len() > size as usize {
+            return_errno_with_message!(Errno::ERANGE, "xattr list larger than the buffer");
+        }
+        Ok(list)
     }
 
     /// Remove an extended attribute.
-    fn fuse_removexattr(&mut self, ino: u64, name: &str) {
-        unimplemented!();
+    pub fn fuse_removexattr(&mut self, ino: u64, name: &str) -> Result<usize> {
+        self.removexattr(ino as u32, name)
     }
 
     /// Test for a POSIX file lock.
@@ -627,8 +690,19 @@ impl Ext4 {
     /// Map block index within file to block index within device.
     /// Note: This makes sense only for block device backed filesystems mounted
     /// with the 'blkdev' option
-    fn fuse_bmap(&mut self, ino: u64, blocksize: u32, idx: u64) {
-        unimplemented!();
+    /// Holes and unwritten blocks map to block 0.
+    pub fn fuse_bmap(&mut self, ino: u64, blocksize: u32, idx: u64) -> Result<u64> {
+        if blocksize == 0 {
+            return_errno_with_message!(Errno::EINVAL, "invalid bmap block size");
+        }
+        let offset = idx * blocksize as u64;
+        let extents = self.fiemap(ino as u32, offset, 1, 0)?;
+        match extents.first() {
+            Some(ex) if ex.logical <= offset && ex.flags & FIEMAP_EXTENT_UNWRITTEN == 0 => {
+                Ok((ex.physical + offset - ex.logical) / blocksize as u64)
+            }
+            _ => Ok(0),
+        }
     }
 
     /// control device
@@ -657,17 +731,26 @@ impl Ext4 {
     // }
 
     /// Preallocate or deallocate space to a file
-    fn fuse_fallocate(&mut self, ino: u64, fh: u64, offset: i64, length: i64, mode: i32) {
-        unimplemented!();
+    pub fn fuse_fallocate(&mut self, ino: u64, fh: u64, offset: i64, length: i64, mode: i32) -> Result<usize> {
+        if offset < 0 || length <= 0 {
+            return_errno_with_message!(Errno::EINVAL, "invalid fallocate range");
+        }
+        self.fallocate(ino as u32, mode as u32, offset as u64, length as u64)
     }
 
     /// Reposition read/write file offset
-    fn fuse_lseek(&mut self, ino: u64, fh: u64, offset: i64, whence: i32) {
-        unimplemented!();
+    /// The kernel resolves SEEK_SET and SEEK_CUR itself, the offset of the
+    /// open file is not known here, so SEEK_CUR is refused.
+    pub fn fuse_lseek(&mut self, ino: u64, fh: u64, offset: i64, whence: i32) -> Result<i64> {
+        if whence as u32 == SEEK_CUR {
+            return_errno_with_message!(Errno::EINVAL, "SEEK_CUR without the file offset");
+        }
+        let pos = self.lseek(ino as u32, 0, offset, whence as u32)?;
+        Ok(pos as i64)
     }
 
     /// Copy the specified range from the source inode to the destination inode
-    fn fuse_copy_file_range(
+    pub fn fuse_copy_file_range(
         &mut self,
         ino_in: u64,
         fh_in: u64,
@@ -677,7 +760,84 @@ impl Ext4 {
         offset_out: i64,
         len: u64,
         flags: u32,
-    ) {
-        unimplemented!();
+    ) -> Result<usize> {
+        if offset_in < 0 || offset_out < 0 {
+            return_errno_with_message!(Errno::EINVAL, "invalid copy_file_range offset");
+        }
+        let copied = self.copy_file_range(
+            ino_in as u32,
+            offset_in as u64,
+            ino_out as u32,
+            offset_out as u64,
+            len,
+            flags,
+        )?;
+        Ok(copied as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ext4_impls::test_utils::*;
+
+    #[test]
+    fn test_bmap() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let mut ext4 = mount(&disk);
+        let block_size = ext4.super_block.block_size();
+        let inode = create_file(&ext4, "file");
+        ext4.write_at(inode, 0, &pattern(3 * block_size as usize, 0)).unwrap();
+        ext4.fallocate(inode, 0, 5 * block_size as u64, block_size as u64).unwrap();
+        let pblock = file_extents(&ext4, inode)[0].get_pblock();
+        let ino = inode as u64;
+
+        assert_eq!(ext4.fuse_bmap(ino, block_size, 0).unwrap(), pblock);
+        assert_eq!(ext4.fuse_bmap(ino, block_size, 2).unwrap(), pblock + 2);
+        // a smaller block size maps into the filesystem block
+        assert_eq!(ext4.fuse_bmap(ino, block_size / 2, 3).unwrap(), 2 * pblock + 3);
+        // holes and unwritten blocks map to 0
+        assert_eq!(ext4.fuse_bmap(ino, block_size, 4).unwrap(), 0);
+        assert_eq!(ext4.fuse_bmap(ino, block_size, 5).unwrap(), 0);
+        assert_eq!(ext4.fuse_bmap(ino, block_size, 100).unwrap(), 0);
+
+        let err = ext4.fuse_bmap(ino, 0, 0).unwrap_err();
+        assert_eq!(err.error(), Errno::EINVAL);
+    }
+
+    #[test]
+    fn test_lseek() {
+        let disk = MemDisk::new(EXT4_IMAGE);
+        let mut ext4 = mount(&disk);
+        let inode = create_file(&ext4, "file");
+        ext4.write_at(inode, 0, &pattern(5000, 0)).unwrap();
+        let ino = inode as u64;
+
+        assert_eq!(ext4.fuse_lseek(ino, 0, 100, SEEK_SET as i32).unwrap(), 100);
+        assert_eq!(ext4.fuse_lseek(ino, 0, -100, SEEK_END as i32).unwrap(), 4900);
+        assert_eq!(ext4.fuse_lseek(ino, 0, 100, SEEK_DATA as i32).unwrap(), 100);
+        assert_eq!(ext4.fuse_lseek(ino, 0, 100, SEEK_HOLE as i32).unwrap(), 5000);
+        // the offset of the open file is not known
+        let err = ext4.fuse_lseek(ino, 0, 100, SEEK_CUR as i32).unwrap_err();
+        assert_eq!(err.error(), Errno::EINVAL);
+    }
+
+    #[test]
+    fn test_bmap_indirect() {
+        let disk = MemDisk::new(INDIRECT_IMAGE);
+        let mut ext4 = mount(&disk);
+        let block_size = ext4.super_block.block_size();
+        let inode = lookup(&ext4, "holey").unwrap();
+        let data = image_file("holey");
+
+        for (idx, mapped) in [(0, true), (50, false), (98, true), (200, false), (293, true)] {
+            let pblock = ext4.fuse_bmap(inode as u64, block_size, idx).unwrap();
+            assert_eq!(pblock != 0, mapped, "block {}", idx);
+            if mapped {
+                let offset = idx as usize * block_size as usize;
+                let on_disk = disk.bytes(pblock as usize * block_size as usize, 512);
+                assert_eq!(on_disk, data[offset..offset + 512]);
+            }
+        }
     }
 }
@@ -1,6 +1,7 @@
 #![feature(error_in_core)]
 #![no_std]
 #![allow(unused)]
+#![deny(unused_must_use)]
 
 extern crate alloc;
 
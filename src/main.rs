@@ -69,7 +69,7 @@ enum ColorCode {
 pub struct Disk {}
 
 impl BlockDevice for Disk {
-    fn read_offset(&self, offset: usize) -> Vec<u8> {
+    fn read_offset(&self, offset: usize) -> Result<Vec<u8>> {
         // log::info!("read_offset: {:x?}", offset);
         use std::fs::OpenOptions;
         use std::io::{Read, Seek};
@@ -77,25 +77,29 @@ impl BlockDevice for Disk {
             .read(true)
             .write(true)
             .open("ex4.img")
-            .unwrap();
-        let mut buf = vec![0u8; BLOCK_SIZE as usize];
-        let _r = file.seek(std::io::SeekFrom::Start(offset as u64));
-        let _r = file.read_exact(&mut buf);
-
-        buf
+            .map_err(|_| Ext4Error::new(Errno::EIO))?;
+        let mut buf = vec![0u8; BLOCK_SIZE];
+        file.seek(std::io::SeekFrom::Start(offset as u64))
+            .map_err(|_| Ext4Error::new(Errno::EIO))?;
+        file.read_exact(&mut buf)
+            .map_err(|_| Ext4Error::new(Errno::EIO))?;
+
+        Ok(buf)
     }
 
-    fn write_offset(&self, offset: usize, data: &[u8]) {
+    fn write_offset(&self, offset: usize, data: &[u8]) -> Result<()> {
         use std::fs::OpenOptions;
         use std::io::{Seek, Write};
         let mut file = OpenOptions::new()
             .read(true)
             .write(true)
             .open("ex4.img")
-            .unwrap();
+            .map_err(|_| Ext4Error::new(Errno::EIO))?;
 
-        let _r = file.seek(std::io::SeekFrom::Start(offset as u64));
-        let _r = file.write_all(&data);
+        file.seek(std::io::SeekFrom::Start(offset as u64))
+            .map_err(|_| Ext4Error::new(Errno::EIO))?;
+        file.write_all(data)
+            .map_err(|_| Ext4Error::new(Errno::EIO))
     }
 }
 
@@ -103,26 +107,26 @@ fn main() {
     log::set_logger(&SimpleLogger).unwrap();
     log::set_max_level(LevelFilter::Trace);
     let disk = Arc::new(Disk {});
-    let ext4 = Ext4::open(disk);
+    let ext4 = Ext4::open(disk).unwrap();
 
     // file read
     let path = "test_files/0.txt";
     // 1G
     const READ_SIZE: usize = (0x100000 * 1024);
-    let mut read_buf = vec![0u8;  READ_SIZE as usize];
+    let mut read_buf = vec![0u8;  READ_SIZE];
     let child_inode = ext4.generic_open(path, &mut 2, false, 0, &mut 0).unwrap();
-    let mut data = vec![0u8; READ_SIZE as usize];
-    let read_data = ext4.read_at(child_inode, 0 as usize, &mut data);
+    let mut data = vec![0u8; READ_SIZE];
+    let read_data = ext4.read_at(child_inode, 0_usize, &mut data);
     log::info!("read data  {:?}", &data[..10]);
 
 
 
     let path = "test_files/linktest";
-    let mut read_buf = vec![0u8;  READ_SIZE as usize];
-    // 2 is root inode
-    let child_inode = ext4.generic_open(path, &mut 2, false, 0, &mut 0).unwrap();
-    let mut data = vec![0u8; READ_SIZE as usize];
-    let read_data = ext4.read_at(child_inode, 0 as usize, &mut data);
+    let mut read_buf = vec![0u8;  READ_SIZE];
+    // 2 is root inode, the link is followed to its target
+    let child_inode = ext4.resolve_path(2, path, 0).unwrap();
+    let mut data = vec![0u8; READ_SIZE];
+    let read_data = ext4.read_at(child_inode, 0_usize, &mut data);
     log::info!("read data  {:?}", &data[..10]);
 
     // dir make
@@ -131,16 +135,16 @@ fn main() {
         let path = format!("dirtest{}", i);
         let path = path.as_str();
         log::info!("mkdir making {:?}", path);
-        let r = ext4.dir_mk(&path);
+        let r = ext4.dir_mk(path);
         assert!(r.is_ok(), "dir make error {:?}", r.err());
     }
     let path = "dir1/dir2/dir3/dir4/dir5/dir6";
     log::info!("mkdir making {:?}", path);
-    let r = ext4.dir_mk(&path);
+    let r = ext4.dir_mk(path);
     assert!(r.is_ok(), "dir make error {:?}", r.err());
 
     // dir ls
-    let entries = ext4.dir_get_entries(ROOT_INODE);
+    let entries = ext4.dir_get_entries(ROOT_INODE).unwrap();
     log::info!("dir ls root");
     for entry in entries {
         log::info!("{:?}", entry.get_name());
@@ -148,11 +152,11 @@ fn main() {
 
     // file remove
     let path = "test_files/file_to_remove";
-    let r = ext4.file_remove(&path);
+    let r = ext4.file_remove(path);
 
     // dir remove
     let path = "dir_to_remove";
-    let r = ext4.dir_remove(ROOT_INODE, &path);
+    let r = ext4.dir_remove(ROOT_INODE, path);
 
     // file create/write
     log::info!("----create file----");
@@ -161,15 +165,17 @@ fn main() {
     let inode_ref = ext4.create(ROOT_INODE, "4G.txt", inode_mode | inode_perm).unwrap();
     log::info!("----write file----");
     const WRITE_SIZE: usize = (0x100000 * (4096));
-    let write_buf = vec![0x41 as u8; WRITE_SIZE];
+    let write_buf = vec![0x41_u8; WRITE_SIZE];
     let r = ext4.write_at(inode_ref.inode_num, 0, &write_buf);
 
     // check
     let path = "4G.txt";
-    let mut read_buf = vec![0u8;  WRITE_SIZE as usize];
+    let mut read_buf = vec![0u8;  WRITE_SIZE];
     let child_inode = ext4.generic_open(path, &mut 2, false, 0, &mut 0).unwrap();
-    let mut data = vec![0u8; WRITE_SIZE as usize];
-    let read_data = ext4.read_at(child_inode, 0 as usize, &mut data);
+    let mut data = vec![0u8; WRITE_SIZE];
+    let read_data = ext4.read_at(child_inode, 0_usize, &mut data);
     log::info!("read data  {:?}", &data[..10]);
 
+    // flush cached blocks to the image
+    ext4.sync().unwrap();
 }
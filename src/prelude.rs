@@ -23,6 +23,7 @@ pub(crate) use core::cmp::min;
 
 
 pub(crate) use bitflags::bitflags;
+pub(crate) use spin::Mutex;
 pub(crate) use log::{debug, info, trace, warn};
 
 pub(crate) use crate::utils::errors::*;
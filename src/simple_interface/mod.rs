@@ -9,9 +9,25 @@ use crate::utils::path_check;
 
 // export some definitions
 pub use crate::ext4_defs::Ext4;
+pub use crate::ext4_defs::Ext4MountOptions;
+pub use crate::ext4_defs::Ext4ChecksumPolicy;
+pub use crate::ext4_defs::Ext4Superblock;
+pub use crate::ext4_defs::Ext4FeatureCompat;
+pub use crate::ext4_defs::Ext4FeatureIncompat;
+pub use crate::ext4_defs::Ext4FeatureRoCompat;
 pub use crate::ext4_defs::BLOCK_SIZE;
 pub use crate::ext4_defs::BlockDevice;
 pub use crate::ext4_defs::InodeFileType;
+pub use crate::ext4_defs::{RENAME_EXCHANGE, RENAME_NOREPLACE};
+pub use crate::ext4_defs::O_NOFOLLOW;
+pub use crate::ext4_defs::{XATTR_CREATE, XATTR_REPLACE};
+pub use crate::ext4_defs::{FALLOC_FL_KEEP_SIZE, FALLOC_FL_PUNCH_HOLE, FALLOC_FL_ZERO_RANGE};
+pub use crate::ext4_defs::{FALLOC_FL_COLLAPSE_RANGE, FALLOC_FL_INSERT_RANGE};
+pub use crate::ext4_defs::{SEEK_SET, SEEK_CUR, SEEK_END, SEEK_DATA, SEEK_HOLE};
+pub use crate::ext4_defs::{Ext4FiemapExtent, FIEMAP_FLAG_SYNC, FIEMAP_FLAG_METADATA};
+pub use crate::ext4_defs::{FIEMAP_EXTENT_LAST, FIEMAP_EXTENT_UNWRITTEN, FIEMAP_EXTENT_MERGED, FIEMAP_EXTENT_METADATA};
+pub use crate::ext4_defs::{Ext4Acl, Ext4AclEntry, EXT4_XATTR_POSIX_ACL_ACCESS, EXT4_XATTR_POSIX_ACL_DEFAULT};
+pub use crate::ext4_defs::{ACL_USER_OBJ, ACL_USER, ACL_GROUP_OBJ, ACL_GROUP, ACL_MASK, ACL_OTHER, ACL_UNDEFINED_ID};
 
 
 /// simple interface for ext4
@@ -59,14 +75,37 @@ impl Ext4 {
 
         let iflags = self.ext4_parse_flags(flags).unwrap();
 
-        let filetype = InodeFileType::S_IFDIR;
-
         let mut create = false;
         if iflags & O_CREAT != 0 {
             create = true;
         }
 
-        self.generic_open(path, &mut parent_inode_num, create, filetype.bits(), &mut 0)
+        if iflags & (O_WRONLY | O_RDWR) != 0 {
+            self.check_writable()?;
+        }
+
+        // follow symlinks to an existing file, create it otherwise
+        match self.resolve_path(ROOT_INODE, path, iflags) {
+            Err(e) if e.error() == Errno::ENOENT && create => {
+                // a dangling link creates the file it points to
+                match self.resolve_create_path(ROOT_INODE, path, iflags) {
+                    Ok((mut parent, name)) => {
+                        self.generic_open(&name, &mut parent, create, filetype.bits(), &mut 0)
+                    }
+                    // missing directories on the way are created
+                    Err(e) if e.error() == Errno::ENOENT => {
+                        let inode = self.generic_open(path, &mut parent_inode_num, create, filetype.bits(), &mut 0)?;
+                        // never hand out a link whose target could not be created
+                        if self.get_inode_ref(inode)?.inode.is_link() {
+                            return Err(e);
+                        }
+                        Ok(inode)
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            r => r,
+        }
     }
 
     /// Create a new directory at the specified path.
@@ -81,8 +120,15 @@ impl Ext4 {
     /// * `Result<u32>` - The inode number of the newly created directory if successful, 
     ///   or an error (`Errno::EEXIST`) if the directory already exists.
     pub fn ext4_dir_mk(&self, path: &str) -> Result<u32> {
+        self.check_writable()?;
+
         let mut search_result = Ext4DirSearchResult::new(Ext4DirEntry::default());
         let r = self.dir_find_entry(ROOT_INODE, path, &mut search_result);
+        if let Err(e) = r {
+            if e.error() != Errno::ENOENT {
+                return Err(e);
+            }
+        }
         if r.is_ok() {
             return_errno!(Errno::EEXIST);
         }
@@ -106,9 +152,7 @@ impl Ext4 {
         &self,
         path: &str,
     ) -> Result<u32> {
-        let mut parent_inode_num = ROOT_INODE;
-        let filetype = InodeFileType::S_IFDIR;
-        self.generic_open(path, &mut parent_inode_num, false, filetype.bits(), &mut 0)
+        self.resolve_path(ROOT_INODE, path, 0)
     }
 
     /// Read data from a file starting from a given offset.
@@ -151,8 +195,190 @@ impl Ext4 {
         offset: i64,
         data: &[u8],
     ) -> Result<usize> {
+        self.check_writable()?;
+
         let write_size = self.write_at(ino as u32, offset as usize, data)?;
         Ok(write_size)
     }
 
+    /// Preallocate, punch, zero, collapse or insert space in a file, see
+    /// `Ext4::fallocate`.
+    ///
+    /// # Arguments
+    /// * `ino` - The inode number of the file.
+    /// * `mode` - 0, `FALLOC_FL_PUNCH_HOLE` or `FALLOC_FL_ZERO_RANGE`, with `FALLOC_FL_KEEP_SIZE`,
+    ///   or `FALLOC_FL_COLLAPSE_RANGE` or `FALLOC_FL_INSERT_RANGE` alone.
+    /// * `offset` - The start of the range.
+    /// * `len` - The length of the range.
+    ///
+    /// # Returns
+    /// * `Result<usize>` - Status of the operation.
+    pub fn ext4_fallocate(&self, ino: u64, mode: u32, offset: i64, len: i64) -> Result<usize> {
+        if offset < 0 || len <= 0 {
+            return_errno_with_message!(Errno::EINVAL, "invalid fallocate range");
+        }
+        self.fallocate(ino as u32, mode, offset as u64, len as u64)
+    }
+
+    /// Reposition a file offset, see `Ext4::lseek`.
+    ///
+    /// # Arguments
+    /// * `ino` - The inode number of the file.
+    /// * `pos` - The current file offset.
+    /// * `offset` - The offset relative to `whence`.
+    /// * `whence` - `SEEK_SET`, `SEEK_CUR`, `SEEK_END`, `SEEK_DATA` or `SEEK_HOLE`.
+    ///
+    /// # Returns
+    /// * `Result<u64>` - The new file offset.
+    pub fn ext4_lseek(&self, ino: u64, pos: u64, offset: i64, whence: u32) -> Result<u64> {
+        self.lseek(ino as u32, pos, offset, whence)
+    }
+
+    /// Get the extents mapping a byte range of a file, see `Ext4::fiemap`.
+    ///
+    /// # Arguments
+    /// * `ino` - The inode number of the file.
+    /// * `start` - The start of the range.
+    /// * `len` - The length of the range.
+    /// * `flags` - `FIEMAP_FLAG_SYNC` and the crate specific `FIEMAP_FLAG_METADATA`.
+    ///
+    /// # Returns
+    /// * `Result<Vec<Ext4FiemapExtent>>` - The extents overlapping the range.
+    pub fn ext4_fiemap(&self, ino: u64, start: u64, len: u64, flags: u32) -> Result<Vec<Ext4FiemapExtent>> {
+        self.fiemap(ino as u32, start, len, flags)
+    }
+
+    /// Copy a byte range between two files, keeping holes and unwritten
+    /// extents, see `Ext4::copy_file_range`.
+    ///
+    /// # Arguments
+    /// * `ino_in` - The inode number of the source file.
+    /// * `off_in` - The offset in the source file.
+    /// * `ino_out` - The inode number of the destination file.
+    /// * `off_out` - The offset in the destination file.
+    /// * `len` - The number of bytes to copy.
+    ///
+    /// # Returns
+    /// * `Result<u64>` - The number of bytes copied.
+    pub fn ext4_copy_file_range(&self, ino_in: u64, off_in: u64, ino_out: u64, off_out: u64, len: u64) -> Result<u64> {
+        self.copy_file_range(ino_in as u32, off_in, ino_out as u32, off_out, len, 0)
+    }
+
+    /// Rename a file or directory, replacing an existing target.
+    ///
+    /// Both paths start from the root directory (`ROOT_INODE`). See `Ext4::rename`
+    /// for the `flags`.
+    ///
+    /// # Arguments
+    /// * `old_path` - The path of the entry to rename.
+    /// * `new_path` - The new path of the entry.
+    /// * `flags` - 0, `RENAME_NOREPLACE` or `RENAME_EXCHANGE`.
+    ///
+    /// # Returns
+    /// * `Result<usize>` - Status of the operation.
+    pub fn ext4_rename(&self, old_path: &str, new_path: &str, flags: u32) -> Result<usize> {
+        self.check_writable()?;
+
+        let (parent, name) = self.ext4_split_path(old_path)?;
+        let (new_parent, new_name) = self.ext4_split_path(new_path)?;
+        self.rename(parent, name, new_parent, new_name, flags)
+    }
+
+    /// Create a symbolic link at `path` pointing to `target`.
+    ///
+    /// # Arguments
+    /// * `target` - The path the link points to, stored as is.
+    /// * `path` - The path of the new link, from the root directory (`ROOT_INODE`).
+    ///
+    /// # Returns
+    /// * `Result<u32>` - The inode number of the new link.
+    pub fn ext4_symlink(&self, target: &str, path: &str) -> Result<u32> {
+        self.check_writable()?;
+
+        let (parent, name) = self.ext4_split_path(path)?;
+        Ok(self.symlink(parent, name, target)?.inode_num)
+    }
+
+    /// Read the target of the symbolic link at `path`.
+    ///
+    /// The link itself is read, it is not followed.
+    ///
+    /// # Arguments
+    /// * `path` - The path of the link, from the root directory (`ROOT_INODE`).
+    ///
+    /// # Returns
+    /// * `Result<Vec<u8>>` - The target of the link.
+    pub fn ext4_readlink(&self, path: &str) -> Result<Vec<u8>> {
+        let mut parent_inode_num = ROOT_INODE;
+        let ino = self.generic_open(path, &mut parent_inode_num, false, 0, &mut 0)?;
+        self.readlink(ino)
+    }
+
+    /// Set an extended attribute of the file at `path`.
+    ///
+    /// # Arguments
+    /// * `path` - The path of the file, from the root directory (`ROOT_INODE`).
+    /// * `name` - The full attribute name, e.g. "user.mime_type".
+    /// * `value` - The attribute value.
+    /// * `flags` - 0, `XATTR_CREATE` or `XATTR_REPLACE`.
+    ///
+    /// # Returns
+    /// * `Result<usize>` - Status of the operation.
+    pub fn ext4_setxattr(&self, path: &str, name: &str, value: &[u8], flags: u32) -> Result<usize> {
+        let ino = self.resolve_path(ROOT_INODE, path, 0)?;
+        self.setxattr(ino, name, value, flags)
+    }
+
+    /// Get an extended attribute of the file at `path`.
+    ///
+    /// # Arguments
+    /// * `path` - The path of the file, from the root directory (`ROOT_INODE`).
+    /// * `name` - The full attribute name.
+    ///
+    /// # Returns
+    /// * `Result<Vec<u8>>` - The attribute value.
+    pub fn ext4_getxattr(&self, path: &str, name: &str) -> Result<Vec<u8>> {
+        let ino = self.resolve_path(ROOT_INODE, path, 0)?;
+        self.getxattr(ino, name)
+    }
+
+    /// List the extended attributes of the file at `path`.
+    ///
+    /// # Arguments
+    /// * `path` - The path of the file, from the root directory (`ROOT_INODE`).
+    ///
+    /// # Returns
+    /// * `Result<Vec<u8>>` - The attribute names, each ending with a NUL.
+    pub fn ext4_listxattr(&self, path: &str) -> Result<Vec<u8>> {
+        let ino = self.resolve_path(ROOT_INODE, path, 0)?;
+        self.listxattr(ino)
+    }
+
+    /// Remove an extended attribute of the file at `path`.
+    ///
+    /// # Arguments
+    /// * `path` - The path of the file, from the root directory (`ROOT_INODE`).
+    /// * `name` - The full attribute name.
+    ///
+    /// # Returns
+    /// * `Result<usize>` - Status of the operation.
+    pub fn ext4_removexattr(&self, path: &str, name: &str) -> Result<usize> {
+        let ino = self.resolve_path(ROOT_INODE, path, 0)?;
+        self.removexattr(ino, name)
+    }
+
+    /// Split a path into the inode number of its parent directory and its last component.
+    fn ext4_split_path<'a>(&self, path: &'a str) -> Result<(u32, &'a str)> {
+        let path = path.trim_end_matches('/');
+        let (dir, name) = match path.rfind('/') {
+            Some(i) => (&path[..i], &path[i + 1..]),
+            None => ("", path),
+        };
+
+        if dir.trim_matches('/').is_empty() {
+            return Ok((ROOT_INODE, name));
+        }
+        Ok((self.ext4_dir_open(dir)?, name))
+    }
+
 }
\ No newline at end of file
@@ -43,7 +43,7 @@ pub fn ext4_bmap_bit_find_clr(bmap: &[u8], sbit: u32, ebit: u32, bit_id: &mut u3
         }
 
         if ext4_bmap_is_bit_clr(bmap, i) {
-            *bit_id = sbit;
+            *bit_id = i;
             return true;
         }
 
@@ -86,4 +86,14 @@ pub fn ext4_bmap_bits_free(bmap: &mut [u8], start_bit: u32, end_bit: u32) {
     for bit in start_bit..=end_bit {
         ext4_bmap_bit_clr(bmap, bit);
     }
-}
\ No newline at end of file
+}
+
+/// 设置位图中的一段位
+/// 参数 bmap: Mutable reference to the bitmap array.
+/// 参数 start_bit: The start index of the bit range to set.
+/// 参数 end_bit: The end index of the bit range to set.
+pub fn ext4_bmap_bits_set(bmap: &mut [u8], start_bit: u32, end_bit: u32) {
+    for bit in start_bit..=end_bit {
+        ext4_bmap_bit_set(bmap, bit);
+    }
+}
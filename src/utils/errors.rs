@@ -1,6 +1,7 @@
 
 
 /// Ext4Error number.
+#[allow(clippy::upper_case_acronyms)]
 #[repr(i32)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Errno {
@@ -33,7 +34,12 @@ pub enum Errno {
     EROFS = 30,        /* Read-only file system */
     EMLINK = 31,       /* Too many links */
     EPIPE = 32,        /* Broken pipe */
+    ERANGE = 34,       /* Math result not representable */
     ENAMETOOLONG = 36, /* File name too long */
+    ENOTEMPTY = 39,    /* Directory not empty */
+    ELOOP = 40,        /* Too many symbolic links encountered */
+    EBADR = 53,        /* Invalid request descriptor */
+    ENODATA = 61,      /* No data available */
     ENOTSUP   = 95,   /* Not supported */
 }
 
@@ -0,0 +1,191 @@
+use crate::ext4_defs::*;
+
+/// Default seed when the superblock `hash_seed` is all zero.
+const DX_HASH_DEFAULT_SEED: [u32; 4] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476];
+
+/// The "legacy" hash, r5 style.
+fn dx_hack_hash(name: &[u8], signed: bool) -> u32 {
+    let mut hash0: u32 = 0x12a3fe2d;
+    let mut hash1: u32 = 0x37abe8f9;
+
+    for &c in name {
+        let c = if signed { c as i8 as i32 } else { c as i32 };
+        let mut hash = hash1.wrapping_add(hash0 ^ (c.wrapping_mul(7152373) as u32));
+        if hash & 0x80000000 != 0 {
+            hash = hash.wrapping_sub(0x7fffffff);
+        }
+        hash1 = hash0;
+        hash0 = hash;
+    }
+
+    hash0 << 1
+}
+
+/// Pack at most `num * 4` bytes of `msg` into `buf`, padded with the length.
+fn str2hashbuf(msg: &[u8], buf: &mut [u32], num: usize, signed: bool) {
+    let mut pad = msg.len() as u32 | ((msg.len() as u32) << 8);
+    pad |= pad << 16;
+
+    let len = msg.len().min(num * 4);
+    let mut val = pad;
+    let mut idx = 0;
+    for (i, &c) in msg[..len].iter().enumerate() {
+        let c = if signed { c as i8 as i32 as u32 } else { c as u32 };
+        val = c.wrapping_add(val << 8);
+        if i % 4 == 3 {
+            buf[idx] = val;
+            idx += 1;
+            val = pad;
+        }
+    }
+    if idx < num {
+        buf[idx] = val;
+        idx += 1;
+    }
+    while idx < num {
+        buf[idx] = pad;
+        idx += 1;
+    }
+}
+
+fn tea_transform(buf: &mut [u32; 4], input: &[u32]) {
+    let mut sum: u32 = 0;
+    let (mut b0, mut b1) = (buf[0], buf[1]);
+    let (a, b, c, d) = (input[0], input[1], input[2], input[3]);
+
+    for _ in 0..16 {
+        sum = sum.wrapping_add(0x9E3779B9);
+        b0 = b0.wrapping_add(
+            (b1 << 4).wrapping_add(a) ^ b1.wrapping_add(sum) ^ (b1 >> 5).wrapping_add(b),
+        );
+        b1 = b1.wrapping_add(
+            (b0 << 4).wrapping_add(c) ^ b0.wrapping_add(sum) ^ (b0 >> 5).wrapping_add(d),
+        );
+    }
+
+    buf[0] = buf[0].wrapping_add(b0);
+    buf[1] = buf[1].wrapping_add(b1);
+}
+
+fn half_md4_transform(buf: &mut [u32; 4], input: &[u32]) {
+    const K1: u32 = 0;
+    const K2: u32 = 0o13240474631;
+    const K3: u32 = 0o15666365641;
+
+    let f = |x: u32, y: u32, z: u32| z ^ (x & (y ^ z));
+    let g = |x: u32, y: u32, z: u32| (x & y).wrapping_add((x ^ y) & z);
+    let h = |x: u32, y: u32, z: u32| x ^ y ^ z;
+    let round = |a: u32, fv: u32, x: u32, s: u32| a.wrapping_add(fv).wrapping_add(x).rotate_left(s);
+
+    let (mut a, mut b, mut c, mut d) = (buf[0], buf[1], buf[2], buf[3]);
+
+    // Round 1
+    for i in [0, 4] {
+        a = round(a, f(b, c, d), input[i].wrapping_add(K1), 3);
+        d = round(d, f(a, b, c), input[i + 1].wrapping_add(K1), 7);
+        c = round(c, f(d, a, b), input[i + 2].wrapping_add(K1), 11);
+        b = round(b, f(c, d, a), input[i + 3].wrapping_add(K1), 19);
+    }
+
+    // Round 2
+    for i in [1, 0] {
+        a = round(a, g(b, c, d), input[i].wrapping_add(K2), 3);
+        d = round(d, g(a, b, c), input[i + 2].wrapping_add(K2), 5);
+        c = round(c, g(d, a, b), input[i + 4].wrapping_add(K2), 9);
+        b = round(b, g(c, d, a), input[i + 6].wrapping_add(K2), 13);
+    }
+
+    // Round 3
+    for (i, j, k, l) in [(3, 7, 2, 6), (1, 5, 0, 4)] {
+        a = round(a, h(b, c, d), input[i].wrapping_add(K3), 3);
+        d = round(d, h(a, b, c), input[j].wrapping_add(K3), 9);
+        c = round(c, h(d, a, b), input[k].wrapping_add(K3), 11);
+        b = round(b, h(c, d, a), input[l].wrapping_add(K3), 15);
+    }
+
+    buf[0] = buf[0].wrapping_add(a);
+    buf[1] = buf[1].wrapping_add(b);
+    buf[2] = buf[2].wrapping_add(c);
+    buf[3] = buf[3].wrapping_add(d);
+}
+
+/// Compute the htree hash of a directory entry name.
+///
+/// Params:
+/// name: &[u8] - entry name
+/// version: u8 - one of the `EXT4_DX_HASH_*` versions
+/// seed: &[u32; 4] - superblock `hash_seed`
+///
+/// Returns:
+/// `Option<(u32, u32)>` - major and minor hash, `None` if the version is not supported
+pub fn ext4_dx_hash(name: &[u8], version: u8, seed: &[u32; 4]) -> Option<(u32, u32)> {
+    let mut buf = if seed.iter().any(|&s| s != 0) {
+        *seed
+    } else {
+        DX_HASH_DEFAULT_SEED
+    };
+    let mut input = [0u32; 8];
+
+    let (hash, minor_hash) = match version {
+        EXT4_DX_HASH_LEGACY | EXT4_DX_HASH_LEGACY_UNSIGNED => {
+            (dx_hack_hash(name, version == EXT4_DX_HASH_LEGACY), 0)
+        }
+        EXT4_DX_HASH_HALF_MD4 | EXT4_DX_HASH_HALF_MD4_UNSIGNED => {
+            let signed = version == EXT4_DX_HASH_HALF_MD4;
+            // the padding depends on the remaining length, not the chunk length
+            let mut p = name;
+            while !p.is_empty() {
+                str2hashbuf(p, &mut input, 8, signed);
+                half_md4_transform(&mut buf, &input);
+                p = &p[p.len().min(32)..];
+            }
+            (buf[1], buf[2])
+        }
+        EXT4_DX_HASH_TEA | EXT4_DX_HASH_TEA_UNSIGNED => {
+            let signed = version == EXT4_DX_HASH_TEA;
+            let mut p = name;
+            while !p.is_empty() {
+                str2hashbuf(p, &mut input, 4, signed);
+                tea_transform(&mut buf, &input);
+                p = &p[p.len().min(16)..];
+            }
+            (buf[0], buf[1])
+        }
+        _ => return None,
+    };
+
+    // the low bit is reserved for the collision flag in index entries
+    let mut hash = hash & !1;
+    if hash == EXT4_HTREE_EOF_32BIT << 1 {
+        hash = (EXT4_HTREE_EOF_32BIT - 1) << 1;
+    }
+
+    Some((hash, minor_hash))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dx_hash() {
+        // reference values from e2fsprogs `debugfs -R "dx_hash -h <alg> <name>"`
+        let seed = [0u32; 4];
+        let long = b"a_much_longer_file_name_that_spans_chunks_0123456789";
+        let cases: [(&[u8], u8, u32, u32); 9] = [
+            (b"hello", EXT4_DX_HASH_LEGACY, 0x32252546, 0),
+            (long, EXT4_DX_HASH_LEGACY, 0x39ef5256, 0),
+            ("é".as_bytes(), EXT4_DX_HASH_LEGACY, 0x11083c86, 0),
+            (b"hello", EXT4_DX_HASH_HALF_MD4, 0x1746da32, 0x420013b5),
+            (long, EXT4_DX_HASH_HALF_MD4, 0x39e2f0bc, 0x2750bd6e),
+            ("é".as_bytes(), EXT4_DX_HASH_HALF_MD4, 0x89d4704e, 0x75d52d82),
+            (b"hello", EXT4_DX_HASH_TEA, 0x6f5bb1a8, 0x231917c2),
+            (long, EXT4_DX_HASH_TEA, 0x8229fd08, 0x85b382bf),
+            ("é".as_bytes(), EXT4_DX_HASH_TEA, 0x591e9bd6, 0xf780721f),
+        ];
+        for (name, version, hash, minor) in cases {
+            assert_eq!(ext4_dx_hash(name, version, &seed), Some((hash, minor)));
+        }
+        assert_eq!(ext4_dx_hash(b"hello", EXT4_DX_HASH_SIPHASH, &seed), None);
+    }
+}
@@ -2,10 +2,12 @@ pub mod bitmap;
 pub mod crc;
 pub mod path;
 pub mod errors;
+pub mod hash;
 
 
 
 pub use bitmap::*;
 pub use crc::*;
 pub use path::*;
-pub use errors::*;
\ No newline at end of file
+pub use errors::*;
+pub use hash::*;
\ No newline at end of file
@@ -0,0 +1,108 @@
+#!/bin/sh
+# Regenerate the filesystem images used by the unit tests.
+#
+# ext4.img     8M, 1k blocks, default ext4 features with a journal, empty
+# indirect.img 4M, 1k blocks, no extents, files mapped by indirect blocks
+# journal.img  8M, 1k blocks, like ext4.img with a dirty journal to replay
+# linux.img    8M, 1k blocks, like ext4.img with files made by Linux tools
+# groups.img   4M, 1k blocks, four groups of 128 inodes, the last three
+#              still INODE_UNINIT
+set -e
+cd "$(dirname "$0")"
+
+export E2FSPROGS_FAKE_TIME=1700000000
+uuid=6c1a5f0e-5a5c-4d6e-9a57-3b8f0d2c1e01
+hash_seed=0b1c2d3e-4f50-4617-8293-a4b5c6d7e8f9
+
+rm -f ext4.img
+dd if=/dev/zero of=ext4.img bs=1M count=8 status=none
+mkfs.ext4 -q -F -b 1024 -U $uuid -E hash_seed=$hash_seed ext4.img
+
+src=$(mktemp -d)
+python3 - "$src" <<'PY'
+import os, sys
+src = sys.argv[1]
+def pattern(n, seed):
+    return bytes((i * 7 + seed) % 251 for i in range(n))
+# direct blocks only
+open(os.path.join(src, "direct"), "wb").write(pattern(5000, 1))
+# up to the single indirect block
+open(os.path.join(src, "indirect"), "wb").write(pattern(200000, 2))
+# up to the double indirect block
+open(os.path.join(src, "dindirect"), "wb").write(pattern(400000, 3))
+# data at 0, 100000 and 300000 with holes in between and at the end
+with open(os.path.join(src, "holey"), "wb") as f:
+    for off in (0, 100000, 300000):
+        f.seek(off)
+        f.write(pattern(3000, off % 251))
+    f.truncate(350000)
+# a fast link and a slow one, mapped by a direct block
+os.symlink("direct", os.path.join(src, "fastlink"))
+os.symlink("./" * 40 + "indirect", os.path.join(src, "slowlink"))
+PY
+rm -f indirect.img
+dd if=/dev/zero of=indirect.img bs=1M count=4 status=none
+mkfs.ext4 -q -F -b 1024 -O ^extent,^64bit,^has_journal -U $uuid -E hash_seed=$hash_seed -d "$src" indirect.img
+rm -rf "$src"
+
+# three one block files; the journal logs new content for `replayed` and
+# `revoked` in transaction 1, revokes the block of `revoked` in transaction 2
+# and logs `uncommitted` in transaction 3 without a commit block
+src=$(mktemp -d)
+new=$(mktemp)
+python3 - "$src" "$new" <<'PY'
+import os, sys
+src, new = sys.argv[1:]
+def pattern(n, seed):
+    return bytes((i * 7 + seed) % 251 for i in range(n))
+for seed, name in enumerate(("replayed", "revoked", "uncommitted"), 1):
+    open(os.path.join(src, name), "wb").write(pattern(1024, seed))
+open(new, "wb").write(bytes([0x5a]) * 2048)
+PY
+rm -f journal.img
+dd if=/dev/zero of=journal.img bs=1M count=8 status=none
+mkfs.ext4 -q -F -b 1024 -U $uuid -E hash_seed=$hash_seed -d "$src" journal.img
+bmap() { debugfs -R "bmap $1 0" journal.img 2>/dev/null; }
+debugfs -w -f - journal.img >/dev/null <<EOF
+jo -c
+jw -b $(bmap replayed),$(bmap revoked) $new
+jw -r $(bmap revoked)
+jw -b $(bmap uncommitted) -c $new
+jc
+EOF
+rm -rf "$src" "$new"
+
+src=$(mktemp -d)
+value=$(mktemp)
+python3 - "$src" "$value" <<'PY'
+import os, sys
+src, value = sys.argv[1:]
+def pattern(n, seed):
+    return bytes((i * 7 + seed) % 251 for i in range(n))
+open(value, "wb").write(pattern(200, 2))
+os.mkdir(os.path.join(src, "dir"))
+open(os.path.join(src, "dir", "file"), "wb").write(pattern(3000, 1))
+# the target of `fast` fits in the inode, the one of `slow` needs a block
+os.symlink("dir/file", os.path.join(src, "fast"))
+os.symlink("./" * 40 + "dir/file", os.path.join(src, "slow"))
+os.symlink("/dir", os.path.join(src, "dirlink"))
+# enough empty files for an htree with several leaves
+os.mkdir(os.path.join(src, "htree"))
+for i in range(500):
+    open(os.path.join(src, "htree", "file%04d" % i), "wb").close()
+PY
+rm -f linux.img
+dd if=/dev/zero of=linux.img bs=1M count=8 status=none
+mkfs.ext4 -q -F -b 1024 -U $uuid -E hash_seed=$hash_seed -d "$src" linux.img
+# `user.small` fits in the inode, `user.big` goes to an xattr block
+debugfs -w -f - linux.img >/dev/null <<EOF
+ea_set dir/file user.small hello
+ea_set -f $value dir/file user.big
+EOF
+# index the directories of more than one block, exit status 1 means fixed
+e2fsck -fyD linux.img >/dev/null || [ $? -eq 1 ]
+rm -rf "$src" "$value"
+
+rm -f groups.img
+dd if=/dev/zero of=groups.img bs=1M count=4 status=none
+mkfs.ext4 -q -F -b 1024 -g 1024 -N 512 -U $uuid -E hash_seed=$hash_seed groups.img